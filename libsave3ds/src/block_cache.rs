@@ -0,0 +1,446 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use crate::sd_nand_common::*;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Length of a cached block.
+const BLOCK_LEN: usize = 0x1000;
+
+/// Hit and miss counters of the block cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// Number of block accesses served from the cache.
+    pub hits: u64,
+    /// Number of blocks read from the underlying file.
+    pub misses: u64,
+    /// Number of dirty blocks written back to the underlying file.
+    pub write_backs: u64,
+}
+
+/// The configuration and the counters shared by all files opened with the block cache.
+#[derive(Default)]
+pub(crate) struct BlockCache {
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    write_backs: AtomicU64,
+}
+
+impl BlockCache {
+    /// Creates a block cache that keeps up to `capacity` bytes of every opened file.
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            ..BlockCache::default()
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            write_backs: self.write_backs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Puts a cache layer on top of `data`.
+    pub fn wrap(self: &Arc<Self>, data: Arc<dyn RandomAccessFile>) -> Arc<dyn RandomAccessFile> {
+        Arc::new(CacheFile::new(data, self.clone()))
+    }
+}
+
+struct CacheBlock {
+    data: Vec<u8>,
+    /// The order in which the block was first written since it was last written back,
+    /// or None if it is clean.
+    dirty: Option<u64>,
+}
+
+/// Implements `RandomAccessFile` layer that keeps recently used blocks in memory.
+/// Writes stay in memory until the block is evicted or the file is committed or dropped.
+///
+/// Dirty blocks are written back in the order they were first written, so that the underlying file
+/// sees data written before a commit record, such as a header, before the record itself.
+pub(crate) struct CacheFile {
+    data: Arc<dyn RandomAccessFile>,
+    len: usize,
+    blocks: Mutex<LruCache<usize, CacheBlock>>,
+    next_seq: AtomicU64,
+    cache: Arc<BlockCache>,
+}
+
+impl CacheFile {
+    pub fn new(data: Arc<dyn RandomAccessFile>, cache: Arc<BlockCache>) -> CacheFile {
+        let len = data.len();
+        let capacity = NonZeroUsize::new(cache.capacity / BLOCK_LEN).unwrap_or(NonZeroUsize::MIN);
+        CacheFile {
+            data,
+            len,
+            blocks: Mutex::new(LruCache::new(capacity)),
+            next_seq: AtomicU64::new(0),
+            cache,
+        }
+    }
+
+    fn block_range(&self, index: usize) -> std::ops::Range<usize> {
+        index * BLOCK_LEN..std::cmp::min((index + 1) * BLOCK_LEN, self.len)
+    }
+
+    /// Marks a block dirty, keeping the order of its first write since it was last written back.
+    fn mark_dirty(&self, block: &mut CacheBlock) {
+        if block.dirty.is_none() {
+            block.dirty = Some(self.next_seq.fetch_add(1, Ordering::Relaxed));
+        }
+    }
+
+    /// Inserts a block. If a dirty block is evicted, it is written back
+    /// after all blocks first written before it.
+    fn insert(
+        &self,
+        blocks: &mut LruCache<usize, CacheBlock>,
+        index: usize,
+        block: CacheBlock,
+    ) -> Result<(), Error> {
+        if let Some((evicted, block)) = blocks.push(index, block) {
+            if let (true, Some(seq)) = (evicted != index, block.dirty) {
+                self.write_back(blocks, seq)?;
+                self.data.write(evicted * BLOCK_LEN, &block.data)?;
+                self.cache.write_backs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the blocks `begin..end` that are not cached yet into the cache.
+    /// Consecutive missing blocks are read with a single call.
+    fn fill(
+        &self,
+        blocks: &mut LruCache<usize, CacheBlock>,
+        begin: usize,
+        end: usize,
+    ) -> Result<(), Error> {
+        let mut index = begin;
+        while index < end {
+            if blocks.contains(&index) {
+                index += 1;
+                continue;
+            }
+            let run_end = (index..end).find(|i| blocks.contains(i)).unwrap_or(end);
+            let run_begin = index * BLOCK_LEN;
+            let mut run = vec![0; self.block_range(run_end - 1).end - run_begin];
+            self.data.read(run_begin, &mut run)?;
+            self.cache
+                .misses
+                .fetch_add((run_end - index) as u64, Ordering::Relaxed);
+            for (i, chunk) in (index..run_end).zip(run.chunks(BLOCK_LEN)) {
+                let block = CacheBlock {
+                    data: chunk.to_vec(),
+                    dirty: None,
+                };
+                self.insert(blocks, i, block)?;
+            }
+            index = run_end;
+        }
+        Ok(())
+    }
+
+    /// Writes back the dirty blocks first written before `before`, in the order they were first written.
+    /// Blocks adjacent in both order and position are merged into a single call.
+    fn write_back(
+        &self,
+        blocks: &mut LruCache<usize, CacheBlock>,
+        before: u64,
+    ) -> Result<(), Error> {
+        let mut dirty: Vec<(u64, usize)> = blocks
+            .iter()
+            .filter_map(|(&index, block)| match block.dirty {
+                Some(seq) if seq < before => Some((seq, index)),
+                _ => None,
+            })
+            .collect();
+        dirty.sort_unstable();
+        let mut i = 0;
+        while i < dirty.len() {
+            let mut run_end = i + 1;
+            while run_end < dirty.len() && dirty[run_end].1 == dirty[run_end - 1].1 + 1 {
+                run_end += 1;
+            }
+            let mut run = vec![];
+            for (_, index) in &dirty[i..run_end] {
+                let block = blocks.peek_mut(index).unwrap();
+                run.extend_from_slice(&block.data);
+                block.dirty = None;
+            }
+            self.data.write(dirty[i].1 * BLOCK_LEN, &run)?;
+            self.cache
+                .write_backs
+                .fetch_add((run_end - i) as u64, Ordering::Relaxed);
+            i = run_end;
+        }
+        Ok(())
+    }
+
+    /// Writes all dirty blocks back.
+    fn flush(&self) -> Result<(), Error> {
+        self.write_back(&mut self.blocks.lock().unwrap(), u64::MAX)
+    }
+}
+
+impl RandomAccessFile for CacheFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let (begin_block, end_block) = (pos / BLOCK_LEN, (end - 1) / BLOCK_LEN + 1);
+        let mut blocks = self.blocks.lock().unwrap();
+        if end_block - begin_block > blocks.cap().get() {
+            // The range doesn't fit in the cache. Write back the dirty blocks and read around
+            // the cache.
+            drop(blocks);
+            self.flush()?;
+            return self.data.read(pos, buf);
+        }
+        // Looking up the cached blocks first keeps them from being evicted by the missing ones.
+        let hits = (begin_block..end_block)
+            .filter(|i| blocks.get(i).is_some())
+            .count();
+        self.cache.hits.fetch_add(hits as u64, Ordering::Relaxed);
+        self.fill(&mut blocks, begin_block, end_block)?;
+        for index in begin_block..end_block {
+            let range = self.block_range(index);
+            let (from, to) = (
+                std::cmp::max(range.start, pos),
+                std::cmp::min(range.end, end),
+            );
+            let block = blocks.get(&index).unwrap();
+            buf[from - pos..to - pos]
+                .copy_from_slice(&block.data[from - range.start..to - range.start]);
+        }
+        Ok(())
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let (begin_block, end_block) = (pos / BLOCK_LEN, (end - 1) / BLOCK_LEN + 1);
+        let mut blocks = self.blocks.lock().unwrap();
+        if end_block - begin_block > blocks.cap().get() {
+            // The range doesn't fit in the cache. Write back the dirty blocks first to keep
+            // the order, then write through and update the cached blocks.
+            self.write_back(&mut blocks, u64::MAX)?;
+            self.data.write(pos, buf)?;
+            for index in begin_block..end_block {
+                if let Some(block) = blocks.peek_mut(&index) {
+                    let range = self.block_range(index);
+                    let (from, to) = (
+                        std::cmp::max(range.start, pos),
+                        std::cmp::min(range.end, end),
+                    );
+                    block.data[from - range.start..to - range.start]
+                        .copy_from_slice(&buf[from - pos..to - pos]);
+                }
+            }
+            return Ok(());
+        }
+        for index in begin_block..end_block {
+            let range = self.block_range(index);
+            let (from, to) = (
+                std::cmp::max(range.start, pos),
+                std::cmp::min(range.end, end),
+            );
+            if let Some(block) = blocks.get_mut(&index) {
+                self.cache.hits.fetch_add(1, Ordering::Relaxed);
+                block.data[from - range.start..to - range.start]
+                    .copy_from_slice(&buf[from - pos..to - pos]);
+                self.mark_dirty(block);
+            } else if from == range.start && to == range.end {
+                let mut block = CacheBlock {
+                    data: buf[from - pos..to - pos].to_vec(),
+                    dirty: None,
+                };
+                self.mark_dirty(&mut block);
+                self.insert(&mut blocks, index, block)?;
+            } else {
+                self.fill(&mut blocks, index, index + 1)?;
+                let block = blocks.get_mut(&index).unwrap();
+                block.data[from - range.start..to - range.start]
+                    .copy_from_slice(&buf[from - pos..to - pos]);
+                self.mark_dirty(block);
+            }
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn commit(&self) -> Result<(), Error> {
+        self.flush()?;
+        self.data.commit()
+    }
+}
+
+impl Drop for CacheFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("Failed to write back cached blocks: {}", e);
+        }
+    }
+}
+
+/// Implements `SdNandFileSystem` on top of another one, putting a block cache on every opened file.
+pub(crate) struct CachedSdNand {
+    pub base: Arc<dyn SdNandFileSystem>,
+    pub cache: Arc<BlockCache>,
+}
+
+impl SdNandFileSystem for CachedSdNand {
+    fn open(&self, path: &[&str], write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        Ok(self.cache.wrap(self.base.open(path, write)?))
+    }
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.base.create(path, len)
+    }
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        self.base.remove(path)
+    }
+    fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+        self.base.remove_dir(path)
+    }
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        self.base.list(path)
+    }
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+        self.base.replace(path, len, fill)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block_cache::*;
+    use crate::memory_file::MemoryFile;
+
+    #[test]
+    fn fuzz() {
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let len = rng.gen_range(1..0x10000);
+            let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            let data = Arc::new(MemoryFile::new(init.clone()));
+            let cache = Arc::new(BlockCache::new(rng.gen_range(0..0x8000)));
+            let plain = MemoryFile::new(init);
+            crate::random_access_file::fuzzer(
+                CacheFile::new(data.clone(), cache.clone()),
+                |file| file,
+                |file| file.commit().unwrap(),
+                || CacheFile::new(data.clone(), cache.clone()),
+                plain,
+            );
+        }
+    }
+
+    #[test]
+    fn write_back() {
+        let data = Arc::new(MemoryFile::new(vec![0; BLOCK_LEN * 4]));
+        let cache = Arc::new(BlockCache::new(BLOCK_LEN * 2));
+        let file = CacheFile::new(data.clone(), cache.clone());
+        let mut buf = [0; 4];
+
+        file.write(1, &[1, 2, 3]).unwrap();
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+        data.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(
+            cache.stats(),
+            BlockCacheStats {
+                hits: 1,
+                misses: 1,
+                write_backs: 0
+            }
+        );
+
+        // evicting the dirty block writes it back
+        file.read(BLOCK_LEN, &mut buf).unwrap();
+        file.read(BLOCK_LEN * 2, &mut buf).unwrap();
+        data.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+        assert_eq!(cache.stats().write_backs, 1);
+
+        file.write(BLOCK_LEN * 2, &[4; 4]).unwrap();
+        file.commit().unwrap();
+        data.read(BLOCK_LEN * 2, &mut buf).unwrap();
+        assert_eq!(buf, [4; 4]);
+        assert_eq!(
+            cache.stats(),
+            BlockCacheStats {
+                hits: 2,
+                misses: 3,
+                write_backs: 2
+            }
+        );
+    }
+
+    // Records the offsets of the writes reaching the underlying file.
+    struct WriteLog {
+        data: MemoryFile,
+        writes: Mutex<Vec<usize>>,
+    }
+
+    impl RandomAccessFile for WriteLog {
+        fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+            self.data.read(pos, buf)
+        }
+        fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+            self.writes.lock().unwrap().push(pos);
+            self.data.write(pos, buf)
+        }
+        fn len(&self) -> usize {
+            self.data.len()
+        }
+        fn commit(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_order() {
+        let log = Arc::new(WriteLog {
+            data: MemoryFile::new(vec![0; BLOCK_LEN * 4]),
+            writes: Mutex::new(vec![]),
+        });
+        let cache = Arc::new(BlockCache::new(BLOCK_LEN * 2));
+        let file = CacheFile::new(log.clone(), cache);
+
+        // evicting a dirty block writes back the blocks written before it first
+        file.write(0, &[1]).unwrap();
+        file.write(BLOCK_LEN, &[2]).unwrap();
+        file.read(0, &mut [0]).unwrap();
+        file.read(BLOCK_LEN * 2, &mut [0]).unwrap();
+        assert_eq!(*log.writes.lock().unwrap(), vec![0, BLOCK_LEN]);
+
+        // commit writes back in the order the blocks were first written, not by position
+        log.writes.lock().unwrap().clear();
+        file.write(BLOCK_LEN * 3, &[3]).unwrap();
+        file.write(BLOCK_LEN * 2, &[4]).unwrap();
+        file.write(BLOCK_LEN * 3 + 1, &[5]).unwrap();
+        file.commit().unwrap();
+        assert_eq!(
+            *log.writes.lock().unwrap(),
+            vec![BLOCK_LEN * 3, BLOCK_LEN * 2]
+        );
+    }
+}
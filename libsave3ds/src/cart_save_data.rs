@@ -1,10 +1,16 @@
 use crate::aes_ctr_file::*;
+use crate::check::{Check, CheckParts};
+use crate::difi_partition::*;
 use crate::error::*;
+use crate::extent::*;
 use crate::file_system::*;
+use crate::inspect::InspectNode;
+use crate::ivfc_level::LevelReport;
 use crate::random_access_file::*;
 use crate::save_data::*;
+use crate::save_ext_common::*;
 use crate::wear_leveling::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub(crate) struct CartFormat {
     pub wear_leveling: bool,
@@ -16,13 +22,14 @@ pub(crate) struct CartFormat {
 /// A wrapper of [`SaveData`](../save_data/struct.SaveData.html),
 /// specialized for cartridge save data. Implements [`FileSystem`](../file_system/trait.FileSystem.html).
 pub struct CartSaveData {
-    wear_leveling: Option<Rc<WearLeveling>>,
+    len: usize,
+    wear_leveling: Option<Arc<WearLeveling>>,
     save_data: SaveData,
 }
 
 impl CartSaveData {
     pub(crate) fn format(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         &CartFormat {
             wear_leveling,
             key,
@@ -31,15 +38,15 @@ impl CartSaveData {
         }: &CartFormat,
         param: &SaveDataFormatParam,
     ) -> Result<(), Error> {
-        let (wear_leveling, file): (_, Rc<dyn RandomAccessFile>) = if wear_leveling {
-            Rc::new(WearLeveling::format(file.clone())?);
-            let wear_leveling = Rc::new(WearLeveling::new(file)?);
+        let (wear_leveling, file): (_, Arc<dyn RandomAccessFile>) = if wear_leveling {
+            Arc::new(WearLeveling::format(file.clone())?);
+            let wear_leveling = Arc::new(WearLeveling::new(file)?);
             (Some(wear_leveling.clone()), wear_leveling)
         } else {
             (None, file)
         };
 
-        let save = Rc::new(AesCtrFile::new(file, key, [0; 16], repeat_ctr));
+        let save = Arc::new(AesCtrFile::new(file, key, [0; 16], repeat_ctr));
 
         SaveData::format(save, SaveDataType::Cart(key_cmac), param)?;
         if let Some(wear_leveling) = wear_leveling {
@@ -49,28 +56,129 @@ impl CartSaveData {
     }
 
     pub(crate) fn new(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         &CartFormat {
             wear_leveling,
             key,
             key_cmac,
             repeat_ctr,
         }: &CartFormat,
+        inversion: SelectorInversion,
+        salvage: bool,
     ) -> Result<CartSaveData, Error> {
-        let (wear_leveling, file): (_, Rc<dyn RandomAccessFile>) = if wear_leveling {
-            let wear_leveling = Rc::new(WearLeveling::new(file)?);
+        let len = file.len();
+        let (wear_leveling, file): (_, Arc<dyn RandomAccessFile>) = if wear_leveling {
+            let wear_leveling = Arc::new(WearLeveling::new(file)?);
             (Some(wear_leveling.clone()), wear_leveling)
         } else {
             (None, file)
         };
 
-        let save = Rc::new(AesCtrFile::new(file, key, [0; 16], repeat_ctr));
+        let save = Arc::new(AesCtrFile::new(file, key, [0; 16], repeat_ctr));
 
         Ok(CartSaveData {
+            len,
             wear_leveling,
-            save_data: SaveData::new(save, SaveDataType::Cart(key_cmac))?,
+            save_data: SaveData::new(save, SaveDataType::Cart(key_cmac), inversion, salvage)?,
         })
     }
+
+    /// Returns the parameters this save data was formatted with.
+    pub fn format_param(&self) -> Result<SaveDataFormatParam, Error> {
+        self.save_data.format_param()
+    }
+
+    /// Returns the length of the raw cartridge save image.
+    pub fn image_len(&self) -> usize {
+        self.len
+    }
+
+    /// See [`SaveData::salvage_report`](../save_data/struct.SaveData.html#method.salvage_report).
+    pub fn salvage_report(&self) -> Result<Vec<BrokenBlock>, Error> {
+        self.save_data.salvage_report()
+    }
+
+    /// See [`SaveData::file_extents`](../save_data/struct.SaveData.html#method.file_extents).
+    pub fn file_extents(&self) -> Result<Vec<FileExtents>, Error> {
+        self.save_data.file_extents()
+    }
+
+    /// See [`SaveData::fragmentation`](../save_data/struct.SaveData.html#method.fragmentation).
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        self.save_data.fragmentation()
+    }
+
+    /// See [`SaveData::verify_all`](../save_data/struct.SaveData.html#method.verify_all).
+    pub fn verify_all(&self) -> Result<Vec<LevelReport>, Error> {
+        self.save_data.verify_all()
+    }
+
+    /// Describes the wear leveling map, if any, along with
+    /// [`SaveData::inspect`](../save_data/struct.SaveData.html#method.inspect).
+    pub fn inspect(&self) -> Result<InspectNode, Error> {
+        let mut node = InspectNode::new("cartridge save").hex("image_len", self.len as u64);
+        if let Some(wear_leveling) = &self.wear_leveling {
+            node = node.child(wear_leveling.inspect()?);
+        }
+        Ok(node.child(self.save_data.inspect()?))
+    }
+
+    /// See [`SaveData::defragment`](../save_data/struct.SaveData.html#method.defragment).
+    pub fn defragment(self) -> Result<CartSaveData, Error> {
+        let save_data = self.save_data.defragment()?;
+        if let Some(wear_leveling) = &self.wear_leveling {
+            wear_leveling.commit()?;
+        }
+        Ok(CartSaveData {
+            len: self.len,
+            wear_leveling: self.wear_leveling,
+            save_data,
+        })
+    }
+
+    /// Recalculates all hashes and signatures, and commits them.
+    pub fn rehash(&self) -> Result<(), Error> {
+        let _lock = self.save_data.lock_write();
+        self.save_data.rehash_locked()?;
+        if let Some(wear_leveling) = &self.wear_leveling {
+            wear_leveling.commit()?;
+        }
+        Ok(())
+    }
+
+    /// See [`SaveData::compare_generation`](../save_data/struct.SaveData.html#method.compare_generation).
+    pub fn compare_generation(&self, other: &CartSaveData) -> Result<Vec<GenerationReport>, Error> {
+        self.save_data.compare_generation(&other.save_data)
+    }
+
+    /// Formats `file` as a new cartridge save data with `param`,
+    /// and copies all directories and files of this save data into it.
+    /// Files failing hash verification are copied as read, and counted in the report.
+    pub(crate) fn repack(
+        &self,
+        file: Arc<dyn RandomAccessFile>,
+        format: &CartFormat,
+        param: &SaveDataFormatParam,
+    ) -> Result<CopyReport, Error> {
+        CartSaveData::format(file.clone(), format, param)?;
+        let save = CartSaveData::new(file, format, SelectorInversion::default(), false)?;
+        let report = copy_tree(
+            self,
+            &self.open_root()?,
+            &save,
+            &save.open_root()?,
+            CopyMode::Merge,
+            |name| Ok(*name),
+        )?;
+        save.commit()?;
+        Ok(report)
+    }
+}
+
+impl Check for CartSaveData {
+    fn check_parts(&self) -> Result<CheckParts, Error> {
+        self.save_data.check_parts()
+    }
 }
 
 impl FileSystem for CartSaveData {
@@ -87,13 +195,32 @@ impl FileSystem for CartSaveData {
     }
 
     fn commit(&self) -> Result<(), Error> {
-        self.save_data.commit()?;
+        let _lock = self.save_data.lock_write();
+        self.save_data.commit_locked()?;
+        if let Some(wear_leveling) = &self.wear_leveling {
+            wear_leveling.commit()?;
+        }
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<(), Error> {
+        let _lock = self.save_data.lock_write();
+        self.save_data.begin_locked()?;
         if let Some(wear_leveling) = &self.wear_leveling {
             wear_leveling.commit()?;
         }
         Ok(())
     }
 
+    fn rollback(&self) -> Result<(), Error> {
+        let _lock = self.save_data.lock_write();
+        self.save_data.rollback_locked()?;
+        if let Some(wear_leveling) = &self.wear_leveling {
+            wear_leveling.rollback()?;
+        }
+        Ok(())
+    }
+
     fn stat(&self) -> Result<Stat, Error> {
         self.save_data.stat()
     }
@@ -149,18 +276,230 @@ mod test {
             };
 
             let len = [0x20_000, 0x80_000, 0x100_000][rng.gen_range(0..3)];
-            let raw = Rc::new(MemoryFile::new(vec![0; len]));
+            let raw = Arc::new(MemoryFile::new(vec![0; len]));
             CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
-            let file_system = CartSaveData::new(raw.clone(), &cart_format).unwrap();
+            let file_system = CartSaveData::new(
+                raw.clone(),
+                &cart_format,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(file_system.format_param().unwrap(), param);
+            assert_eq!(file_system.image_len(), len);
 
             crate::file_system::test::fuzzer(
                 file_system,
                 param.max_dir,
                 param.max_file,
-                || CartSaveData::new(raw.clone(), &cart_format).unwrap(),
+                || {
+                    CartSaveData::new(
+                        raw.clone(),
+                        &cart_format,
+                        SelectorInversion::default(),
+                        false,
+                    )
+                    .unwrap()
+                },
                 gen_name,
                 gen_len,
             );
         }
     }
+
+    #[test]
+    fn inspect() {
+        use crate::inspect::InspectValue;
+        use crate::memory_file::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let cart_format = CartFormat {
+            wear_leveling: true,
+            key: [1; 16],
+            key_cmac: [2; 16],
+            repeat_ctr: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x20_000]));
+        CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+        let save =
+            CartSaveData::new(raw, &cart_format, SelectorInversion::default(), false).unwrap();
+        let node = save.inspect().unwrap();
+        let wear_leveling = &node.children[0];
+        assert_eq!(wear_leveling.name, "wear leveling");
+        assert_eq!(
+            wear_leveling.get("large_save"),
+            Some(&InspectValue::Bool(false))
+        );
+        let blocks: Vec<_> = wear_leveling
+            .children
+            .iter()
+            .filter(|c| c.name == "virtual block")
+            .collect();
+        assert_eq!(blocks.len(), 0x20 - 1);
+        let mut physical: Vec<_> = blocks.iter().map(|b| b.offset.unwrap()).collect();
+        physical.sort_unstable();
+        physical.dedup();
+        assert_eq!(physical.len(), blocks.len());
+        assert!(physical.iter().all(|p| (0x1000..0x20_000).contains(p)));
+        assert_eq!(node.children[1].name, "DISA");
+    }
+
+    #[test]
+    fn concurrent_commit() {
+        use crate::memory_file::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let cart_format = CartFormat {
+            wear_leveling: true,
+            key: [1; 16],
+            key_cmac: [2; 16],
+            repeat_ctr: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x20_000]));
+        CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+        let save = Arc::new(
+            CartSaveData::new(
+                raw.clone(),
+                &cart_format,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap(),
+        );
+        let threads: Vec<_> = (0..3u8)
+            .map(|t| {
+                let save = save.clone();
+                std::thread::spawn(move || {
+                    for round in 0..10u8 {
+                        let data = vec![t * 16 + round; 500 + round as usize];
+                        write_all(&*save, &format!("{}", t), &data).unwrap();
+                        save.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        drop(save);
+
+        let save =
+            CartSaveData::new(raw, &cart_format, SelectorInversion::default(), false).unwrap();
+        for t in 0..3u8 {
+            assert_eq!(
+                read_to_vec(&save, &format!("{}", t)).unwrap(),
+                vec![t * 16 + 9; 509]
+            );
+        }
+        assert_eq!(save.check().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn transaction() {
+        use crate::file_system::*;
+        use crate::memory_file::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let cart_format = CartFormat {
+            wear_leveling: true,
+            key: [1; 16],
+            key_cmac: [2; 16],
+            repeat_ctr: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x20_000]));
+        CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+        let open = || {
+            CartSaveData::new(
+                raw.clone(),
+                &cart_format,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap()
+        };
+        let save = open();
+        write_all(&save, "x", &[1; 2000]).unwrap();
+        save.commit().unwrap();
+        save.begin().unwrap();
+        write_all(&save, "x", &[2; 2000]).unwrap();
+        write_all(&save, "y", &[3; 100]).unwrap();
+        save.rollback().unwrap();
+        assert_eq!(read_to_vec(&save, "x").unwrap(), vec![1; 2000]);
+        assert!(!exists(&save, "y").unwrap());
+        drop(save);
+        let save = open();
+        assert_eq!(read_to_vec(&save, "x").unwrap(), vec![1; 2000]);
+        assert_eq!(save.check().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn crash_consistency() {
+        use crate::fault_file::*;
+        use crate::file_system::*;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        for &wear_leveling in &[false, true] {
+            let cart_format = CartFormat {
+                wear_leveling,
+                key: [1; 16],
+                key_cmac: [2; 16],
+                repeat_ctr: false,
+            };
+            let disk = Arc::new(FaultDisk::new());
+            let raw = disk.insert(&["cart"], vec![0; 0x20_000]);
+            CartSaveData::format(raw, &cart_format, &param).unwrap();
+            let open = |disk: &Arc<FaultDisk>| {
+                CartSaveData::new(
+                    disk.file(&["cart"]),
+                    &cart_format,
+                    SelectorInversion::default(),
+                    false,
+                )
+            };
+
+            let save = open(&disk).unwrap();
+            let old: Vec<u8> = (0..0x800).map(|_| rng.gen()).collect();
+            write_all(&save, "/a", &old).unwrap();
+            save.commit().unwrap();
+            drop(save);
+
+            let new_a: Vec<u8> = (0..0xA00).map(|_| rng.gen()).collect();
+            let change = |disk: &Arc<FaultDisk>| {
+                let save = open(disk)?;
+                write_all(&save, "/a", &new_a)?;
+                write_all(&save, "/b", &[3; 0x100])?;
+                save.commit()
+            };
+            let state = |disk: &Arc<FaultDisk>| snapshot(&open(disk)?, true);
+            // The signed header is written as one sector, which is not torn
+            crash_test_all(&disk, change, state, Tolerance::Exact, &[]);
+        }
+    }
 }
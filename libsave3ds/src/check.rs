@@ -0,0 +1,208 @@
+use crate::difi_partition::DifiPartition;
+use crate::error::*;
+use crate::fat::*;
+use crate::misc::*;
+use std::sync::Arc;
+
+/// Which metadata table an entry belongs to.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum MetaTableKind {
+    Dir,
+    File,
+}
+
+/// A structural problem found by `check`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum CheckIssue {
+    /// A chain in the allocation table has a broken node or an inconsistent back link.
+    /// `first_block` is the start of the chain, or `None` for the free list.
+    BrokenChain { first_block: Option<usize> },
+    /// A chain in the allocation table runs back into itself.
+    ChainLoop { first_block: Option<usize> },
+    /// A block is claimed by more than one chain, including the free list.
+    CrossLink { block: usize },
+    /// Some blocks belong to neither the free list nor any chain.
+    LostBlocks { count: usize },
+    /// The number of blocks in the free list differs from the data block count recorded in
+    /// the file system info minus the blocks allocated to the metadata tables and files.
+    FreeCountMismatch {
+        free_list: usize,
+        free_blocks: usize,
+    },
+    /// The entry count of a metadata table is out of range.
+    BrokenMetaTable { table: MetaTableKind },
+    /// The free entry list, a hash bucket chain or a sibling list points to a free,
+    /// out-of-range or already visited entry.
+    BrokenLink { table: MetaTableKind, ino: u32 },
+    /// An entry in use is not reachable from the root directory.
+    Unreachable { table: MetaTableKind, ino: u32 },
+    /// An entry in use can't be found through the hash table by its name.
+    Unhashed { table: MetaTableKind, ino: u32 },
+    /// An entry is listed under a directory other than its recorded parent.
+    WrongParent {
+        table: MetaTableKind,
+        ino: u32,
+        parent: u32,
+        listed_in: u32,
+    },
+    /// Two entries have the same parent and name.
+    DuplicateName {
+        table: MetaTableKind,
+        ino: u32,
+        other: u32,
+    },
+    /// The size of a file doesn't match the number of blocks allocated to it.
+    SizeMismatch { ino: u32, size: u64, blocks: usize },
+    /// An IVFC level 4 block holding data in use fails hash verification.
+    /// `partition` has the same meaning as in [`BrokenBlock`](../struct.BrokenBlock.html).
+    BrokenHash { partition: usize, index: usize },
+}
+
+/// An archive whose structure can be checked.
+pub trait Check {
+    /// Lists the hashed data in use and checks the metadata and the allocation table,
+    /// for [`check`](#method.check).
+    #[doc(hidden)]
+    fn check_parts(&self) -> Result<CheckParts, Error>;
+
+    /// Checks the structure of the archive: hash tables and directory tree of the metadata,
+    /// the allocation table, file sizes, and IVFC hashes of all data in use.
+    /// Returns all problems found, which is empty if the archive is consistent.
+    ///
+    /// Uncommitted changes are not hashed yet and would be reported as broken hashes,
+    /// so this should be called after committing. Missing or mismatched extdata sub-files
+    /// are not reported here. See [`ExtData::scan`](../struct.ExtData.html#method.scan).
+    fn check(&self) -> Result<Vec<CheckIssue>, Error> {
+        let parts = self.check_parts()?;
+        let mut issues = vec![];
+        for (index, partition, ranges) in parts.hashed.iter() {
+            issues.append(&mut check_hash(*index, partition, ranges)?);
+        }
+        match parts.structure {
+            Ok((mut structure_issues, hashed)) => {
+                issues.append(&mut structure_issues);
+                for (index, partition, ranges) in hashed.iter() {
+                    issues.append(&mut check_hash(*index, partition, ranges)?);
+                }
+            }
+            // the metadata lies in a broken block, which is reported above
+            Err(Error::HashMismatch(_)) if !issues.is_empty() => {}
+            Err(e) => return Err(e),
+        }
+        Ok(issues)
+    }
+}
+
+/// A partition with the ranges of it in use, labeled with its index as in
+/// [`CheckIssue::BrokenHash`].
+pub(crate) type HashedRanges = (usize, Arc<DifiPartition>, Vec<(usize, usize)>);
+
+/// What [`Check::check`] goes through, as gathered by each archive.
+pub struct CheckParts {
+    /// Data in use that can be located without reading the metadata.
+    pub(crate) hashed: Vec<HashedRanges>,
+    /// Issues found in the metadata and the allocation table, along with
+    /// data in use that is located through the metadata, or the error reading the metadata.
+    pub(crate) structure: Result<(Vec<CheckIssue>, Vec<HashedRanges>), Error>,
+}
+
+/// The block pointer a file entry holds when the file is empty.
+pub(crate) const EMPTY_FILE_BLOCK: u32 = 0x8000_0000;
+
+/// Checks the allocation table with the chains of the metadata tables in `tables`
+/// and of the files in `files`, given as `(ino, block, size)`,
+/// and checks that each file size fits its allocated blocks.
+/// `block_count` is the data block count recorded in the file system info.
+/// The ranges of the allocation table in use are added to `ranges`,
+/// with the table starting at `fat_offset` of the partition.
+pub(crate) fn check_fat_files(
+    fat: &Fat,
+    block_len: usize,
+    block_count: usize,
+    tables: &[u32],
+    files: &[(u32, u32, u64)],
+    fat_offset: usize,
+    ranges: &mut Vec<(usize, usize)>,
+) -> Result<Vec<CheckIssue>, Error> {
+    let mut issues = vec![];
+    let mut first_blocks: Vec<usize> = tables.iter().map(|&b| b as usize).collect();
+    let mut sized = vec![];
+    for &(ino, block, size) in files {
+        if block == EMPTY_FILE_BLOCK {
+            if size != 0 {
+                issues.push(CheckIssue::SizeMismatch {
+                    ino,
+                    size,
+                    blocks: 0,
+                });
+            }
+        } else {
+            sized.push((ino, size));
+            first_blocks.push(block as usize);
+        }
+    }
+
+    let fat_check = fat.check(&first_blocks, block_count, &mut issues)?;
+    for (&(ino, size), blocks) in sized.iter().zip(fat_check.chain_len[tables.len()..].iter()) {
+        if let Some(blocks) = *blocks {
+            if size == 0 || divide_up(size as usize, block_len) != blocks {
+                issues.push(CheckIssue::SizeMismatch { ino, size, blocks });
+            }
+        }
+    }
+    ranges.extend(
+        fat_check
+            .table_ranges
+            .into_iter()
+            .map(|(offset, len)| (fat_offset + offset, len)),
+    );
+    Ok(issues)
+}
+
+/// Appends the ranges of the data region, which starts at `data_offset` of the partition,
+/// that hold the first `len` bytes of the chain starting at `first_block`.
+/// Broken chains are skipped, as they are reported by `check_fat_files`.
+pub(crate) fn fat_file_ranges(
+    fat: &Arc<Fat>,
+    block_len: usize,
+    data_offset: usize,
+    first_block: u32,
+    len: usize,
+    ranges: &mut Vec<(usize, usize)>,
+) -> Result<(), Error> {
+    let file = match FatFile::open(fat.clone(), first_block as usize) {
+        Ok(file) => file,
+        Err(Error::BrokenFat(_)) | Err(Error::OutOfBound(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for (i, block) in file
+        .block_indices()
+        .take(divide_up(len, block_len))
+        .enumerate()
+    {
+        ranges.push((
+            data_offset + block * block_len,
+            std::cmp::min(block_len, len - i * block_len),
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies the IVFC hashes of `ranges` in a partition.
+///
+/// Only data in use is verified, because space that has never been written since formatting
+/// doesn't have valid hashes.
+fn check_hash(
+    partition_index: usize,
+    partition: &DifiPartition,
+    ranges: &[(usize, usize)],
+) -> Result<Vec<CheckIssue>, Error> {
+    Ok(partition
+        .broken_data_blocks(ranges)?
+        .into_iter()
+        .map(|index| CheckIssue::BrokenHash {
+            partition: partition_index,
+            index,
+        })
+        .collect())
+}
@@ -1,15 +1,20 @@
+use crate::check::*;
 use crate::diff::Diff;
+use crate::difi_partition::SelectorInversion;
 use crate::error::*;
+use crate::extent::*;
 use crate::fat::*;
 use crate::file_system::*;
 use crate::fs_meta::{self, DirInfo, FileInfo, FsInfo, ParentedKey};
+use crate::inspect::*;
+use crate::ivfc_level::LevelReport;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(ByteStruct, Clone, PartialEq)]
 #[byte_struct_le]
@@ -132,7 +137,7 @@ pub enum DbType {
 }
 
 struct FakeSizeFile {
-    parent: Rc<dyn RandomAccessFile>,
+    parent: Arc<dyn RandomAccessFile>,
     len: usize,
 }
 
@@ -173,21 +178,24 @@ impl Signer for DbSigner {
 }
 
 struct DbInner {
-    diff: Rc<Diff>,
-    fat: Rc<Fat>,
-    fs: Rc<FsMeta>,
+    lock: ArchiveLock,
+    diff: Arc<Diff>,
+    fat: Arc<Fat>,
+    fs: Arc<FsMeta>,
     block_len: usize,
     block_count: usize,
+    pre_len: usize,
+    db_type: DbType,
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for title database.
 pub struct Db {
-    center: Rc<DbInner>,
+    center: Arc<DbInner>,
 }
 
 impl Db {
     pub(crate) fn new(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         db_type: DbType,
         key: [u8; 16],
     ) -> Result<Db, Error> {
@@ -203,7 +211,16 @@ impl Db {
             }),
             key,
         );
-        let diff = Rc::new(Diff::new(file, Some(signer))?);
+        let diff = Arc::new(Diff::new(
+            file,
+            Some(signer),
+            SelectorInversion::default(),
+            false,
+        )?);
+        Db::from_diff(diff, db_type)
+    }
+
+    pub(crate) fn from_diff(diff: Arc<Diff>, db_type: DbType) -> Result<Db, Error> {
         let pre_len = if db_type == DbType::Ticket {
             0x10
         } else {
@@ -215,7 +232,7 @@ impl Db {
             diff.partition().read(0, &mut magic)?;
             if magic != *b"TICK" {
                 error!("Unexpected TICK magic {:?}", magic);
-                return make_error(Error::MagicMismatch);
+                return make_error(Error::MagicMismatch(Contexts::new()));
             }
         } else {
             let mut magic = [0; 8];
@@ -232,11 +249,14 @@ impl Db {
                 }
             {
                 error!("Unexpected database magic {:?}", magic);
-                return make_error(Error::MagicMismatch);
+                return make_error(Error::MagicMismatch(Contexts::new()));
             }
         }
 
-        let without_pre = Rc::new(SubFile::new(
+        if diff.partition().len() < pre_len {
+            return make_error(Error::SizeMismatch(Contexts::new()));
+        }
+        let without_pre = Arc::new(SubFile::new(
             diff.partition().clone(),
             pre_len,
             diff.partition().len() - pre_len,
@@ -245,7 +265,7 @@ impl Db {
         let header: DbHeader = read_struct(without_pre.as_ref(), 0)?;
         if header.magic != *b"BDRI" || header.version != 0x30000 {
             error!("Unexpected magic {:?} {:X}", header.magic, header.version);
-            return make_error(Error::MagicMismatch);
+            return make_error(Error::MagicMismatch(Contexts::new()));
         }
         let fs_info: FsInfo = read_struct(without_pre.as_ref(), header.fs_info_offset as usize)?;
         if fs_info.data_block_count != fs_info.fat_size {
@@ -253,40 +273,42 @@ impl Db {
                 "Unexpected data_block_count={}, fat_size={}",
                 fs_info.data_block_count, fs_info.fat_size
             );
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
 
-        let dir_hash = Rc::new(SubFile::new(
+        let dir_hash = Arc::new(SubFile::new(
             without_pre.clone(),
             fs_info.dir_hash_offset as usize,
             fs_info.dir_buckets as usize * 4,
         )?);
 
-        let file_hash = Rc::new(SubFile::new(
+        let file_hash = Arc::new(SubFile::new(
             without_pre.clone(),
             fs_info.file_hash_offset as usize,
             fs_info.file_buckets as usize * 4,
         )?);
 
-        let fat_table = Rc::new(SubFile::new(
+        let fat_table = Arc::new(SubFile::new(
             without_pre.clone(),
             fs_info.fat_offset as usize,
-            (fs_info.fat_size + 1) as usize * 8,
+            (fs_info.fat_size as usize + 1) * 8,
         )?);
 
         let data_offset = fs_info.data_offset as usize;
-        let data_len = (fs_info.data_block_count * fs_info.block_len) as usize;
-        let data_end = data_len + data_offset;
-        let data_delta = if without_pre.len() < data_end {
-            data_end - without_pre.len()
-        } else {
-            0
+        let data_len = fs_info.data_block_count as usize * fs_info.block_len as usize;
+        let data_end = match data_offset.checked_add(data_len) {
+            Some(data_end) => data_end,
+            None => return make_error(Error::OutOfBound(Contexts::new())),
         };
+        let data_delta = data_end.saturating_sub(without_pre.len());
+        if data_delta > data_len {
+            return make_error(Error::OutOfBound(Contexts::new()));
+        }
 
         info!("Database file end fixup: 0x{:x}", data_delta);
 
-        let data: Rc<dyn RandomAccessFile> = Rc::new(FakeSizeFile {
-            parent: Rc::new(SubFile::new(
+        let data: Arc<dyn RandomAccessFile> = Arc::new(FakeSizeFile {
+            parent: Arc::new(SubFile::new(
                 without_pre,
                 fs_info.data_offset as usize,
                 data_len - data_delta,
@@ -296,12 +318,12 @@ impl Db {
 
         let fat = Fat::new(fat_table, data, fs_info.block_len as usize)?;
 
-        let dir_table: Rc<dyn RandomAccessFile> = Rc::new(FatFile::open(
+        let dir_table: Arc<dyn RandomAccessFile> = Arc::new(FatFile::open(
             fat.clone(),
             fs_info.dir_table.block_index as usize,
         )?);
 
-        let file_table: Rc<dyn RandomAccessFile> = Rc::new(FatFile::open(
+        let file_table: Arc<dyn RandomAccessFile> = Arc::new(FatFile::open(
             fat.clone(),
             fs_info.file_table.block_index as usize,
         )?);
@@ -309,40 +331,277 @@ impl Db {
         let fs = FsMeta::new(dir_hash, dir_table, file_hash, file_table)?;
 
         Ok(Db {
-            center: Rc::new(DbInner {
+            center: Arc::new(DbInner {
+                lock: ArchiveLock::default(),
                 diff,
                 fat,
                 fs,
                 block_len: fs_info.block_len as usize,
                 block_count: fs_info.data_block_count as usize,
+                pre_len,
+                db_type,
             }),
         })
     }
+
+    /// Hashes every block of the partition table and of all IVFC levels,
+    /// spread over all CPU cores, and reports the broken blocks of each level.
+    /// If the metadata is broken, all broken blocks are reported as in use.
+    pub fn verify_all(&self) -> Result<Vec<LevelReport>, Error> {
+        let _lock = self.center.lock.read();
+        let used = match self.used_ranges() {
+            Ok((ranges, Ok(_))) => Some(ranges),
+            Ok((_, Err(Error::HashMismatch(_)))) | Err(Error::HashMismatch(_)) => None,
+            Ok((_, Err(e))) | Err(e) => return Err(e),
+        };
+        self.center.diff.verify_all(0, used.as_deref())
+    }
+
+    /// Describes the container headers, the DPFS selectors and the file system layout
+    /// of this database.
+    pub fn inspect(&self) -> Result<InspectNode, Error> {
+        let center = &self.center;
+        let partition = center.diff.partition();
+        let header: DbHeader = read_struct(partition.as_ref(), center.pre_len)?;
+        let fs_info: FsInfo = read_struct(
+            partition.as_ref(),
+            center.pre_len + header.fs_info_offset as usize,
+        )?;
+        let mut files = vec![];
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            files.push((ino, info.block as usize));
+        }
+        let (mut data, fs_data) = inspect_fs(
+            &fs_info,
+            header.fs_info_offset as usize,
+            &center.fat,
+            None,
+            &files,
+        )?;
+        data.insert(
+            0,
+            InspectNode::new("BDRI header")
+                .at(0, DbHeader::BYTE_LEN)
+                .hex("version", header.version)
+                .hex("image_size", header.image_size)
+                .hex("image_block_len", header.image_block_len),
+        );
+        data.push(fs_data);
+        let mut bdri =
+            InspectNode::new("BDRI").at(center.pre_len, partition.len() - center.pre_len);
+        bdri.children = data;
+        center.diff.inspect(vec![
+            InspectNode::new("database header").at(0, center.pre_len),
+            bdri,
+        ])
+    }
+
+    fn file_inos(&self) -> Result<Vec<u32>, Error> {
+        DirMeta::open_ino(self.center.fs.clone(), 1)?.list_files_recursive()
+    }
+
+    /// Lists the blocks allocated to each file, merged into runs of consecutive blocks,
+    /// ordered by inode.
+    pub fn file_extents(&self) -> Result<Vec<FileExtents>, Error> {
+        let center = &self.center;
+        let partition = center.diff.partition();
+        let header: DbHeader = read_struct(partition.as_ref(), center.pre_len)?;
+        let fs_info: FsInfo = read_struct(
+            partition.as_ref(),
+            center.pre_len + header.fs_info_offset as usize,
+        )?;
+        let mut files = vec![];
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            files.push((ino, info.block, info.size));
+        }
+        file_extents(
+            &center.fat,
+            center.block_len,
+            0,
+            center.pre_len + fs_info.data_offset as usize,
+            &files,
+        )
+    }
+
+    /// Reports how fragmented the files and the free space are.
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        fragmentation(&self.center.fat, &self.file_extents()?)
+    }
+
+    /// Rearranges the allocation table so that each file and metadata table occupies consecutive
+    /// blocks, updates the metadata pointing to them, and commits.
+    /// The result is checked afterwards, and the reopened database is returned.
+    ///
+    /// See [`SaveData::defragment`](../save_data/struct.SaveData.html#method.defragment)
+    /// for the requirements.
+    pub fn defragment(self) -> Result<Db, Error> {
+        let diff = self.center.diff.clone();
+        let partition = diff.partition().clone();
+        let pre_len = self.center.pre_len;
+        let db_type = self.center.db_type;
+        let header: DbHeader = read_struct(partition.as_ref(), pre_len)?;
+        let fs_info_offset = pre_len + header.fs_info_offset as usize;
+        let mut fs_info: FsInfo = read_struct(partition.as_ref(), fs_info_offset)?;
+        if !self.check_fs(&fs_info, &mut vec![])?.is_empty() {
+            return make_error(Error::BrokenFat(Contexts::new()));
+        }
+
+        let mut files = vec![];
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(self.center.fs.clone(), ino)?.get_info()?;
+            if info.block != EMPTY_FILE_BLOCK {
+                files.push((ino, info.block as usize));
+            }
+        }
+        files.sort_unstable();
+        let mut first_blocks = vec![
+            fs_info.dir_table.block_index as usize,
+            fs_info.file_table.block_index as usize,
+        ];
+        first_blocks.extend(files.iter().map(|&(_, block)| block));
+
+        let center = match Arc::try_unwrap(self.center) {
+            Ok(center) => center,
+            Err(_) => return make_error(Error::Busy),
+        };
+        center.fat.defragment(&first_blocks, |new_first_blocks| {
+            for (&(ino, _), &block) in files.iter().zip(&new_first_blocks[2..]) {
+                let meta = FileMeta::open_ino(center.fs.clone(), ino)?;
+                let mut info = meta.get_info()?;
+                info.block = block as u32;
+                meta.set_info(info)?;
+            }
+            fs_info.dir_table.block_index = new_first_blocks[0] as u32;
+            fs_info.file_table.block_index = new_first_blocks[1] as u32;
+            write_struct(partition.as_ref(), fs_info_offset, fs_info)
+        })?;
+        drop(center);
+
+        // Everything opened before refers to the old block locations.
+        let db = Db::from_diff(diff.clone(), db_type)?;
+        let fs_info: FsInfo = read_struct(partition.as_ref(), fs_info_offset)?;
+        if !db.check_fs(&fs_info, &mut vec![])?.is_empty() {
+            error!("Defragmented database is inconsistent");
+            return make_error(Error::BrokenFat(Contexts::new()));
+        }
+        diff.commit()?;
+        let issues = db.check()?;
+        if !issues.is_empty() {
+            error!("Defragmented database failed checking: {:?}", issues);
+            return make_error(Error::BrokenFat(Contexts::new()));
+        }
+        Ok(db)
+    }
+
+    // Lists the ranges in use,
+    // along with the result of checking the metadata and the allocation table.
+    #[allow(clippy::type_complexity)]
+    fn used_ranges(&self) -> Result<(Vec<(usize, usize)>, Result<Vec<CheckIssue>, Error>), Error> {
+        let center = &self.center;
+        let partition = center.diff.partition();
+        let header: DbHeader = read_struct(partition.as_ref(), center.pre_len)?;
+        let fs_info_offset = center.pre_len + header.fs_info_offset as usize;
+        let fs_info: FsInfo = read_struct(partition.as_ref(), fs_info_offset)?;
+        let mut ranges = vec![
+            (0, center.pre_len + DbHeader::BYTE_LEN),
+            (fs_info_offset, FsInfo::BYTE_LEN),
+            (
+                center.pre_len + fs_info.dir_hash_offset as usize,
+                fs_info.dir_buckets as usize * 4,
+            ),
+            (
+                center.pre_len + fs_info.file_hash_offset as usize,
+                fs_info.file_buckets as usize * 4,
+            ),
+        ];
+        let fs_issues = self.check_fs(&fs_info, &mut ranges);
+        Ok((ranges, fs_issues))
+    }
+
+    // Checks the metadata and the allocation table, and adds the ranges in use to `ranges`.
+    fn check_fs(
+        &self,
+        fs_info: &FsInfo,
+        ranges: &mut Vec<(usize, usize)>,
+    ) -> Result<Vec<CheckIssue>, Error> {
+        let center = &self.center;
+        let mut issues = vec![];
+        let mut files = vec![];
+        for ino in center.fs.check(&mut issues)? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            files.push((ino, info.block, info.size));
+        }
+
+        let (dir_len, file_len) = center.fs.used_table_len()?;
+        let tables = [
+            (fs_info.dir_table.block_index, dir_len),
+            (fs_info.file_table.block_index, file_len),
+        ];
+        let data_offset = center.pre_len + fs_info.data_offset as usize;
+        let used = tables.iter().cloned().chain(
+            files
+                .iter()
+                .filter(|&&(_, block, _)| block != EMPTY_FILE_BLOCK)
+                .map(|&(_, block, size)| (block, size as usize)),
+        );
+        for (block, len) in used {
+            fat_file_ranges(
+                &center.fat,
+                center.block_len,
+                data_offset,
+                block,
+                len,
+                ranges,
+            )?;
+        }
+
+        let tables = [
+            fs_info.dir_table.block_index,
+            fs_info.file_table.block_index,
+        ];
+        issues.append(&mut check_fat_files(
+            &center.fat,
+            center.block_len,
+            fs_info.data_block_count as usize,
+            &tables,
+            &files,
+            center.pre_len + fs_info.fat_offset as usize,
+            ranges,
+        )?);
+        Ok(issues)
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for title database file.
 pub struct File {
-    center: Rc<DbInner>,
+    center: Arc<DbInner>,
     meta: FileMeta,
     data: Option<FatFile>,
     len: usize,
 }
 
 impl File {
-    fn from_meta(center: Rc<DbInner>, meta: FileMeta) -> Result<File, Error> {
+    /// Returns whether `dir` belongs to the same opened archive as this file.
+    pub fn same_archive(&self, dir: &Dir) -> bool {
+        Arc::ptr_eq(&self.center, &dir.center)
+    }
+
+    fn from_meta(center: Arc<DbInner>, meta: FileMeta) -> Result<File, Error> {
         let info = meta.get_info()?;
         let len = info.size as usize;
-        let data = if info.block == 0x8000_0000 {
+        let data = if info.block == EMPTY_FILE_BLOCK {
             if len != 0 {
                 error!("Non-empty file with invalid pointer");
-                return make_error(Error::SizeMismatch);
+                return make_error(Error::SizeMismatch(Contexts::new()));
             }
             None
         } else {
             let fat_file = FatFile::open(center.fat.clone(), info.block as usize)?;
             if len == 0 || len > fat_file.len() {
                 error!("Empty file with valid pointer");
-                return make_error(Error::SizeMismatch);
+                return make_error(Error::SizeMismatch(Contexts::new()));
             }
             Some(fat_file)
         };
@@ -361,6 +620,7 @@ impl FileSystemFile for File {
     type DirType = Dir;
 
     fn rename(&mut self, parent: &Self::DirType, name: u64) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if parent.meta.open_sub_file(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -368,6 +628,7 @@ impl FileSystemFile for File {
     }
 
     fn get_parent_ino(&self) -> Result<u32, Error> {
+        let _lock = self.center.lock.read();
         self.meta.get_parent_ino()
     }
 
@@ -376,6 +637,8 @@ impl FileSystemFile for File {
     }
 
     fn delete(self) -> Result<(), Error> {
+        let center = self.center.clone();
+        let _lock = center.lock.write();
         if let Some(f) = self.data {
             f.delete()?;
         }
@@ -383,6 +646,7 @@ impl FileSystemFile for File {
     }
 
     fn resize(&mut self, len: usize) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if len == self.len {
             return Ok(());
         }
@@ -402,7 +666,7 @@ impl FileSystemFile for File {
         } else if len == 0 {
             // non-zero => zero
             self.data.take().unwrap().delete()?;
-            info.block = 0x8000_0000;
+            info.block = EMPTY_FILE_BLOCK;
         } else {
             self.data
                 .as_mut()
@@ -419,15 +683,17 @@ impl FileSystemFile for File {
     }
 
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len {
-            return make_error(Error::OutOfBound);
+        let _lock = self.center.lock.read();
+        if pos.checked_add(buf.len()).is_none_or(|end| end > self.len) {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         self.data.as_ref().unwrap().read(pos, buf)
     }
 
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len {
-            return make_error(Error::OutOfBound);
+        let _lock = self.center.lock.write();
+        if pos.checked_add(buf.len()).is_none_or(|end| end > self.len) {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         self.data.as_ref().unwrap().write(pos, buf)
     }
@@ -447,16 +713,24 @@ impl FileSystemFile for File {
 /// Title database does not support directories other than the root directory, so this struct only
 /// serves as an interface to access files the database contains.
 pub struct Dir {
-    center: Rc<DbInner>,
+    center: Arc<DbInner>,
     meta: DirMeta,
 }
 
+impl Dir {
+    /// Returns whether `other` belongs to the same opened archive as this directory.
+    pub fn same_archive(&self, other: &Dir) -> bool {
+        Arc::ptr_eq(&self.center, &other.center)
+    }
+}
+
 impl FileSystemDir for Dir {
     /// The name type for title database directory is a placeholder.
     type NameType = u64;
     type FileType = File;
 
     fn get_parent_ino(&self) -> Result<u32, Error> {
+        let _lock = self.center.lock.read();
         self.meta.get_parent_ino()
     }
 
@@ -465,6 +739,7 @@ impl FileSystemDir for Dir {
     }
 
     fn open_sub_file(&self, name: u64) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.read();
         File::from_meta(self.center.clone(), self.meta.open_sub_file(name)?)
     }
 
@@ -474,15 +749,17 @@ impl FileSystemDir for Dir {
     }
 
     fn list_sub_file(&self) -> Result<Vec<(u64, u32)>, Error> {
+        let _lock = self.center.lock.read();
         self.meta.list_sub_file()
     }
 
     fn new_sub_file(&self, name: u64, len: usize) -> Result<Self::FileType, Error> {
-        if self.open_sub_file(name).is_ok() || self.open_sub_dir(name).is_ok() {
+        let _lock = self.center.lock.write();
+        if self.meta.open_sub_file(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
         let (fat_file, block) = if len == 0 {
-            (None, 0x8000_0000)
+            (None, EMPTY_FILE_BLOCK)
         } else {
             let (fat_file, block) = FatFile::create(
                 self.center.fat.clone(),
@@ -531,12 +808,23 @@ impl FileSystemDir for Dir {
     }
 }
 
+impl Check for Db {
+    fn check_parts(&self) -> Result<CheckParts, Error> {
+        let (ranges, structure) = self.used_ranges()?;
+        Ok(CheckParts {
+            hashed: vec![(0, self.center.diff.partition().clone(), ranges)],
+            structure: structure.map(|issues| (issues, vec![])),
+        })
+    }
+}
+
 impl FileSystem for Db {
     type FileType = File;
     type DirType = Dir;
     type NameType = u64;
 
     fn open_file(&self, ino: u32) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.read();
         let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
         File::from_meta(self.center.clone(), meta)
     }
@@ -544,6 +832,7 @@ impl FileSystem for Db {
     /// Opens the directory with the specified inode.
     /// Only the root directory (`ino = 1`) is supported.
     fn open_dir(&self, ino: u32) -> Result<Self::DirType, Error> {
+        let _lock = self.center.lock.read();
         let meta = DirMeta::open_ino(self.center.fs.clone(), ino)?;
         Ok(Dir {
             center: self.center.clone(),
@@ -556,10 +845,27 @@ impl FileSystem for Db {
     /// If the save data is dropped with uncommitted change,
     /// all data rolls back to the state the last time `commit` is called.
     fn commit(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         self.center.diff.commit()
     }
 
+    fn begin(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        self.center.diff.begin()
+    }
+
+    /// Fails with `Error::Busy` if any file is still open.
+    fn rollback(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        if Arc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        self.center.diff.rollback()?;
+        self.center.fat.rollback()
+    }
+
     fn stat(&self) -> Result<Stat, Error> {
+        let _lock = self.center.lock.read();
         let meta_stat = self.center.fs.stat()?;
         Ok(Stat {
             block_len: self.center.block_len,
@@ -572,3 +878,263 @@ impl FileSystem for Db {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::db::*;
+    use crate::difi_partition::DifiPartitionParam;
+    use crate::fs_meta::OffsetOrFatFile;
+    use crate::memory_file::MemoryFile;
+
+    // Builds an unsigned ticket database with room for `max_file` files
+    // and `data_blocks` blocks of file data.
+    fn format(max_file: usize, data_blocks: usize) -> Arc<MemoryFile> {
+        let pre_len = 0x10;
+        let block_len = 0x80;
+        let dir_buckets = 1;
+        let file_buckets = 13;
+        let fs_info_offset = DbHeader::BYTE_LEN;
+        let dir_hash_offset = fs_info_offset + FsInfo::BYTE_LEN;
+        let file_hash_offset = dir_hash_offset + dir_buckets * 4;
+        let fat_offset = file_hash_offset + file_buckets * 4;
+        let dir_table_len = 2 * (DbDirKey::BYTE_LEN + DbDir::BYTE_LEN + 4);
+        let file_table_len = (max_file + 1) * (DbFileKey::BYTE_LEN + DbFile::BYTE_LEN + 4);
+        let data_block_count = divide_up(dir_table_len, block_len)
+            + divide_up(file_table_len, block_len)
+            + data_blocks;
+        let data_offset = align_up(fat_offset + (data_block_count + 1) * 8, block_len);
+        let param = DifiPartitionParam {
+            dpfs_level2_block_len: 128,
+            dpfs_level3_block_len: 4096,
+            ivfc_level1_block_len: 512,
+            ivfc_level2_block_len: 512,
+            ivfc_level3_block_len: 4096,
+            ivfc_level4_block_len: 4096,
+            data_len: pre_len + data_offset + data_block_count * block_len,
+            external_ivfc_level4: true,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; Diff::calculate_size(&param)]));
+        Diff::format(raw.clone(), None, &param, 0).unwrap();
+        let diff = Diff::new(raw.clone(), None, SelectorInversion::default(), false).unwrap();
+        let partition = diff.partition().clone();
+        partition.write(0, b"TICK").unwrap();
+        let without_pre: Arc<dyn RandomAccessFile> =
+            Arc::new(SubFile::new(partition.clone(), pre_len, partition.len() - pre_len).unwrap());
+        let sub = |offset, len| -> Arc<dyn RandomAccessFile> {
+            Arc::new(SubFile::new(without_pre.clone(), offset, len).unwrap())
+        };
+
+        let fat_table = sub(fat_offset, (data_block_count + 1) * 8);
+        Fat::format(fat_table.as_ref()).unwrap();
+        let fat = Fat::new(
+            fat_table,
+            sub(data_offset, data_block_count * block_len),
+            block_len,
+        )
+        .unwrap();
+        let (dir_table, dir_table_block) =
+            FatFile::create(fat.clone(), divide_up(dir_table_len, block_len)).unwrap();
+        let (file_table, file_table_block) =
+            FatFile::create(fat, divide_up(file_table_len, block_len)).unwrap();
+        let dir_table_combo = OffsetOrFatFile {
+            block_index: dir_table_block as u32,
+            block_count: (dir_table.len() / block_len) as u32,
+        };
+        let file_table_combo = OffsetOrFatFile {
+            block_index: file_table_block as u32,
+            block_count: (file_table.len() / block_len) as u32,
+        };
+        FsMeta::format(
+            sub(dir_hash_offset, dir_buckets * 4),
+            Arc::new(dir_table),
+            2,
+            sub(file_hash_offset, file_buckets * 4),
+            Arc::new(file_table),
+            max_file + 1,
+        )
+        .unwrap();
+
+        write_struct(
+            without_pre.as_ref(),
+            0,
+            DbHeader {
+                magic: *b"BDRI",
+                version: 0x30000,
+                fs_info_offset: fs_info_offset as u64,
+                image_size: (without_pre.len() / block_len) as u64,
+                image_block_len: block_len as u32,
+                padding: 0,
+            },
+        )
+        .unwrap();
+        write_struct(
+            without_pre.as_ref(),
+            fs_info_offset,
+            FsInfo {
+                unknown: 0,
+                block_len: block_len as u32,
+                dir_hash_offset: dir_hash_offset as u64,
+                dir_buckets: dir_buckets as u32,
+                p0: 0,
+                file_hash_offset: file_hash_offset as u64,
+                file_buckets: file_buckets as u32,
+                p1: 0,
+                fat_offset: fat_offset as u64,
+                fat_size: data_block_count as u32,
+                p2: 0,
+                data_offset: data_offset as u64,
+                data_block_count: data_block_count as u32,
+                p3: 0,
+                dir_table: dir_table_combo,
+                max_dir: 0,
+                p4: 0,
+                file_table: file_table_combo,
+                max_file: max_file as u32,
+                p5: 0,
+            },
+        )
+        .unwrap();
+        diff.commit().unwrap();
+        raw
+    }
+
+    fn open(raw: &Arc<MemoryFile>) -> Db {
+        let diff = Diff::new(raw.clone(), None, SelectorInversion::default(), false).unwrap();
+        Db::from_diff(Arc::new(diff), DbType::Ticket).unwrap()
+    }
+
+    #[test]
+    fn file_extents() {
+        let db = open(&format(10, 40));
+        assert_eq!(db.check().unwrap(), vec![]);
+        let root = db.open_dir(1).unwrap();
+        let a = root.new_sub_file(1, 0x200).unwrap();
+        root.new_sub_file(2, 0x100).unwrap();
+        a.delete().unwrap();
+        let empty = root.new_sub_file(3, 0).unwrap().get_ino();
+        let c = root.new_sub_file(4, 0x400).unwrap();
+        let data: Vec<u8> = (0..0x400).map(|i| (i % 251) as u8).collect();
+        c.write(0, &data).unwrap();
+
+        let extents = db.file_extents().unwrap();
+        assert_eq!(extents.len(), 3);
+        let e = extents.iter().find(|e| e.ino == empty).unwrap();
+        assert!(e.extents.is_empty());
+        let e = extents.iter().find(|e| e.ino == c.get_ino()).unwrap();
+        assert_eq!(e.size, 0x400);
+        assert!(e.extents.len() > 1);
+        let mut read_back = vec![];
+        for extent in e.extents.iter() {
+            assert_eq!(extent.partition, 0);
+            let mut buf = vec![0; extent.block_count * 0x80];
+            db.center
+                .diff
+                .partition()
+                .read(extent.offset, &mut buf)
+                .unwrap();
+            read_back.append(&mut buf);
+        }
+        assert_eq!(read_back, data);
+
+        let stat = db.fragmentation().unwrap();
+        assert_eq!(stat.files, 2);
+        assert_eq!(stat.fragmented_files, 1);
+        assert_eq!(stat.file_extents, 1 + e.extents.len());
+        assert_eq!(stat.free_blocks, db.center.fat.free_blocks());
+    }
+
+    #[test]
+    fn defragment() {
+        let raw = format(10, 60);
+        let db = open(&raw);
+        let root = db.open_dir(1).unwrap();
+        let mut contents = vec![];
+        let mut files = vec![];
+        for i in 0..6u8 {
+            let len = 0x50 + i as usize * 0x180;
+            let file = root.new_sub_file(i as u64 + 1, len).unwrap();
+            file.write(0, &vec![i; len]).unwrap();
+            files.push(file);
+            contents.push((i as u64 + 1, vec![i; len]));
+        }
+        for (i, file) in files.into_iter().enumerate() {
+            if i % 2 == 0 {
+                file.delete().unwrap();
+            }
+        }
+        contents = contents.into_iter().skip(1).step_by(2).collect();
+        let file = root.new_sub_file(9, 0x900).unwrap();
+        file.write(0, &[9; 0x900]).unwrap();
+        contents.push((9, vec![9; 0x900]));
+        root.new_sub_file(10, 0).unwrap();
+        drop(file);
+        db.commit().unwrap();
+        assert!(db.fragmentation().unwrap().fragmented_files > 0);
+
+        let busy = root.open_sub_file(9).unwrap();
+        drop(root);
+        assert!(matches!(db.defragment(), Err(Error::Busy)));
+        drop(busy);
+
+        let db = open(&raw).defragment().unwrap();
+        let stat = db.fragmentation().unwrap();
+        assert_eq!(stat.fragmented_files, 0);
+        assert_eq!(stat.free_extents, 1);
+        assert_eq!(stat.largest_free_extent, stat.free_blocks);
+
+        let db = open(&raw);
+        assert_eq!(db.check().unwrap(), vec![]);
+        let root = db.open_dir(1).unwrap();
+        for (name, data) in contents {
+            let file = root.open_sub_file(name).unwrap();
+            let mut buf = vec![0; data.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, data);
+        }
+        assert_eq!(root.open_sub_file(10).unwrap().len(), 0);
+    }
+    #[test]
+    fn crash_consistency() {
+        use crate::fault_file::*;
+        let image = format(10, 60);
+        let mut data = vec![0; image.len()];
+        image.read(0, &mut data).unwrap();
+        let disk = Arc::new(FaultDisk::new());
+        disk.insert(&["db"], data);
+        let open = |disk: &Arc<FaultDisk>| {
+            let diff = Diff::new(
+                disk.file(&["db"]),
+                None,
+                SelectorInversion::default(),
+                false,
+            )?;
+            Db::from_diff(Arc::new(diff), DbType::Ticket)
+        };
+
+        let db = open(&disk).unwrap();
+        let root = db.open_dir(1).unwrap();
+        for i in 0..4u8 {
+            let file = root.new_sub_file(i as u64 + 1, 0x180).unwrap();
+            file.write(0, &[i; 0x180]).unwrap();
+        }
+        root.open_sub_file(2).unwrap().delete().unwrap();
+        drop(root);
+        db.commit().unwrap();
+        drop(db);
+
+        // The file system is written in place, sharing hash blocks with file data,
+        // so a crash can only be detected
+        let state = |disk: &Arc<FaultDisk>| snapshot(&open(disk)?, true);
+        let change = |disk: &Arc<FaultDisk>| {
+            let db = open(disk)?;
+            let root = db.open_dir(1)?;
+            root.open_sub_file(1)?.write(0x100, &[5; 0x80])?;
+            root.new_sub_file(6, 0x300)?.write(0, &[6; 0x300])?;
+            drop(root);
+            db.commit()
+        };
+        crash_test_all(&disk, change, state, Tolerance::Detected, &[0x11]);
+        let defragment = |disk: &Arc<FaultDisk>| open(disk)?.defragment().map(|_| ());
+        crash_test_all(&disk, defragment, state, Tolerance::Detected, &[0x11]);
+    }
+}
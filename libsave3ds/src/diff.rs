@@ -1,14 +1,19 @@
 use crate::difi_partition::*;
+use crate::disa::{salvage_table, stage_tables};
 use crate::dual_file::DualFile;
 use crate::error::*;
-use crate::ivfc_level::IvfcLevel;
+use crate::inspect::InspectNode;
+use crate::inverted_file::InvertedFile;
+use crate::ivfc_level::{IvfcLevel, LevelReport};
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::signed_file::*;
+use crate::staged_file::StagedFile;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 #[derive(ByteStruct)]
 #[byte_struct_le]
@@ -28,12 +33,17 @@ struct DiffHeader {
 
 /// DIFF container format that contains one DIFI partition.
 pub struct Diff {
+    parent: Arc<dyn RandomAccessFile>,
     parent_len: usize,
-    header_file: Rc<dyn RandomAccessFile>,
-    table_upper: Rc<DualFile>,
-    table_lower: Rc<IvfcLevel>,
-    partition: Rc<DifiPartition>,
+    header_region: Arc<StagedFile>,
+    header_file: Arc<dyn RandomAccessFile>,
+    table_upper: Arc<DualFile>,
+    table_lower: Arc<IvfcLevel>,
+    staged_tables: Vec<Arc<StagedFile>>,
+    salvaged: Vec<(usize, usize, usize)>,
+    partition: Arc<DifiPartition>,
     unique_id: u64,
+    transaction: AtomicBool,
 }
 
 struct DiffInfo {
@@ -69,17 +79,17 @@ impl Diff {
     }
 
     pub fn format(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
         param: &DifiPartitionParam,
         unique_id: u64,
     ) -> Result<(), Error> {
         file.write(0, &[0; 0x200])?;
-        let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
-        let header_file: Rc<dyn RandomAccessFile> = match signer {
+        let header_file_bare = Arc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
+        let header_file: Arc<dyn RandomAccessFile> = match signer {
             None => header_file_bare,
-            Some((signer, key)) => Rc::new(SignedFile::new_unverified(
-                Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
+            Some((signer, key)) => Arc::new(SignedFile::new_unverified(
+                Arc::new(SubFile::new(file.clone(), 0, 0x10)?),
                 header_file_bare,
                 signer,
                 key,
@@ -104,14 +114,15 @@ impl Diff {
 
         write_struct(header_file.as_ref(), 0, header)?;
 
-        let table = Rc::new(IvfcLevel::new(
-            Rc::new(SubFile::new(header_file.clone(), 0x34, 0x20)?),
-            Rc::new(SubFile::new(
+        let table = Arc::new(IvfcLevel::new(
+            Arc::new(SubFile::new(header_file.clone(), 0x34, 0x20)?),
+            Arc::new(SubFile::new(
                 file.clone(),
                 info.secondary_table_offset,
                 info.table_len,
             )?),
             info.table_len,
+            0,
         )?);
 
         DifiPartition::format(table.as_ref(), param)?;
@@ -121,19 +132,29 @@ impl Diff {
     }
 
     pub fn new(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
+        inversion: SelectorInversion,
+        salvage: bool,
     ) -> Result<Diff, Error> {
         let parent_len = file.len();
-        let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
-        let header_file: Rc<dyn RandomAccessFile> = match signer {
+        // The signature and the header are written together on commit
+        let header_region = Arc::new(StagedFile::new(Arc::new(SubFile::new(
+            file.clone(),
+            0,
+            0x200,
+        )?))?);
+        let header_file_bare = Arc::new(SubFile::new(header_region.clone(), 0x100, 0x100)?);
+        let header_file: Arc<dyn RandomAccessFile> = match signer {
             None => header_file_bare,
-            Some((signer, key)) => Rc::new(SignedFile::new(
-                Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
-                header_file_bare,
-                signer,
-                key,
-            )?),
+            Some((signer, key)) => {
+                let signature = Arc::new(SubFile::new(header_region.clone(), 0, 0x10)?);
+                Arc::new(if salvage {
+                    SignedFile::new_unverified(signature, header_file_bare, signer, key)?
+                } else {
+                    SignedFile::new(signature, header_file_bare, signer, key)?
+                })
+            }
         };
 
         let header: DiffHeader = read_struct(header_file.as_ref(), 0)?;
@@ -142,48 +163,88 @@ impl Diff {
                 "Unexpected DIFF magic {:?} {:X}",
                 header.magic, header.version
             );
-            return make_error(Error::MagicMismatch);
+            return make_error_at(
+                Error::MagicMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DiffHeader),
+            );
         }
 
-        let table_selector = Rc::new(SubFile::new(header_file.clone(), 0x30, 1)?);
+        let table_selector = InvertedFile::wrap_if(
+            Arc::new(SubFile::new(header_file.clone(), 0x30, 1)?),
+            1,
+            inversion.table,
+        );
 
-        let table_hash = Rc::new(SubFile::new(header_file.clone(), 0x34, 0x20)?);
+        let table_hash: Arc<dyn RandomAccessFile> =
+            Arc::new(SubFile::new(header_file.clone(), 0x34, 0x20)?);
 
-        let table_pair: [Rc<dyn RandomAccessFile>; 2] = [
-            Rc::new(SubFile::new(
+        let mut table_pair: [Arc<dyn RandomAccessFile>; 2] = [
+            Arc::new(SubFile::new(
                 file.clone(),
                 header.primary_table_offset as usize,
                 header.table_size as usize,
             )?),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 file.clone(),
                 header.secondary_table_offset as usize,
                 header.table_size as usize,
             )?),
         ];
+        let staged_tables = stage_tables(&mut table_pair, salvage)?;
 
-        let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
+        let table_upper = Arc::new(DualFile::new(table_selector.clone(), table_pair.clone())?);
 
-        let table_lower = Rc::new(IvfcLevel::new(
-            table_hash,
+        let table_lower = Arc::new(IvfcLevel::new(
+            table_hash.clone(),
             table_upper.clone(),
             header.table_size as usize,
+            0,
         )?);
 
-        let partition = Rc::new(SubFile::new(
+        let mut salvaged = vec![];
+        if salvage
+            && salvage_table(
+                &table_lower,
+                table_hash,
+                table_selector.as_ref(),
+                &table_pair,
+                &[0],
+            )?
+        {
+            salvaged.push((0, 0, 0));
+        }
+
+        // The table hash only matches the active table, so the inactive one is read unverified.
+        let table: Arc<dyn RandomAccessFile> = if inversion.table {
+            table_upper.clone()
+        } else {
+            table_lower.clone()
+        };
+
+        let partition = Arc::new(SubFile::new(
             file.clone(),
             header.partition_offset as usize,
             header.partition_size as usize,
         )?);
-        let partition = Rc::new(DifiPartition::new(table_lower.clone(), partition)?);
+        let partition = Arc::new(DifiPartition::new(table, partition, inversion)?);
+        if salvage {
+            for (level, broken) in partition.salvage()?.iter().enumerate() {
+                salvaged.extend(broken.iter().map(|&index| (0, level + 1, index)));
+            }
+        }
 
         Ok(Diff {
+            parent: file.clone(),
             parent_len,
+            header_region,
             header_file,
             table_upper,
             table_lower,
+            staged_tables,
+            salvaged,
             partition,
             unique_id: header.unique_id,
+            transaction: AtomicBool::new(false),
         })
     }
 
@@ -194,17 +255,109 @@ impl Diff {
     pub fn commit(&self) -> Result<(), Error> {
         self.partition.commit()?;
         self.table_lower.commit()?;
+        for table in self.staged_tables.iter() {
+            table.commit()?;
+        }
         self.table_upper.commit()?;
-        self.header_file.commit()
+        // Everything the new header points to must land before the header does
+        self.parent.commit()?;
+        self.header_file.commit()?;
+        self.header_region.commit()?;
+        // Lets the layers below, such as the block cache, write everything back in order
+        self.parent.commit()?;
+        self.transaction.store(false, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn partition(&self) -> &Rc<DifiPartition> {
+    /// See `Disa::begin`.
+    pub fn begin(&self) -> Result<(), Error> {
+        self.commit()?;
+        self.partition.begin();
+        self.transaction.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// See `Disa::rollback`.
+    pub fn rollback(&self) -> Result<(), Error> {
+        if !self.transaction.load(Ordering::Relaxed) {
+            return make_error(Error::NoTransaction);
+        }
+        self.transaction.store(false, Ordering::Relaxed);
+        self.partition.rollback()?;
+        self.table_lower.rollback()?;
+        for table in self.staged_tables.iter() {
+            table.rollback()?;
+        }
+        self.table_upper.rollback()?;
+        self.header_region.rollback()
+    }
+
+    pub fn partition(&self) -> &Arc<DifiPartition> {
         &self.partition
     }
 
     pub fn unique_id(&self) -> u64 {
         self.unique_id
     }
+
+    /// Broken blocks found when opened in salvage mode, as (0, IVFC level, block index).
+    /// Level 0 stands for the partition table.
+    pub fn salvaged_blocks(&self) -> &[(usize, usize, usize)] {
+        &self.salvaged
+    }
+
+    /// Describes the DIFF header, the partition tables and the partition.
+    /// `data` describes the content of the partition.
+    pub fn inspect(&self, data: Vec<InspectNode>) -> Result<InspectNode, Error> {
+        let header: DiffHeader = read_struct(self.header_file.as_ref(), 0)?;
+        Ok(InspectNode::new("DIFF")
+            .at(0, self.parent_len)
+            .hex("version", header.version)
+            .field("active_table", header.active_table)
+            .hex("unique_id", header.unique_id)
+            .child(InspectNode::new("signature").at(0, 0x10))
+            .child(InspectNode::new("DIFF header").at(0x100, 0x100))
+            .child(
+                InspectNode::new("primary partition table")
+                    .at(
+                        header.primary_table_offset as usize,
+                        header.table_size as usize,
+                    )
+                    .field("active", header.active_table == 0),
+            )
+            .child(
+                InspectNode::new("secondary partition table")
+                    .at(
+                        header.secondary_table_offset as usize,
+                        header.table_size as usize,
+                    )
+                    .field("active", header.active_table != 0),
+            )
+            .child(self.partition.inspect(
+                header.partition_offset as usize,
+                header.partition_size as usize,
+                data,
+            )?))
+    }
+
+    /// Verifies the partition table and all IVFC levels, reporting them as part of `partition`.
+    /// See [`DifiPartition::verify_all`] for `used`.
+    pub fn verify_all(
+        &self,
+        partition: usize,
+        used: Option<&[(usize, usize)]>,
+    ) -> Result<Vec<LevelReport>, Error> {
+        let mut reports = vec![self.table_lower.verify_report(partition, 0)?];
+        reports.extend(self.partition.verify_all(partition, used)?);
+        Ok(reports)
+    }
+
+    /// Marks all hashes as outdated, so that the next commit recalculates them
+    /// together with the signature.
+    pub fn rehash(&self) {
+        self.table_lower.rehash();
+        self.partition.rehash();
+    }
 }
 #[cfg(test)]
 mod test {
@@ -256,10 +409,16 @@ mod test {
             let len = param.data_len;
 
             let parent_len = Diff::calculate_size(&param);
-            let parent = Rc::new(MemoryFile::new(vec![0; parent_len]));
+            let parent = Arc::new(MemoryFile::new(vec![0; parent_len]));
 
             Diff::format(parent.clone(), Some((signer.clone(), key)), &param, 0).unwrap();
-            let diff = Diff::new(parent.clone(), Some((signer.clone(), key))).unwrap();
+            let diff = Diff::new(
+                parent.clone(),
+                Some((signer.clone(), key)),
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
             let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
             diff.partition().write(0, &init).unwrap();
             let plain = MemoryFile::new(init);
@@ -268,7 +427,15 @@ mod test {
                 diff,
                 |diff| diff.partition().as_ref(),
                 |diff| diff.commit().unwrap(),
-                || Diff::new(parent.clone(), Some((signer.clone(), key))).unwrap(),
+                || {
+                    Diff::new(
+                        parent.clone(),
+                        Some((signer.clone(), key)),
+                        SelectorInversion::default(),
+                        false,
+                    )
+                    .unwrap()
+                },
                 plain,
             );
         }
@@ -1,13 +1,15 @@
 use crate::dpfs_level::DpfsLevel;
 use crate::dual_file::DualFile;
 use crate::error::*;
-use crate::ivfc_level::IvfcLevel;
+use crate::inspect::*;
+use crate::inverted_file::InvertedFile;
+use crate::ivfc_level::{IvfcLevel, LevelReport};
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(ByteStruct)]
 #[byte_struct_le]
@@ -70,6 +72,62 @@ struct DpfsDescriptor {
     padding3: u32,
 }
 
+/// Selects which dual-image levels are read from their inactive copies.
+///
+/// The inactive copies hold the data as it was before the last commit for any block written
+/// by that commit. Inverting `table` alone gives the complete previous generation, because the
+/// previous partition table also carries the previous DPFS level 1 selector and master hash.
+/// Other combinations give a mix of generations, which IVFC verification would usually reject.
+/// An external IVFC level 4 is not duplicated at all, so only its current content can be read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SelectorInversion {
+    /// Inverts the selector of the partition table in the DISA / DIFF header.
+    /// The table hash in the header is not checked in this case, as only one copy of it exists.
+    pub table: bool,
+    /// Inverts the DPFS level 1 selector in the DIFI header.
+    pub dpfs_level1: bool,
+    /// Inverts the DPFS level 2 selector bits.
+    pub dpfs_level2: bool,
+    /// Inverts the DPFS level 3 selector bits.
+    pub dpfs_level3: bool,
+}
+
+impl SelectorInversion {
+    /// Inverts all levels.
+    pub fn all() -> SelectorInversion {
+        SelectorInversion {
+            table: true,
+            dpfs_level1: true,
+            dpfs_level2: true,
+            dpfs_level3: true,
+        }
+    }
+
+    /// Inverts only the partition table, which views the generation before the last commit.
+    pub fn previous() -> SelectorInversion {
+        SelectorInversion {
+            table: true,
+            ..SelectorInversion::default()
+        }
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        *self != SelectorInversion::default()
+    }
+}
+
+/// Differences between two generations of a DIFI partition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GenerationReport {
+    /// Block length of DPFS level 3, in which `changed_blocks` are counted.
+    pub block_len: usize,
+    /// Indices of DPFS level 3 blocks whose content differs between the two generations.
+    pub changed_blocks: Vec<usize>,
+    /// Indices of blocks that fail hash verification in the inverted generation,
+    /// for IVFC level 1 to 4.
+    pub broken_blocks: [Vec<usize>; 4],
+}
+
 pub struct DifiPartitionParam {
     pub dpfs_level2_block_len: usize,
     pub dpfs_level3_block_len: usize,
@@ -119,13 +177,15 @@ impl DifiPartitionParam {
 /// It implements fast data integrity checking and atomic operation by wrapping
 /// multiple DPFS and IVFC layers.
 pub struct DifiPartition {
-    dpfs_level1: Rc<DualFile>,
-    dpfs_level2: Rc<DpfsLevel>,
-    dpfs_level3: Rc<DpfsLevel>,
-    ivfc_level1: Rc<IvfcLevel>,
-    ivfc_level2: Rc<IvfcLevel>,
-    ivfc_level3: Rc<IvfcLevel>,
-    ivfc_level4: Rc<IvfcLevel>,
+    descriptor: Arc<dyn RandomAccessFile>,
+    dpfs_level1: Arc<DualFile>,
+    dpfs_level2: Arc<DpfsLevel>,
+    dpfs_level3: Arc<DpfsLevel>,
+    ivfc_level1: Arc<IvfcLevel>,
+    ivfc_level2: Arc<IvfcLevel>,
+    ivfc_level3: Arc<IvfcLevel>,
+    ivfc_level4: Arc<IvfcLevel>,
+    external_ivfc_level4: bool,
 }
 
 struct DifiPartitionInfo {
@@ -136,6 +196,30 @@ struct DifiPartitionInfo {
     partition_len: usize,
 }
 
+// Block lengths are stored as their log2. Real images use 128 to 4096 bytes,
+// so anything beyond 1 MiB is treated as corruption rather than allocated.
+fn block_len(log: u32) -> Result<usize, Error> {
+    if log > 20 {
+        error!("Unexpected block_log {}", log);
+        return make_error_at(
+            Error::InvalidValue(Contexts::new()),
+            ErrorContext::new(Layer::DifiHeader),
+        );
+    }
+    Ok(1 << log)
+}
+
+// The second copy of a DPFS level immediately follows the first one.
+fn pair_end(offset: u64, size: u64) -> Result<usize, Error> {
+    match offset.checked_add(size) {
+        Some(end) => Ok(end as usize),
+        None => make_error_at(
+            Error::OutOfBound(Contexts::new()),
+            ErrorContext::new(Layer::DifiHeader),
+        ),
+    }
+}
+
 impl DifiPartition {
     fn calculate_info(param: &DifiPartitionParam) -> DifiPartitionInfo {
         let ivfc_level4_len = param.data_len;
@@ -291,8 +375,9 @@ impl DifiPartition {
     }
 
     pub fn new(
-        descriptor: Rc<dyn RandomAccessFile>,
-        partition: Rc<dyn RandomAccessFile>,
+        descriptor: Arc<dyn RandomAccessFile>,
+        partition: Arc<dyn RandomAccessFile>,
+        inversion: SelectorInversion,
     ) -> Result<DifiPartition, Error> {
         let header: DifiHeader = read_struct(descriptor.as_ref(), 0)?;
 
@@ -301,7 +386,10 @@ impl DifiPartition {
                 "Unexpected DIFI magic {:?} {:X}",
                 header.magic, header.version
             );
-            return make_error(Error::MagicMismatch);
+            return make_error_at(
+                Error::MagicMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DifiHeader),
+            );
         }
 
         if header.ivfc_descriptor_size as usize != IvfcDescriptor::BYTE_LEN {
@@ -309,20 +397,29 @@ impl DifiPartition {
                 "Unexpected ivfc_descriptor_size {}",
                 header.ivfc_descriptor_size
             );
-            return make_error(Error::SizeMismatch);
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DifiHeader),
+            );
         }
         let ivfc: IvfcDescriptor =
             read_struct(descriptor.as_ref(), header.ivfc_descriptor_offset as usize)?;
         if ivfc.magic != *b"IVFC" || ivfc.version != 0x20000 {
             error!("Unexpected IVFC magic {:?} {:X}", ivfc.magic, ivfc.version);
-            return make_error(Error::MagicMismatch);
+            return make_error_at(
+                Error::MagicMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DifiHeader).offset(header.ivfc_descriptor_offset as usize),
+            );
         }
         if header.partition_hash_size != ivfc.master_hash_size {
             error!(
                 "Unexpected partition_hash_size {}",
                 header.partition_hash_size
             );
-            return make_error(Error::SizeMismatch);
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DifiHeader),
+            );
         }
 
         if header.dpfs_descriptor_size as usize != DpfsDescriptor::BYTE_LEN {
@@ -330,109 +427,122 @@ impl DifiPartition {
                 "Unexpected dpfs_descriptor_size {}",
                 header.dpfs_descriptor_size
             );
-            return make_error(Error::SizeMismatch);
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DifiHeader),
+            );
         }
         let dpfs: DpfsDescriptor =
             read_struct(descriptor.as_ref(), header.dpfs_descriptor_offset as usize)?;
         if dpfs.magic != *b"DPFS" || dpfs.version != 0x10000 {
             error!("Unexpected DPFS magic {:?} {:X}", dpfs.magic, dpfs.version);
-            return make_error(Error::MagicMismatch);
+            return make_error_at(
+                Error::MagicMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DifiHeader).offset(header.dpfs_descriptor_offset as usize),
+            );
         }
 
-        let dpfs_level0 = Rc::new(SubFile::new(descriptor.clone(), 0x39, 1)?);
+        let dpfs_level0 = InvertedFile::wrap_if(
+            Arc::new(SubFile::new(descriptor.clone(), 0x39, 1)?),
+            1,
+            inversion.dpfs_level1,
+        );
 
-        let dpfs_level1_pair: [Rc<dyn RandomAccessFile>; 2] = [
-            Rc::new(SubFile::new(
+        let dpfs_level1_pair: [Arc<dyn RandomAccessFile>; 2] = [
+            Arc::new(SubFile::new(
                 partition.clone(),
                 dpfs.level1_offset as usize,
                 dpfs.level1_size as usize,
             )?),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 partition.clone(),
-                (dpfs.level1_offset + dpfs.level1_size) as usize,
+                pair_end(dpfs.level1_offset, dpfs.level1_size)?,
                 dpfs.level1_size as usize,
             )?),
         ];
 
-        let dpfs_level2_pair: [Rc<dyn RandomAccessFile>; 2] = [
-            Rc::new(SubFile::new(
+        let dpfs_level2_pair: [Arc<dyn RandomAccessFile>; 2] = [
+            Arc::new(SubFile::new(
                 partition.clone(),
                 dpfs.level2_offset as usize,
                 dpfs.level2_size as usize,
             )?),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 partition.clone(),
-                (dpfs.level2_offset + dpfs.level2_size) as usize,
+                pair_end(dpfs.level2_offset, dpfs.level2_size)?,
                 dpfs.level2_size as usize,
             )?),
         ];
 
-        let dpfs_level3_pair: [Rc<dyn RandomAccessFile>; 2] = [
-            Rc::new(SubFile::new(
+        let dpfs_level3_pair: [Arc<dyn RandomAccessFile>; 2] = [
+            Arc::new(SubFile::new(
                 partition.clone(),
                 dpfs.level3_offset as usize,
                 dpfs.level3_size as usize,
             )?),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 partition.clone(),
-                (dpfs.level3_offset + dpfs.level3_size) as usize,
+                pair_end(dpfs.level3_offset, dpfs.level3_size)?,
                 dpfs.level3_size as usize,
             )?),
         ];
 
-        let dpfs_level1 = Rc::new(DualFile::new(dpfs_level0, dpfs_level1_pair)?);
+        let dpfs_level1 = Arc::new(DualFile::new(dpfs_level0, dpfs_level1_pair)?);
 
-        let dpfs_level2 = Rc::new(DpfsLevel::new(
-            dpfs_level1.clone(),
+        let dpfs_level2 = Arc::new(DpfsLevel::new(
+            InvertedFile::wrap_if(dpfs_level1.clone(), 0xFF, inversion.dpfs_level2),
             dpfs_level2_pair,
-            1 << dpfs.level2_block_log,
+            block_len(dpfs.level2_block_log)?,
         )?);
 
-        let dpfs_level3 = Rc::new(DpfsLevel::new(
-            dpfs_level2.clone(),
+        let dpfs_level3 = Arc::new(DpfsLevel::new(
+            InvertedFile::wrap_if(dpfs_level2.clone(), 0xFF, inversion.dpfs_level3),
             dpfs_level3_pair,
-            1 << dpfs.level3_block_log,
+            block_len(dpfs.level3_block_log)?,
         )?);
 
-        let ivfc_level0 = Rc::new(SubFile::new(
+        let ivfc_level0 = Arc::new(SubFile::new(
             descriptor.clone(),
             header.partition_hash_offset as usize,
             header.partition_hash_size as usize,
         )?);
 
-        let ivfc_level1 = Rc::new(IvfcLevel::new(
+        let ivfc_level1 = Arc::new(IvfcLevel::new(
             ivfc_level0,
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 dpfs_level3.clone(),
                 ivfc.level1_offset as usize,
                 ivfc.level1_size as usize,
             )?),
-            1 << ivfc.level1_block_log,
+            block_len(ivfc.level1_block_log)?,
+            1,
         )?);
 
-        let ivfc_level2 = Rc::new(IvfcLevel::new(
+        let ivfc_level2 = Arc::new(IvfcLevel::new(
             ivfc_level1.clone(),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 dpfs_level3.clone(),
                 ivfc.level2_offset as usize,
                 ivfc.level2_size as usize,
             )?),
-            1 << ivfc.level2_block_log,
+            block_len(ivfc.level2_block_log)?,
+            2,
         )?);
 
-        let ivfc_level3 = Rc::new(IvfcLevel::new(
+        let ivfc_level3 = Arc::new(IvfcLevel::new(
             ivfc_level2.clone(),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 dpfs_level3.clone(),
                 ivfc.level3_offset as usize,
                 ivfc.level3_size as usize,
             )?),
-            1 << ivfc.level3_block_log,
+            block_len(ivfc.level3_block_log)?,
+            3,
         )?);
 
-        let ivfc_level4 = Rc::new(IvfcLevel::new(
+        let ivfc_level4 = Arc::new(IvfcLevel::new(
             ivfc_level3.clone(),
-            Rc::new(if header.external_ivfc_level4 == 0 {
+            Arc::new(if header.external_ivfc_level4 == 0 {
                 SubFile::new(
                     dpfs_level3.clone(),
                     ivfc.level4_offset as usize,
@@ -445,10 +555,12 @@ impl DifiPartition {
                     ivfc.level4_size as usize,
                 )?
             }),
-            1 << ivfc.level4_block_log,
+            block_len(ivfc.level4_block_log)?,
+            4,
         )?);
 
         Ok(DifiPartition {
+            descriptor,
             dpfs_level1,
             dpfs_level2,
             dpfs_level3,
@@ -456,6 +568,239 @@ impl DifiPartition {
             ivfc_level2,
             ivfc_level3,
             ivfc_level4,
+            external_ivfc_level4: header.external_ivfc_level4 != 0,
+        })
+    }
+
+    /// Starts a transaction that lasts until the next commit or rollback.
+    /// An external IVFC level 4 is written in place, so its original data is kept for rollback.
+    pub fn begin(&self) {
+        if self.external_ivfc_level4 {
+            self.ivfc_level4.keep_undo();
+        }
+    }
+
+    /// Block length of IVFC level 4, the level that holds the partition data.
+    pub fn data_block_len(&self) -> usize {
+        self.ivfc_level4.block_len()
+    }
+
+    /// Describes the DIFI, IVFC and DPFS descriptors and the DPFS selectors of this partition,
+    /// which lies at `offset` with length `len` in its container.
+    /// `data` describes the content of IVFC level 4 and is placed under its node.
+    pub fn inspect(
+        &self,
+        offset: usize,
+        len: usize,
+        data: Vec<InspectNode>,
+    ) -> Result<InspectNode, Error> {
+        let descriptor = self.descriptor.as_ref();
+        let header: DifiHeader = read_struct(descriptor, 0)?;
+        let ivfc: IvfcDescriptor = read_struct(descriptor, header.ivfc_descriptor_offset as usize)?;
+        let dpfs: DpfsDescriptor = read_struct(descriptor, header.dpfs_descriptor_offset as usize)?;
+        let mut master_hash = vec![0; header.partition_hash_size as usize];
+        descriptor.read(header.partition_hash_offset as usize, &mut master_hash)?;
+        let mut level2_selector = vec![0; self.dpfs_level1.len()];
+        self.dpfs_level1.read(0, &mut level2_selector)?;
+        let mut level3_selector = vec![0; self.dpfs_level2.len()];
+        self.dpfs_level2.read(0, &mut level3_selector)?;
+
+        let dpfs_level = |level: usize, offset: u64, size: u64, block_log: u32| {
+            InspectNode::new(&format!("DPFS level {}", level))
+                .at(offset as usize, size as usize * 2)
+                .hex("copy_len", size)
+                .hex("block_len", 1u64 << block_log)
+                .field("block_count", divide_up(size as usize, 1 << block_log))
+        };
+        let ivfc_level = |level: usize, offset: u64, size: u64, block_log: u32| {
+            InspectNode::new(&format!("IVFC level {}", level))
+                .at(offset as usize, size as usize)
+                .hex("block_len", 1u64 << block_log)
+        };
+
+        let mut ivfc_level4 = ivfc_level(
+            4,
+            if header.external_ivfc_level4 == 0 {
+                ivfc.level4_offset
+            } else {
+                header.ivfc_level4_offset
+            },
+            ivfc.level4_size,
+            ivfc.level4_block_log,
+        );
+        ivfc_level4.children = data;
+
+        let mut dpfs_level3 = dpfs_level(
+            3,
+            dpfs.level3_offset,
+            dpfs.level3_size,
+            dpfs.level3_block_log,
+        )
+        .field("selector", &level3_selector[..])
+        .child(ivfc_level(
+            1,
+            ivfc.level1_offset,
+            ivfc.level1_size,
+            ivfc.level1_block_log,
+        ))
+        .child(ivfc_level(
+            2,
+            ivfc.level2_offset,
+            ivfc.level2_size,
+            ivfc.level2_block_log,
+        ))
+        .child(ivfc_level(
+            3,
+            ivfc.level3_offset,
+            ivfc.level3_size,
+            ivfc.level3_block_log,
+        ));
+        let external_ivfc_level4 = if header.external_ivfc_level4 == 0 {
+            dpfs_level3 = dpfs_level3.child(ivfc_level4);
+            None
+        } else {
+            Some(ivfc_level4)
+        };
+
+        let mut node = InspectNode::new("DIFI partition")
+            .at(offset, len)
+            .hex("version", header.version)
+            .hex("ivfc_descriptor_offset", header.ivfc_descriptor_offset)
+            .hex("dpfs_descriptor_offset", header.dpfs_descriptor_offset)
+            .hex("master_hash_offset", header.partition_hash_offset)
+            .field("master_hash", &master_hash[..])
+            .field("external_ivfc_level4", header.external_ivfc_level4 != 0)
+            .child(
+                dpfs_level(
+                    1,
+                    dpfs.level1_offset,
+                    dpfs.level1_size,
+                    dpfs.level1_block_log,
+                )
+                .field("selector", header.dpfs_selector),
+            )
+            .child(
+                dpfs_level(
+                    2,
+                    dpfs.level2_offset,
+                    dpfs.level2_size,
+                    dpfs.level2_block_log,
+                )
+                .field("selector", &level2_selector[..]),
+            )
+            .child(dpfs_level3);
+        if let Some(ivfc_level4) = external_ivfc_level4 {
+            node = node.child(ivfc_level4);
+        }
+        Ok(node)
+    }
+
+    /// Salvages IVFC levels from top to bottom, so that broken blocks read back their
+    /// raw data and get rehashed on the next commit.
+    /// Returns the indices of the broken blocks for IVFC level 1 to 4.
+    pub fn salvage(&self) -> Result<[Vec<usize>; 4], Error> {
+        Ok([
+            self.ivfc_level1.salvage()?,
+            self.ivfc_level2.salvage()?,
+            self.ivfc_level3.salvage()?,
+            self.ivfc_level4.salvage()?,
+        ])
+    }
+
+    /// Verifies the IVFC level 4 blocks that overlap `ranges`, given as `(offset, len)`,
+    /// along with the upper levels they depend on. Returns the indices of the broken level 4 blocks.
+    pub fn broken_data_blocks(&self, ranges: &[(usize, usize)]) -> Result<Vec<usize>, Error> {
+        self.ivfc_level4.broken_blocks_in(ranges)
+    }
+
+    /// Gets the data range that block `index` of IVFC level `level` (from 1 to 4) holds,
+    /// or holds the hashes of, as `(begin, end)`.
+    fn covered_range(&self, level: usize, index: usize) -> (usize, usize) {
+        let levels = [
+            &self.ivfc_level1,
+            &self.ivfc_level2,
+            &self.ivfc_level3,
+            &self.ivfc_level4,
+        ];
+        let block_len = levels[level - 1].block_len();
+        let (mut begin, mut end) = (index * block_len, (index + 1) * block_len);
+        for lower in levels[level..].iter() {
+            begin = begin / 0x20 * lower.block_len();
+            end = divide_up(end, 0x20) * lower.block_len();
+        }
+        (begin, end)
+    }
+
+    /// Verifies all IVFC levels from top to bottom, reporting them as part of `partition`.
+    /// Broken blocks that don't overlap `used`, given as `(offset, len)`, are reported as unused.
+    /// If `used` is `None`, all data counts as in use.
+    pub fn verify_all(
+        &self,
+        partition: usize,
+        used: Option<&[(usize, usize)]>,
+    ) -> Result<Vec<LevelReport>, Error> {
+        let levels = [
+            &self.ivfc_level1,
+            &self.ivfc_level2,
+            &self.ivfc_level3,
+            &self.ivfc_level4,
+        ];
+        let mut reports = vec![];
+        for (i, ivfc_level) in levels.iter().enumerate() {
+            let mut report = ivfc_level.verify_report(partition, i + 1)?;
+            if let Some(used) = used {
+                let (broken, unused) = report.broken_blocks.iter().partition(|&&index| {
+                    let (begin, end) = self.covered_range(i + 1, index);
+                    used.iter()
+                        .any(|&(offset, len)| offset < end && begin < offset + len)
+                });
+                report.broken_blocks = broken;
+                report.unused_broken_blocks = unused;
+            }
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+
+    /// Marks all IVFC blocks as modified, so that all hashes are recalculated on the next commit.
+    pub fn rehash(&self) {
+        self.ivfc_level1.rehash();
+        self.ivfc_level2.rehash();
+        self.ivfc_level3.rehash();
+        self.ivfc_level4.rehash();
+    }
+
+    /// Compares this partition with `other`, which is the same partition opened with
+    /// different `SelectorInversion`, and verifies all IVFC levels of this partition.
+    pub fn compare_generation(&self, other: &DifiPartition) -> Result<GenerationReport, Error> {
+        let len = self.dpfs_level3.len();
+        let block_len = self.dpfs_level3.block_len();
+        if other.dpfs_level3.len() != len || other.dpfs_level3.block_len() != block_len {
+            return make_error(Error::SizeMismatch(Contexts::new()));
+        }
+
+        let mut changed_blocks = vec![];
+        let mut buf_a = vec![0; block_len];
+        let mut buf_b = vec![0; block_len];
+        for i in 0..divide_up(len, block_len) {
+            let begin = i * block_len;
+            let end = std::cmp::min(begin + block_len, len);
+            self.dpfs_level3.read(begin, &mut buf_a[0..end - begin])?;
+            other.dpfs_level3.read(begin, &mut buf_b[0..end - begin])?;
+            if buf_a[0..end - begin] != buf_b[0..end - begin] {
+                changed_blocks.push(i);
+            }
+        }
+
+        Ok(GenerationReport {
+            block_len,
+            changed_blocks,
+            broken_blocks: [
+                self.ivfc_level1.broken_blocks()?,
+                self.ivfc_level2.broken_blocks()?,
+                self.ivfc_level3.broken_blocks()?,
+                self.ivfc_level4.broken_blocks()?,
+            ],
         })
     }
 }
@@ -479,6 +824,15 @@ impl RandomAccessFile for DifiPartition {
         self.dpfs_level2.commit()?;
         self.dpfs_level1.commit()
     }
+    fn rollback(&self) -> Result<(), Error> {
+        self.ivfc_level4.rollback()?;
+        self.ivfc_level3.rollback()?;
+        self.ivfc_level2.rollback()?;
+        self.ivfc_level1.rollback()?;
+        self.dpfs_level3.rollback()?;
+        self.dpfs_level2.rollback()?;
+        self.dpfs_level1.rollback()
+    }
 }
 
 #[cfg(test)]
@@ -504,11 +858,16 @@ mod test {
             let len = param.data_len;
 
             let (descriptor_len, partition_len) = DifiPartition::calculate_size(&param);
-            let descriptor = Rc::new(MemoryFile::new(vec![0; descriptor_len]));
-            let partition = Rc::new(MemoryFile::new(vec![0; partition_len]));
+            let descriptor = Arc::new(MemoryFile::new(vec![0; descriptor_len]));
+            let partition = Arc::new(MemoryFile::new(vec![0; partition_len]));
 
             DifiPartition::format(descriptor.as_ref(), &param).unwrap();
-            let difi = DifiPartition::new(descriptor.clone(), partition.clone()).unwrap();
+            let difi = DifiPartition::new(
+                descriptor.clone(),
+                partition.clone(),
+                SelectorInversion::default(),
+            )
+            .unwrap();
             let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
             difi.write(0, &init).unwrap();
             let plain = MemoryFile::new(init);
@@ -517,7 +876,14 @@ mod test {
                 difi,
                 |file| file,
                 |file| file.commit().unwrap(),
-                || DifiPartition::new(descriptor.clone(), partition.clone()).unwrap(),
+                || {
+                    DifiPartition::new(
+                        descriptor.clone(),
+                        partition.clone(),
+                        SelectorInversion::default(),
+                    )
+                    .unwrap()
+                },
                 plain,
             );
         }
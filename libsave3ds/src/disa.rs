@@ -1,15 +1,19 @@
 use crate::difi_partition::*;
 use crate::dual_file::DualFile;
 use crate::error::*;
-use crate::ivfc_level::IvfcLevel;
+use crate::inspect::InspectNode;
+use crate::inverted_file::InvertedFile;
+use crate::ivfc_level::{IvfcLevel, LevelReport};
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::signed_file::*;
+use crate::staged_file::StagedFile;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
 use std::ops::Index;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 #[derive(ByteStruct)]
 #[byte_struct_le]
@@ -42,10 +46,16 @@ struct DisaHeader {
 
 /// DISA container format that contains one or two DIFI partitions.
 pub struct Disa {
-    header_file: Rc<dyn RandomAccessFile>,
-    table_upper: Rc<DualFile>,
-    table_lower: Rc<IvfcLevel>,
-    partitions: Vec<Rc<DifiPartition>>,
+    parent: Arc<dyn RandomAccessFile>,
+    parent_len: usize,
+    header_region: Arc<StagedFile>,
+    header_file: Arc<dyn RandomAccessFile>,
+    table_upper: Arc<DualFile>,
+    table_lower: Arc<IvfcLevel>,
+    staged_tables: Vec<Arc<StagedFile>>,
+    salvaged: Vec<(usize, usize, usize)>,
+    partitions: Vec<Arc<DifiPartition>>,
+    transaction: AtomicBool,
 }
 
 struct DisaInfo {
@@ -120,17 +130,17 @@ impl Disa {
     }
 
     pub fn format(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
         partition_a_param: &DifiPartitionParam,
         partition_b_param: Option<&DifiPartitionParam>,
     ) -> Result<(), Error> {
         file.write(0, &[0; 0x200])?;
-        let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
-        let header_file: Rc<dyn RandomAccessFile> = match signer {
+        let header_file_bare = Arc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
+        let header_file: Arc<dyn RandomAccessFile> = match signer {
             None => header_file_bare,
-            Some((signer, key)) => Rc::new(SignedFile::new_unverified(
-                Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
+            Some((signer, key)) => Arc::new(SignedFile::new_unverified(
+                Arc::new(SubFile::new(file.clone(), 0, 0x10)?),
                 header_file_bare,
                 signer,
                 key,
@@ -172,17 +182,18 @@ impl Disa {
 
         write_struct(header_file.as_ref(), 0, header)?;
 
-        let table = Rc::new(IvfcLevel::new(
-            Rc::new(SubFile::new(header_file.clone(), 0x6C, 0x20)?),
-            Rc::new(SubFile::new(
+        let table = Arc::new(IvfcLevel::new(
+            Arc::new(SubFile::new(header_file.clone(), 0x6C, 0x20)?),
+            Arc::new(SubFile::new(
                 file.clone(),
                 info.secondary_table_offset,
                 info.table_len,
             )?),
             info.table_len,
+            0,
         )?);
 
-        let descriptor_a = Rc::new(SubFile::new(
+        let descriptor_a = Arc::new(SubFile::new(
             table.clone(),
             info.descriptor_a_offset,
             info.descriptor_a_len,
@@ -191,7 +202,7 @@ impl Disa {
         DifiPartition::format(descriptor_a.as_ref(), partition_a_param)?;
 
         if let Some(partition_b_param) = partition_b_param {
-            let descriptor_b = Rc::new(SubFile::new(
+            let descriptor_b = Arc::new(SubFile::new(
                 table.clone(),
                 info.descriptor_b_offset,
                 info.descriptor_b_len,
@@ -205,18 +216,29 @@ impl Disa {
     }
 
     pub fn new(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
+        inversion: SelectorInversion,
+        salvage: bool,
     ) -> Result<Disa, Error> {
-        let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
-        let header_file: Rc<dyn RandomAccessFile> = match signer {
+        let parent_len = file.len();
+        // The signature and the header are written together on commit
+        let header_region = Arc::new(StagedFile::new(Arc::new(SubFile::new(
+            file.clone(),
+            0,
+            0x200,
+        )?))?);
+        let header_file_bare = Arc::new(SubFile::new(header_region.clone(), 0x100, 0x100)?);
+        let header_file: Arc<dyn RandomAccessFile> = match signer {
             None => header_file_bare,
-            Some((signer, key)) => Rc::new(SignedFile::new(
-                Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
-                header_file_bare,
-                signer,
-                key,
-            )?),
+            Some((signer, key)) => {
+                let signature = Arc::new(SubFile::new(header_region.clone(), 0, 0x10)?);
+                Arc::new(if salvage {
+                    SignedFile::new_unverified(signature, header_file_bare, signer, key)?
+                } else {
+                    SignedFile::new(signature, header_file_bare, signer, key)?
+                })
+            }
         };
 
         let header: DisaHeader = read_struct(header_file.as_ref(), 0)?;
@@ -225,60 +247,108 @@ impl Disa {
                 "Unexpected DISA magic {:?} {:X}",
                 header.magic, header.version
             );
-            return make_error(Error::MagicMismatch);
+            return make_error_at(
+                Error::MagicMismatch(Contexts::new()),
+                ErrorContext::new(Layer::DisaHeader),
+            );
         }
         if header.partition_count != 1 && header.partition_count != 2 {
             error!("Unexpected partition_count {}", header.partition_count);
-            return make_error(Error::InvalidValue);
+            return make_error_at(
+                Error::InvalidValue(Contexts::new()),
+                ErrorContext::new(Layer::DisaHeader),
+            );
         }
 
-        let table_selector = Rc::new(SubFile::new(header_file.clone(), 0x68, 1)?);
+        let table_selector = InvertedFile::wrap_if(
+            Arc::new(SubFile::new(header_file.clone(), 0x68, 1)?),
+            1,
+            inversion.table,
+        );
 
-        let table_hash = Rc::new(SubFile::new(header_file.clone(), 0x6C, 0x20)?);
+        let table_hash: Arc<dyn RandomAccessFile> =
+            Arc::new(SubFile::new(header_file.clone(), 0x6C, 0x20)?);
 
-        let table_pair: [Rc<dyn RandomAccessFile>; 2] = [
-            Rc::new(SubFile::new(
+        let mut table_pair: [Arc<dyn RandomAccessFile>; 2] = [
+            Arc::new(SubFile::new(
                 file.clone(),
                 header.primary_table_offset as usize,
                 header.table_size as usize,
             )?),
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 file.clone(),
                 header.secondary_table_offset as usize,
                 header.table_size as usize,
             )?),
         ];
+        let staged_tables = stage_tables(&mut table_pair, salvage)?;
 
-        let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
+        let table_upper = Arc::new(DualFile::new(table_selector.clone(), table_pair.clone())?);
 
-        let table_lower = Rc::new(IvfcLevel::new(
-            table_hash,
+        let table_lower = Arc::new(IvfcLevel::new(
+            table_hash.clone(),
             table_upper.clone(),
             header.table_size as usize,
+            0,
         )?);
 
+        let mut salvaged = vec![];
+        if salvage
+            && salvage_table(
+                &table_lower,
+                table_hash,
+                table_selector.as_ref(),
+                &table_pair,
+                &header.partition_descriptor[0..header.partition_count as usize]
+                    .iter()
+                    .map(|d| d.offset as usize)
+                    .collect::<Vec<_>>(),
+            )?
+        {
+            salvaged.push((0, 0, 0));
+        }
+
+        // The table hash only matches the active table, so the inactive one is read unverified.
+        let table: Arc<dyn RandomAccessFile> = if inversion.table {
+            table_upper.clone()
+        } else {
+            table_lower.clone()
+        };
+
         let mut partitions = Vec::with_capacity(header.partition_count as usize);
         for i in 0..header.partition_count as usize {
             let d = &header.partition_descriptor[i];
             let p = &header.partition[i];
-            let descriptor = Rc::new(SubFile::new(
-                table_lower.clone(),
+            let descriptor = Arc::new(SubFile::new(
+                table.clone(),
                 d.offset as usize,
                 d.size as usize,
             )?);
-            let partition = Rc::new(SubFile::new(
+            let partition = Arc::new(SubFile::new(
                 file.clone(),
                 p.offset as usize,
                 p.size as usize,
             )?);
-            partitions.push(Rc::new(DifiPartition::new(descriptor, partition)?));
+            let partition = Arc::new(DifiPartition::new(descriptor, partition, inversion)?);
+            if salvage {
+                for (level, broken) in partition.salvage()?.iter().enumerate() {
+                    salvaged.extend(broken.iter().map(|&index| (i, level + 1, index)));
+                }
+            }
+            partitions.push(partition);
         }
 
         Ok(Disa {
+            parent: file.clone(),
+            parent_len,
+            header_region,
             header_file,
             table_upper,
             table_lower,
+            staged_tables,
+            salvaged,
             partitions,
+            transaction: AtomicBool::new(false),
         })
     }
 
@@ -287,18 +357,199 @@ impl Disa {
             partition.commit()?;
         }
         self.table_lower.commit()?;
+        for table in self.staged_tables.iter() {
+            table.commit()?;
+        }
         self.table_upper.commit()?;
-        self.header_file.commit()
+        // Everything the new header points to must land before the header does
+        self.parent.commit()?;
+        self.header_file.commit()?;
+        self.header_region.commit()?;
+        // Lets the layers below, such as the block cache, write everything back in order
+        self.parent.commit()?;
+        self.transaction.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Commits pending changes and starts a transaction that lasts until the next commit or rollback.
+    pub fn begin(&self) -> Result<(), Error> {
+        self.commit()?;
+        for partition in self.partitions.iter() {
+            partition.begin();
+        }
+        self.transaction.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Discards all changes made since the transaction started.
+    pub fn rollback(&self) -> Result<(), Error> {
+        if !self.transaction.load(Ordering::Relaxed) {
+            return make_error(Error::NoTransaction);
+        }
+        self.transaction.store(false, Ordering::Relaxed);
+        for partition in self.partitions.iter() {
+            partition.rollback()?;
+        }
+        self.table_lower.rollback()?;
+        for table in self.staged_tables.iter() {
+            table.rollback()?;
+        }
+        self.table_upper.rollback()?;
+        self.header_region.rollback()
     }
 
     pub fn partition_count(&self) -> usize {
         self.partitions.len()
     }
+
+    pub fn parent_len(&self) -> usize {
+        self.parent_len
+    }
+
+    /// Broken blocks found when opened in salvage mode, as (partition, IVFC level, block index).
+    /// Level 0 stands for the partition table.
+    pub fn salvaged_blocks(&self) -> &[(usize, usize, usize)] {
+        &self.salvaged
+    }
+
+    /// Describes the DISA header, the partition tables and the partitions.
+    /// `data` describes the content of each partition, in partition order.
+    pub fn inspect(&self, mut data: Vec<Vec<InspectNode>>) -> Result<InspectNode, Error> {
+        let header: DisaHeader = read_struct(self.header_file.as_ref(), 0)?;
+        data.resize(self.partitions.len(), vec![]);
+        let mut node = InspectNode::new("DISA")
+            .at(0, self.parent_len)
+            .hex("version", header.version)
+            .field("partition_count", header.partition_count)
+            .field("active_table", header.active_table)
+            .child(InspectNode::new("signature").at(0, 0x10))
+            .child(InspectNode::new("DISA header").at(0x100, 0x100))
+            .child(
+                InspectNode::new("primary partition table")
+                    .at(
+                        header.primary_table_offset as usize,
+                        header.table_size as usize,
+                    )
+                    .field("active", header.active_table == 0),
+            )
+            .child(
+                InspectNode::new("secondary partition table")
+                    .at(
+                        header.secondary_table_offset as usize,
+                        header.table_size as usize,
+                    )
+                    .field("active", header.active_table != 0),
+            );
+        for (i, (partition, data)) in self.partitions.iter().zip(data).enumerate() {
+            let d = &header.partition_descriptor[i];
+            let p = &header.partition[i];
+            node = node.child(
+                partition
+                    .inspect(p.offset as usize, p.size as usize, data)?
+                    .field("index", i)
+                    .hex("descriptor_offset", d.offset)
+                    .hex("descriptor_size", d.size),
+            );
+        }
+        Ok(node)
+    }
+
+    /// Verifies the partition table and all IVFC levels of each partition.
+    /// `used` lists the ranges in use of each partition, if known.
+    pub fn verify_all(
+        &self,
+        used: Option<&[Vec<(usize, usize)>]>,
+    ) -> Result<Vec<LevelReport>, Error> {
+        let mut reports = vec![self.table_lower.verify_report(0, 0)?];
+        for (i, partition) in self.partitions.iter().enumerate() {
+            reports.extend(partition.verify_all(i, used.map(|used| &used[i][..]))?);
+        }
+        Ok(reports)
+    }
+
+    /// Marks all hashes as outdated, so that the next commit recalculates them
+    /// together with the signature.
+    pub fn rehash(&self) {
+        self.table_lower.rehash();
+        for partition in self.partitions.iter() {
+            partition.rehash();
+        }
+    }
+}
+
+/// Keeps both partition table copies in memory when opening in salvage mode,
+/// so that a table repaired by `salvage_table` is only written to the file on commit.
+/// Returns the staged copies, which replace the ones in `table_pair`.
+pub(crate) fn stage_tables(
+    table_pair: &mut [Arc<dyn RandomAccessFile>; 2],
+    salvage: bool,
+) -> Result<Vec<Arc<StagedFile>>, Error> {
+    if !salvage {
+        return Ok(vec![]);
+    }
+    let mut staged = vec![];
+    for table in table_pair.iter_mut() {
+        let file = Arc::new(StagedFile::new(table.clone())?);
+        *table = file.clone();
+        staged.push(file);
+    }
+    Ok(staged)
+}
+
+/// Checks the active partition table when opening in salvage mode, and falls back to
+/// the other copy if the other copy still matches the table hash, or if the DIFI descriptors
+/// (at `descriptor_offsets`) are only intact in the other copy. The latter rolls the archive
+/// back to the generation before the last commit. Otherwise the broken table is salvaged as-is.
+/// Returns whether the table in use fails hash verification.
+pub(crate) fn salvage_table(
+    table_lower: &IvfcLevel,
+    table_hash: Arc<dyn RandomAccessFile>,
+    table_selector: &dyn RandomAccessFile,
+    table_pair: &[Arc<dyn RandomAccessFile>; 2],
+    descriptor_offsets: &[usize],
+) -> Result<bool, Error> {
+    if table_lower.broken_blocks()?.is_empty() {
+        return Ok(false);
+    }
+
+    let has_descriptors = |table: &dyn RandomAccessFile| -> Result<bool, Error> {
+        for &offset in descriptor_offsets {
+            let mut magic = [0; 4];
+            table.read(offset, &mut magic)?;
+            if magic != *b"DIFI" {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+
+    let mut select = [0; 1];
+    table_selector.read(0, &mut select)?;
+    let active = table_pair[(select[0] & 1) as usize].clone();
+    let other = table_pair[((select[0] & 1) ^ 1) as usize].clone();
+    let other = IvfcLevel::new(table_hash, other, table_lower.len(), 0)?;
+    let mut table = vec![0; other.len()];
+    let other_verified = other.read(0, &mut table).is_ok();
+    if !other_verified {
+        // Fall back to the raw content
+        other.salvage()?;
+        other.read(0, &mut table)?;
+    }
+
+    if other_verified || (!has_descriptors(active.as_ref())? && has_descriptors(&other)?) {
+        warn!("Active partition table is broken. Falling back to the other one");
+        table_lower.write(0, &table)?;
+        Ok(!other_verified)
+    } else {
+        error!("Partition table is broken");
+        table_lower.salvage()?;
+        Ok(true)
+    }
 }
 
 impl Index<usize> for Disa {
-    type Output = Rc<DifiPartition>;
-    fn index(&self, index: usize) -> &Rc<DifiPartition> {
+    type Output = Arc<DifiPartition>;
+    fn index(&self, index: usize) -> &Arc<DifiPartition> {
         &self.partitions[index]
     }
 }
@@ -317,7 +568,7 @@ mod test {
     }
 
     fn fuzz_one_file(
-        raw_file: Rc<MemoryFile>,
+        raw_file: Arc<MemoryFile>,
         partition_index: usize,
         signer: Option<(Box<SimpleSigner>, [u8; 16])>,
     ) {
@@ -327,6 +578,8 @@ mod test {
             signer
                 .as_ref()
                 .map(|(a, b)| (a.clone() as Box<dyn Signer>, *b)),
+            SelectorInversion::default(),
+            false,
         )
         .unwrap();
         let partition = &disa[partition_index];
@@ -345,6 +598,8 @@ mod test {
                     signer
                         .as_ref()
                         .map(|(a, b)| (a.clone() as Box<dyn Signer>, *b)),
+                    SelectorInversion::default(),
+                    false,
                 )
                 .unwrap()
             },
@@ -360,7 +615,7 @@ mod test {
             let key = rng.gen();
             let param = DifiPartitionParam::random();
             let outer_len = Disa::calculate_size(&param, None);
-            let outer = Rc::new(MemoryFile::new(vec![0; outer_len]));
+            let outer = Arc::new(MemoryFile::new(vec![0; outer_len]));
             Disa::format(outer.clone(), Some((signer.clone(), key)), &param, None).unwrap();
             fuzz_one_file(outer, 0, Some((signer.clone(), key)));
         }
@@ -374,7 +629,7 @@ mod test {
             let param_a = DifiPartitionParam::random();
             let param_b = DifiPartitionParam::random();
             let outer_len = Disa::calculate_size(&param_a, Some(&param_b));
-            let outer = Rc::new(MemoryFile::new(vec![0; outer_len]));
+            let outer = Arc::new(MemoryFile::new(vec![0; outer_len]));
             Disa::format(
                 outer.clone(),
                 Some((signer.clone(), key)),
@@ -386,4 +641,95 @@ mod test {
             fuzz_one_file(outer, 1, Some((signer.clone(), key)));
         }
     }
+
+    #[test]
+    fn previous_generation() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            // An external IVFC level 4 is not duplicated, so its old content is lost
+            let mut param = DifiPartitionParam::random();
+            param.external_ivfc_level4 = false;
+            let outer_len = Disa::calculate_size(&param, None);
+            let outer = Arc::new(MemoryFile::new(vec![0; outer_len]));
+            Disa::format(outer.clone(), None, &param, None).unwrap();
+
+            let disa = Disa::new(outer.clone(), None, SelectorInversion::default(), false).unwrap();
+            let len = disa[0].len();
+            let old: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            disa[0].write(0, &old).unwrap();
+            disa.commit().unwrap();
+
+            let mut new = old.clone();
+            let begin = rng.gen_range(0..len);
+            let end = rng.gen_range(begin + 1..=len);
+            for b in &mut new[begin..end] {
+                *b = !*b;
+            }
+            disa[0].write(begin, &new[begin..end]).unwrap();
+            disa.commit().unwrap();
+
+            let current =
+                Disa::new(outer.clone(), None, SelectorInversion::default(), false).unwrap();
+            let previous =
+                Disa::new(outer.clone(), None, SelectorInversion::previous(), false).unwrap();
+            let mut buf = vec![0; len];
+            previous[0].read(0, &mut buf).unwrap();
+            assert_eq!(buf, old);
+
+            let report = previous[0].compare_generation(&current[0]).unwrap();
+            assert!(!report.changed_blocks.is_empty());
+            assert!(report.broken_blocks.iter().all(Vec::is_empty));
+
+            let report = current[0].compare_generation(&current[0]).unwrap();
+            assert!(report.changed_blocks.is_empty());
+        }
+    }
+
+    #[test]
+    fn salvage_table() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let mut param = DifiPartitionParam::random();
+            param.external_ivfc_level4 = false;
+            let outer_len = Disa::calculate_size(&param, None);
+            let outer = Arc::new(MemoryFile::new(vec![0; outer_len]));
+            Disa::format(outer.clone(), None, &param, None).unwrap();
+
+            let disa = Disa::new(outer.clone(), None, SelectorInversion::default(), false).unwrap();
+            let len = disa[0].len();
+            let old: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            disa[0].write(0, &old).unwrap();
+            disa.commit().unwrap();
+            let new: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            disa[0].write(0, &new).unwrap();
+            disa.commit().unwrap();
+
+            // Damage the DIFI descriptor in the active table
+            let header: DisaHeader = read_struct(outer.as_ref(), 0x100).unwrap();
+            let table_offset = if header.active_table == 0 {
+                header.primary_table_offset
+            } else {
+                header.secondary_table_offset
+            } + header.partition_descriptor[0].offset;
+            outer.write(table_offset as usize, b"XXXX").unwrap();
+            assert!(Disa::new(outer.clone(), None, SelectorInversion::default(), false).is_err());
+
+            // The repaired table stays in memory until commit
+            let mut image = vec![0; outer_len];
+            outer.read(0, &mut image).unwrap();
+            let disa = Disa::new(outer.clone(), None, SelectorInversion::default(), true).unwrap();
+            assert_eq!(disa.salvaged_blocks(), &[(0, 0, 0)]);
+            let mut buf = vec![0; len];
+            disa[0].read(0, &mut buf).unwrap();
+            assert_eq!(buf, old);
+            let mut unchanged = vec![0; outer_len];
+            outer.read(0, &mut unchanged).unwrap();
+            assert!(unchanged == image);
+            disa.commit().unwrap();
+
+            let disa = Disa::new(outer.clone(), None, SelectorInversion::default(), false).unwrap();
+            disa[0].read(0, &mut buf).unwrap();
+            assert_eq!(buf, old);
+        }
+    }
 }
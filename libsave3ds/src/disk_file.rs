@@ -1,11 +1,14 @@
 use crate::error::*;
+use crate::mmap_file::MmapFile;
 use crate::random_access_file::*;
-use std::cell::RefCell;
+use crate::sd_nand_common::Fill;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 pub struct DiskFile {
-    file: RefCell<File>,
+    file: Mutex<File>,
     len: usize,
 }
 
@@ -13,7 +16,7 @@ impl DiskFile {
     pub fn new(file: File) -> std::io::Result<DiskFile> {
         let len = file.metadata()?.len() as usize;
         Ok(DiskFile {
-            file: RefCell::new(file),
+            file: Mutex::new(file),
             len,
         })
     }
@@ -21,19 +24,25 @@ impl DiskFile {
 
 impl RandomAccessFile for DiskFile {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len() {
-            return make_error(Error::OutOfBound);
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
-        let mut file = self.file.borrow_mut();
+        let mut file = self.file.lock().unwrap();
         file.seek(std::io::SeekFrom::Start(pos as u64))?;
         file.read_exact(buf)?;
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len() {
-            return make_error(Error::OutOfBound);
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
-        let mut file = self.file.borrow_mut();
+        let mut file = self.file.lock().unwrap();
         file.seek(std::io::SeekFrom::Start(pos as u64))?;
         file.write_all(buf)?;
         Ok(())
@@ -42,7 +51,90 @@ impl RandomAccessFile for DiskFile {
         self.len
     }
     fn commit(&self) -> Result<(), Error> {
-        self.file.borrow_mut().flush()?;
+        self.file.lock().unwrap().flush()?;
         Ok(())
     }
 }
+
+/// How files on the host are opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileBackend {
+    /// Reads and writes through the file handle. This is the default, and the one to use for
+    /// files that another program may change, such as untrusted uploads.
+    #[default]
+    Disk,
+    /// Maps files opened read-only into memory. Writable files, and files that can't be
+    /// mapped, are opened as with `Disk`. Only use it when requested, as a mapped file that
+    /// another program truncates crashes this one.
+    Mmap,
+}
+
+/// Opens the file at `path` with `backend`.
+pub fn open_file(
+    path: &Path,
+    write: bool,
+    backend: FileBackend,
+) -> Result<Arc<dyn RandomAccessFile>, Error> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(path)?;
+    if backend == FileBackend::Mmap && !write {
+        match MmapFile::new(&file) {
+            Ok(file) => return Ok(Arc::new(file)),
+            Err(e) => log::info!("Failed to map {}, falling back: {}", path.display(), e),
+        }
+    }
+    Ok(Arc::new(DiskFile::new(file)?))
+}
+
+/// Creates a temporary file of `len` bytes next to `path`, lets `fill` write its content
+/// through the layer `wrap`, and then renames the temporary file over `path`.
+pub fn replace_file(
+    path: &Path,
+    len: usize,
+    wrap: impl FnOnce(Arc<dyn RandomAccessFile>) -> Arc<dyn RandomAccessFile>,
+    fill: Fill,
+) -> Result<(), Error> {
+    let mut tmp_name = path.file_name().ok_or(Error::NotFound)?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let write_tmp = || -> Result<(), Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.set_len(len as u64)?;
+        let wrapped = wrap(Arc::new(DiskFile::new(file.try_clone()?)?));
+        fill(wrapped.clone())?;
+        wrapped.commit()?;
+        file.sync_all()?;
+        Ok(())
+    };
+
+    if let Err(e) = write_tmp() {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent(path)
+}
+
+/// Flushes the directory containing `path`, so that a rename into it survives a power loss.
+fn sync_parent(path: &Path) -> Result<(), Error> {
+    // Other systems don't allow opening a directory to flush it.
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
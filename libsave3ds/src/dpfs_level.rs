@@ -1,8 +1,7 @@
 use crate::error::*;
 use crate::misc::*;
 use crate::random_access_file::*;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 /// Implements `RandomAccessFile` layer for a DPFS level.
 ///
@@ -13,27 +12,27 @@ use std::rc::Rc;
 ///
 /// The bit string in the selector file is grouped into 32-bit little endian and MSB-first integers.
 pub struct DpfsLevel {
-    selector: Rc<dyn RandomAccessFile>,
-    pair: [Rc<dyn RandomAccessFile>; 2],
+    selector: Arc<dyn RandomAccessFile>,
+    pair: [Arc<dyn RandomAccessFile>; 2],
     block_len: usize,
     len: usize,
-    dirty: RefCell<Vec<u32>>,
+    dirty: Mutex<Vec<u32>>,
 }
 
 impl DpfsLevel {
     pub fn new(
-        selector: Rc<dyn RandomAccessFile>,
-        pair: [Rc<dyn RandomAccessFile>; 2],
+        selector: Arc<dyn RandomAccessFile>,
+        pair: [Arc<dyn RandomAccessFile>; 2],
         block_len: usize,
     ) -> Result<DpfsLevel, Error> {
         let len = pair[0].len();
         if pair[1].len() != len {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         let block_count = divide_up(len, block_len);
         let chunk_count = divide_up(block_count, 32);
         if chunk_count * 4 > selector.len() {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
 
         Ok(DpfsLevel {
@@ -41,17 +40,21 @@ impl DpfsLevel {
             pair,
             block_len,
             len,
-            dirty: RefCell::new(vec![0; chunk_count]),
+            dirty: Mutex::new(vec![0; chunk_count]),
         })
     }
+
+    pub fn block_len(&self) -> usize {
+        self.block_len
+    }
 }
 
 impl RandomAccessFile for DpfsLevel {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // block index range the operation covers
         let begin_block = pos / self.block_len;
@@ -68,7 +71,7 @@ impl RandomAccessFile for DpfsLevel {
         for chunk_i in begin_chunk..end_chunk {
             // we are going to read from the active partition if the block is clean;
             // otherwise we read from the inactive partition
-            let dirty = self.dirty.borrow()[chunk_i];
+            let dirty = self.dirty.lock().unwrap()[chunk_i];
             let raw = &selector[(chunk_i - begin_chunk) * 4..(chunk_i + 1 - begin_chunk) * 4];
             let select = dirty ^ u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
 
@@ -93,10 +96,10 @@ impl RandomAccessFile for DpfsLevel {
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // block index range the operation covers
         let begin_block = pos / self.block_len;
@@ -111,7 +114,7 @@ impl RandomAccessFile for DpfsLevel {
         self.selector.read(begin_chunk * 4, &mut selector)?;
 
         for chunk_i in begin_chunk..end_chunk {
-            let dirty = &mut self.dirty.borrow_mut()[chunk_i];
+            let dirty = &mut self.dirty.lock().unwrap()[chunk_i];
 
             // we always write to the inactive partition
             let raw = &selector[(chunk_i - begin_chunk) * 4..(chunk_i + 1 - begin_chunk) * 4];
@@ -170,7 +173,7 @@ impl RandomAccessFile for DpfsLevel {
     }
     fn commit(&self) -> Result<(), Error> {
         // Flip selector bits for all dirty blocks
-        let mut dirty = self.dirty.borrow_mut();
+        let mut dirty = self.dirty.lock().unwrap();
         for (i, word) in dirty.iter_mut().enumerate() {
             if *word != 0 {
                 let mut bytes = [0; 4];
@@ -183,6 +186,13 @@ impl RandomAccessFile for DpfsLevel {
         }
         Ok(())
     }
+    fn rollback(&self) -> Result<(), Error> {
+        // Dirty blocks were only written to the inactive partition
+        for word in self.dirty.lock().unwrap().iter_mut() {
+            *word = 0;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -191,12 +201,12 @@ mod test {
     use crate::memory_file::MemoryFile;
     use crate::misc::*;
     use crate::random_access_file::*;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test] #[rustfmt::skip]
     fn test() {
-        let selector = Rc::new(MemoryFile::new(vec![0xF0, 0x0F, 0xFF, 0x00, 0xA0, 0xAA, 0x55, 0x55]));
-        let pair: [Rc<dyn RandomAccessFile>; 2] = [Rc::new(MemoryFile::new(vec![
+        let selector = Arc::new(MemoryFile::new(vec![0xF0, 0x0F, 0xFF, 0x00, 0xA0, 0xAA, 0x55, 0x55]));
+        let pair: [Arc<dyn RandomAccessFile>; 2] = [Arc::new(MemoryFile::new(vec![
             0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
             0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
             0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
@@ -216,7 +226,7 @@ mod test {
             0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
             0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
             0xFF
-        ])), Rc::new(MemoryFile::new(vec![
+        ])), Arc::new(MemoryFile::new(vec![
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -272,17 +282,17 @@ mod test {
             let block_count = divide_up(len, block_len);
             let chunk_count = divide_up(block_count, 32);
             let selector_len = chunk_count * 4;
-            let selector = Rc::new(MemoryFile::new(
+            let selector = Arc::new(MemoryFile::new(
                 (&mut rng)
                     .sample_iter(&Standard)
                     .take(selector_len)
                     .collect(),
             ));
-            let pair: [Rc<dyn RandomAccessFile>; 2] = [
-                Rc::new(MemoryFile::new(
+            let pair: [Arc<dyn RandomAccessFile>; 2] = [
+                Arc::new(MemoryFile::new(
                     (&mut rng).sample_iter(&Standard).take(len).collect(),
                 )),
-                Rc::new(MemoryFile::new(
+                Arc::new(MemoryFile::new(
                     (&mut rng).sample_iter(&Standard).take(len).collect(),
                 )),
             ];
@@ -1,61 +1,67 @@
 use crate::error::*;
 use crate::random_access_file::*;
-use std::cell::Cell;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 
 /// Implements `RandomAccessFile` layer for a dual-image file.
 ///
 /// Only one of the two images is active, which is indicated by another one-byte file.
 /// `DualFile` can be viewed as a special case of `DpdsLevel` which has `block_len = file.len()`.
 pub struct DualFile {
-    selector: Rc<dyn RandomAccessFile>,
-    pair: [Rc<dyn RandomAccessFile>; 2],
-    modified: Cell<u8>,
+    selector: Arc<dyn RandomAccessFile>,
+    pair: [Arc<dyn RandomAccessFile>; 2],
+    modified: AtomicU8,
     len: usize,
 }
 
 impl DualFile {
     pub fn new(
-        selector: Rc<dyn RandomAccessFile>,
-        pair: [Rc<dyn RandomAccessFile>; 2],
+        selector: Arc<dyn RandomAccessFile>,
+        pair: [Arc<dyn RandomAccessFile>; 2],
     ) -> Result<DualFile, Error> {
         let len = pair[0].len();
         if pair[1].len() != len {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         if selector.len() != 1 {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         Ok(DualFile {
             selector,
             pair,
-            modified: Cell::new(0),
+            modified: AtomicU8::new(0),
             len,
         })
     }
+
+    // Reads the index of the active image, which must be 0 or 1.
+    fn active(&self) -> Result<u8, Error> {
+        let mut select = [0; 1];
+        self.selector.read(0, &mut select)?;
+        if select[0] > 1 {
+            return make_error(Error::InvalidValue(Contexts::new()));
+        }
+        Ok(select[0])
+    }
 }
 
 impl RandomAccessFile for DualFile {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len {
-            return make_error(Error::OutOfBound);
+        if pos.checked_add(buf.len()).is_none_or(|end| end > self.len) {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
-        let mut select = [0; 1];
-        self.selector.read(0, &mut select)?;
-        select[0] ^= self.modified.get();
-        self.pair[select[0] as usize].read(pos, buf)
+        let select = self.active()? ^ self.modified.load(Ordering::Relaxed);
+        self.pair[select as usize].read(pos, buf)
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
-        if end > self.len {
-            return make_error(Error::OutOfBound);
-        }
-        let mut select = [0; 1];
-        self.selector.read(0, &mut select)?;
-        let prev = select[0] as usize;
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
+        let prev = self.active()? as usize;
         let cur = 1 - prev;
         self.pair[cur].write(pos, buf)?;
-        if self.modified.get() == 0 {
+        if self.modified.load(Ordering::Relaxed) == 0 {
             if pos != 0 {
                 let mut edge_buf = vec![0; pos];
                 self.pair[prev].read(0, &mut edge_buf)?;
@@ -66,7 +72,7 @@ impl RandomAccessFile for DualFile {
                 self.pair[prev].read(end, &mut edge_buf)?;
                 self.pair[cur].write(end, &edge_buf)?;
             }
-            self.modified.set(1);
+            self.modified.store(1, Ordering::Relaxed);
         }
         Ok(())
     }
@@ -74,15 +80,17 @@ impl RandomAccessFile for DualFile {
         self.len
     }
     fn commit(&self) -> Result<(), Error> {
-        if self.modified.get() == 1 {
-            let mut select = [0; 1];
-            self.selector.read(0, &mut select)?;
-            select[0] = 1 - select[0];
-            self.selector.write(0, &select)?;
-            self.modified.set(0);
+        if self.modified.load(Ordering::Relaxed) == 1 {
+            self.selector.write(0, &[1 - self.active()?])?;
+            self.modified.store(0, Ordering::Relaxed);
         }
         Ok(())
     }
+    fn rollback(&self) -> Result<(), Error> {
+        // The modified image is the inactive one, so switching back to the active one is enough
+        self.modified.store(0, Ordering::Relaxed);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -90,7 +98,7 @@ mod test {
     use crate::dual_file::DualFile;
     use crate::memory_file::MemoryFile;
     use crate::random_access_file::*;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn fuzz() {
@@ -100,12 +108,12 @@ mod test {
         let mut rng = rand::thread_rng();
         for _ in 0..10 {
             let len = rng.gen_range(1..10_000);
-            let selector = Rc::new(MemoryFile::new(vec![0; 1]));
-            let pair: [Rc<dyn RandomAccessFile>; 2] = [
-                Rc::new(MemoryFile::new(
+            let selector = Arc::new(MemoryFile::new(vec![0; 1]));
+            let pair: [Arc<dyn RandomAccessFile>; 2] = [
+                Arc::new(MemoryFile::new(
                     (&mut rng).sample_iter(&Standard).take(len).collect(),
                 )),
-                Rc::new(MemoryFile::new(
+                Arc::new(MemoryFile::new(
                     (&mut rng).sample_iter(&Standard).take(len).collect(),
                 )),
             ];
@@ -1,20 +1,145 @@
 use log::*;
 use std::fmt;
 
+/// The layer of the archive format an error comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    /// The wear leveling journal and block map of a cartridge save.
+    WearLeveling,
+    /// The DISA header and partition table of save data.
+    DisaHeader,
+    /// The DIFF header and partition table of extdata and databases.
+    DiffHeader,
+    /// The DIFI descriptor of a partition.
+    DifiHeader,
+    /// An IVFC level, from 1 to 4. Level 0 stands for the partition table.
+    Ivfc(usize),
+    /// The file allocation table.
+    Fat,
+    /// The directory and file entry tables.
+    FsMeta,
+    /// The file system as seen through archive-relative paths.
+    FileSystem,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layer::WearLeveling => write!(f, "wear leveling"),
+            Layer::DisaHeader => write!(f, "DISA header"),
+            Layer::DiffHeader => write!(f, "DIFF header"),
+            Layer::DifiHeader => write!(f, "DIFI header"),
+            Layer::Ivfc(0) => write!(f, "partition table"),
+            Layer::Ivfc(level) => write!(f, "IVFC level {}", level),
+            Layer::Fat => write!(f, "FAT"),
+            Layer::FsMeta => write!(f, "file system metadata"),
+            Layer::FileSystem => write!(f, "file system"),
+        }
+    }
+}
+
+/// Where an error happened: the layer, and the offset, index and path when known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub layer: Layer,
+    /// Byte offset within the layer.
+    pub offset: Option<usize>,
+    /// Index of the block, FAT node or entry within the layer.
+    pub index: Option<usize>,
+    /// Archive-relative path of the file or directory being accessed.
+    pub path: Option<String>,
+}
+
+impl ErrorContext {
+    pub fn new(layer: Layer) -> ErrorContext {
+        ErrorContext {
+            layer,
+            offset: None,
+            index: None,
+            path: None,
+        }
+    }
+
+    pub fn offset(mut self, offset: usize) -> ErrorContext {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn index(mut self, index: usize) -> ErrorContext {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> ErrorContext {
+        self.path = Some(path.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.layer)?;
+        if let Some(index) = self.index {
+            let name = match self.layer {
+                Layer::Fat => "node",
+                Layer::FsMeta | Layer::WearLeveling => "entry",
+                _ => "block",
+            };
+            write!(f, " {} {}", name, index)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at 0x{:X}", offset)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " ({})", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where an error caused by corrupted data happened, innermost first.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Contexts(Vec<ErrorContext>);
+
+impl Contexts {
+    /// Creates an empty list.
+    pub const fn new() -> Contexts {
+        Contexts(Vec::new())
+    }
+
+    // Adds `context` outside of the ones recorded so far, unless it repeats the last one.
+    fn push(&mut self, context: ErrorContext) {
+        if self.0.last() != Some(&context) {
+            self.0.push(context);
+        }
+    }
+}
+
+impl fmt::Debug for Contexts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.iter()).finish()
+    }
+}
+
+/// Errors from this crate.
+///
+/// Errors caused by corrupted data carry where they happened, which is recorded as they
+/// propagate and can be read with [`contexts`](#method.contexts) or
+/// [`with_contexts`](#method.with_contexts).
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
-    HashMismatch,
-    OutOfBound,
-    MagicMismatch,
-    SizeMismatch,
-    InvalidValue,
-    BrokenFat,
+    HashMismatch(Contexts),
+    OutOfBound(Contexts),
+    MagicMismatch(Contexts),
+    SizeMismatch(Contexts),
+    InvalidValue(Contexts),
+    BrokenFat(Contexts),
     NoSpace,
     NotFound,
     AlreadyExist,
     DeletingRoot,
-    SignatureMismatch,
+    SignatureMismatch(Contexts),
     MissingBoot9,
     MissingSd,
     MissingNand,
@@ -27,30 +152,114 @@ pub enum Error {
     BrokenSd,
     NotEmpty,
     Unsupported,
-    UniqueIdMismatch,
+    UniqueIdMismatch(Contexts),
     BrokenOtp,
     Busy,
     BrokenGame,
+    NotADirectory,
+    IsADirectory,
+    InvalidName,
+    NoTransaction,
+}
+
+impl Error {
+    // The contexts carried by an error caused by corrupted data.
+    fn context_list(&self) -> Option<&Contexts> {
+        match self {
+            Error::HashMismatch(contexts)
+            | Error::OutOfBound(contexts)
+            | Error::MagicMismatch(contexts)
+            | Error::SizeMismatch(contexts)
+            | Error::InvalidValue(contexts)
+            | Error::BrokenFat(contexts)
+            | Error::SignatureMismatch(contexts)
+            | Error::UniqueIdMismatch(contexts) => Some(contexts),
+            _ => None,
+        }
+    }
+
+    fn context_list_mut(&mut self) -> Option<&mut Contexts> {
+        match self {
+            Error::HashMismatch(contexts)
+            | Error::OutOfBound(contexts)
+            | Error::MagicMismatch(contexts)
+            | Error::SizeMismatch(contexts)
+            | Error::InvalidValue(contexts)
+            | Error::BrokenFat(contexts)
+            | Error::SignatureMismatch(contexts)
+            | Error::UniqueIdMismatch(contexts) => Some(contexts),
+            _ => None,
+        }
+    }
+
+    /// Lists where this error happened, from the outermost context to the innermost.
+    /// Returns an empty list for errors not caused by corrupted data.
+    pub fn contexts(&self) -> Vec<ErrorContext> {
+        self.context_list().map_or(vec![], |contexts| {
+            contexts.0.iter().rev().cloned().collect()
+        })
+    }
+
+    /// Attaches the [`contexts`](#method.contexts) of this error, for display
+    /// and for chaining via `source()`.
+    pub fn with_contexts(self) -> ContextError {
+        ContextError {
+            contexts: self.contexts(),
+            error: self,
+        }
+    }
+
+    /// Whether the error is caused by corrupted or uninitialized data.
+    pub fn is_corruption(&self) -> bool {
+        self.context_list().is_some()
+    }
+}
+
+/// An [`Error`](enum.Error.html) along with where it happened, from
+/// [`Error::with_contexts`](enum.Error.html#method.with_contexts).
+/// Displays the contexts before the error, and chains to the error via `source()`.
+#[derive(Debug)]
+pub struct ContextError {
+    /// From the outermost context to the innermost.
+    pub contexts: Vec<ErrorContext>,
+    pub error: Error,
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for context in self.contexts.iter() {
+            write!(f, "{}: ", context)?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IO(e) => write!(f, "IO error from host file system: {:?}", e),
-            Error::HashMismatch => write!(
+            Error::HashMismatch(_) => write!(
                 f,
                 "SHA256 mismatch, caused by either corrupted data or uninitialized data"
             ),
-            Error::OutOfBound => write!(f, "Out-of-bound access, caused by corrupted data"),
-            Error::MagicMismatch => write!(f, "Magic mismatch, caused by corrupted data"),
-            Error::SizeMismatch => write!(f, "Size mismatch, caused by corrupted data"),
-            Error::InvalidValue => write!(f, "Invalid value, caused by corrupted data"),
-            Error::BrokenFat => write!(f, "Broken FAT,  caused by corrupted data"),
+            Error::OutOfBound(_) => write!(f, "Out-of-bound access, caused by corrupted data"),
+            Error::MagicMismatch(_) => write!(f, "Magic mismatch, caused by corrupted data"),
+            Error::SizeMismatch(_) => write!(f, "Size mismatch, caused by corrupted data"),
+            Error::InvalidValue(_) => write!(f, "Invalid value, caused by corrupted data"),
+            Error::BrokenFat(_) => write!(f, "Broken FAT,  caused by corrupted data"),
             Error::NoSpace => write!(f, "Insufficient space for the operation"),
             Error::NotFound => write!(f, "The requested file or directory is not found"),
             Error::AlreadyExist => write!(f, "The file or directory to create already exists"),
             Error::DeletingRoot => write!(f, "Trying to delete the root directory"),
-            Error::SignatureMismatch => write!(f, "Signature mismatch, caused by corrupted data"),
+            Error::SignatureMismatch(_) => {
+                write!(f, "Signature mismatch, caused by corrupted data")
+            }
             Error::MissingBoot9 => write!(f, "Missing boot9.bin"),
             Error::MissingSd => write!(f, "Cannot open SD due to missing SD or movable.sed"),
             Error::MissingNand => write!(f, "Missing NAND"),
@@ -63,7 +272,7 @@ impl fmt::Display for Error {
             Error::BrokenSd => write!(f, "Corrupted SD"),
             Error::NotEmpty => write!(f, "Trying to delete a non-empty directory"),
             Error::Unsupported => write!(f, "The operation is not supported on this archive"),
-            Error::UniqueIdMismatch => {
+            Error::UniqueIdMismatch(_) => {
                 write!(f, "Extdata unique ID mismatch, caused by corrupted data")
             }
             Error::BrokenOtp => write!(f, "Corrupted OTP"),
@@ -72,6 +281,10 @@ impl fmt::Display for Error {
                 "The file or directory is currently used by other program"
             ),
             Error::BrokenGame => write!(f, "Provided game file is broken"),
+            Error::NotADirectory => write!(f, "A file is found where a directory is expected"),
+            Error::IsADirectory => write!(f, "A directory is found where a file is expected"),
+            Error::InvalidName => write!(f, "The path contains an invalid name"),
+            Error::NoTransaction => write!(f, "No transaction is in progress"),
         }
     }
 }
@@ -85,7 +298,51 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// Converts to an `std::io::Error` for `std::io` adapters. Host IO errors are unwrapped,
+/// `HashMismatch` becomes `ErrorKind::InvalidData`, and other errors keep `Error` as the inner error.
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        use std::io::ErrorKind;
+        if let Error::IO(e) = e {
+            return e;
+        }
+        let kind = match e {
+            Error::HashMismatch(_) => ErrorKind::InvalidData,
+            Error::NotFound => ErrorKind::NotFound,
+            Error::AlreadyExist => ErrorKind::AlreadyExists,
+            Error::Unsupported => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
 pub(crate) fn make_error<T>(e: Error) -> Result<T, Error> {
     info!("Error thrown: {:?}", e);
     Err(e)
 }
+
+/// Like `make_error`, but records `context` for the error.
+pub(crate) fn make_error_at<T>(mut e: Error, context: ErrorContext) -> Result<T, Error> {
+    info!("Error thrown: {:?} in {}", e, context);
+    if let Some(contexts) = e.context_list_mut() {
+        contexts.push(context);
+    }
+    Err(e)
+}
+
+pub(crate) trait ResultExt<T> {
+    /// Records the context made by `f` for an error caused by corrupted data.
+    fn context(self, f: impl FnOnce() -> ErrorContext) -> Result<T, Error>;
+}
+
+impl<T> ResultExt<T> for Result<T, Error> {
+    fn context(self, f: impl FnOnce() -> ErrorContext) -> Result<T, Error> {
+        self.map_err(|mut e| {
+            if let Some(contexts) = e.context_list_mut() {
+                contexts.push(f());
+            }
+            e
+        })
+    }
+}
@@ -1,9 +1,12 @@
+use crate::check::*;
 use crate::diff::Diff;
-use crate::difi_partition::DifiPartitionParam;
+use crate::difi_partition::{DifiPartitionParam, GenerationReport, SelectorInversion};
 use crate::error::*;
 use crate::fat::*;
 use crate::file_system::*;
 use crate::fs_meta::{self, FileInfo, FsInfo, OffsetOrFatFile};
+use crate::inspect::*;
+use crate::ivfc_level::LevelReport;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::save_ext_common::*;
@@ -12,7 +15,8 @@ use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
-use std::rc::Rc;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
 
 #[derive(ByteStruct, Clone)]
 #[byte_struct_le]
@@ -106,19 +110,151 @@ pub struct ExtDataFormatParam {
 }
 
 struct ExtDataInner {
-    sd_nand: Rc<dyn SdNandFileSystem>,
+    lock: ArchiveLock,
+    sd_nand: Arc<dyn SdNandFileSystem>,
     base_path: Vec<String>,
     id: u64,
-    fs: Rc<FsMeta>,
+    fs: Arc<FsMeta>,
+    fat: Arc<Fat>,
+    block_len: usize,
     meta_file: Diff,
     quota_file: Option<Diff>,
     key: [u8; 16],
     write: bool,
+    inversion: SelectorInversion,
+    salvage: bool,
+    // Original content of the physical sub-files changed in the current transaction,
+    // or `None` for those that didn't exist. Unset outside of transactions.
+    journal: Mutex<Option<HashMap<u32, Option<Vec<u8>>>>>,
+}
+
+impl ExtDataInner {
+    fn sub_file_path(&self, file_index: u32) -> Vec<String> {
+        let fid_high = file_index / 126;
+        let fid_low = file_index % 126;
+        self.base_path
+            .iter()
+            .cloned()
+            .chain(vec![
+                format!("{:08x}", self.id >> 32),
+                format!("{:08x}", self.id & 0xFFFF_FFFF),
+                format!("{:08x}", fid_high),
+                format!("{:08x}", fid_low),
+            ])
+            .collect()
+    }
+
+    // Lists the indices of the physical sub-files present, from the sub-file directories.
+    fn sub_file_indices(&self) -> Result<Vec<u32>, Error> {
+        let parse = |name: &str| match u32::from_str_radix(name, 16) {
+            Ok(value) if name.len() == 8 => Some(value),
+            _ => None,
+        };
+        let dir = self.sub_file_path(0);
+        let dir: Vec<&str> = dir[..dir.len() - 2].iter().map(|s| s as &str).collect();
+        let mut indices = vec![];
+        for high_name in self.sd_nand.list(&dir)? {
+            let fid_high = match parse(&high_name) {
+                Some(fid_high) => fid_high,
+                None => continue,
+            };
+            let mut high_dir = dir.clone();
+            high_dir.push(&high_name);
+            for low_name in self.sd_nand.list(&high_dir)? {
+                match parse(&low_name) {
+                    Some(fid_low) if fid_low < 126 => indices.push(fid_high * 126 + fid_low),
+                    _ => (),
+                }
+            }
+        }
+        indices.sort_unstable();
+        Ok(indices)
+    }
+
+    fn sub_file_signer(&self, file_index: u32) -> Box<ExtSigner> {
+        let fid_high = file_index / 126;
+        let fid_low = file_index % 126;
+        Box::new(ExtSigner {
+            id: self.id,
+            sub_id: Some((u64::from(fid_high) << 32) | u64::from(fid_low)),
+        })
+    }
+
+    // Opens the physical sub-file, or returns `None` if it does not exist.
+    fn open_sub_file_raw(
+        &self,
+        file_index: u32,
+        write: bool,
+    ) -> Result<Option<Arc<dyn RandomAccessFile>>, Error> {
+        let path = self.sub_file_path(file_index);
+        let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
+        match self.sd_nand.open(&path, write) {
+            Ok(file) => Ok(Some(file)),
+            Err(Error::NotFound) => Ok(None),
+            Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Saves the content of a physical sub-file before it is first changed in a transaction.
+    fn journal_sub_file(&self, file_index: u32) -> Result<(), Error> {
+        let mut journal = self.journal.lock().unwrap();
+        let journal = match journal.as_mut() {
+            Some(journal) if !journal.contains_key(&file_index) => journal,
+            _ => return Ok(()),
+        };
+        let content = match self.open_sub_file_raw(file_index, false)? {
+            Some(file) => {
+                let mut content = vec![0; file.len()];
+                file.read(0, &mut content)?;
+                Some(content)
+            }
+            None => None,
+        };
+        journal.insert(file_index, content);
+        Ok(())
+    }
+
+    // Quota changes are kept pending during a transaction, so that they can be rolled back.
+    fn commit_quota(&self) -> Result<(), Error> {
+        match self.quota_file.as_ref() {
+            Some(quota_file) if self.journal.lock().unwrap().is_none() => quota_file.commit(),
+            _ => Ok(()),
+        }
+    }
+
+    fn release_quota(&self, file_index: u32, physical_len: usize) -> Result<(), Error> {
+        if let Some(quota_file) = self.quota_file.as_ref() {
+            let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            quota.mount_id = file_index;
+            quota.mount_len = physical_len as u64;
+            let block = (divide_up(physical_len, 0x1000)) as u32;
+            quota.free_block += block;
+            quota.potential_free_block = quota.free_block;
+            write_struct(quota_file.partition().as_ref(), 0, quota)?;
+        }
+        self.commit_quota()
+    }
+}
+
+/// A problem found by [`ExtData::scan`](struct.ExtData.html#method.scan).
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ExtDataIssue {
+    /// A physical sub-file that no file entry refers to.
+    /// `file_index` is the index encoded in the sub-file path.
+    Orphan { file_index: u32 },
+    /// A file entry without its physical sub-file.
+    /// A physical sub-file of length zero counts as present. Zero-length files created by this
+    /// library have no physical sub-file at all, so they fall into this category,
+    /// as 3DS refuses to open them anyway.
+    Missing { ino: u32 },
+    /// A file entry whose unique ID differs from the one in its physical sub-file.
+    UniqueIdMismatch { ino: u32, stored: u64, actual: u64 },
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for extdata.
 pub struct ExtData {
-    center: Rc<ExtDataInner>,
+    center: Arc<ExtDataInner>,
 }
 
 impl ExtData {
@@ -201,7 +337,12 @@ impl ExtData {
                 0x01234567_89ABCDEF,
             )?;
 
-            let quota_file = Diff::new(sd_nand.open(&quota_path, true)?, Some((signer, key)))?;
+            let quota_file = Diff::new(
+                sd_nand.open(&quota_path, true)?,
+                Some((signer, key)),
+                SelectorInversion::default(),
+                false,
+            )?;
             write_struct(
                 quota_file.partition().as_ref(),
                 0,
@@ -241,21 +382,26 @@ impl ExtData {
             &diff_param,
             0x01234567_89ABCDEF,
         )?;
-        let meta_file = Diff::new(meta_raw, Some((signer, key)))?;
+        let meta_file = Diff::new(
+            meta_raw,
+            Some((signer, key)),
+            SelectorInversion::default(),
+            false,
+        )?;
 
-        let dir_hash = Rc::new(SubFile::new(
+        let dir_hash = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             dir_hash_offset,
             param.dir_buckets * 4,
         )?);
 
-        let file_hash = Rc::new(SubFile::new(
+        let file_hash = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             file_hash_offset,
             param.file_buckets * 4,
         )?);
 
-        let fat_table = Rc::new(SubFile::new(
+        let fat_table = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             fat_offset,
             (data_block_count + 1) * 8,
@@ -263,7 +409,7 @@ impl ExtData {
 
         Fat::format(fat_table.as_ref())?;
 
-        let data = Rc::new(SubFile::new(
+        let data = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             data_offset,
             data_block_count * block_len,
@@ -284,10 +430,10 @@ impl ExtData {
         };
         FsMeta::format(
             dir_hash,
-            Rc::new(dir_table),
+            Arc::new(dir_table),
             param.max_dir + 2,
             file_hash,
-            Rc::new(file_table),
+            Arc::new(file_table),
             param.max_file + 1,
         )?;
 
@@ -338,13 +484,16 @@ impl ExtData {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
-        sd_nand: Rc<dyn SdNandFileSystem>,
+        sd_nand: Arc<dyn SdNandFileSystem>,
         base_path: &[&str],
         id: u64,
         key: [u8; 16],
         has_quota: bool,
         write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
     ) -> Result<ExtData, Error> {
         let id_high = format!("{:08x}", id >> 32);
         let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
@@ -360,6 +509,8 @@ impl ExtData {
             Some(Diff::new(
                 sd_nand.open(&quota_path, write)?,
                 Some((Box::new(ExtSigner { id, sub_id: None }), key)),
+                inversion,
+                salvage,
             )?)
         } else {
             None
@@ -378,6 +529,8 @@ impl ExtData {
                 }),
                 key,
             )),
+            inversion,
+            salvage,
         )?;
 
         let header: ExtHeader = read_struct(meta_file.partition().as_ref(), 0)?;
@@ -386,7 +539,7 @@ impl ExtData {
                 "Unexpected VSXE magic {:?} {:X}",
                 header.magic, header.version
             );
-            return make_error(Error::MagicMismatch);
+            return make_error(Error::MagicMismatch(Contexts::new()));
         }
         let fs_info: FsInfo = read_struct(
             meta_file.partition().as_ref(),
@@ -397,93 +550,474 @@ impl ExtData {
                 "Unexpected data_block_count={}, fat_size={}",
                 fs_info.data_block_count, fs_info.fat_size
             );
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
 
-        let dir_hash = Rc::new(SubFile::new(
+        let dir_hash = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             fs_info.dir_hash_offset as usize,
             fs_info.dir_buckets as usize * 4,
         )?);
 
-        let file_hash = Rc::new(SubFile::new(
+        let file_hash = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             fs_info.file_hash_offset as usize,
             fs_info.file_buckets as usize * 4,
         )?);
 
-        let fat_table = Rc::new(SubFile::new(
+        let fat_table = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             fs_info.fat_offset as usize,
-            (fs_info.fat_size + 1) as usize * 8,
+            (fs_info.fat_size as usize + 1) * 8,
         )?);
 
-        let data: Rc<dyn RandomAccessFile> = Rc::new(SubFile::new(
+        let data: Arc<dyn RandomAccessFile> = Arc::new(SubFile::new(
             meta_file.partition().clone(),
             fs_info.data_offset as usize,
-            (fs_info.data_block_count * fs_info.block_len) as usize,
+            fs_info.data_block_count as usize * fs_info.block_len as usize,
         )?);
 
         let fat = Fat::new(fat_table, data, fs_info.block_len as usize)?;
 
-        let dir_table: Rc<dyn RandomAccessFile> = Rc::new(FatFile::open(
+        let dir_table: Arc<dyn RandomAccessFile> = Arc::new(FatFile::open(
             fat.clone(),
             fs_info.dir_table.block_index as usize,
         )?);
 
-        let file_table: Rc<dyn RandomAccessFile> =
-            Rc::new(FatFile::open(fat, fs_info.file_table.block_index as usize)?);
+        let file_table: Arc<dyn RandomAccessFile> = Arc::new(FatFile::open(
+            fat.clone(),
+            fs_info.file_table.block_index as usize,
+        )?);
 
-        let fs = FsMeta::new(dir_hash, dir_table, file_hash, file_table)?;
+        let fs = if salvage {
+            FsMeta::new_salvage(dir_hash, dir_table, file_hash, file_table)?
+        } else {
+            FsMeta::new(dir_hash, dir_table, file_hash, file_table)?
+        };
 
         Ok(ExtData {
-            center: Rc::new(ExtDataInner {
+            center: Arc::new(ExtDataInner {
+                lock: ArchiveLock::default(),
                 sd_nand,
                 base_path: base_path.iter().map(|&s| s.to_string()).collect(),
                 id,
                 fs,
+                fat,
+                block_len: fs_info.block_len as usize,
                 meta_file,
                 quota_file,
                 key,
                 write,
+                inversion,
+                salvage,
+                journal: Mutex::new(None),
             }),
         })
     }
+
+    /// Returns the parameters this extdata was formatted with.
+    pub fn format_param(&self) -> Result<ExtDataFormatParam, Error> {
+        let partition = self.center.meta_file.partition();
+        let header: ExtHeader = read_struct(partition.as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(partition.as_ref(), header.fs_info_offset as usize)?;
+        Ok(ExtDataFormatParam {
+            max_dir: fs_info.max_dir as usize,
+            dir_buckets: fs_info.dir_buckets as usize,
+            max_file: fs_info.max_file as usize,
+            file_buckets: fs_info.file_buckets as usize,
+        })
+    }
+
+    // Lists the inodes of all files reachable from the root, in ascending order.
+    fn referenced_files(&self) -> Result<Vec<u32>, Error> {
+        DirMeta::open_ino(self.center.fs.clone(), 1)?.list_files_recursive()
+    }
+
+    fn open_sub_file_raw(
+        &self,
+        file_index: u32,
+    ) -> Result<Option<Arc<dyn RandomAccessFile>>, Error> {
+        self.center.open_sub_file_raw(file_index, self.center.write)
+    }
+
+    fn open_sub_file_diff(&self, file_index: u32) -> Result<Option<Diff>, Error> {
+        self.open_sub_file_raw(file_index)?
+            .map(|file| self.sub_file_diff(file_index, file))
+            .transpose()
+    }
+
+    fn sub_file_diff(
+        &self,
+        file_index: u32,
+        file: Arc<dyn RandomAccessFile>,
+    ) -> Result<Diff, Error> {
+        Diff::new(
+            file,
+            Some((self.center.sub_file_signer(file_index), self.center.key)),
+            self.center.inversion,
+            self.center.salvage,
+        )
+    }
+
+    /// Compares this ext data with `other`, which is the same ext data opened with
+    /// different `SelectorInversion`. Returns one report for the metadata file (index 1)
+    /// and for each referenced sub-file present in both, keyed by sub-file index.
+    pub fn compare_generation(
+        &self,
+        other: &ExtData,
+    ) -> Result<Vec<(u32, GenerationReport)>, Error> {
+        let mut reports = vec![(
+            1,
+            self.center
+                .meta_file
+                .partition()
+                .compare_generation(other.center.meta_file.partition())?,
+        )];
+        for ino in self.referenced_files()? {
+            let file_index = ino + 1;
+            if let (Some(a), Some(b)) = (
+                self.open_sub_file_diff(file_index)?,
+                other.open_sub_file_diff(file_index)?,
+            ) {
+                reports.push((file_index, a.partition().compare_generation(b.partition())?));
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Reports the broken blocks found when this ext data was opened in salvage mode,
+    /// together with the owners of their data.
+    pub fn salvage_report(&self) -> Result<Vec<BrokenBlock>, Error> {
+        let mut report = vec![];
+        let mut add = |file_index: u32, diff: &Diff, owner: BlockOwner| {
+            for &(_, level, index) in diff.salvaged_blocks() {
+                report.push(BrokenBlock {
+                    partition: file_index as usize,
+                    level,
+                    index,
+                    owners: vec![if level == 4 { owner } else { BlockOwner::Hash }],
+                })
+            }
+        };
+        if let Some(quota_file) = &self.center.quota_file {
+            add(0, quota_file, BlockOwner::Metadata);
+        }
+        add(1, &self.center.meta_file, BlockOwner::Metadata);
+        for ino in self.referenced_files()? {
+            if let Some(diff) = self.open_sub_file_diff(ino + 1)? {
+                add(ino + 1, &diff, BlockOwner::File(ino));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Recalculates all hashes and signatures of the metadata and all sub-files, and commits them.
+    /// Used after opening in salvage mode to make the ext data valid again
+    /// with whatever data survived.
+    pub fn rehash(&self) -> Result<(), Error> {
+        for ino in self.referenced_files()? {
+            self.center.journal_sub_file(ino + 1)?;
+            if let Some(diff) = self.open_sub_file_diff(ino + 1)? {
+                diff.rehash();
+                diff.commit()?;
+            }
+        }
+        if let Some(quota_file) = &self.center.quota_file {
+            quota_file.rehash();
+            quota_file.commit()?;
+        }
+        self.center.meta_file.rehash();
+        self.center.meta_file.commit()
+    }
+
+    /// Hashes every block of the partition table and of all IVFC levels of the quota file,
+    /// the metadata file and each referenced sub-file, spread over all CPU cores,
+    /// and reports the broken blocks of each level.
+    /// If the metadata is broken, all broken blocks of the metadata file are reported as in use.
+    pub fn verify_all(&self) -> Result<Vec<LevelReport>, Error> {
+        let _lock = self.center.lock.read();
+        let used = match self.used_ranges() {
+            Ok((ranges, Ok(_))) => Some(ranges),
+            Ok((_, Err(Error::HashMismatch(_)))) | Err(Error::HashMismatch(_)) => None,
+            Ok((_, Err(e))) | Err(e) => return Err(e),
+        };
+        let mut reports = vec![];
+        if let Some(quota_file) = &self.center.quota_file {
+            reports.extend(quota_file.verify_all(0, Some(&[(0, Quota::BYTE_LEN)]))?);
+        }
+        reports.extend(self.center.meta_file.verify_all(1, used.as_deref())?);
+        for ino in self.referenced_files()? {
+            if let Some(diff) = self.open_sub_file_diff(ino + 1)? {
+                reports.extend(diff.verify_all(ino as usize + 1, None)?);
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Describes the container headers, the DPFS selectors and the file system layout
+    /// of the quota file, the metadata file and each referenced sub-file.
+    pub fn inspect(&self) -> Result<InspectNode, Error> {
+        let center = &self.center;
+        let mut node = InspectNode::new("extdata").hex("id", center.id);
+        if let Some(quota_file) = &center.quota_file {
+            let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            let quota = InspectNode::new("quota")
+                .at(0, Quota::BYTE_LEN)
+                .hex("block_len", quota.block_len)
+                .field("dir_capacity", quota.dir_capacity)
+                .field("max_block", quota.max_block)
+                .field("free_block", quota.free_block)
+                .field("potential_free_block", quota.potential_free_block)
+                .field("mount_id", quota.mount_id)
+                .hex("mount_len", quota.mount_len);
+            node =
+                node.child(InspectNode::new("Quota.dat").child(quota_file.inspect(vec![quota])?));
+        }
+
+        let meta = center.meta_file.partition();
+        let header: ExtHeader = read_struct(meta.as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(meta.as_ref(), header.fs_info_offset as usize)?;
+        let (mut data, fs_data) = inspect_fs(
+            &fs_info,
+            header.fs_info_offset as usize,
+            &center.fat,
+            None,
+            &[],
+        )?;
+        data.insert(
+            0,
+            InspectNode::new("VSXE header")
+                .at(0, ExtHeader::BYTE_LEN)
+                .hex("version", header.version)
+                .hex("image_size", header.image_size)
+                .hex("image_block_len", header.image_block_len)
+                .field("mount_id", header.mount_id),
+        );
+        data.push(fs_data);
+        node = node.child(
+            InspectNode::new("sub file")
+                .field("index", 1u32)
+                .child(center.meta_file.inspect(data)?),
+        );
+
+        for ino in self.referenced_files()? {
+            if let Some(diff) = self.open_sub_file_diff(ino + 1)? {
+                node = node.child(
+                    InspectNode::new("sub file")
+                        .field("index", ino + 1)
+                        .child(diff.inspect(vec![])?),
+                );
+            }
+        }
+        Ok(node)
+    }
+
+    /// Returns the length of the physical image of the metadata file (index 1)
+    /// and of each referenced sub-file that exists, keyed by sub-file index.
+    pub fn sub_file_sizes(&self) -> Result<Vec<(u32, usize)>, Error> {
+        let mut sizes = vec![(1, self.center.meta_file.parent_len())];
+        for ino in self.referenced_files()? {
+            if let Some(file) = self.open_sub_file_raw(ino + 1)? {
+                sizes.push((ino + 1, file.len()));
+            }
+        }
+        Ok(sizes)
+    }
+
+    // Lists the ranges in use of the metadata file,
+    // along with the result of checking the metadata and the allocation table.
+    #[allow(clippy::type_complexity)]
+    fn used_ranges(
+        &self,
+    ) -> Result<
+        (
+            Vec<(usize, usize)>,
+            Result<(Vec<CheckIssue>, Vec<u32>), Error>,
+        ),
+        Error,
+    > {
+        let meta = self.center.meta_file.partition();
+        let header: ExtHeader = read_struct(meta.as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(meta.as_ref(), header.fs_info_offset as usize)?;
+        let mut ranges = vec![
+            (0, ExtHeader::BYTE_LEN),
+            (header.fs_info_offset as usize, FsInfo::BYTE_LEN),
+            (
+                fs_info.dir_hash_offset as usize,
+                fs_info.dir_buckets as usize * 4,
+            ),
+            (
+                fs_info.file_hash_offset as usize,
+                fs_info.file_buckets as usize * 4,
+            ),
+        ];
+        let fs_issues = self.check_fs(&fs_info, &mut ranges);
+        Ok((ranges, fs_issues))
+    }
+
+    // Checks the metadata and the allocation table, and adds the ranges in use to `ranges`.
+    // Returns the issues found and the inodes of all reachable files.
+    fn check_fs(
+        &self,
+        fs_info: &FsInfo,
+        ranges: &mut Vec<(usize, usize)>,
+    ) -> Result<(Vec<CheckIssue>, Vec<u32>), Error> {
+        let center = &self.center;
+        let mut issues = vec![];
+        let inos = center.fs.check(&mut issues)?;
+
+        let (dir_len, file_len) = center.fs.used_table_len()?;
+        let tables = [
+            (fs_info.dir_table.block_index, dir_len),
+            (fs_info.file_table.block_index, file_len),
+        ];
+        for &(block, len) in tables.iter() {
+            fat_file_ranges(
+                &center.fat,
+                center.block_len,
+                fs_info.data_offset as usize,
+                block,
+                len,
+                ranges,
+            )?;
+        }
+
+        let tables = [
+            fs_info.dir_table.block_index,
+            fs_info.file_table.block_index,
+        ];
+        issues.append(&mut check_fat_files(
+            &center.fat,
+            center.block_len,
+            fs_info.data_block_count as usize,
+            &tables,
+            &[],
+            fs_info.fat_offset as usize,
+            ranges,
+        )?);
+        Ok((issues, inos))
+    }
+
+    /// Compares the file entries against the physical sub-files found in the extdata directory,
+    /// and reports orphan sub-files, missing sub-files and unique ID mismatches.
+    pub fn scan(&self) -> Result<Vec<ExtDataIssue>, Error> {
+        let referenced = self.referenced_files()?;
+        // Index 1 is the metadata file, and sub-files of file entries start at 2
+        let mut file_indices: BTreeSet<u32> = self
+            .center
+            .sub_file_indices()?
+            .into_iter()
+            .filter(|&file_index| file_index >= 2)
+            .collect();
+        file_indices.extend(referenced.iter().map(|ino| ino + 1));
+        let mut issues = vec![];
+        for file_index in file_indices {
+            let ino = file_index - 1;
+            if referenced.binary_search(&ino).is_err() {
+                issues.push(ExtDataIssue::Orphan { file_index });
+                continue;
+            }
+            match self.open_sub_file_raw(file_index)? {
+                None => issues.push(ExtDataIssue::Missing { ino }),
+                // An empty sub-file is present, but has no header to compare the unique ID with
+                Some(file) if file.len() == 0 => (),
+                Some(file) => {
+                    let data = self.sub_file_diff(file_index, file)?;
+                    let stored = FileMeta::open_ino(self.center.fs.clone(), ino)?
+                        .get_info()?
+                        .unique_id;
+                    let actual = data.unique_id();
+                    if stored != actual {
+                        issues.push(ExtDataIssue::UniqueIdMismatch {
+                            ino,
+                            stored,
+                            actual,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Removes a physical sub-file that no file entry refers to,
+    /// and returns its space to the quota.
+    pub fn delete_orphan(&self, file_index: u32) -> Result<(), Error> {
+        if file_index < 2 || self.referenced_files()?.contains(&(file_index - 1)) {
+            return make_error(Error::InvalidValue(Contexts::new()));
+        }
+        let physical_len = match self.open_sub_file_raw(file_index)? {
+            Some(file) => file.len(),
+            None => return make_error(Error::NotFound),
+        };
+        self.center.journal_sub_file(file_index)?;
+        let path = self.center.sub_file_path(file_index);
+        let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
+        self.center.sd_nand.remove(&path)?;
+        self.center.release_quota(file_index, physical_len)
+    }
+
+    /// Recreates the missing physical sub-file of a file entry as a zero-filled file
+    /// with the specified length, keeping the stored unique ID.
+    pub fn recreate_file(&self, ino: u32, len: usize) -> Result<File, Error> {
+        if len == 0 {
+            return make_error(Error::InvalidValue(Contexts::new()));
+        }
+        if !self.referenced_files()?.contains(&ino) {
+            return make_error(Error::NotFound);
+        }
+        if self.open_sub_file_raw(ino + 1)?.is_some() {
+            return make_error(Error::AlreadyExist);
+        }
+        let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
+        let unique_id = meta.get_info()?.unique_id;
+        File::from_meta(self.center.clone(), meta, Some((len, unique_id)))
+    }
+
+    /// Re-adopts the physical sub-file of a file entry by updating the stored unique ID
+    /// to the one in the sub-file.
+    ///
+    /// The change takes effect after [`commit`](#method.commit).
+    pub fn adopt_file(&self, ino: u32) -> Result<(), Error> {
+        if !self.referenced_files()?.contains(&ino) {
+            return make_error(Error::NotFound);
+        }
+        let data = match self.open_sub_file_diff(ino + 1)? {
+            Some(data) => data,
+            None => return make_error(Error::NotFound),
+        };
+        let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
+        let mut info = meta.get_info()?;
+        info.unique_id = data.unique_id();
+        meta.set_info(info)
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for extdata file.
 pub struct File {
-    center: Rc<ExtDataInner>,
+    center: Arc<ExtDataInner>,
     meta: FileMeta,
     data: Option<Diff>,
 }
 
 impl File {
+    /// Returns whether `dir` belongs to the same opened archive as this file.
+    pub fn same_archive(&self, dir: &Dir) -> bool {
+        Arc::ptr_eq(&self.center, &dir.center)
+    }
+
     fn from_meta(
-        center: Rc<ExtDataInner>,
+        center: Arc<ExtDataInner>,
         meta: FileMeta,
         new: Option<(usize, u64)>,
     ) -> Result<File, Error> {
         let file_index = meta.get_ino() + 1;
-        let id_high = format!("{:08x}", center.id >> 32);
-        let id_low = format!("{:08x}", center.id & 0xFFFF_FFFF);
-        let fid_high = file_index / 126;
-        let fid_low = file_index % 126;
-        let fid_high_s = format!("{:08x}", fid_high);
-        let fid_low_s = format!("{:08x}", fid_low);
-        let path: Vec<&str> = center
-            .base_path
-            .iter()
-            .map(|s| s as &str)
-            .chain(
-                [&id_high, &id_low, &fid_high_s, &fid_low_s]
-                    .iter()
-                    .map(|s| s as &str),
-            )
-            .collect();
+        let path = center.sub_file_path(file_index);
+        let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
 
         let mut param = None;
         if let Some((len, _)) = new {
+            center.journal_sub_file(file_index)?;
             if len != 0 {
                 param = Some(DifiPartitionParam {
                     dpfs_level2_block_len: 128,
@@ -509,17 +1043,14 @@ impl File {
                     quota.potential_free_block = quota.free_block;
                     quota.free_block -= block;
                     write_struct(quota_file.partition().as_ref(), 0, quota)?;
-                    quota_file.commit()?;
                 }
+                center.commit_quota()?;
 
                 center.sd_nand.create(&path, physical_len)?
             }
         }
         let file = center.sd_nand.open(&path, center.write).ok();
-        let signer = Box::new(ExtSigner {
-            id: center.id,
-            sub_id: Some((u64::from(fid_high) << 32) | u64::from(fid_low)),
-        });
+        let signer = center.sub_file_signer(file_index);
 
         if let Some((_, unique_id)) = new {
             if let Some(file) = file.as_ref() {
@@ -533,13 +1064,20 @@ impl File {
         }
 
         let data = file
-            .map(|file| Diff::new(file, Some((signer, center.key))))
+            .map(|file| {
+                Diff::new(
+                    file,
+                    Some((signer, center.key)),
+                    center.inversion,
+                    center.salvage,
+                )
+            })
             .transpose()?;
 
         let info = meta.get_info()?;
         if data.is_some() && info.unique_id != data.as_ref().unwrap().unique_id() {
             error!("Unique ID mismatch");
-            return make_error(Error::UniqueIdMismatch);
+            return make_error(Error::UniqueIdMismatch(Contexts::new()));
         }
         Ok(File { center, meta, data })
     }
@@ -550,38 +1088,13 @@ impl File {
 
         if let Some(file) = self.data.take() {
             std::mem::drop(file); // close the file first
-            let id_high = format!("{:08x}", self.center.id >> 32);
-            let id_low = format!("{:08x}", self.center.id & 0xFFFF_FFFF);
-            let fid_high = file_index / 126;
-            let fid_low = file_index % 126;
-            let fid_high_s = format!("{:08x}", fid_high);
-            let fid_low_s = format!("{:08x}", fid_low);
-            let path: Vec<&str> = self
-                .center
-                .base_path
-                .iter()
-                .map(|s| s as &str)
-                .chain(
-                    [&id_high, &id_low, &fid_high_s, &fid_low_s]
-                        .iter()
-                        .map(|s| s as &str),
-                )
-                .collect();
+            self.center.journal_sub_file(file_index)?;
+            let path = self.center.sub_file_path(file_index);
+            let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
             self.center.sd_nand.remove(&path)?;
         }
 
-        if let Some(quota_file) = self.center.quota_file.as_ref() {
-            let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
-            quota.mount_id = file_index;
-            quota.mount_len = physical_len as u64;
-            let block = (divide_up(physical_len, 0x1000)) as u32;
-            quota.free_block += block;
-            quota.potential_free_block = quota.free_block;
-            write_struct(quota_file.partition().as_ref(), 0, quota)?;
-            quota_file.commit()?;
-        }
-
-        Ok(())
+        self.center.release_quota(file_index, physical_len)
     }
 }
 
@@ -590,6 +1103,7 @@ impl FileSystemFile for File {
     type DirType = Dir;
 
     fn rename(&mut self, parent: &Self::DirType, name: [u8; 16]) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if parent.meta.open_sub_file(name).is_ok() || parent.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -597,6 +1111,7 @@ impl FileSystemFile for File {
     }
 
     fn get_parent_ino(&self) -> Result<u32, Error> {
+        let _lock = self.center.lock.read();
         self.meta.get_parent_ino()
     }
 
@@ -609,6 +1124,8 @@ impl FileSystemFile for File {
     /// Warning: this operation is extremely slow for extdata.
     /// Also, if the size is changed to zero, 3DS will refuse to open the file.
     fn resize(&mut self, len: usize) -> Result<(), Error> {
+        let center = self.center.clone();
+        let _lock = center.lock.write();
         if len == self.len() {
             return Ok(());
         }
@@ -616,9 +1133,14 @@ impl FileSystemFile for File {
         self.meta.check_exclusive()?;
 
         let mut buf = vec![0; len];
-        match self.read(0, &mut buf[0..std::cmp::min(len, self.len())]) {
-            Ok(()) | Err(Error::HashMismatch) => {}
-            e => return e,
+        if let Some(f) = self.data.as_ref() {
+            match f
+                .partition()
+                .read(0, &mut buf[0..std::cmp::min(len, self.len())])
+            {
+                Ok(()) | Err(Error::HashMismatch(_)) => {}
+                e => return e,
+            }
         }
 
         self.delete_data()?;
@@ -626,20 +1148,28 @@ impl FileSystemFile for File {
         let unique_id = self.meta.get_info()?.unique_id;
         let meta = FileMeta::open_ino(self.center.fs.clone(), self.meta.get_ino())?;
         *self = File::from_meta(self.center.clone(), meta, Some((len, unique_id)))?;
-        self.write(0, &buf)?;
-        self.commit()?;
+        if let Some(f) = self.data.as_ref() {
+            f.partition().write(0, &buf)?;
+            f.commit()?;
+        }
         Ok(())
     }
 
     fn delete(mut self) -> Result<(), Error> {
+        let center = self.center.clone();
+        let _lock = center.lock.write();
         self.delete_data()?;
         self.meta.delete()?;
         Ok(())
     }
 
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len() {
-            return make_error(Error::OutOfBound);
+        let _lock = self.center.lock.read();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         if buf.is_empty() {
             return Ok(());
@@ -648,13 +1178,18 @@ impl FileSystemFile for File {
     }
 
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len() {
-            return make_error(Error::OutOfBound);
+        let _lock = self.center.lock.write();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         if buf.is_empty() {
             return Ok(());
         }
         self.meta.check_exclusive()?;
+        self.center.journal_sub_file(self.meta.get_ino() + 1)?;
         self.data.as_ref().unwrap().partition().write(pos, buf)
     }
 
@@ -667,6 +1202,7 @@ impl FileSystemFile for File {
     /// If the file is dropped with uncommitted change, the changed region
     /// becomes unintialized.
     fn commit(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         self.meta.check_exclusive()?;
         if let Some(f) = self.data.as_ref() {
             f.commit()?;
@@ -677,15 +1213,23 @@ impl FileSystemFile for File {
 
 /// Implements [`FileSystemDir`](../file_system/trait.FileSystemDir.html) for extdata directory.
 pub struct Dir {
-    center: Rc<ExtDataInner>,
+    center: Arc<ExtDataInner>,
     meta: DirMeta,
 }
 
+impl Dir {
+    /// Returns whether `other` belongs to the same opened archive as this directory.
+    pub fn same_archive(&self, other: &Dir) -> bool {
+        Arc::ptr_eq(&self.center, &other.center)
+    }
+}
+
 impl FileSystemDir for Dir {
     type NameType = [u8; 16];
     type FileType = File;
 
     fn rename(&mut self, parent: &Dir, name: [u8; 16]) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if parent.meta.open_sub_file(name).is_ok() || parent.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -693,6 +1237,7 @@ impl FileSystemDir for Dir {
     }
 
     fn get_parent_ino(&self) -> Result<u32, Error> {
+        let _lock = self.center.lock.read();
         self.meta.get_parent_ino()
     }
 
@@ -701,6 +1246,7 @@ impl FileSystemDir for Dir {
     }
 
     fn open_sub_dir(&self, name: [u8; 16]) -> Result<Self, Error> {
+        let _lock = self.center.lock.read();
         Ok(Dir {
             center: self.center.clone(),
             meta: self.meta.open_sub_dir(name)?,
@@ -708,18 +1254,22 @@ impl FileSystemDir for Dir {
     }
 
     fn open_sub_file(&self, name: [u8; 16]) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.read();
         File::from_meta(self.center.clone(), self.meta.open_sub_file(name)?, None)
     }
 
     fn list_sub_dir(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
+        let _lock = self.center.lock.read();
         self.meta.list_sub_dir()
     }
 
     fn list_sub_file(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
+        let _lock = self.center.lock.read();
         self.meta.list_sub_file()
     }
 
     fn new_sub_dir(&self, name: [u8; 16]) -> Result<Self, Error> {
+        let _lock = self.center.lock.write();
         if self.meta.open_sub_file(name).is_ok() || self.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -739,6 +1289,7 @@ impl FileSystemDir for Dir {
     ///
     /// Warning: if the file size is zero, 3DS will refuse to open the file.
     fn new_sub_file(&self, name: [u8; 16], len: usize) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.write();
         if self.meta.open_sub_file(name).is_ok() || self.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -757,21 +1308,58 @@ impl FileSystemDir for Dir {
     }
 
     fn delete(self) -> Result<(), Error> {
+        let center = self.center.clone();
+        let _lock = center.lock.write();
         self.meta.delete()
     }
 }
 
+impl Check for ExtData {
+    fn check_parts(&self) -> Result<CheckParts, Error> {
+        let center = &self.center;
+        let (ranges, structure) = self.used_ranges()?;
+
+        let mut hashed = vec![];
+        if let Some(quota_file) = &center.quota_file {
+            hashed.push((
+                0,
+                quota_file.partition().clone(),
+                vec![(0, Quota::BYTE_LEN)],
+            ));
+        }
+        hashed.push((1, center.meta_file.partition().clone(), ranges));
+
+        let structure = match structure {
+            Ok((issues, inos)) => {
+                let mut sub_files = vec![];
+                for ino in inos {
+                    if let Some(diff) = self.open_sub_file_diff(ino + 1)? {
+                        let partition = diff.partition().clone();
+                        let len = partition.len();
+                        sub_files.push((ino as usize + 1, partition, vec![(0, len)]));
+                    }
+                }
+                Ok((issues, sub_files))
+            }
+            Err(e) => Err(e),
+        };
+        Ok(CheckParts { hashed, structure })
+    }
+}
+
 impl FileSystem for ExtData {
     type FileType = File;
     type DirType = Dir;
     type NameType = [u8; 16];
 
     fn open_file(&self, ino: u32) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.read();
         let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
         File::from_meta(self.center.clone(), meta, None)
     }
 
     fn open_dir(&self, ino: u32) -> Result<Self::DirType, Error> {
+        let _lock = self.center.lock.read();
         let meta = DirMeta::open_ino(self.center.fs.clone(), ino)?;
         Ok(Dir {
             center: self.center.clone(),
@@ -787,13 +1375,71 @@ impl FileSystem for ExtData {
     /// The flush behavior of changing file data is controlled by
     /// [`File::commit`](struct.File.html).
     fn commit(&self) -> Result<(), Error> {
-        self.center.meta_file.commit()
+        let _lock = self.center.lock.write();
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            quota_file.commit()?;
+        }
+        self.center.meta_file.commit()?;
+        *self.center.journal.lock().unwrap() = None;
+        Ok(())
+    }
+
+    /// Fails with `Error::Busy` if any file or directory is still open.
+    ///
+    /// The original content of the physical sub-files changed during the transaction is kept
+    /// in memory, so file data written and committed by [`File::commit`](struct.File.html)
+    /// stays if the program is interrupted before the transaction ends.
+    fn begin(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        if Arc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            quota_file.begin()?;
+        }
+        self.center.meta_file.begin()?;
+        *self.center.journal.lock().unwrap() = Some(HashMap::new());
+        Ok(())
+    }
+
+    /// Fails with `Error::Busy` if any file or directory is still open.
+    fn rollback(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        if Arc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        let journal = match self.center.journal.lock().unwrap().take() {
+            Some(journal) => journal,
+            None => return make_error(Error::NoTransaction),
+        };
+        for (file_index, content) in journal {
+            let path = self.center.sub_file_path(file_index);
+            let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
+            match content {
+                Some(content) => {
+                    self.center
+                        .sd_nand
+                        .replace(&path, content.len(), &mut |file| file.write(0, &content))?
+                }
+                None => {
+                    if self.center.open_sub_file_raw(file_index, false)?.is_some() {
+                        self.center.sd_nand.remove(&path)?
+                    }
+                }
+            }
+        }
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            quota_file.rollback()?;
+        }
+        self.center.meta_file.rollback()?;
+        self.center.fat.rollback()
     }
 
     /// Returns the capacity information of the archive.
     ///
     /// `block_len`, `total_blocks` and `free_blocks` are set to 0.
     fn stat(&self) -> Result<Stat, Error> {
+        let _lock = self.center.lock.read();
         let meta_stat = self.center.fs.stat()?;
         Ok(Stat {
             block_len: 0,
@@ -817,6 +1463,20 @@ mod test {
         assert_eq!(Quota::BYTE_LEN, 0x48);
     }
 
+    // Opens the extdata formatted with ID 0 and a zero key for writing.
+    fn open(sd_nand: Arc<dyn SdNandFileSystem>, has_quota: bool) -> Result<ExtData, Error> {
+        ExtData::new(
+            sd_nand,
+            &[],
+            0,
+            [0; 16],
+            has_quota,
+            true,
+            SelectorInversion::default(),
+            false,
+        )
+    }
+
     fn gen_name() -> [u8; 16] {
         use rand::prelude::*;
         let mut rng = rand::thread_rng();
@@ -841,7 +1501,7 @@ mod test {
         let mut rng = rand::thread_rng();
 
         for _ in 0..10 {
-            let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+            let nand = Arc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
 
             let param = ExtDataFormatParam {
                 max_dir: rng.gen_range(10..100),
@@ -851,15 +1511,319 @@ mod test {
             };
 
             ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
-            let file_system = ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap();
+            let file_system = open(nand.clone(), false).unwrap();
+            assert_eq!(file_system.format_param().unwrap(), param);
             crate::file_system::test::fuzzer(
                 file_system,
                 param.max_dir,
                 param.max_file,
-                || ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap(),
+                || open(nand.clone(), false).unwrap(),
                 gen_name,
                 gen_len,
             );
         }
     }
+
+    #[test]
+    fn scan_fix() {
+        let nand = Arc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = open(nand.clone(), false).unwrap();
+        let root = ext.open_dir(1).unwrap();
+        let a = root.new_sub_file([1; 16], 100).unwrap().get_ino();
+        let b = root.new_sub_file([2; 16], 100).unwrap().get_ino();
+        let c = root.new_sub_file([3; 16], 100).unwrap().get_ino();
+        ext.commit().unwrap();
+        assert_eq!(ext.scan().unwrap(), vec![]);
+
+        // orphan: forget the entry but keep the physical file
+        let meta = DirMeta::open_ino(ext.center.fs.clone(), 1).unwrap();
+        meta.open_sub_file([1; 16]).unwrap().delete().unwrap();
+
+        // missing: remove the physical file
+        let path = ext.center.sub_file_path(b + 1);
+        let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
+        nand.remove(&path).unwrap();
+
+        // mismatch: change the stored unique ID
+        let meta = FileMeta::open_ino(ext.center.fs.clone(), c).unwrap();
+        let mut info = meta.get_info().unwrap();
+        info.unique_id = 0x1234;
+        meta.set_info(info).unwrap();
+
+        // stray: a sub-file past the indices the file table can address, and a file that
+        // isn't a sub-file at all
+        let stray = ext.center.sub_file_path(300);
+        let stray: Vec<&str> = stray.iter().map(|s| s as &str).collect();
+        nand.create(&stray, 0x200).unwrap();
+        let mut junk = stray.clone();
+        *junk.last_mut().unwrap() = "notes.txt";
+        nand.create(&junk, 0x10).unwrap();
+
+        let issues = ext.scan().unwrap();
+        assert_eq!(issues.len(), 4);
+        assert!(issues.contains(&ExtDataIssue::Orphan { file_index: a + 1 }));
+        assert!(issues.contains(&ExtDataIssue::Orphan { file_index: 300 }));
+        assert!(issues.contains(&ExtDataIssue::Missing { ino: b }));
+        assert!(issues.contains(&ExtDataIssue::UniqueIdMismatch {
+            ino: c,
+            stored: 0x1234,
+            actual: 0xDEAD_BEEF
+        }));
+        assert!(ext.open_file(c).is_err());
+
+        ext.delete_orphan(a + 1).unwrap();
+        ext.delete_orphan(300).unwrap();
+        assert_eq!(ext.recreate_file(b, 50).unwrap().len(), 50);
+        ext.adopt_file(c).unwrap();
+        ext.commit().unwrap();
+        assert_eq!(ext.scan().unwrap(), vec![]);
+        assert!(ext.open_file(c).is_ok());
+
+        // an empty physical sub-file is present
+        let d = root.new_sub_file([4; 16], 0).unwrap().get_ino();
+        ext.commit().unwrap();
+        assert_eq!(ext.scan().unwrap(), vec![ExtDataIssue::Missing { ino: d }]);
+        let path = ext.center.sub_file_path(d + 1);
+        let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
+        nand.create(&path, 0).unwrap();
+        assert_eq!(ext.scan().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn copy_tree() {
+        use crate::file_system::*;
+        use crate::memory_file::MemoryFile;
+        use crate::save_data::*;
+        let nand = Arc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 4,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = open(nand, false).unwrap();
+        let save_param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &save_param).unwrap();
+        let save =
+            SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false).unwrap();
+        let paths = |ext: &ExtData| -> Vec<String> {
+            let mut paths: Vec<String> =
+                walk(ext, "").unwrap().into_iter().map(|e| e.path).collect();
+            paths.sort();
+            paths
+        };
+        let same = |name: &[u8; 16]| Ok(*name);
+
+        create_dir_all(&save, "a").unwrap();
+        write_all(&save, "a/x", &[1; 100]).unwrap();
+        write_all(&save, "y", &[2; 5000]).unwrap();
+        write_all(&ext, "y", &[3; 10]).unwrap();
+        write_all(&ext, "w", &[4; 10]).unwrap();
+        let (save_root, ext_root) = (save.open_root().unwrap(), ext.open_dir(1).unwrap());
+
+        let report = copy_tree(&save, &save_root, &ext, &ext_root, CopyMode::Mirror, same).unwrap();
+        assert_eq!((report.files_created, report.files_deleted), (1, 1));
+        assert_eq!(report.unverified_files, 0);
+        assert_eq!(paths(&ext), vec!["a", "a/x", "y"]);
+        assert_eq!(read_to_vec(&ext, "a/x").unwrap(), vec![1; 100]);
+        assert_eq!(read_to_vec(&ext, "y").unwrap(), vec![2; 5000]);
+        ext.commit().unwrap();
+        assert_eq!(ext.check().unwrap(), vec![]);
+
+        // a file created without writing reads as a hash mismatch
+        let mut name = [0; 16];
+        name[0] = b'u';
+        ext_root.new_sub_file(name, 50).unwrap();
+        let report = copy_tree(&ext, &ext_root, &save, &save_root, CopyMode::Merge, same).unwrap();
+        assert_eq!(report.unverified_files, 1);
+        assert_eq!(read_to_vec(&save, "u").unwrap().len(), 50);
+
+        write_all(&save, "b", &[5; 10]).unwrap();
+        write_all(&save, "c", &[5; 10]).unwrap();
+        assert!(matches!(
+            copy_tree(&save, &save_root, &ext, &ext_root, CopyMode::Merge, same),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(paths(&ext), vec!["a", "a/x", "u", "y"]);
+    }
+
+    #[test]
+    fn transaction() {
+        use crate::file_system::*;
+        let nand = Arc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(1000), &param).unwrap();
+        let ext = open(nand.clone(), true).unwrap();
+        let quota = || -> Quota {
+            read_struct(
+                ext.center.quota_file.as_ref().unwrap().partition().as_ref(),
+                0,
+            )
+            .unwrap()
+        };
+        create_dir_all(&ext, "a").unwrap();
+        write_all(&ext, "a/x", &[1; 3000]).unwrap();
+        write_all(&ext, "y", &[2; 700]).unwrap();
+        write_all(&ext, "v", &[3; 100]).unwrap();
+        ext.commit().unwrap();
+        let stat = ext.stat().unwrap();
+        let free_block = quota().free_block;
+        let sizes = ext.sub_file_sizes().unwrap();
+
+        assert!(matches!(ext.rollback(), Err(Error::NoTransaction)));
+        let root = ext.open_dir(1).unwrap();
+        assert!(matches!(ext.begin(), Err(Error::Busy)));
+        drop(root);
+        ext.begin().unwrap();
+        write_all(&ext, "a/x", &[3; 5000]).unwrap();
+        write_all(&ext, "y", &[4; 700]).unwrap();
+        write_all(&ext, "z", &[5; 100]).unwrap();
+        remove_dir_all(&ext, "a").unwrap();
+        let root = ext.open_dir(1).unwrap();
+        let mut name = [0; 16];
+        name[0] = b'v';
+        root.open_sub_file(name).unwrap().delete().unwrap();
+        assert!(matches!(ext.rollback(), Err(Error::Busy)));
+        drop(root);
+        ext.rollback().unwrap();
+
+        assert_eq!(ext.stat().unwrap(), stat);
+        assert_eq!(quota().free_block, free_block);
+        assert_eq!(ext.sub_file_sizes().unwrap(), sizes);
+        assert_eq!(read_to_vec(&ext, "a/x").unwrap(), vec![1; 3000]);
+        assert_eq!(read_to_vec(&ext, "y").unwrap(), vec![2; 700]);
+        assert_eq!(read_to_vec(&ext, "v").unwrap(), vec![3; 100]);
+        assert!(!exists(&ext, "z").unwrap());
+        assert_eq!(ext.scan().unwrap(), vec![]);
+        assert_eq!(ext.check().unwrap(), vec![]);
+
+        ext.begin().unwrap();
+        write_all(&ext, "y", &[6; 700]).unwrap();
+        ext.commit().unwrap();
+        assert!(matches!(ext.rollback(), Err(Error::NoTransaction)));
+        let ext = open(nand, true).unwrap();
+        assert_eq!(read_to_vec(&ext, "y").unwrap(), vec![6; 700]);
+        assert_eq!(ext.check().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn sub_file_sizes() {
+        let nand = Arc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = open(nand.clone(), false).unwrap();
+        let root = ext.open_dir(1).unwrap();
+        let a = root.new_sub_file([1; 16], 100).unwrap().get_ino();
+        let dir = root.new_sub_dir([2; 16]).unwrap();
+        let b = dir.new_sub_file([3; 16], 5000).unwrap().get_ino();
+        root.new_sub_file([4; 16], 0).unwrap();
+        ext.commit().unwrap();
+
+        let physical_len = |file_index: u32| {
+            let path = ext.center.sub_file_path(file_index);
+            let path: Vec<&str> = path.iter().map(|s| s as &str).collect();
+            nand.open(&path, false).unwrap().len()
+        };
+        let mut sizes = ext.sub_file_sizes().unwrap();
+        sizes.sort_unstable();
+        assert_eq!(
+            sizes,
+            vec![
+                (1, physical_len(1)),
+                (a + 1, physical_len(a + 1)),
+                (b + 1, physical_len(b + 1)),
+            ]
+        );
+        assert!(physical_len(b + 1) > physical_len(a + 1));
+    }
+
+    #[test]
+    fn check() {
+        let nand = Arc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = open(nand, false).unwrap();
+        let root = ext.open_dir(1).unwrap();
+        root.new_sub_dir([1; 16]).unwrap();
+        let file = root.new_sub_file([2; 16], 100).unwrap();
+        file.write(0, &[0xAA; 100]).unwrap();
+        file.commit().unwrap();
+        let ino = file.get_ino();
+        drop(file);
+        ext.commit().unwrap();
+        assert_eq!(ext.check().unwrap(), vec![]);
+
+        let meta = FileMeta::open_ino(ext.center.fs.clone(), ino).unwrap();
+        let mut info = meta.get_info().unwrap();
+        info.next = ino;
+        meta.set_info(info).unwrap();
+        ext.commit().unwrap();
+        assert_eq!(
+            ext.check().unwrap(),
+            vec![CheckIssue::BrokenLink {
+                table: MetaTableKind::File,
+                ino
+            }]
+        );
+    }
+
+    #[test]
+    fn crash_consistency() {
+        use crate::fault_file::*;
+        use crate::file_system::*;
+        let disk = Arc::new(FaultDisk::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(disk.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = open(disk.clone(), false).unwrap();
+        write_all(&ext, "/a", &[1; 0x300]).unwrap();
+        ext.commit().unwrap();
+        drop(ext);
+
+        // A crash after a sub file is created may leave it orphaned, which scan reports
+        let change = |disk: &Arc<FaultDisk>| {
+            let ext = open(disk.clone(), false)?;
+            create_dir_all(&ext, "/d")?;
+            write_all(&ext, "/d/b", &[2; 0x500])?;
+            ext.commit()
+        };
+        let state = |disk: &Arc<FaultDisk>| snapshot(&open(disk.clone(), false)?, true);
+        // The signed header is written as one sector, which is not torn
+        crash_test_all(&disk, change, state, Tolerance::Exact, &[]);
+    }
 }
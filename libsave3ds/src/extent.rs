@@ -0,0 +1,101 @@
+use crate::check::EMPTY_FILE_BLOCK;
+use crate::error::*;
+use crate::fat::*;
+use std::sync::Arc;
+
+/// A run of consecutive blocks allocated to a file.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub struct Extent {
+    /// Index of the first block in the allocation table.
+    pub first_block: usize,
+    pub block_count: usize,
+    /// Index of the DIFI partition holding the blocks.
+    pub partition: usize,
+    /// Offset of the first block in the data of the DIFI partition.
+    pub offset: usize,
+}
+
+/// The blocks allocated to a file, in file order.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct FileExtents {
+    pub ino: u32,
+    pub size: u64,
+    pub extents: Vec<Extent>,
+}
+
+/// Fragmentation metrics of the files and the free space of an archive.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub struct Fragmentation {
+    /// Number of files with blocks allocated.
+    pub files: usize,
+    /// Number of files made of more than one extent.
+    pub fragmented_files: usize,
+    /// Total number of extents of all files.
+    pub file_extents: usize,
+    pub free_blocks: usize,
+    /// Number of runs of consecutive free blocks.
+    pub free_extents: usize,
+    /// Length in blocks of the longest run of consecutive free blocks.
+    pub largest_free_extent: usize,
+}
+
+/// Merges block indices into runs of consecutive blocks, as `(first_block, block_count)`.
+fn runs(blocks: impl Iterator<Item = usize>) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for block in blocks {
+        match runs.last_mut() {
+            Some((first, count)) if *first + *count == block => *count += 1,
+            _ => runs.push((block, 1)),
+        }
+    }
+    runs
+}
+
+/// Lists the extents of the files in `files`, given as `(ino, block, size)`,
+/// whose blocks lie at `data_offset` of DIFI partition `partition`.
+pub(crate) fn file_extents(
+    fat: &Arc<Fat>,
+    block_len: usize,
+    partition: usize,
+    data_offset: usize,
+    files: &[(u32, u32, u64)],
+) -> Result<Vec<FileExtents>, Error> {
+    let mut result = Vec::with_capacity(files.len());
+    for &(ino, block, size) in files {
+        let extents = if block == EMPTY_FILE_BLOCK {
+            vec![]
+        } else {
+            let file = FatFile::open(fat.clone(), block as usize)?;
+            runs(file.block_indices())
+                .into_iter()
+                .map(|(first_block, block_count)| Extent {
+                    first_block,
+                    block_count,
+                    partition,
+                    offset: data_offset + first_block * block_len,
+                })
+                .collect()
+        };
+        result.push(FileExtents { ino, size, extents });
+    }
+    Ok(result)
+}
+
+/// Computes the fragmentation metrics from the extents of all files and the free list of `fat`.
+pub(crate) fn fragmentation(fat: &Fat, files: &[FileExtents]) -> Result<Fragmentation, Error> {
+    let mut free: Vec<usize> = fat
+        .free_extents()?
+        .into_iter()
+        .flat_map(|(first, count)| first..first + count)
+        .collect();
+    free.sort_unstable();
+    let free = runs(free.into_iter());
+    Ok(Fragmentation {
+        files: files.iter().filter(|f| !f.extents.is_empty()).count(),
+        fragmented_files: files.iter().filter(|f| f.extents.len() > 1).count(),
+        file_extents: files.iter().map(|f| f.extents.len()).sum(),
+        free_blocks: fat.free_blocks(),
+        free_extents: free.len(),
+        largest_free_extent: free.iter().map(|&(_, count)| count).max().unwrap_or(0),
+    })
+}
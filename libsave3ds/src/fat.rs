@@ -1,10 +1,11 @@
+use crate::check::*;
 use crate::error::*;
 use crate::misc::*;
 use crate::random_access_file::*;
 use byte_struct::*;
 use log::*;
-use std::cell::Cell;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 bitfields!(
     #[derive(PartialEq, Clone)]
@@ -21,12 +22,20 @@ struct Entry {
     v: EntryHalf,
 }
 
+/// Result of `Fat::check`.
+pub struct FatCheck {
+    /// Block count of each checked chain, or `None` if the chain is broken.
+    pub chain_len: Vec<Option<usize>>,
+    /// Ranges of the table holding the entries that were read, given as `(offset, len)`.
+    pub table_ranges: Vec<(usize, usize)>,
+}
+
 /// A file allocation table with ninty flavor.
 pub struct Fat {
-    table: Rc<dyn RandomAccessFile>,
-    data: Rc<dyn RandomAccessFile>,
+    table: Arc<dyn RandomAccessFile>,
+    data: Arc<dyn RandomAccessFile>,
     block_len: usize,
-    free_blocks: Cell<usize>,
+    free_blocks: AtomicUsize,
 }
 
 struct BlockMap {
@@ -52,31 +61,43 @@ fn index_good_to_bad(index: Option<usize>) -> u32 {
     index.map_or(0, |i| i as u32 + 1)
 }
 
+fn count_free_blocks(table: &dyn RandomAccessFile) -> Result<usize, Error> {
+    let mut free_blocks = 0;
+    if let Some(head) = get_head(table)? {
+        iterate_fat_entry(table, head, |_node_start, node_size| {
+            free_blocks += node_size;
+        })?;
+    }
+    Ok(free_blocks)
+}
+
 fn get_node(table: &dyn RandomAccessFile, index: usize) -> Result<Node, Error> {
-    let node_start: Entry = read_struct(table, (index + 1) * Entry::BYTE_LEN)?;
+    let context = || ErrorContext::new(Layer::Fat).index(index);
+    let node_start: Entry = read_struct(table, (index + 1) * Entry::BYTE_LEN).context(context)?;
     if (node_start.u.flag == 1) != (node_start.u.index == 0) {
         error!("Node has broken entry");
-        return make_error(Error::BrokenFat);
+        return make_error_at(Error::BrokenFat(Contexts::new()), context());
     }
 
     let size = if node_start.v.flag == 1 {
         let start_i = index + 2;
-        let expand_start: Entry = read_struct(table, start_i * Entry::BYTE_LEN)?;
+        let expand_start: Entry = read_struct(table, start_i * Entry::BYTE_LEN).context(context)?;
 
         if expand_start.u.flag == 0
             || expand_start.v.flag == 1
             || expand_start.u.index as usize != index + 1
+            || expand_start.v.index < expand_start.u.index
         {
             error!("Expanded node has broken starting entry");
-            return make_error(Error::BrokenFat);
+            return make_error_at(Error::BrokenFat(Contexts::new()), context());
         }
 
         let end_i = expand_start.v.index as usize;
-        let expand_end: Entry = read_struct(table, end_i * Entry::BYTE_LEN)?;
+        let expand_end: Entry = read_struct(table, end_i * Entry::BYTE_LEN).context(context)?;
 
         if expand_start != expand_end {
             error!("Expanded node has broken end entry");
-            return make_error(Error::BrokenFat);
+            return make_error_at(Error::BrokenFat(Contexts::new()), context());
         }
         (expand_start.v.index - expand_start.u.index + 1) as usize
     } else {
@@ -125,7 +146,10 @@ fn get_head(table: &dyn RandomAccessFile) -> Result<Option<usize>, Error> {
     let head: Entry = read_struct(table, 0)?;
     if head.u.index != 0 || head.u.flag != 0 || head.v.flag != 0 {
         error!("FAT has broken head");
-        return make_error(Error::BrokenFat);
+        return make_error_at(
+            Error::BrokenFat(Contexts::new()),
+            ErrorContext::new(Layer::Fat).offset(0),
+        );
     }
     Ok(index_bad_to_good(head.v.index))
 }
@@ -146,7 +170,15 @@ fn set_head(table: &dyn RandomAccessFile, index: Option<usize>) -> Result<(), Er
 fn allocate(table: &dyn RandomAccessFile, mut block_count: usize) -> Result<Vec<BlockMap>, Error> {
     let mut block_list = Vec::with_capacity(block_count);
 
-    let mut cur = get_head(table)?.unwrap();
+    let mut cur = match get_head(table)? {
+        Some(head) => head,
+        None => {
+            return make_error_at(
+                Error::BrokenFat(Contexts::new()),
+                ErrorContext::new(Layer::Fat).offset(0),
+            )
+        }
+    };
 
     loop {
         let mut node = get_node(table, cur)?; // get the front free node
@@ -182,7 +214,16 @@ fn allocate(table: &dyn RandomAccessFile, mut block_count: usize) -> Result<Vec<
             }
 
             // iterate to the next free node
-            cur = node.next.unwrap();
+            cur = match node.next {
+                Some(next) => next,
+                None => {
+                    error!("FAT has less space than it should");
+                    return make_error_at(
+                        Error::BrokenFat(Contexts::new()),
+                        ErrorContext::new(Layer::Fat).index(cur),
+                    );
+                }
+            };
         } else {
             // if we need less than the current node
             // we need to split the current node
@@ -210,7 +251,10 @@ fn allocate(table: &dyn RandomAccessFile, mut block_count: usize) -> Result<Vec<
                 let mut next_node = get_node(table, next)?;
                 if next_node.prev != Some(cur) {
                     error!("FAT has less space than it should");
-                    return make_error(Error::BrokenFat);
+                    return make_error_at(
+                        Error::BrokenFat(Contexts::new()),
+                        ErrorContext::new(Layer::Fat).index(next),
+                    );
                 }
                 next_node.prev = Some(cur + block_count);
                 set_node(table, next, next_node)?;
@@ -243,7 +287,10 @@ fn free(table: &dyn RandomAccessFile, block_list: &[BlockMap]) -> Result<(), Err
         let mut free_front = get_node(table, free_front_index)?;
         if free_front.prev.is_some() {
             error!("Trying to free a block list from middle");
-            return make_error(Error::BrokenFat);
+            return make_error_at(
+                Error::BrokenFat(Contexts::new()),
+                ErrorContext::new(Layer::Fat).index(free_front_index),
+            );
         }
         free_front.prev = Some(last_node_index);
         set_node(table, free_front_index, free_front)?;
@@ -252,7 +299,10 @@ fn free(table: &dyn RandomAccessFile, block_list: &[BlockMap]) -> Result<(), Err
     let mut last_node = get_node(table, last_node_index)?;
     if last_node.next.is_some() {
         error!("Trying to free a block list that ends too early");
-        return make_error(Error::BrokenFat);
+        return make_error_at(
+            Error::BrokenFat(Contexts::new()),
+            ErrorContext::new(Layer::Fat).index(last_node_index),
+        );
     }
     last_node.next = maybe_free_front_index;
     set_node(table, last_node_index, last_node)?;
@@ -272,7 +322,10 @@ fn iterate_fat_entry(
         let node = get_node(table, cur)?;
         if node.prev != prev {
             error!("Inconsistent prev pointer detected while iterating");
-            return make_error(Error::BrokenFat);
+            return make_error_at(
+                Error::BrokenFat(Contexts::new()),
+                ErrorContext::new(Layer::Fat).index(cur),
+            );
         }
 
         callback(cur, node.size);
@@ -283,6 +336,60 @@ fn iterate_fat_entry(
     Ok(())
 }
 
+// Walks one chain for `Fat::check`, marking its blocks in `claimed` with `chain`
+// and recording the table entries it reads in `entries`.
+// Returns the number of blocks in the chain, or None if it is broken.
+fn check_chain(
+    table: &dyn RandomAccessFile,
+    claimed: &mut [Option<usize>],
+    chain: usize,
+    first: usize,
+    label: Option<usize>,
+    issues: &mut Vec<CheckIssue>,
+    entries: &mut Vec<usize>,
+) -> Result<Option<usize>, Error> {
+    let mut cur = Some(first);
+    let mut prev = None;
+    let mut count = 0;
+    while let Some(c) = cur {
+        if c >= claimed.len() {
+            issues.push(CheckIssue::BrokenChain { first_block: label });
+            return Ok(None);
+        }
+        if claimed[c] == Some(chain) {
+            issues.push(CheckIssue::ChainLoop { first_block: label });
+            return Ok(None);
+        }
+        entries.push(c + 1);
+        let node = match get_node(table, c) {
+            Ok(node) => node,
+            Err(Error::BrokenFat(_)) | Err(Error::OutOfBound(_)) => {
+                issues.push(CheckIssue::BrokenChain { first_block: label });
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        if node.size != 1 {
+            entries.push(c + 2);
+            entries.push(c + node.size);
+        }
+        if node.prev != prev || c + node.size > claimed.len() {
+            issues.push(CheckIssue::BrokenChain { first_block: label });
+            return Ok(None);
+        }
+        for (block, owner) in claimed.iter_mut().enumerate().skip(c).take(node.size) {
+            match owner {
+                Some(_) => issues.push(CheckIssue::CrossLink { block }),
+                None => *owner = Some(chain),
+            }
+        }
+        count += node.size;
+        prev = Some(c);
+        cur = node.next;
+    }
+    Ok(Some(count))
+}
+
 impl Fat {
     pub fn format(table: &dyn RandomAccessFile) -> Result<(), Error> {
         let block_count = table.len() / 8 - 1;
@@ -299,48 +406,275 @@ impl Fat {
     }
 
     pub fn new(
-        table: Rc<dyn RandomAccessFile>,
-        data: Rc<dyn RandomAccessFile>,
+        table: Arc<dyn RandomAccessFile>,
+        data: Arc<dyn RandomAccessFile>,
         block_len: usize,
-    ) -> Result<Rc<Fat>, Error> {
+    ) -> Result<Arc<Fat>, Error> {
         let table_len = table.len();
         let data_len = data.len();
-        if table_len % 8 != 0 {
-            return make_error(Error::SizeMismatch);
+        if table_len % 8 != 0 || table_len == 0 || block_len == 0 {
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         let block_count = table_len / 8 - 1;
-        if data_len != block_count * block_len {
-            return make_error(Error::SizeMismatch);
-        }
-
-        let mut free_blocks = 0;
-        if let Some(head) = get_head(table.as_ref())? {
-            iterate_fat_entry(table.as_ref(), head, |_node_start, node_size| {
-                free_blocks += node_size;
-            })?;
+        if block_count.checked_mul(block_len) != Some(data_len) {
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
 
-        Ok(Rc::new(Fat {
+        let free_blocks = count_free_blocks(table.as_ref())?;
+        Ok(Arc::new(Fat {
             table,
             data,
             block_len,
-            free_blocks: Cell::new(free_blocks),
+            free_blocks: AtomicUsize::new(free_blocks),
         }))
     }
 
+    /// Counts the free blocks again after the table is rolled back.
+    pub fn rollback(&self) -> Result<(), Error> {
+        self.free_blocks
+            .store(count_free_blocks(self.table.as_ref())?, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub fn free_blocks(&self) -> usize {
-        self.free_blocks.get()
+        self.free_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Returns the nodes of the free list in order, as `(first_block, block_count)`.
+    pub fn free_extents(&self) -> Result<Vec<(usize, usize)>, Error> {
+        let mut extents = vec![];
+        if let Some(head) = get_head(self.table.as_ref())? {
+            iterate_fat_entry(self.table.as_ref(), head, |start, size| {
+                extents.push((start, size))
+            })?;
+        }
+        Ok(extents)
+    }
+
+    /// Returns the nodes of every allocated chain in order, as `(first_block, block_count)`.
+    /// Chains are ordered by their first block.
+    pub fn chains(&self) -> Result<Vec<Vec<(usize, usize)>>, Error> {
+        let table = self.table.as_ref();
+        let block_count = table.len() / 8 - 1;
+        let free_head = get_head(table)?;
+        let mut chains = vec![];
+        let mut block = 0;
+        while block < block_count {
+            let node = get_node(table, block)?;
+            if node.size == 0 {
+                return make_error_at(
+                    Error::BrokenFat(Contexts::new()),
+                    ErrorContext::new(Layer::Fat).index(block),
+                );
+            }
+            if node.prev.is_none() && Some(block) != free_head {
+                let mut chain = vec![];
+                iterate_fat_entry(table, block, |start, size| chain.push((start, size)))?;
+                chains.push(chain);
+            }
+            block += node.size;
+        }
+        Ok(chains)
+    }
+
+    /// Rearranges the chains starting at `first_blocks` so that each of them occupies consecutive
+    /// blocks, placed in the given order from block 0 and followed by all free blocks.
+    ///
+    /// `update` is called with the new first block of each chain before any block is moved,
+    /// so that metadata stored in these chains can point to the new locations and be moved
+    /// along with them. The chains together with the free blocks must cover all blocks exactly.
+    pub fn defragment(
+        &self,
+        first_blocks: &[usize],
+        update: impl FnOnce(&[usize]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let table = self.table.as_ref();
+        let block_count = table.len() / 8 - 1;
+        let mut chains = Vec::with_capacity(first_blocks.len());
+        for &first in first_blocks {
+            let mut blocks = vec![];
+            iterate_fat_entry(table, first, |start, size| {
+                blocks.extend(start..start + size)
+            })?;
+            chains.push(blocks);
+        }
+
+        let mut new_first_blocks = Vec::with_capacity(chains.len());
+        let mut used = 0;
+        for chain in chains.iter() {
+            new_first_blocks.push(used);
+            used += chain.len();
+        }
+        if used + self.free_blocks.load(Ordering::Relaxed) != block_count {
+            error!(
+                "Chains and free blocks don't cover the table: used={}, free={}",
+                used,
+                self.free_blocks.load(Ordering::Relaxed)
+            );
+            return make_error_at(
+                Error::BrokenFat(Contexts::new()),
+                ErrorContext::new(Layer::Fat),
+            );
+        }
+
+        update(&new_first_blocks)?;
+
+        // Block `i` of the result comes from `source[i]`. The moves form paths starting
+        // at a free block and cycles, which are followed one block at a time.
+        let source: Vec<usize> = chains.iter().flatten().cloned().collect();
+        let mut in_use = vec![false; block_count];
+        for &block in source.iter() {
+            in_use[block] = true;
+        }
+        let mut done = vec![false; used];
+        let mut buf = vec![0; self.block_len];
+        // Blocks are moved as read, so that data never written, which fails hash verification,
+        // moves along like any other data.
+        let read = |block: usize, buf: &mut [u8]| -> Result<(), Error> {
+            match self.data.read(block * self.block_len, buf) {
+                Err(Error::HashMismatch(_)) => Ok(()),
+                result => result,
+            }
+        };
+        let copy = |from: usize, to: usize, buf: &mut [u8]| -> Result<(), Error> {
+            read(from, buf)?;
+            self.data.write(to * self.block_len, buf)
+        };
+        for start in (0..used).filter(|&block| !in_use[block]) {
+            let mut to = start;
+            loop {
+                let from = source[to];
+                copy(from, to, &mut buf)?;
+                done[to] = true;
+                if from >= used {
+                    break;
+                }
+                to = from;
+            }
+        }
+        let mut saved = vec![0; self.block_len];
+        for start in 0..used {
+            if done[start] || source[start] == start {
+                continue;
+            }
+            read(start, &mut saved)?;
+            let mut to = start;
+            while source[to] != start {
+                let from = source[to];
+                copy(from, to, &mut buf)?;
+                done[to] = true;
+                to = from;
+            }
+            self.data.write(to * self.block_len, &saved)?;
+            done[to] = true;
+        }
+
+        for (chain, &first) in chains.iter().zip(new_first_blocks.iter()) {
+            set_node(
+                table,
+                first,
+                Node {
+                    size: chain.len(),
+                    prev: None,
+                    next: None,
+                },
+            )?;
+        }
+        if used == block_count {
+            set_head(table, None)
+        } else {
+            set_head(table, Some(used))?;
+            set_node(
+                table,
+                used,
+                Node {
+                    size: block_count - used,
+                    prev: None,
+                    next: None,
+                },
+            )
+        }
+    }
+
+    /// Walks the free list and the chains starting at `first_blocks`, and reports broken
+    /// or looping chains, blocks claimed more than once, lost blocks, and a free list that
+    /// doesn't match `block_count`, the data block count recorded in the file system info.
+    pub fn check(
+        &self,
+        first_blocks: &[usize],
+        block_count: usize,
+        issues: &mut Vec<CheckIssue>,
+    ) -> Result<FatCheck, Error> {
+        let table = self.table.as_ref();
+        let mut claimed = vec![None; table.len() / 8 - 1];
+        let mut entries = vec![0];
+
+        let free_chain = first_blocks.len();
+        let free_list = match get_head(table) {
+            Ok(Some(head)) => check_chain(
+                table,
+                &mut claimed,
+                free_chain,
+                head,
+                None,
+                issues,
+                &mut entries,
+            )?,
+            Ok(None) => Some(0),
+            Err(Error::BrokenFat(_)) => {
+                issues.push(CheckIssue::BrokenChain { first_block: None });
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut chain_len = Vec::with_capacity(first_blocks.len());
+        for (chain, &first) in first_blocks.iter().enumerate() {
+            chain_len.push(check_chain(
+                table,
+                &mut claimed,
+                chain,
+                first,
+                Some(first),
+                issues,
+                &mut entries,
+            )?);
+        }
+
+        // Only comparable if all chains could be counted
+        let used: Option<usize> = chain_len.iter().copied().sum();
+        if let (Some(free_list), Some(used)) = (free_list, used) {
+            let free_blocks = block_count.saturating_sub(used);
+            if free_list != free_blocks {
+                issues.push(CheckIssue::FreeCountMismatch {
+                    free_list,
+                    free_blocks,
+                });
+            }
+        }
+
+        let lost = claimed.iter().filter(|c| c.is_none()).count();
+        if lost != 0 {
+            issues.push(CheckIssue::LostBlocks { count: lost });
+        }
+        Ok(FatCheck {
+            chain_len,
+            table_ranges: entries
+                .into_iter()
+                .map(|i| (i * Entry::BYTE_LEN, Entry::BYTE_LEN))
+                .collect(),
+        })
     }
 }
 
 /// A handle to a file in `Fat` that implements resizing, releasing, reading and writing.
 pub struct FatFile {
-    fat: Rc<Fat>,
+    fat: Arc<Fat>,
     block_list: Vec<BlockMap>,
 }
 impl FatFile {
     /// Opens the file at the specific block index.
-    pub fn open(fat: Rc<Fat>, first_block: usize) -> Result<FatFile, Error> {
+    pub fn open(fat: Arc<Fat>, first_block: usize) -> Result<FatFile, Error> {
         let mut block_list = Vec::new();
 
         iterate_fat_entry(fat.table.as_ref(), first_block, |node_start, node_size| {
@@ -355,16 +689,22 @@ impl FatFile {
         Ok(FatFile { fat, block_list })
     }
 
+    /// Returns the indices of the blocks this file occupies, in file order.
+    pub fn block_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.block_list.iter().map(|b| b.block_index)
+    }
+
     /// Allocates a new file in `Fat` and returns its handle and block index.
-    pub fn create(fat: Rc<Fat>, block_count: usize) -> Result<(FatFile, usize), Error> {
+    pub fn create(fat: Arc<Fat>, block_count: usize) -> Result<(FatFile, usize), Error> {
         if block_count == 0 {
-            return make_error(Error::InvalidValue);
+            return make_error(Error::InvalidValue(Contexts::new()));
         }
-        let free_blocks = fat.free_blocks.get();
+        let free_blocks = fat.free_blocks.load(Ordering::Relaxed);
         if free_blocks < block_count {
             return make_error(Error::NoSpace);
         }
-        fat.free_blocks.set(free_blocks - block_count);
+        fat.free_blocks
+            .store(free_blocks - block_count, Ordering::Relaxed);
 
         let block_list = allocate(fat.table.as_ref(), block_count)?;
         let first = block_list[0].block_index;
@@ -376,14 +716,14 @@ impl FatFile {
         free(self.fat.table.as_ref(), &self.block_list)?;
         self.fat
             .free_blocks
-            .set(self.fat.free_blocks.get() + self.block_list.len());
+            .fetch_add(self.block_list.len(), Ordering::Relaxed);
         Ok(())
     }
 
     /// Allocates more blocks for the file or releases some blocks.
     pub fn resize(&mut self, block_count: usize) -> Result<(), Error> {
         if block_count == 0 {
-            return make_error(Error::InvalidValue);
+            return make_error(Error::InvalidValue(Contexts::new()));
         }
         if block_count == self.block_list.len() {
             return Ok(());
@@ -391,7 +731,7 @@ impl FatFile {
 
         let table = self.fat.table.as_ref();
 
-        let free_blocks = self.fat.free_blocks.get();
+        let free_blocks = self.fat.free_blocks.load(Ordering::Relaxed);
 
         if block_count > self.block_list.len() {
             let delta = block_count - self.block_list.len();
@@ -414,7 +754,9 @@ impl FatFile {
 
             self.block_list.append(&mut block_list);
 
-            self.fat.free_blocks.set(free_blocks - delta);
+            self.fat
+                .free_blocks
+                .store(free_blocks - delta, Ordering::Relaxed);
         } else {
             let delta = self.block_list.len() - block_count;
             let head = &self.block_list[block_count];
@@ -465,7 +807,10 @@ impl FatFile {
                     let mut next = get_node(table, next_index)?;
                     if next.prev != Some(tail_index) {
                         error!("Inconsistent prev pointer detected while resizing");
-                        return make_error(Error::BrokenFat);
+                        return make_error_at(
+                            Error::BrokenFat(Contexts::new()),
+                            ErrorContext::new(Layer::Fat).index(next_index),
+                        );
                     }
                     next.prev = Some(head_index);
                     set_node(table, next_index, next)?;
@@ -475,7 +820,9 @@ impl FatFile {
             free(table, &self.block_list[block_count..])?;
             self.block_list.truncate(block_count);
 
-            self.fat.free_blocks.set(free_blocks + delta);
+            self.fat
+                .free_blocks
+                .store(free_blocks + delta, Ordering::Relaxed);
         }
 
         Ok(())
@@ -484,10 +831,10 @@ impl FatFile {
 
 impl RandomAccessFile for FatFile {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // block index range the operation covers
         let begin_block = pos / self.fat.block_len;
@@ -507,10 +854,10 @@ impl RandomAccessFile for FatFile {
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // block index range the operation covers
         let begin_block = pos / self.fat.block_len;
@@ -541,7 +888,7 @@ impl RandomAccessFile for FatFile {
 mod test {
     use crate::fat::*;
     use crate::memory_file::MemoryFile;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn struct_size() {
@@ -558,8 +905,8 @@ mod test {
             let block_len = rng.gen_range(1..10);
             let block_count = rng.gen_range(1..100);
 
-            let table = Rc::new(MemoryFile::new(vec![0; 8 * (block_count + 1)]));
-            let data = Rc::new(MemoryFile::new(vec![0; block_count * block_len]));
+            let table = Arc::new(MemoryFile::new(vec![0; 8 * (block_count + 1)]));
+            let data = Arc::new(MemoryFile::new(vec![0; block_count * block_len]));
             Fat::format(table.as_ref()).unwrap();
             let fat = Fat::new(table, data, block_len).unwrap();
 
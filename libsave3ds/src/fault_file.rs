@@ -0,0 +1,445 @@
+use crate::error::*;
+use crate::file_system::*;
+use crate::memory_file::MemoryFile;
+use crate::random_access_file::*;
+use crate::sd_nand_common::*;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// A fault to inject into the files of a `FaultDisk`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// All writes land.
+    None,
+    /// Power is lost after `writes` more writes land and `bytes` bytes of the next one land.
+    /// All later reads and writes fail.
+    Cut { writes: usize, bytes: usize },
+    /// The writes with these indices, counted from when the fault is set, are lost
+    /// from the write cache, and power is lost at the next commit of any file.
+    Drop(BTreeSet<usize>),
+    /// Writes are held until their file is committed, and then land in reverse order.
+    /// Power is lost after `n` of them land.
+    Reorder(usize),
+    /// Reads see the bits in `mask` flipped in the byte at `pos` of every file.
+    /// Triggers when a file longer than `pos` is read.
+    FlipBits { pos: usize, mask: u8 },
+}
+
+struct FaultState {
+    fault: Fault,
+    writes: usize,
+    triggered: bool,
+    power_lost: bool,
+}
+
+fn power_lost<T>() -> Result<T, Error> {
+    make_error(Error::IO(std::io::Error::other("injected power loss")))
+}
+
+/// Test-support `RandomAccessFile` in memory that simulates an unreliable medium.
+///
+/// It keeps what the program sees apart from what has landed on the medium.
+/// The files of a `FaultDisk` share the fault, so that one power loss cuts all of them.
+pub struct FaultFile {
+    data: Mutex<Vec<u8>>,
+    medium: Mutex<Vec<u8>>,
+    pending: Mutex<Vec<(usize, Vec<u8>)>>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultFile {
+    fn new(data: Vec<u8>, state: Arc<Mutex<FaultState>>) -> FaultFile {
+        FaultFile {
+            data: Mutex::new(data.clone()),
+            medium: Mutex::new(data),
+            pending: Mutex::new(vec![]),
+            state,
+        }
+    }
+
+    /// Returns the content that has landed on the medium.
+    pub fn medium(&self) -> Vec<u8> {
+        self.medium.lock().unwrap().clone()
+    }
+
+    fn land(&self, pos: usize, buf: &[u8]) {
+        self.medium.lock().unwrap()[pos..pos + buf.len()].copy_from_slice(buf);
+    }
+}
+
+impl RandomAccessFile for FaultFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.power_lost {
+            return power_lost();
+        }
+        let data = self.data.lock().unwrap();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > data.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
+        }
+        buf.copy_from_slice(&data[pos..pos + buf.len()]);
+        if let Fault::FlipBits { pos: flip, mask } = state.fault {
+            if flip < data.len() {
+                state.triggered = true;
+            }
+            if flip >= pos && flip < pos + buf.len() {
+                buf[flip - pos] ^= mask;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.power_lost {
+            return power_lost();
+        }
+        let mut data = self.data.lock().unwrap();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > data.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
+        }
+        data[pos..pos + buf.len()].copy_from_slice(buf);
+        drop(data);
+
+        let index = state.writes;
+        state.writes += 1;
+        match &state.fault {
+            Fault::Cut { writes, bytes } if index >= *writes => {
+                let landed = std::cmp::min(*bytes, buf.len());
+                self.land(pos, &buf[0..landed]);
+                state.triggered = true;
+                state.power_lost = true;
+                return power_lost();
+            }
+            Fault::Drop(dropped) if dropped.contains(&index) => state.triggered = true,
+            Fault::Reorder(_) => self.pending.lock().unwrap().push((pos, buf.to_vec())),
+            _ => self.land(pos, buf),
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    fn commit(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.power_lost {
+            return power_lost();
+        }
+        if matches!(state.fault, Fault::Drop(_)) && state.triggered {
+            state.power_lost = true;
+            return power_lost();
+        }
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (pos, buf) in pending.into_iter().rev() {
+            if let Fault::Reorder(n) = &mut state.fault {
+                if *n == 0 {
+                    state.triggered = true;
+                    state.power_lost = true;
+                    return power_lost();
+                }
+                *n -= 1;
+            }
+            self.land(pos, &buf);
+        }
+        Ok(())
+    }
+}
+
+/// A set of `FaultFile`s sharing one fault, which also serves as SD / NAND for extdata.
+/// Creating, removing and replacing whole files are atomic.
+pub struct FaultDisk {
+    files: Mutex<HashMap<Vec<String>, Arc<FaultFile>>>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultDisk {
+    pub fn new() -> FaultDisk {
+        FaultDisk {
+            files: Mutex::new(HashMap::new()),
+            state: Arc::new(Mutex::new(FaultState {
+                fault: Fault::None,
+                writes: 0,
+                triggered: false,
+                power_lost: false,
+            })),
+        }
+    }
+
+    /// Sets the fault to inject from now on, and resets the write counter.
+    pub fn set_fault(&self, fault: Fault) {
+        let mut state = self.state.lock().unwrap();
+        state.fault = fault;
+        state.writes = 0;
+        state.triggered = false;
+    }
+
+    /// Returns whether the fault has changed anything so far.
+    pub fn triggered(&self) -> bool {
+        self.state.lock().unwrap().triggered
+    }
+
+    /// Adds a file with `data` on the medium.
+    pub fn insert(&self, path: &[&str], data: Vec<u8>) -> Arc<FaultFile> {
+        let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
+        let file = Arc::new(FaultFile::new(data, self.state.clone()));
+        self.files.lock().unwrap().insert(path, file.clone());
+        file
+    }
+
+    /// Gets the file at `path`.
+    pub fn file(&self, path: &[&str]) -> Arc<FaultFile> {
+        let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
+        self.files.lock().unwrap()[&path].clone()
+    }
+
+    /// Simulates a reboot: returns a new disk with the content of each file as on the medium,
+    /// and no fault.
+    pub fn reboot(&self) -> Arc<FaultDisk> {
+        let disk = Arc::new(FaultDisk::new());
+        for (path, file) in self.files.lock().unwrap().iter() {
+            let path: Vec<_> = path.iter().map(|s| s.as_str()).collect();
+            disk.insert(&path, file.medium());
+        }
+        disk
+    }
+
+    fn check_power(&self) -> Result<(), Error> {
+        if self.state.lock().unwrap().power_lost {
+            return power_lost();
+        }
+        Ok(())
+    }
+}
+
+impl SdNandFileSystem for FaultDisk {
+    fn open(&self, path: &[&str], _write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        self.check_power()?;
+        let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
+        match self.files.lock().unwrap().get(&path) {
+            Some(file) => Ok(file.clone()),
+            None => make_error(Error::NotFound),
+        }
+    }
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.check_power()?;
+        self.insert(path, vec![0; len]);
+        Ok(())
+    }
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        self.check_power()?;
+        let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
+        self.files.lock().unwrap().remove(&path);
+        Ok(())
+    }
+    fn remove_dir(&self, _path: &[&str]) -> Result<(), Error> {
+        self.check_power()
+    }
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        self.check_power()?;
+        Ok(list_paths(self.files.lock().unwrap().keys(), path))
+    }
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+        self.check_power()?;
+        let file = Arc::new(MemoryFile::new(vec![0; len]));
+        fill(file.clone())?;
+        self.check_power()?;
+        let mut data = vec![0; len];
+        file.read(0, &mut data)?;
+        self.insert(path, data);
+        Ok(())
+    }
+}
+
+/// An entry found by [`snapshot`]: path, file length (0 for directories) and file content.
+pub type SnapshotEntry = (String, usize, Option<Vec<u8>>);
+
+/// Lists every directory and file of `file_system`. With `data`, file content is included,
+/// or `None` for files with uninitialized data.
+pub fn snapshot<T: FileSystem>(file_system: &T, data: bool) -> Result<Vec<SnapshotEntry>, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let mut entries = vec![];
+    for entry in walk(file_system, "")? {
+        if entry.is_dir {
+            entries.push((entry.path, 0, None));
+            continue;
+        }
+        let file = file_system.open_file(entry.ino)?;
+        let mut buf = vec![0; file.len()];
+        let content = if !data {
+            None
+        } else {
+            match file.read(0, &mut buf) {
+                Ok(()) => Some(buf),
+                Err(Error::HashMismatch(_)) => None,
+                Err(e) => return Err(e),
+            }
+        };
+        entries.push((entry.path, file.len(), content));
+    }
+    Ok(entries)
+}
+
+/// How far the state of an archive after a crash may be from the old or the new state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tolerance {
+    /// It must be the old or the new state.
+    Exact,
+    /// File data is written in place and may be torn,
+    /// so file content that fails verification matches any content.
+    TornData,
+    /// Like `TornData`, and the archive may also fail to open with an error caused by
+    /// corrupted data. The damage must be detected rather than read back.
+    Detected,
+}
+
+// Whether `after` is `expected` within `tolerance`.
+fn matches(after: &[SnapshotEntry], expected: &[SnapshotEntry], tolerance: Tolerance) -> bool {
+    if tolerance == Tolerance::Exact {
+        return after == expected;
+    }
+    after.len() == expected.len()
+        && after.iter().zip(expected.iter()).all(|(a, b)| {
+            a.0 == b.0 && a.1 == b.1 && (a.2.is_none() || b.2.is_none() || a.2 == b.2)
+        })
+}
+
+/// Driver for crash-consistency test.
+///
+/// - `disk`: the medium holding a committed archive.
+/// - `change`: method to open the archive on a disk, change it and commit it.
+/// - `state`: method to open the archive on a disk and take its [`snapshot`].
+/// - `fault`: method to make the fault for each crash point, counted from 0.
+/// - `tolerance`: how far the state after each crash may be from the old or the new state.
+///
+/// `change` is first run without fault to get the new state, which is the old one
+/// for a change that only moves data around. Then for each crash point,
+/// `change` is run on a copy of `disk` with the fault injected, until the fault no longer
+/// triggers. After each crash, the archive must reopen in either the old or the new state.
+/// Returns the number of crash points tested.
+pub fn crash_test(
+    disk: &FaultDisk,
+    change: impl Fn(&Arc<FaultDisk>) -> Result<(), Error>,
+    state: impl Fn(&Arc<FaultDisk>) -> Result<Vec<SnapshotEntry>, Error>,
+    fault: impl Fn(usize) -> Fault,
+    tolerance: Tolerance,
+) -> usize {
+    let old = state(&disk.reboot()).unwrap();
+    let clean = disk.reboot();
+    change(&clean).unwrap();
+    let new = state(&clean.reboot()).unwrap();
+
+    for point in 0.. {
+        let crashed = disk.reboot();
+        crashed.set_fault(fault(point));
+        let result = change(&crashed);
+        if !crashed.triggered() {
+            result.unwrap();
+            return point;
+        }
+        let after = match state(&crashed.reboot()) {
+            Err(e) if tolerance == Tolerance::Detected && e.is_corruption() => continue,
+            after => {
+                after.unwrap_or_else(|e| panic!("Failed to reopen after {:?}: {}", fault(point), e))
+            }
+        };
+        assert!(
+            matches(&after, &old, tolerance) || matches(&after, &new, tolerance),
+            "Neither old nor new state after {:?}",
+            fault(point)
+        );
+    }
+    unreachable!()
+}
+
+/// Runs `crash_test` with every kind of fault: power loss after each write, with the write
+/// also torn after each of `torn` bytes, each write dropped, and the writes between
+/// each two commits of a file reordered, all with `tolerance`.
+/// Then a bit is flipped on read at positions throughout the files. It may be copied along
+/// with the data around a write, which is only detected on later reads.
+pub fn crash_test_all(
+    disk: &FaultDisk,
+    change: impl Fn(&Arc<FaultDisk>) -> Result<(), Error>,
+    state: impl Fn(&Arc<FaultDisk>) -> Result<Vec<SnapshotEntry>, Error>,
+    tolerance: Tolerance,
+    torn: &[usize],
+) {
+    for &bytes in [0].iter().chain(torn) {
+        let fault = |point| Fault::Cut {
+            writes: point,
+            bytes,
+        };
+        crash_test(disk, &change, &state, fault, tolerance);
+    }
+    let fault = |point| Fault::Drop([point].iter().cloned().collect());
+    crash_test(disk, &change, &state, fault, tolerance);
+    crash_test(disk, &change, &state, Fault::Reorder, tolerance);
+    // An odd stride reaches every offset within the blocks over the whole file
+    let fault = |point: usize| Fault::FlipBits {
+        pos: point * 0x101,
+        mask: 1u8 << (point % 8),
+    };
+    crash_test(disk, &change, &state, fault, Tolerance::Detected);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fault_file::*;
+
+    #[test]
+    fn faults() {
+        let disk = FaultDisk::new();
+        let file = disk.insert(&["a"], vec![0; 8]);
+
+        disk.set_fault(Fault::Cut {
+            writes: 1,
+            bytes: 2,
+        });
+        file.write(0, &[1; 4]).unwrap();
+        assert!(!disk.triggered());
+        assert!(file.write(4, &[2; 4]).is_err());
+        assert!(disk.triggered());
+        assert!(file.read(0, &mut [0; 1]).is_err());
+        assert_eq!(file.medium(), [1, 1, 1, 1, 2, 2, 0, 0]);
+
+        let disk = disk.reboot();
+        let file = disk.file(&["a"]);
+        disk.set_fault(Fault::Drop([1].iter().cloned().collect()));
+        file.write(0, &[3; 2]).unwrap();
+        file.write(2, &[4; 2]).unwrap();
+        let mut buf = [0; 8];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [3, 3, 4, 4, 2, 2, 0, 0]);
+        assert_eq!(file.medium(), [3, 3, 1, 1, 2, 2, 0, 0]);
+        assert!(file.commit().is_err());
+
+        let disk = disk.reboot();
+        let file = disk.file(&["a"]);
+
+        disk.set_fault(Fault::Reorder(1));
+        file.write(0, &[5; 2]).unwrap();
+        file.write(6, &[6; 2]).unwrap();
+        assert_eq!(file.medium(), [3, 3, 1, 1, 2, 2, 0, 0]);
+        assert!(file.commit().is_err());
+        assert_eq!(file.medium(), [3, 3, 1, 1, 2, 2, 6, 6]);
+
+        let disk = disk.reboot();
+        let file = disk.file(&["a"]);
+        disk.set_fault(Fault::FlipBits { pos: 6, mask: 0x81 });
+        file.read(4, &mut buf[0..4]).unwrap();
+        assert_eq!(buf[0..4], [2, 2, 0x87, 6]);
+        assert!(disk.triggered());
+        disk.set_fault(Fault::FlipBits { pos: 8, mask: 0x81 });
+        file.read(0, &mut buf).unwrap();
+        assert!(!disk.triggered());
+    }
+}
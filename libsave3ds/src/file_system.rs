@@ -1,4 +1,6 @@
 use crate::error::*;
+use std::convert::TryInto;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 /// The interface for a file opened from [`FileSystem`](trait.FileSystem.html).
 pub trait FileSystemFile {
@@ -24,8 +26,8 @@ pub trait FileSystemFile {
     fn resize(&mut self, len: usize) -> Result<(), Error>;
 
     /// Reads bytes at position `pos` into `buf`. The lenth is determined by `buf.len()`.
-    /// If the read range contains uninitialized data, Error::HashMismatch is returned,
-    /// and the unintialized region will be filled with `0xDD`.
+    /// If the read range contains uninitialized data, an error of kind Error::HashMismatch
+    /// is returned, and the unintialized region will be filled with `0xDD`.
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error>;
 
     /// Writes bytes to position `pos` from `buf`. The lenth is determined by `buf.len()`.
@@ -89,6 +91,21 @@ pub trait FileSystemDir {
     fn delete(self) -> Result<(), Error>;
 }
 
+/// Serializes the operations on an archive shared between threads.
+/// Reading operations run concurrently, while changing operations run exclusively.
+#[derive(Default)]
+pub(crate) struct ArchiveLock(RwLock<()>);
+
+impl ArchiveLock {
+    pub fn read(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, ()> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
 /// Describes the capacity of a [`FileSystem`](trait.FileSystem.html).
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Stat {
@@ -116,6 +133,10 @@ pub struct Stat {
 
 /// The common interface for a 3DS archive (save data, extdata, or title database).
 /// It supports inode-like file system operations.
+///
+/// Archives and their files and directories are `Send` and `Sync`. Operations on the same
+/// archive from several threads are serialized: reads run concurrently, while changes run
+/// one at a time.
 pub trait FileSystem {
     /// The type of files this archive contains.
     type FileType: FileSystemFile<NameType = Self::NameType, DirType = Self::DirType>;
@@ -140,12 +161,764 @@ pub trait FileSystem {
 
     /// Flushes all changes made to the archive.
     /// The behaviour of dropping with uncommitted changes is implementation-defined.
+    /// Use `begin` and `rollback` to discard changes in a defined way.
     fn commit(&self) -> Result<(), Error>;
 
+    /// Commits pending changes and starts a transaction, which ends with `commit` or `rollback`.
+    ///
+    /// The default implementation fails with `Error::Unsupported`.
+    fn begin(&self) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+
+    /// Discards all changes made since `begin`, leaving the archive as it was then.
+    /// All files and directories opened from the archive must be dropped beforehand.
+    ///
+    /// The default implementation fails with `Error::Unsupported`.
+    fn rollback(&self) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+
     /// Returns the capacity information of the archive.
     fn stat(&self) -> Result<Stat, Error>;
 }
 
+fn is_legal_char(c: u8) -> bool {
+    (32..127).contains(&c) && c != 47 && c != 92
+}
+
+/// Conversion between the names an archive uses and their textual form in paths.
+///
+/// `[u8; 16]` names are written as their bytes up to the trailing zeros, where bytes other than
+/// printable ASCII, `/` and `\` are escaped as `\xNN`. `u64` names are written in hexadecimal.
+pub trait NameConvert {
+    /// Converts a name to its textual form.
+    fn name_3ds_to_str(name: &Self) -> String;
+
+    /// Parses the textual form of a name. Returns `None` if `name` is not a valid name.
+    fn name_str_to_3ds(name: &str) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+impl NameConvert for u64 {
+    fn name_3ds_to_str(name: &u64) -> String {
+        format!("{:016x}", name)
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<u64> {
+        u64::from_str_radix(name, 16).ok()
+    }
+}
+
+impl NameConvert for [u8; 16] {
+    fn name_3ds_to_str(name: &[u8; 16]) -> String {
+        let mut last_char = 15;
+        loop {
+            if name[last_char] != 0 || last_char == 0 {
+                break;
+            }
+            last_char -= 1;
+        }
+
+        name[0..=last_char]
+            .iter()
+            .map(|x| {
+                if is_legal_char(*x) {
+                    String::from_utf8(vec![*x]).unwrap()
+                } else {
+                    format!("\\x{:02x}", *x)
+                }
+            })
+            .fold("".to_owned(), |mut x, y| {
+                x.push_str(&y);
+                x
+            })
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<[u8; 16]> {
+        let mut name_converted = [0; 16];
+        let bytes = name.as_bytes();
+        let mut out_i = 0;
+        let mut in_i = 0;
+        loop {
+            if in_i == bytes.len() {
+                break;
+            }
+            if out_i == name_converted.len() {
+                return None;
+            }
+
+            if bytes[in_i] != b'\\' {
+                name_converted[out_i] = bytes[in_i];
+                out_i += 1;
+                in_i += 1;
+            } else {
+                in_i += 1;
+                if *bytes.get(in_i)? != b'x' {
+                    return None;
+                }
+                in_i += 1;
+                name_converted[out_i] =
+                    u8::from_str_radix(std::str::from_utf8(bytes.get(in_i..in_i + 2)?).ok()?, 16)
+                        .ok()?;
+                out_i += 1;
+                in_i += 2;
+            }
+        }
+        Some(name_converted)
+    }
+}
+
+/// A file or a directory opened by [`open_path`](fn.open_path.html).
+pub enum Entry<F, D> {
+    File(F),
+    Dir(D),
+}
+
+/// A file or a directory listed by [`walk`](fn.walk.html).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WalkEntry {
+    /// Path relative to the walked directory, with components separated by `/`.
+    pub path: String,
+    pub ino: u32,
+    pub is_dir: bool,
+}
+
+/// Splits `path` into names. Empty and `.` components are ignored, so leading, trailing and
+/// repeated `/` are allowed, and an empty path refers to the root directory.
+fn split_path<N: NameConvert>(path: &str) -> Result<Vec<N>, Error> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .map(|component| match N::name_str_to_3ds(component) {
+            Some(name) if component != ".." => Ok(name),
+            _ => make_error(Error::InvalidName),
+        })
+        .collect()
+}
+
+/// Opens the sub directory `name` of `dir`, failing with `Error::NotADirectory` if `name` is a file.
+fn open_sub_dir<D: FileSystemDir>(dir: &D, name: D::NameType) -> Result<D, Error>
+where
+    D::NameType: Clone,
+{
+    match dir.open_sub_dir(name.clone()) {
+        Err(Error::NotFound) => {
+            dir.open_sub_file(name)?;
+            make_error(Error::NotADirectory)
+        }
+        result => result,
+    }
+}
+
+fn open_dir_names<T: FileSystem>(
+    file_system: &T,
+    names: &[T::NameType],
+) -> Result<T::DirType, Error>
+where
+    T::NameType: Clone,
+{
+    let mut dir = file_system.open_root()?;
+    for name in names {
+        dir = open_sub_dir(&dir, name.clone())?;
+    }
+    Ok(dir)
+}
+
+// Makes the context for errors caused by corrupted data while accessing `path`.
+fn path_context(path: &str) -> impl FnOnce() -> ErrorContext + '_ {
+    move || ErrorContext::new(Layer::FileSystem).path(path)
+}
+
+/// Opens the file or the directory at `path`, given relative to the root directory.
+///
+/// Fails with `Error::NotFound` if any component does not exist,
+/// and with `Error::NotADirectory` if any component but the last one is a file.
+/// Errors caused by corrupted data carry `path` as context, as in the other path functions.
+pub fn open_path<T: FileSystem>(
+    file_system: &T,
+    path: &str,
+) -> Result<Entry<T::FileType, T::DirType>, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let mut names = split_path::<T::NameType>(path)?;
+    let last = match names.pop() {
+        Some(last) => last,
+        None => return Ok(Entry::Dir(file_system.open_root()?)),
+    };
+    let parent = open_dir_names(file_system, &names).context(path_context(path))?;
+    match parent.open_sub_dir(last.clone()) {
+        Err(Error::NotFound) => Ok(Entry::File(
+            parent.open_sub_file(last).context(path_context(path))?,
+        )),
+        result => Ok(Entry::Dir(result.context(path_context(path))?)),
+    }
+}
+
+/// Returns whether a file or a directory exists at `path`.
+pub fn exists<T: FileSystem>(file_system: &T, path: &str) -> Result<bool, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    match open_path(file_system, path) {
+        Ok(_) => Ok(true),
+        Err(Error::NotFound) | Err(Error::NotADirectory) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates the directory at `path` along with all its missing parents, and opens it.
+pub fn create_dir_all<T: FileSystem>(file_system: &T, path: &str) -> Result<T::DirType, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let mut dir = file_system.open_root()?;
+    for name in split_path::<T::NameType>(path)? {
+        dir = match open_sub_dir(&dir, name.clone()) {
+            Err(Error::NotFound) => dir.new_sub_dir(name)?,
+            result => result?,
+        };
+    }
+    Ok(dir)
+}
+
+fn clear_dir<T: FileSystem>(file_system: &T, dir: &T::DirType) -> Result<(), Error> {
+    for (_, ino) in dir.list_sub_dir()? {
+        let sub_dir = file_system.open_dir(ino)?;
+        clear_dir(file_system, &sub_dir)?;
+        sub_dir.delete()?;
+    }
+    for (_, ino) in dir.list_sub_file()? {
+        file_system.open_file(ino)?.delete()?;
+    }
+    Ok(())
+}
+
+/// Deletes the directory at `path` together with everything it contains.
+///
+/// Fails with `Error::DeletingRoot` before deleting anything if `path` is the root directory.
+pub fn remove_dir_all<T: FileSystem>(file_system: &T, path: &str) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let dir = match open_path(file_system, path)? {
+        Entry::Dir(dir) => dir,
+        Entry::File(_) => return make_error(Error::NotADirectory),
+    };
+    if dir.get_ino() == 1 {
+        return make_error(Error::DeletingRoot);
+    }
+    clear_dir(file_system, &dir).context(path_context(path))?;
+    dir.delete().context(path_context(path))
+}
+
+/// Reads the whole content of the file at `path`.
+pub fn read_to_vec<T: FileSystem>(file_system: &T, path: &str) -> Result<Vec<u8>, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let file = match open_path(file_system, path)? {
+        Entry::File(file) => file,
+        Entry::Dir(_) => return make_error(Error::IsADirectory),
+    };
+    let mut buf = vec![0; file.len()];
+    file.read(0, &mut buf).context(path_context(path))?;
+    Ok(buf)
+}
+
+/// Writes `data` as the whole content of the file at `path`, and commits the file.
+///
+/// The file is created if it does not exist, or resized to the length of `data` otherwise.
+/// The parent directory must exist.
+pub fn write_all<T: FileSystem>(file_system: &T, path: &str, data: &[u8]) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let mut names = split_path::<T::NameType>(path)?;
+    let last = match names.pop() {
+        Some(last) => last,
+        None => return make_error(Error::IsADirectory),
+    };
+    let parent = open_dir_names(file_system, &names).context(path_context(path))?;
+    let file = match parent.open_sub_file(last.clone()) {
+        Ok(mut file) => {
+            if file.len() != data.len() {
+                file.resize(data.len()).context(path_context(path))?;
+            }
+            file
+        }
+        Err(Error::NotFound) => match parent.open_sub_dir(last.clone()) {
+            Ok(_) => return make_error(Error::IsADirectory),
+            Err(Error::NotFound) => parent
+                .new_sub_file(last, data.len())
+                .context(path_context(path))?,
+            Err(e) => return Err(e).context(path_context(path)),
+        },
+        Err(e) => return Err(e).context(path_context(path)),
+    };
+    file.write(0, data).context(path_context(path))?;
+    file.commit().context(path_context(path))
+}
+
+fn walk_dir<T: FileSystem>(
+    file_system: &T,
+    dir: &T::DirType,
+    prefix: &str,
+    entries: &mut Vec<WalkEntry>,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    for (name, ino) in dir.list_sub_dir()? {
+        let path = format!("{}{}", prefix, T::NameType::name_3ds_to_str(&name));
+        entries.push(WalkEntry {
+            path: path.clone(),
+            ino,
+            is_dir: true,
+        });
+        walk_dir(
+            file_system,
+            &file_system.open_dir(ino)?,
+            &(path + "/"),
+            entries,
+        )?;
+    }
+    for (name, ino) in dir.list_sub_file()? {
+        entries.push(WalkEntry {
+            path: format!("{}{}", prefix, T::NameType::name_3ds_to_str(&name)),
+            ino,
+            is_dir: false,
+        });
+    }
+    Ok(())
+}
+
+/// Lists everything under the directory at `path` recursively. Each directory is listed
+/// before its content.
+pub fn walk<T: FileSystem>(file_system: &T, path: &str) -> Result<Vec<WalkEntry>, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let dir = match open_path(file_system, path)? {
+        Entry::Dir(dir) => dir,
+        Entry::File(_) => return make_error(Error::NotADirectory),
+    };
+    let mut entries = vec![];
+    walk_dir(file_system, &dir, "", &mut entries).context(path_context(path))?;
+    Ok(entries)
+}
+
+/// A cursor over a [`FileSystemFile`](trait.FileSystemFile.html) implementing
+/// `std::io::Read`, `std::io::Write` and `std::io::Seek`.
+///
+/// Writing past the end grows the file, filling any gap before the written data with zeros,
+/// and flushing commits it.
+/// Reading uninitialized data fails with `std::io::ErrorKind::InvalidData`
+/// wrapping an error of kind `Error::HashMismatch`.
+pub struct FileCursor<F: FileSystemFile> {
+    file: F,
+    pos: u64,
+}
+
+impl<F: FileSystemFile> FileCursor<F> {
+    /// Wraps `file` with the position at the start.
+    pub fn new(file: F) -> FileCursor<F> {
+        FileCursor { file, pos: 0 }
+    }
+
+    /// Returns the current position.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    pub fn get_mut(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    /// Unwraps the file. Changes not flushed are not committed.
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+}
+
+impl<F: FileSystemFile> std::io::Read for FileCursor<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.file.len() as u64;
+        if self.pos >= len {
+            return Ok(0);
+        }
+        let read_len = std::cmp::min(buf.len() as u64, len - self.pos) as usize;
+        self.file.read(self.pos as usize, &mut buf[0..read_len])?;
+        self.pos += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl<F: FileSystemFile> std::io::Write for FileCursor<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pos: Option<usize> = self.pos.try_into().ok();
+        let end = match pos.and_then(|pos| pos.checked_add(buf.len())) {
+            Some(end) => end,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "writing past the largest possible position",
+                ))
+            }
+        };
+        let pos = end - buf.len();
+        let old_len = self.file.len();
+        if end > old_len {
+            self.file.resize(end)?;
+            // The gap left by seeking past the end reads as zeros.
+            let zeros = [0; 0x1000];
+            let mut gap = old_len;
+            while gap < pos {
+                let len = std::cmp::min(zeros.len(), pos - gap);
+                self.file.write(gap, &zeros[0..len])?;
+                gap += len;
+            }
+        }
+        self.file.write(pos, buf)?;
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(self.file.commit()?)
+    }
+}
+
+impl<F: FileSystemFile> std::io::Seek for FileCursor<F> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            std::io::SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            std::io::SeekFrom::End(offset) => (self.file.len() as u64, offset),
+            std::io::SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seeking to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Maps a name between archives of different name types through its textual form, so that
+/// for example the `u64` name `0x0004000000123400` becomes the `[u8; 16]` name `"0004000000123400"`.
+pub fn convert_name<A: NameConvert, B: NameConvert>(name: &A) -> Result<B, Error> {
+    match B::name_str_to_3ds(&A::name_3ds_to_str(name)) {
+        Some(name) => Ok(name),
+        None => make_error(Error::InvalidName),
+    }
+}
+
+/// How [`copy_tree`](fn.copy_tree.html) treats entries that already exist in the destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CopyMode {
+    /// Makes the destination an exact copy of the source,
+    /// deleting destination entries the source doesn't have.
+    Mirror,
+    /// Copies every source entry, overwriting existing files and keeping other destination entries.
+    Merge,
+    /// Only overwrites files that already exist in the destination. Nothing is created or deleted.
+    UpdateOnly,
+}
+
+/// What [`copy_tree`](fn.copy_tree.html) changed in the destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CopyReport {
+    pub dirs_created: usize,
+    pub files_created: usize,
+    /// Number of files written, including the created ones.
+    pub files_written: usize,
+    pub dirs_deleted: usize,
+    pub files_deleted: usize,
+    /// Number of blocks the written files need beyond what they already had.
+    pub blocks_needed: usize,
+    /// Number of blocks released by deleted files.
+    pub blocks_freed: usize,
+    /// Number of written files whose source failed hash verification,
+    /// such as files with uninitialized data. They are copied as read.
+    pub unverified_files: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CopyPhase {
+    /// Walks both trees without changing anything, to find conflicts and count the usage.
+    Plan,
+    /// Deletes the destination entries missing in the source.
+    Delete,
+    /// Creates directories and writes files.
+    Copy,
+}
+
+/// Returns the number of blocks a file of `len` bytes takes, or zero if blocks are not tracked.
+fn block_count(len: usize, block_len: usize) -> usize {
+    if block_len == 0 {
+        return 0;
+    }
+    len.div_ceil(block_len)
+}
+
+/// Counts the destination entries under `dir`, and deletes them if `delete` is set.
+fn remove_content<B: FileSystem>(
+    dst: &B,
+    dir: &B::DirType,
+    block_len: usize,
+    delete: bool,
+    report: &mut CopyReport,
+) -> Result<(), Error> {
+    for (_, ino) in dir.list_sub_dir()? {
+        let sub_dir = dst.open_dir(ino)?;
+        remove_content(dst, &sub_dir, block_len, delete, report)?;
+        report.dirs_deleted += 1;
+        if delete {
+            sub_dir.delete()?;
+        }
+    }
+    for (_, ino) in dir.list_sub_file()? {
+        let file = dst.open_file(ino)?;
+        report.files_deleted += 1;
+        report.blocks_freed += block_count(file.len(), block_len);
+        if delete {
+            file.delete()?;
+        }
+    }
+    Ok(())
+}
+
+struct CopyTask<'a, A: FileSystem, B: FileSystem, M> {
+    src: &'a A,
+    dst: &'a B,
+    mode: CopyMode,
+    phase: CopyPhase,
+    block_len: usize,
+    map_name: M,
+    report: CopyReport,
+}
+
+impl<'a, A: FileSystem, B: FileSystem, M> CopyTask<'a, A, B, M>
+where
+    B::NameType: Clone + PartialEq,
+    M: Fn(&A::NameType) -> Result<B::NameType, Error>,
+{
+    fn map_list(&self, list: Vec<(A::NameType, u32)>) -> Result<Vec<(B::NameType, u32)>, Error> {
+        list.into_iter()
+            .map(|(name, ino)| Ok(((self.map_name)(&name)?, ino)))
+            .collect()
+    }
+
+    /// Copies `src_dir` into `dst_dir`, which is `None` if it is yet to be created by the copy.
+    fn copy_dir(
+        &mut self,
+        src_dir: &A::DirType,
+        dst_dir: Option<&B::DirType>,
+    ) -> Result<(), Error> {
+        let src_dirs = self.map_list(src_dir.list_sub_dir()?)?;
+        let src_files = self.map_list(src_dir.list_sub_file()?)?;
+        let (dst_dirs, dst_files) = match dst_dir {
+            Some(dst_dir) => (dst_dir.list_sub_dir()?, dst_dir.list_sub_file()?),
+            None => (vec![], vec![]),
+        };
+        let has = |list: &[(B::NameType, u32)], name: &B::NameType| {
+            list.iter().find(|(n, _)| n == name).map(|&(_, ino)| ino)
+        };
+
+        if self.phase == CopyPhase::Plan {
+            for (i, (name, _)) in src_dirs.iter().chain(src_files.iter()).enumerate() {
+                if src_dirs
+                    .iter()
+                    .chain(src_files.iter())
+                    .skip(i + 1)
+                    .any(|(n, _)| n == name)
+                {
+                    return make_error(Error::AlreadyExist);
+                }
+            }
+        }
+
+        if self.mode == CopyMode::Mirror {
+            if self.phase != CopyPhase::Copy {
+                let delete = self.phase == CopyPhase::Delete;
+                for (name, ino) in dst_dirs.iter() {
+                    if has(&src_dirs, name).is_none() {
+                        let dir = self.dst.open_dir(*ino)?;
+                        remove_content(self.dst, &dir, self.block_len, delete, &mut self.report)?;
+                        self.report.dirs_deleted += 1;
+                        if delete {
+                            dir.delete()?;
+                        }
+                    }
+                }
+                for (name, ino) in dst_files.iter() {
+                    if has(&src_files, name).is_none() {
+                        let file = self.dst.open_file(*ino)?;
+                        self.report.files_deleted += 1;
+                        self.report.blocks_freed += block_count(file.len(), self.block_len);
+                        if delete {
+                            file.delete()?;
+                        }
+                    }
+                }
+            }
+        } else if self.phase == CopyPhase::Plan {
+            for (name, _) in src_dirs.iter() {
+                if has(&dst_files, name).is_some() {
+                    return make_error(Error::NotADirectory);
+                }
+            }
+            for (name, _) in src_files.iter() {
+                if has(&dst_dirs, name).is_some() {
+                    return make_error(Error::IsADirectory);
+                }
+            }
+        }
+
+        for (name, ino) in src_dirs {
+            let src_sub_dir = self.src.open_dir(ino)?;
+            match (has(&dst_dirs, &name), dst_dir) {
+                (Some(dst_ino), _) => {
+                    let dst_sub_dir = self.dst.open_dir(dst_ino)?;
+                    self.copy_dir(&src_sub_dir, Some(&dst_sub_dir))?;
+                }
+                _ if self.mode == CopyMode::UpdateOnly => {}
+                (None, Some(dst_dir)) if self.phase == CopyPhase::Copy => {
+                    self.report.dirs_created += 1;
+                    let dst_sub_dir = dst_dir.new_sub_dir(name)?;
+                    self.copy_dir(&src_sub_dir, Some(&dst_sub_dir))?;
+                }
+                _ if self.phase == CopyPhase::Plan => {
+                    self.report.dirs_created += 1;
+                    self.copy_dir(&src_sub_dir, None)?;
+                }
+                _ => {}
+            }
+        }
+
+        if self.phase == CopyPhase::Delete {
+            return Ok(());
+        }
+
+        for (name, ino) in src_files {
+            let dst_ino = has(&dst_files, &name);
+            if dst_ino.is_none() && self.mode == CopyMode::UpdateOnly {
+                continue;
+            }
+            let src_file = self.src.open_file(ino)?;
+            let len = src_file.len();
+            self.report.files_written += 1;
+            if self.phase == CopyPhase::Plan {
+                let old_len = match dst_ino {
+                    Some(dst_ino) => self.dst.open_file(dst_ino)?.len(),
+                    None => {
+                        self.report.files_created += 1;
+                        0
+                    }
+                };
+                self.report.blocks_needed += block_count(len, self.block_len)
+                    .saturating_sub(block_count(old_len, self.block_len));
+                continue;
+            }
+
+            let mut buf = vec![0; len];
+            match src_file.read(0, &mut buf) {
+                Err(Error::HashMismatch(_)) => self.report.unverified_files += 1,
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            }
+            let dst_file = match (dst_ino, dst_dir) {
+                (Some(dst_ino), _) => {
+                    let mut dst_file = self.dst.open_file(dst_ino)?;
+                    dst_file.resize(len)?;
+                    dst_file
+                }
+                (None, Some(dst_dir)) => dst_dir.new_sub_file(name, len)?,
+                (None, None) => return make_error(Error::NotFound),
+            };
+            dst_file.write(0, &buf)?;
+            dst_file.commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the content of `src_dir` in `src` into `dst_dir` in `dst`, treating existing entries
+/// according to `mode`. `map_name` converts source names to destination names, such as
+/// `|name| Ok(*name)` for archives of the same name type, or
+/// [`convert_name`](fn.convert_name.html) otherwise.
+///
+/// Both trees are walked before anything is changed, so that name conflicts, name mapping errors,
+/// and lack of blocks, file slots or directory slots according to `dst.stat()` are reported without
+/// a partial copy. Data blocks are not checked for archives whose `stat()` reports no block size.
+/// Source files failing hash verification, such as those with uninitialized data, are copied
+/// as read and counted in `CopyReport::unverified_files`.
+///
+/// Files are committed as they are written. The destination archive is not committed.
+pub fn copy_tree<A: FileSystem, B: FileSystem>(
+    src: &A,
+    src_dir: &A::DirType,
+    dst: &B,
+    dst_dir: &B::DirType,
+    mode: CopyMode,
+    map_name: impl Fn(&A::NameType) -> Result<B::NameType, Error>,
+) -> Result<CopyReport, Error>
+where
+    B::NameType: Clone + PartialEq,
+{
+    let stat = dst.stat()?;
+    let mut task = CopyTask {
+        src,
+        dst,
+        mode,
+        phase: CopyPhase::Plan,
+        block_len: stat.block_len,
+        map_name,
+        report: CopyReport::default(),
+    };
+    task.copy_dir(src_dir, Some(dst_dir))?;
+    let report = task.report;
+
+    if (stat.block_len != 0 && report.blocks_needed > stat.free_blocks + report.blocks_freed)
+        || report.files_created > stat.free_files + report.files_deleted
+        || report.dirs_created > stat.free_dirs + report.dirs_deleted
+    {
+        return make_error(Error::NoSpace);
+    }
+
+    if mode == CopyMode::Mirror {
+        task.phase = CopyPhase::Delete;
+        task.copy_dir(src_dir, Some(dst_dir))?;
+    }
+    task.phase = CopyPhase::Copy;
+    task.copy_dir(src_dir, Some(dst_dir))?;
+    Ok(CopyReport {
+        unverified_files: task.report.unverified_files,
+        ..report
+    })
+}
+
 #[cfg(test)]
 #[allow(clippy::cognitive_complexity)]
 pub mod test {
@@ -1,11 +1,12 @@
 use crate::byte_struct_common::*;
+use crate::check::*;
 use crate::error::*;
 use crate::random_access_file::*;
 use byte_struct::*;
-use std::cell::*;
-use std::collections::HashMap;
+use log::*;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 #[derive(ByteStruct)]
 #[byte_struct_le]
@@ -54,7 +55,7 @@ pub struct FsInfo {
 
 struct RefTicket<KeyType, InfoType> {
     index: u32,
-    ref_count: Rc<RefCell<HashMap<u32, u32>>>,
+    ref_count: Arc<Mutex<HashMap<u32, u32>>>,
 
     phantom_key: PhantomData<KeyType>,
     phantom_info: PhantomData<InfoType>,
@@ -62,7 +63,7 @@ struct RefTicket<KeyType, InfoType> {
 
 impl<KeyType, InfoType> Drop for RefTicket<KeyType, InfoType> {
     fn drop(&mut self) {
-        let mut ref_count = self.ref_count.borrow_mut();
+        let mut ref_count = self.ref_count.lock().unwrap();
         let previous = *ref_count.get(&self.index).unwrap();
         if previous == 1 {
             ref_count.remove(&self.index);
@@ -74,7 +75,7 @@ impl<KeyType, InfoType> Drop for RefTicket<KeyType, InfoType> {
 
 impl<KeyType, InfoType> RefTicket<KeyType, InfoType> {
     pub fn check_exclusive(&self) -> Result<(), Error> {
-        if *self.ref_count.borrow().get(&self.index).unwrap() != 1 {
+        if *self.ref_count.lock().unwrap().get(&self.index).unwrap() != 1 {
             make_error(Error::Busy)
         } else {
             Ok(())
@@ -90,8 +91,8 @@ pub struct MetaTableStat {
 /// Generic metadata table that implements children lookup / insertion / removal.
 /// This table can then be specialized to file table or directory table.
 struct MetaTable<KeyType, InfoType> {
-    hash: Rc<dyn RandomAccessFile>,
-    table: Rc<dyn RandomAccessFile>,
+    hash: Arc<dyn RandomAccessFile>,
+    table: Arc<dyn RandomAccessFile>,
 
     buckets: usize,
 
@@ -99,7 +100,10 @@ struct MetaTable<KeyType, InfoType> {
     eo_info: usize,
     eo_collision: usize,
 
-    ref_count: Rc<RefCell<HashMap<u32, u32>>>,
+    // Falls back to searching the whole table when an entry can't be found by its hash
+    salvage: bool,
+
+    ref_count: Arc<Mutex<HashMap<u32, u32>>>,
 
     phantom_key: PhantomData<KeyType>,
     phantom_info: PhantomData<InfoType>,
@@ -135,16 +139,22 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
     }
 
     fn new(
-        hash: Rc<dyn RandomAccessFile>,
-        table: Rc<dyn RandomAccessFile>,
+        hash: Arc<dyn RandomAccessFile>,
+        table: Arc<dyn RandomAccessFile>,
     ) -> Result<MetaTable<KeyType, InfoType>, Error> {
         assert!(KeyType::BYTE_LEN % 4 == 0);
 
         if hash.len() % 4 != 0 {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
 
         let buckets = hash.len() / 4;
+        if buckets == 0 {
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::FsMeta),
+            );
+        }
 
         let entry_len = KeyType::BYTE_LEN + InfoType::BYTE_LEN + 4;
         let eo_info = KeyType::BYTE_LEN;
@@ -157,7 +167,8 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
             entry_len,
             eo_info,
             eo_collision,
-            ref_count: Rc::new(RefCell::new(HashMap::new())),
+            salvage: false,
+            ref_count: Arc::new(Mutex::new(HashMap::new())),
             phantom_key: PhantomData,
             phantom_info: PhantomData,
         })
@@ -175,13 +186,37 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
         h as usize % self.buckets
     }
 
+    /// Counts one more step along a chain of entries. A chain longer than the table
+    /// must loop back on itself, so it is reported instead of being followed forever.
+    fn step(&self, steps: &mut usize) -> Result<(), Error> {
+        *steps += 1;
+        if *steps * self.entry_len > self.table.len() {
+            return make_error_at(
+                Error::InvalidValue(Contexts::new()),
+                ErrorContext::new(Layer::FsMeta),
+            );
+        }
+        Ok(())
+    }
+
     /// Looks up an entry given the key and returns its info and index (inode).
     fn get(&self, key: &KeyType) -> Result<(InfoType, u32), Error> {
+        match self.get_hashed(key) {
+            Ok(found) => Ok(found),
+            Err(_) if self.salvage => self.scan(key),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Looks up an entry through its hash bucket chain.
+    fn get_hashed(&self, key: &KeyType) -> Result<(InfoType, u32), Error> {
         let h = self.hash(key);
         let table = self.table.as_ref();
         let hash = self.hash.as_ref();
         let mut index = read_struct::<U32le>(hash, h * 4)?.v;
+        let mut steps = 0;
         while index != 0 {
+            self.step(&mut steps)?;
             let entry_offset = index as usize * self.entry_len;
             let other_key: KeyType = read_struct(table, entry_offset)?;
             if *key == other_key {
@@ -194,6 +229,33 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
         make_error(Error::NotFound)
     }
 
+    /// Looks up an entry by going through all entries not in the free list,
+    /// for entries behind a broken hash bucket chain.
+    fn scan(&self, key: &KeyType) -> Result<(InfoType, u32), Error> {
+        let table = self.table.as_ref();
+        let entry_count = std::cmp::min(
+            read_struct::<U32le>(table, 0)?.v as usize,
+            table.len() / self.entry_len,
+        );
+        let mut free = vec![false; entry_count];
+        let mut index = read_struct::<U32le>(table, self.eo_collision)?.v as usize;
+        while index != 0 && index < entry_count && !free[index] {
+            free[index] = true;
+            index =
+                read_struct::<U32le>(table, index * self.entry_len + self.eo_collision)?.v as usize;
+        }
+        for index in (1..entry_count).filter(|&i| !free[i]) {
+            let entry_offset = index * self.entry_len;
+            let other_key: KeyType = read_struct(table, entry_offset)?;
+            if *key == other_key {
+                warn!("Found entry {} outside of its hash bucket", index);
+                let info = read_struct(table, entry_offset + self.eo_info)?;
+                return Ok((info, index as u32));
+            }
+        }
+        make_error(Error::NotFound)
+    }
+
     /// Gets the entry at the specified index.
     fn get_at(&self, index: u32) -> Result<(InfoType, KeyType), Error> {
         let entry_offset = index as usize * self.entry_len;
@@ -220,9 +282,16 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
         // scan the collision list and relink it
         let h = self.hash(&key);
         let mut prev = (hash, h * 4);
+        let mut steps = 0;
         loop {
+            self.step(&mut steps)?;
             let other = read_struct::<U32le>(prev.0, prev.1)?.v;
-            assert!(other != 0);
+            if other == 0 {
+                return make_error_at(
+                    Error::InvalidValue(Contexts::new()),
+                    ErrorContext::new(Layer::FsMeta).index(index as usize),
+                );
+            }
             if other == index {
                 write_struct(prev.0, prev.1, U32le { v: collision })?;
                 break;
@@ -252,7 +321,7 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
         let entry_offset = if index == 0 {
             let entry_count = read_struct::<U32le>(table, 0)?.v;
             let max_entry_count = read_struct::<U32le>(table, 4)?.v;
-            if entry_count == max_entry_count {
+            if entry_count >= max_entry_count {
                 return make_error(Error::NoSpace);
             }
             write_struct(table, 0, U32le { v: entry_count + 1 })?;
@@ -279,25 +348,109 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
         let table = self.table.as_ref();
         let entry_count = read_struct::<U32le>(table, 0)?.v;
         let max_entry_count = read_struct::<U32le>(table, 4)?.v;
+        if entry_count > max_entry_count || max_entry_count == 0 {
+            return make_error_at(
+                Error::InvalidValue(Contexts::new()),
+                ErrorContext::new(Layer::FsMeta),
+            );
+        }
         let mut index = read_struct::<U32le>(table, self.eo_collision)?.v;
         let mut dummy_count = 0;
         while index != 0 {
-            dummy_count += 1;
+            self.step(&mut dummy_count)?;
             let entry_offset = index as usize * self.entry_len;
             index = read_struct::<U32le>(table, entry_offset + self.eo_collision)?.v;
         }
 
         Ok(MetaTableStat {
             total: max_entry_count as usize - 1,
-            free: (max_entry_count - entry_count + dummy_count) as usize,
+            free: (max_entry_count - entry_count) as usize + dummy_count,
         })
     }
 
+    /// Returns the length of the leading part of the table that holds entries.
+    fn used_len(&self) -> Result<usize, Error> {
+        let entry_count = read_struct::<U32le>(self.table.as_ref(), 0)?.v as usize;
+        Ok(std::cmp::min(
+            entry_count * self.entry_len,
+            self.table.len(),
+        ))
+    }
+
+    /// Walks the free entry list and all hash bucket chains for `FsMeta::check`.
+    /// Returns whether each entry is in use, and whether it can be found through the hash table.
+    fn check(
+        &self,
+        kind: MetaTableKind,
+        issues: &mut Vec<CheckIssue>,
+    ) -> Result<(Vec<bool>, Vec<bool>), Error> {
+        let table = self.table.as_ref();
+        let hash = self.hash.as_ref();
+        let entry_count = read_struct::<U32le>(table, 0)?.v as usize;
+        if entry_count == 0 || entry_count * self.entry_len > table.len() {
+            issues.push(CheckIssue::BrokenMetaTable { table: kind });
+            return Ok((vec![], vec![]));
+        }
+
+        let mut used = vec![true; entry_count];
+        used[0] = false;
+        let mut index = read_struct::<U32le>(table, self.eo_collision)?.v as usize;
+        while index != 0 {
+            if index >= entry_count || !used[index] {
+                issues.push(CheckIssue::BrokenLink {
+                    table: kind,
+                    ino: index as u32,
+                });
+                break;
+            }
+            used[index] = false;
+            index =
+                read_struct::<U32le>(table, index * self.entry_len + self.eo_collision)?.v as usize;
+        }
+
+        let mut names = HashMap::new();
+        for index in (0..entry_count).filter(|&i| used[i]) {
+            let mut bytes = vec![0; KeyType::BYTE_LEN];
+            read_struct::<KeyType>(table, index * self.entry_len)?.write_bytes(&mut bytes);
+            if let Some(&other) = names.get(&bytes) {
+                issues.push(CheckIssue::DuplicateName {
+                    table: kind,
+                    ino: index as u32,
+                    other,
+                });
+            } else {
+                names.insert(bytes, index as u32);
+            }
+        }
+
+        let mut seen = vec![false; entry_count];
+        let mut hashed = vec![false; entry_count];
+        for bucket in 0..self.buckets {
+            let mut index = read_struct::<U32le>(hash, bucket * 4)?.v as usize;
+            while index != 0 {
+                if index >= entry_count || !used[index] || seen[index] {
+                    issues.push(CheckIssue::BrokenLink {
+                        table: kind,
+                        ino: index as u32,
+                    });
+                    break;
+                }
+                seen[index] = true;
+                let entry_offset = index * self.entry_len;
+                // an entry in the wrong bucket can't be found by its name
+                hashed[index] = self.hash(&read_struct(table, entry_offset)?) == bucket;
+                index = read_struct::<U32le>(table, entry_offset + self.eo_collision)?.v as usize;
+            }
+        }
+
+        Ok((used, hashed))
+    }
+
     /// Acquire a ticket that represents the entry is being opened.
     /// The ticket can be used to check exclusive access before doing operations such as
     /// deleting the entry.
     pub fn acquire_ticket(&self, index: u32) -> RefTicket<KeyType, InfoType> {
-        let mut ref_count = self.ref_count.borrow_mut();
+        let mut ref_count = self.ref_count.lock().unwrap();
         let previous = ref_count.get(&index).cloned().unwrap_or(0);
         ref_count.insert(index, previous + 1);
         RefTicket {
@@ -365,11 +518,11 @@ impl<
     > FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>
 {
     pub fn format(
-        dir_hash: Rc<dyn RandomAccessFile>,
-        dir_table: Rc<dyn RandomAccessFile>,
+        dir_hash: Arc<dyn RandomAccessFile>,
+        dir_table: Arc<dyn RandomAccessFile>,
         dir_entry_count: usize,
-        file_hash: Rc<dyn RandomAccessFile>,
-        file_table: Rc<dyn RandomAccessFile>,
+        file_hash: Arc<dyn RandomAccessFile>,
+        file_table: Arc<dyn RandomAccessFile>,
         file_entry_count: usize,
     ) -> Result<(), Error> {
         MetaTable::<DirKeyType, DirInfoType>::format(
@@ -388,29 +541,148 @@ impl<
     }
 
     pub fn new(
-        dir_hash: Rc<dyn RandomAccessFile>,
-        dir_table: Rc<dyn RandomAccessFile>,
-        file_hash: Rc<dyn RandomAccessFile>,
-        file_table: Rc<dyn RandomAccessFile>,
-    ) -> Result<Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>, Error> {
-        Ok(Rc::new(FsMeta {
+        dir_hash: Arc<dyn RandomAccessFile>,
+        dir_table: Arc<dyn RandomAccessFile>,
+        file_hash: Arc<dyn RandomAccessFile>,
+        file_table: Arc<dyn RandomAccessFile>,
+    ) -> Result<Arc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>, Error> {
+        Ok(Arc::new(FsMeta {
             dirs: MetaTable::new(dir_hash, dir_table)?,
             files: MetaTable::new(file_hash, file_table)?,
         }))
     }
 
+    /// Same as `new`, but entries that can't be found through the hash tables
+    /// are looked up in the whole directory or file table, for opening damaged archives.
+    pub fn new_salvage(
+        dir_hash: Arc<dyn RandomAccessFile>,
+        dir_table: Arc<dyn RandomAccessFile>,
+        file_hash: Arc<dyn RandomAccessFile>,
+        file_table: Arc<dyn RandomAccessFile>,
+    ) -> Result<Arc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>, Error> {
+        let mut dirs = MetaTable::new(dir_hash, dir_table)?;
+        let mut files = MetaTable::new(file_hash, file_table)?;
+        dirs.salvage = true;
+        files.salvage = true;
+        Ok(Arc::new(FsMeta { dirs, files }))
+    }
+
     pub fn stat(&self) -> Result<MetaStat, Error> {
         Ok(MetaStat {
             dirs: self.dirs.stat()?,
             files: self.files.stat()?,
         })
     }
+
+    /// Returns the lengths of the leading parts of the directory table and the file table
+    /// that hold entries.
+    pub fn used_table_len(&self) -> Result<(usize, usize), Error> {
+        Ok((self.dirs.used_len()?, self.files.used_len()?))
+    }
+
+    /// Checks the hash bucket chains and the directory tree of both tables, and reports
+    /// broken links, unreachable or unhashed entries, wrong parents and duplicate names.
+    /// Returns the inodes of all files reachable from the root directory.
+    pub fn check(&self, issues: &mut Vec<CheckIssue>) -> Result<Vec<u32>, Error> {
+        let (dir_used, dir_hashed) = self.dirs.check(MetaTableKind::Dir, issues)?;
+        let (file_used, file_hashed) = self.files.check(MetaTableKind::File, issues)?;
+        if dir_used.get(1) != Some(&true) {
+            if !dir_used.is_empty() {
+                issues.push(CheckIssue::Unreachable {
+                    table: MetaTableKind::Dir,
+                    ino: 1,
+                });
+            }
+            return Ok(vec![]);
+        }
+
+        let mut dir_reached = vec![false; dir_used.len()];
+        let mut file_reached = vec![false; file_used.len()];
+        dir_reached[1] = true;
+        let mut dirs = vec![1];
+        let mut files = vec![];
+        while let Some(ino) = dirs.pop() {
+            let (info, _) = self.dirs.get_at(ino)?;
+
+            let mut index = info.get_sub_dir();
+            while index != 0 {
+                let i = index as usize;
+                if i >= dir_used.len() || !dir_used[i] || dir_reached[i] {
+                    issues.push(CheckIssue::BrokenLink {
+                        table: MetaTableKind::Dir,
+                        ino: index,
+                    });
+                    break;
+                }
+                dir_reached[i] = true;
+                let (sub_info, key) = self.dirs.get_at(index)?;
+                if key.get_parent() != ino {
+                    issues.push(CheckIssue::WrongParent {
+                        table: MetaTableKind::Dir,
+                        ino: index,
+                        parent: key.get_parent(),
+                        listed_in: ino,
+                    });
+                }
+                dirs.push(index);
+                index = sub_info.get_next();
+            }
+
+            let mut index = info.get_sub_file();
+            while index != 0 {
+                let i = index as usize;
+                if i >= file_used.len() || !file_used[i] || file_reached[i] {
+                    issues.push(CheckIssue::BrokenLink {
+                        table: MetaTableKind::File,
+                        ino: index,
+                    });
+                    break;
+                }
+                file_reached[i] = true;
+                let (sub_info, key) = self.files.get_at(index)?;
+                if key.get_parent() != ino {
+                    issues.push(CheckIssue::WrongParent {
+                        table: MetaTableKind::File,
+                        ino: index,
+                        parent: key.get_parent(),
+                        listed_in: ino,
+                    });
+                }
+                files.push(index);
+                index = sub_info.get_next();
+            }
+        }
+
+        for (kind, used, reached, hashed) in [
+            (MetaTableKind::Dir, &dir_used, &dir_reached, &dir_hashed),
+            (MetaTableKind::File, &file_used, &file_reached, &file_hashed),
+        ]
+        .iter()
+        {
+            for ino in (0..used.len()).filter(|&i| used[i]) {
+                if !reached[ino] {
+                    issues.push(CheckIssue::Unreachable {
+                        table: *kind,
+                        ino: ino as u32,
+                    });
+                }
+                if !hashed[ino] {
+                    issues.push(CheckIssue::Unhashed {
+                        table: *kind,
+                        ino: ino as u32,
+                    });
+                }
+            }
+        }
+
+        Ok(files)
+    }
 }
 
 /// A handle to a file entry in the meta table.
 pub struct FileMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType> {
     ticket: RefTicket<FileKeyType, FileInfoType>,
-    fs: Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    fs: Arc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
 }
 
 impl<
@@ -422,7 +694,7 @@ impl<
 {
     /// Opens the file at the specified inode.
     pub fn open_ino(
-        fs: Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+        fs: Arc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
         ino: u32,
     ) -> Result<Self, Error> {
         let ticket = fs.files.acquire_ticket(ino);
@@ -474,8 +746,15 @@ impl<
             parent.set_sub_file(self_info.get_next());
             self.fs.dirs.set(parent_index, parent)?;
         } else {
+            let mut steps = 0;
             loop {
-                assert!(head_index != 0);
+                self.fs.files.step(&mut steps)?;
+                if head_index == 0 {
+                    return make_error_at(
+                        Error::InvalidValue(Contexts::new()),
+                        ErrorContext::new(Layer::FsMeta).index(self.ticket.index as usize),
+                    );
+                }
                 let (mut head, _) = self.fs.files.get_at(head_index)?;
                 let next_index = head.get_next();
                 if next_index == self.ticket.index {
@@ -500,7 +779,7 @@ impl<
 /// A handle to a directory entry in the meta table.
 pub struct DirMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType> {
     ticket: RefTicket<DirKeyType, DirInfoType>,
-    fs: Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    fs: Arc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
 }
 
 impl<
@@ -512,7 +791,7 @@ impl<
 {
     /// Opens the directory at the specified inode. Inode 1 represents the root directory.
     pub fn open_ino(
-        fs: Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+        fs: Arc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
         ino: u32,
     ) -> Result<Self, Error> {
         let ticket = fs.dirs.acquire_ticket(ino);
@@ -569,7 +848,9 @@ impl<
         let (self_info, _) = self.fs.dirs.get_at(self.ticket.index)?;
         let mut index = self_info.get_sub_dir();
         let mut result = vec![];
+        let mut steps = 0;
         while index != 0 {
+            self.fs.dirs.step(&mut steps)?;
             let (info, key) = self.fs.dirs.get_at(index)?;
             result.push((key.get_name(), index));
             index = info.get_next();
@@ -581,7 +862,9 @@ impl<
         let (self_info, _) = self.fs.dirs.get_at(self.ticket.index)?;
         let mut index = self_info.get_sub_file();
         let mut result = vec![];
+        let mut steps = 0;
         while index != 0 {
+            self.fs.files.step(&mut steps)?;
             let (info, key) = self.fs.files.get_at(index)?;
             result.push((key.get_name(), index));
             index = info.get_next();
@@ -589,6 +872,25 @@ impl<
         Ok(result)
     }
 
+    /// Lists the inodes of all files in this directory and its sub-directories,
+    /// in ascending order.
+    pub fn list_files_recursive(&self) -> Result<Vec<u32>, Error> {
+        let mut visited = HashSet::new();
+        let mut files = vec![];
+        let mut dirs = vec![self.ticket.index];
+        while let Some(ino) = dirs.pop() {
+            if !visited.insert(ino) {
+                continue;
+            }
+            let dir = DirMeta::open_ino(self.fs.clone(), ino)?;
+            dirs.extend(dir.list_sub_dir()?.into_iter().map(|(_, ino)| ino));
+            files.extend(dir.list_sub_file()?.into_iter().map(|(_, ino)| ino));
+        }
+        files.sort_unstable();
+        files.dedup();
+        Ok(files)
+    }
+
     pub fn new_sub_dir(
         &self,
         name: DirKeyType::NameType,
@@ -663,8 +965,15 @@ impl<
             parent.set_sub_dir(self_info.get_next());
             self.fs.dirs.set(parent_index, parent)?;
         } else {
+            let mut steps = 0;
             loop {
-                assert!(head_index != 0);
+                self.fs.dirs.step(&mut steps)?;
+                if head_index == 0 {
+                    return make_error_at(
+                        Error::InvalidValue(Contexts::new()),
+                        ErrorContext::new(Layer::FsMeta).index(self.ticket.index as usize),
+                    );
+                }
                 let (mut head, _) = self.fs.dirs.get_at(head_index)?;
                 let next_index = head.get_next();
                 if next_index == self.ticket.index {
@@ -708,8 +1017,8 @@ mod test {
         for _ in 0..100 {
             let dir_entry_count = rng.gen_range(10..1000);
             let dir_buckets = rng.gen_range(10..100);
-            let dir_hash = Rc::new(MemoryFile::new(vec![0; dir_buckets * 4]));
-            let dir_table = Rc::new(MemoryFile::new(vec![
+            let dir_hash = Arc::new(MemoryFile::new(vec![0; dir_buckets * 4]));
+            let dir_table = Arc::new(MemoryFile::new(vec![
                 0;
                 dir_entry_count
                     * (SaveExtDir::BYTE_LEN
@@ -719,8 +1028,8 @@ mod test {
 
             let file_entry_count = rng.gen_range(10..1000);
             let file_buckets = rng.gen_range(10..100);
-            let file_hash = Rc::new(MemoryFile::new(vec![0; file_buckets * 4]));
-            let file_table = Rc::new(MemoryFile::new(vec![
+            let file_hash = Arc::new(MemoryFile::new(vec![0; file_buckets * 4]));
+            let file_table = Arc::new(MemoryFile::new(vec![
                 0;
                 file_entry_count
                     * (SaveFile::BYTE_LEN
@@ -995,6 +1304,78 @@ mod test {
         }
     }
 
+    #[test]
+    fn salvage_lookup() {
+        use crate::save_data::SaveFile;
+        use crate::save_ext_common::*;
+        type Fs = FsMeta<SaveExtKey, SaveExtDir, SaveExtKey, SaveFile>;
+        let dir_hash = Arc::new(MemoryFile::new(vec![0; 4 * 4]));
+        let dir_table = Arc::new(MemoryFile::new(vec![
+            0;
+            10 * (SaveExtDir::BYTE_LEN
+                + SaveExtKey::BYTE_LEN
+                + 4)
+        ]));
+        let file_hash = Arc::new(MemoryFile::new(vec![0; 4 * 4]));
+        let file_table = Arc::new(MemoryFile::new(vec![
+            0;
+            10 * (SaveFile::BYTE_LEN
+                + SaveExtKey::BYTE_LEN
+                + 4)
+        ]));
+        Fs::format(
+            dir_hash.clone(),
+            dir_table.clone(),
+            10,
+            file_hash.clone(),
+            file_table.clone(),
+            10,
+        )
+        .unwrap();
+        let open = |salvage| {
+            let new = if salvage { Fs::new_salvage } else { Fs::new };
+            new(
+                dir_hash.clone(),
+                dir_table.clone(),
+                file_hash.clone(),
+                file_table.clone(),
+            )
+            .unwrap()
+        };
+
+        let root = DirMeta::open_ino(open(false), 1).unwrap();
+        let mut inos = vec![];
+        for i in 0..5 {
+            let file = root
+                .new_sub_file(
+                    [i; 16],
+                    SaveFile {
+                        padding1: 0,
+                        block: 0,
+                        size: 0,
+                        padding2: 0,
+                        next: 0,
+                    },
+                )
+                .unwrap();
+            inos.push(file.get_ino());
+        }
+        drop(root);
+
+        // Break all hash bucket chains
+        file_hash.write(0, &[0; 4 * 4]).unwrap();
+        let root = DirMeta::open_ino(open(false), 1).unwrap();
+        assert!(matches!(root.open_sub_file([0; 16]), Err(Error::NotFound)));
+        drop(root);
+
+        let root = DirMeta::open_ino(open(true), 1).unwrap();
+        for (i, &ino) in inos.iter().enumerate() {
+            let file = root.open_sub_file([i as u8; 16]).unwrap();
+            assert_eq!(file.get_ino(), ino);
+        }
+        assert!(matches!(root.open_sub_file([9; 16]), Err(Error::NotFound)));
+    }
+
     #[test]
     fn meta_fuzz() {
         let mut rng = rand::thread_rng();
@@ -1015,8 +1396,8 @@ mod test {
             let mut key_set: HashSet<Key> = HashSet::new();
             let entry_count = rng.gen_range(10..1000);
             let buckets = rng.gen_range(10..100);
-            let hash = Rc::new(MemoryFile::new(vec![0; buckets * 4]));
-            let table = Rc::new(MemoryFile::new(vec![0; entry_count * 16]));
+            let hash = Arc::new(MemoryFile::new(vec![0; buckets * 4]));
+            let table = Arc::new(MemoryFile::new(vec![0; entry_count * 16]));
             MetaTable::<Key, Info>::format(hash.as_ref(), table.as_ref(), entry_count).unwrap();
             let meta = MetaTable::<Key, Info>::new(hash, table).unwrap();
             #[derive(Clone)]
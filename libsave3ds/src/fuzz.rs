@@ -0,0 +1,339 @@
+//! Entry points that feed arbitrary bytes to the parsers of untrusted images.
+//!
+//! None of these should ever panic, whatever the input is. They back the targets in the
+//! `fuzz` directory of the repository, and are also run on mutated images by the tests below.
+
+use crate::check::Check;
+use crate::db::{Db, DbType};
+use crate::diff::Diff;
+use crate::difi_partition::SelectorInversion;
+use crate::fat::{Fat, FatFile};
+use crate::fs_meta::{DirMeta, FileMeta};
+use crate::memory_file::MemoryFile;
+use crate::random_access_file::RandomAccessFile;
+use crate::save_data::{SaveData, SaveDataType, SaveFile};
+use crate::save_ext_common::{SaveExtDir, SaveExtKey};
+use crate::wear_leveling::WearLeveling;
+use crate::Resource;
+use std::sync::Arc;
+
+type FsMeta = crate::fs_meta::FsMeta<SaveExtKey, SaveExtDir, SaveExtKey, SaveFile>;
+
+fn memory(data: &[u8]) -> Arc<MemoryFile> {
+    Arc::new(MemoryFile::new(data.to_vec()))
+}
+
+fn read_all(file: &dyn RandomAccessFile) {
+    let mut buf = vec![0; file.len()];
+    let _ = file.read(0, &mut buf);
+}
+
+/// Opens a bare save data image, which parses the DISA container, the FAT and the
+/// file system metadata, and then checks it.
+/// Salvage mode accepts broken hashes, so that mutated metadata is parsed as well.
+pub fn disa(data: &[u8]) {
+    for &(inversion, salvage) in &[
+        (SelectorInversion::default(), false),
+        (SelectorInversion::all(), true),
+    ] {
+        if let Ok(save) = SaveData::new(memory(data), SaveDataType::Bare, inversion, salvage) {
+            let _ = save.check();
+        }
+    }
+}
+
+/// Opens an unsigned DIFF container and reads its partition.
+pub fn diff(data: &[u8]) {
+    for &salvage in &[false, true] {
+        if let Ok(diff) = Diff::new(memory(data), None, SelectorInversion::default(), salvage) {
+            read_all(diff.partition().as_ref());
+        }
+    }
+}
+
+/// Opens a title database from an unsigned DIFF container and checks it.
+pub fn db(data: &[u8]) {
+    for &db_type in &[DbType::Ticket, DbType::NandTitle] {
+        let diff = match Diff::new(memory(data), None, SelectorInversion::default(), true) {
+            Ok(diff) => Arc::new(diff),
+            Err(_) => return,
+        };
+        if let Ok(db) = Db::from_diff(diff, db_type) {
+            let _ = db.check();
+        }
+    }
+}
+
+/// Opens a cartridge save image with wear leveling and reads it through.
+/// The input is padded with erased bytes to the nearest flash size.
+pub fn wear_leveling(data: &[u8]) {
+    let len = [0x20_000, 0x80_000, 0x100_000]
+        .iter()
+        .cloned()
+        .find(|&len| len >= data.len())
+        .unwrap_or(0x100_000);
+    let mut image = data[..std::cmp::min(data.len(), len)].to_vec();
+    image.resize(len, 0xFF);
+    if let Ok(wear_leveling) = WearLeveling::new(Arc::new(MemoryFile::new(image))) {
+        read_all(&wear_leveling);
+    }
+}
+
+/// Opens a FAT whose block length is given by the first byte and whose table is the rest,
+/// then walks, checks, allocates and frees blocks.
+pub fn fat(data: &[u8]) {
+    let (&block_log, table) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let block_len = 1 << (block_log % 10);
+    let table = &table[..table.len() / 8 * 8];
+    let block_count = (table.len() / 8).saturating_sub(1);
+    let fat = match Fat::new(
+        memory(table),
+        Arc::new(MemoryFile::new(vec![0; block_count * block_len])),
+        block_len,
+    ) {
+        Ok(fat) => fat,
+        Err(_) => return,
+    };
+    let _ = fat.free_extents();
+    let _ = fat.check(&[0], block_count, &mut vec![]);
+    if let Ok(file) = FatFile::open(fat.clone(), 0) {
+        read_all(&file);
+    }
+    if let Ok((file, _)) = FatFile::create(fat, 1) {
+        let _ = file.delete();
+    }
+}
+
+/// Opens the directory and file tables of a save data. The first two bytes give the number
+/// of hash buckets of each table, followed by both hash tables and then both entry tables.
+/// Walks the tree and deletes everything in the root directory.
+pub fn fs_meta(data: &[u8]) {
+    if data.len() < 2 {
+        return;
+    }
+    let dir_hash_len = data[0] as usize % 16 * 4;
+    let file_hash_len = data[1] as usize % 16 * 4;
+    let rest = &data[2..];
+    if rest.len() < dir_hash_len + file_hash_len {
+        return;
+    }
+    let (dir_hash, rest) = rest.split_at(dir_hash_len);
+    let (file_hash, rest) = rest.split_at(file_hash_len);
+    let (dir_table, file_table) = rest.split_at(rest.len() / 2);
+    let fs = match FsMeta::new(
+        memory(dir_hash),
+        memory(dir_table),
+        memory(file_hash),
+        memory(file_table),
+    ) {
+        Ok(fs) => fs,
+        Err(_) => return,
+    };
+    let _ = fs.stat();
+    let _ = fs.check(&mut vec![]);
+    let root = match DirMeta::open_ino(fs.clone(), 1) {
+        Ok(root) => root,
+        Err(_) => return,
+    };
+    if let Ok(files) = root.list_sub_file() {
+        for (_, ino) in files {
+            if let Ok(file) = FileMeta::open_ino(fs.clone(), ino) {
+                let _ = file.delete();
+            }
+        }
+    }
+    if let Ok(dirs) = root.list_sub_dir() {
+        for (name, _) in dirs {
+            if let Ok(dir) = root.open_sub_dir(name) {
+                let _ = dir.delete();
+            }
+        }
+    }
+}
+
+/// Derives the cartridge save format from a game image, with all keys set to zero.
+pub fn cart_format(data: &[u8]) {
+    let mut resource = match Resource::new(
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some([0; 16]),
+        Some([0; 16]),
+        Some([0; 16]),
+    ) {
+        Ok(resource) => resource,
+        Err(_) => return,
+    };
+    resource.key_x_ncch = Some([0; 16]);
+    resource.key_x_dec = Some([0; 16]);
+    resource.key_x_sign = Some([0; 16]);
+    resource.cart_id_short = Some([0; 8]);
+    resource.cart_id_long = Some([0; 0x40]);
+    let _ = resource.read_cart_format(memory(data));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::difi_partition::DifiPartitionParam;
+    use crate::file_system::*;
+    use crate::save_data::{SaveDataBlockType, SaveDataFormatParam};
+    use rand::prelude::*;
+
+    // Runs `target` on random buffers and on `seed` with a few bytes changed. The random seed is
+    // printed, so that a failure can be run again with it in SAVE3DS_FUZZ_SEED.
+    fn mutate(seed: &[u8], target: fn(&[u8])) {
+        let rng_seed = match std::env::var("SAVE3DS_FUZZ_SEED") {
+            Ok(value) => value.parse().unwrap(),
+            Err(_) => rand::thread_rng().gen(),
+        };
+        println!("SAVE3DS_FUZZ_SEED={}", rng_seed);
+        let mut rng = StdRng::seed_from_u64(rng_seed);
+        for _ in 0..20 {
+            let len = rng.gen_range(0..0x1000);
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            target(&data);
+        }
+        for _ in 0..100 {
+            let mut data = seed.to_vec();
+            for _ in 0..rng.gen_range(1..8) {
+                let pos = rng.gen_range(0..data.len());
+                data[pos] = match rng.gen_range(0..3) {
+                    0 => 0,
+                    1 => 0xFF,
+                    _ => rng.gen(),
+                };
+            }
+            if rng.gen_range(0..10) == 0 {
+                data.truncate(rng.gen_range(0..data.len()));
+            }
+            target(&data);
+        }
+    }
+
+    fn save_image(duplicate_data: bool) -> Vec<u8> {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data,
+        };
+        let file = Arc::new(MemoryFile::new(vec![0; 0x20000]));
+        SaveData::format(file.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(
+            file.clone(),
+            SaveDataType::Bare,
+            SelectorInversion::default(),
+            false,
+        )
+        .unwrap();
+        let root = save.open_root().unwrap();
+        root.new_sub_dir([1; 16]).unwrap();
+        root.new_sub_file([2; 16], 0x1000).unwrap();
+        save.commit().unwrap();
+        let mut data = vec![0; file.len()];
+        file.read(0, &mut data).unwrap();
+        data
+    }
+
+    fn diff_image(magic: &[u8]) -> Vec<u8> {
+        let param = DifiPartitionParam {
+            dpfs_level2_block_len: 128,
+            dpfs_level3_block_len: 4096,
+            ivfc_level1_block_len: 512,
+            ivfc_level2_block_len: 512,
+            ivfc_level3_block_len: 4096,
+            ivfc_level4_block_len: 4096,
+            data_len: 0x2000,
+            external_ivfc_level4: true,
+        };
+        let file = Arc::new(MemoryFile::new(vec![0; Diff::calculate_size(&param)]));
+        Diff::format(file.clone(), None, &param, 0).unwrap();
+        let diff = Diff::new(file.clone(), None, SelectorInversion::default(), false).unwrap();
+        diff.partition().write(0, magic).unwrap();
+        diff.commit().unwrap();
+        let mut data = vec![0; file.len()];
+        file.read(0, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn fuzz_disa() {
+        mutate(&save_image(false), disa);
+        mutate(&save_image(true), disa);
+    }
+
+    #[test]
+    fn fuzz_diff() {
+        mutate(&diff_image(b"TICK"), diff);
+    }
+
+    #[test]
+    fn fuzz_db() {
+        mutate(&diff_image(b"TICK"), db);
+        mutate(&diff_image(b"NANDTDB\0"), db);
+    }
+
+    #[test]
+    fn fuzz_wear_leveling() {
+        let file = Arc::new(MemoryFile::new(vec![0xFF; 0x20000]));
+        WearLeveling::format(file.clone()).unwrap();
+        let mut data = vec![0; file.len()];
+        file.read(0, &mut data).unwrap();
+        mutate(&data, wear_leveling);
+    }
+
+    #[test]
+    fn fuzz_fat() {
+        let table = Arc::new(MemoryFile::new(vec![0; 17 * 8]));
+        Fat::format(table.as_ref()).unwrap();
+        let mut data = vec![0; table.len()];
+        table.read(0, &mut data).unwrap();
+        data.insert(0, 9);
+        mutate(&data, fat);
+    }
+
+    #[test]
+    fn fuzz_fs_meta() {
+        let files: Vec<_> = [0x10, 0x200, 0x10, 0x200]
+            .iter()
+            .map(|&len| Arc::new(MemoryFile::new(vec![0; len])))
+            .collect();
+        FsMeta::format(
+            files[0].clone(),
+            files[1].clone(),
+            8,
+            files[2].clone(),
+            files[3].clone(),
+            8,
+        )
+        .unwrap();
+        let mut data = vec![4, 4];
+        for &i in &[0, 2, 1, 3] {
+            let mut buf = vec![0; files[i].len()];
+            files[i].read(0, &mut buf).unwrap();
+            data.extend_from_slice(&buf);
+        }
+        mutate(&data, fs_meta);
+    }
+
+    #[test]
+    fn fuzz_cart_format() {
+        let mut data = vec![0; 0x800];
+        data[0x100..0x104].copy_from_slice(b"NCSD");
+        data[0x120] = 1;
+        data[0x124] = 3;
+        data[0x18D] = 1;
+        data[0x300..0x304].copy_from_slice(b"NCCH");
+        mutate(&data, cart_format);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::error::*;
+use crate::fat::Fat;
+use crate::fs_meta::FsInfo;
+use byte_struct::*;
+use serde::{Serialize, Serializer};
+use std::fmt::Write;
+
+/// A field value of an [`InspectNode`](struct.InspectNode.html).
+///
+/// Numbers are serialized as JSON numbers and bytes as a hexadecimal string.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum InspectValue {
+    Int(u64),
+    /// An integer that reads better in hexadecimal, such as an offset, a size or a version.
+    Hex(u64),
+    Bool(bool),
+    Text(String),
+    Bytes(#[serde(serialize_with = "serialize_hex")] Vec<u8>),
+}
+
+impl From<bool> for InspectValue {
+    fn from(v: bool) -> InspectValue {
+        InspectValue::Bool(v)
+    }
+}
+
+impl From<u8> for InspectValue {
+    fn from(v: u8) -> InspectValue {
+        InspectValue::Int(v.into())
+    }
+}
+
+impl From<u32> for InspectValue {
+    fn from(v: u32) -> InspectValue {
+        InspectValue::Int(v.into())
+    }
+}
+
+impl From<u64> for InspectValue {
+    fn from(v: u64) -> InspectValue {
+        InspectValue::Int(v)
+    }
+}
+
+impl From<usize> for InspectValue {
+    fn from(v: usize) -> InspectValue {
+        InspectValue::Int(v as u64)
+    }
+}
+
+impl From<&str> for InspectValue {
+    fn from(v: &str) -> InspectValue {
+        InspectValue::Text(v.to_owned())
+    }
+}
+
+impl From<&[u8]> for InspectValue {
+    fn from(v: &[u8]) -> InspectValue {
+        InspectValue::Bytes(v.to_vec())
+    }
+}
+
+/// A header or a region of an archive, as returned by `inspect`.
+///
+/// `offset` and `size` locate the node within the data described by its parent node.
+/// Nodes under a parent without an offset, such as the top-level node, are located in the
+/// file they come from, as seen after decryption and wear leveling.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct InspectNode {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(serialize_with = "serialize_fields")]
+    pub fields: Vec<(String, InspectValue)>,
+    pub children: Vec<InspectNode>,
+}
+
+impl InspectNode {
+    pub fn new(name: &str) -> InspectNode {
+        InspectNode {
+            name: name.to_owned(),
+            offset: None,
+            size: None,
+            fields: vec![],
+            children: vec![],
+        }
+    }
+
+    pub(crate) fn at(mut self, offset: usize, size: usize) -> InspectNode {
+        self.offset = Some(offset as u64);
+        self.size = Some(size as u64);
+        self
+    }
+
+    pub(crate) fn field(mut self, name: &str, value: impl Into<InspectValue>) -> InspectNode {
+        self.fields.push((name.to_owned(), value.into()));
+        self
+    }
+
+    pub(crate) fn hex(self, name: &str, value: impl Into<u64>) -> InspectNode {
+        self.field(name, InspectValue::Hex(value.into()))
+    }
+
+    pub(crate) fn child(mut self, child: InspectNode) -> InspectNode {
+        self.children.push(child);
+        self
+    }
+
+    /// Returns the value of the field `name`.
+    pub fn get(&self, name: &str) -> Option<&InspectValue> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    /// Returns the first node named `name` in this tree, searching depth-first.
+    pub fn find(&self, name: &str) -> Option<&InspectNode> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(name))
+    }
+
+    /// Serializes the tree as a JSON object.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Renders the tree as indented human-readable text.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        self.write_text(&mut out, 0);
+        out
+    }
+
+    fn write_text(&self, out: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth);
+        write!(out, "{}{}", indent, self.name).unwrap();
+        if let (Some(offset), Some(size)) = (self.offset, self.size) {
+            write!(out, " @ 0x{:X} (0x{:X} bytes)", offset, size).unwrap();
+        }
+        out.push('\n');
+        for (name, value) in self.fields.iter() {
+            write!(out, "{}  - {}: ", indent, name).unwrap();
+            match value {
+                InspectValue::Int(v) => write!(out, "{}", v).unwrap(),
+                InspectValue::Hex(v) => write!(out, "0x{:X}", v).unwrap(),
+                InspectValue::Bool(v) => write!(out, "{}", v).unwrap(),
+                InspectValue::Text(v) => out.push_str(v),
+                InspectValue::Bytes(v) => out.push_str(&to_hex(v)),
+            }
+            out.push('\n');
+        }
+        for child in self.children.iter() {
+            child.write_text(out, depth + 1);
+        }
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn serialize_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(data))
+}
+
+// Serializes the fields as a JSON object, keeping their order.
+fn serialize_fields<S: Serializer>(
+    fields: &[(String, InspectValue)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(fields.iter().map(|(name, value)| (name, value)))
+}
+
+/// Describes the file system info at `fs_info_offset`, the hash tables and the allocation table.
+/// The directory and file tables are placed at offsets with lengths `table_lens` if given,
+/// or are FAT files in the data region otherwise.
+/// `files` lists the ino and the first block of each file stored in the data region,
+/// which labels the owners of the allocated chains.
+/// Returns the nodes of the metadata and the node of the data region.
+pub(crate) fn inspect_fs(
+    fs_info: &FsInfo,
+    fs_info_offset: usize,
+    fat: &Fat,
+    table_lens: Option<[usize; 2]>,
+    files: &[(u32, usize)],
+) -> Result<(Vec<InspectNode>, InspectNode), Error> {
+    let mut allocation_table = InspectNode::new("allocation table")
+        .at(
+            fs_info.fat_offset as usize,
+            (fs_info.fat_size as usize + 1) * 8,
+        )
+        .field("block_count", fs_info.fat_size)
+        .field("free_blocks", fat.free_blocks());
+    for (first_block, block_count) in fat.free_extents()? {
+        allocation_table = allocation_table.child(
+            InspectNode::new("free extent")
+                .field("first_block", first_block)
+                .field("block_count", block_count),
+        );
+    }
+    for chain in fat.chains()? {
+        let first_block = chain[0].0;
+        let owner = if table_lens.is_none() && first_block == fs_info.dir_table.block_index as usize
+        {
+            "directory table".to_owned()
+        } else if table_lens.is_none() && first_block == fs_info.file_table.block_index as usize {
+            "file table".to_owned()
+        } else {
+            match files.iter().find(|&&(_, block)| block == first_block) {
+                Some((ino, _)) => format!("file {}", ino),
+                None => "unknown".to_owned(),
+            }
+        };
+        let mut node = InspectNode::new("chain")
+            .field("owner", owner.as_str())
+            .field("first_block", first_block)
+            .field(
+                "block_count",
+                chain.iter().map(|&(_, count)| count).sum::<usize>(),
+            );
+        for (first_block, block_count) in chain {
+            node = node.child(
+                InspectNode::new("extent")
+                    .field("first_block", first_block)
+                    .field("block_count", block_count),
+            );
+        }
+        allocation_table = allocation_table.child(node);
+    }
+
+    let mut meta = vec![
+        InspectNode::new("file system info")
+            .at(fs_info_offset, FsInfo::BYTE_LEN)
+            .hex("block_len", fs_info.block_len)
+            .field("max_dir", fs_info.max_dir)
+            .field("max_file", fs_info.max_file),
+        InspectNode::new("directory hash table")
+            .at(
+                fs_info.dir_hash_offset as usize,
+                fs_info.dir_buckets as usize * 4,
+            )
+            .field("buckets", fs_info.dir_buckets),
+        InspectNode::new("file hash table")
+            .at(
+                fs_info.file_hash_offset as usize,
+                fs_info.file_buckets as usize * 4,
+            )
+            .field("buckets", fs_info.file_buckets),
+        allocation_table,
+    ];
+
+    let mut data = InspectNode::new("data region")
+        .at(
+            fs_info.data_offset as usize,
+            fs_info.data_block_count as usize * fs_info.block_len as usize,
+        )
+        .hex("block_len", fs_info.block_len)
+        .field("block_count", fs_info.data_block_count);
+
+    let tables = [
+        ("directory table", &fs_info.dir_table),
+        ("file table", &fs_info.file_table),
+    ];
+    for (i, &(name, table)) in tables.iter().enumerate() {
+        match table_lens {
+            Some(lens) => {
+                meta.push(InspectNode::new(name).at(table.to_offset() as usize, lens[i]));
+            }
+            None => {
+                data = data.child(
+                    InspectNode::new(name)
+                        .field("first_block", table.block_index)
+                        .field("block_count", table.block_count),
+                );
+            }
+        }
+    }
+    Ok((meta, data))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::inspect::*;
+
+    #[test]
+    fn render() {
+        let node = InspectNode::new("root")
+            .at(0x100, 0x20)
+            .hex("version", 0x40000u32)
+            .field("name", "a\"b\n")
+            .field("active", true)
+            .child(InspectNode::new("leaf").field("hash", &[0xAB, 0x01][..]));
+        assert_eq!(
+            node.to_json(),
+            "{\"name\":\"root\",\"offset\":256,\"size\":32,\"fields\":{\"version\":262144,\
+             \"name\":\"a\\\"b\\n\",\"active\":true},\"children\":[{\"name\":\"leaf\",\
+             \"fields\":{\"hash\":\"ab01\"},\"children\":[]}]}"
+        );
+        assert_eq!(
+            node.to_text(),
+            "root @ 0x100 (0x20 bytes)\n  - version: 0x40000\n  - name: a\"b\n\n  \
+             - active: true\n  leaf\n    - hash: ab01\n"
+        );
+        assert_eq!(
+            node.find("leaf").unwrap().get("hash"),
+            Some(&InspectValue::Bytes(vec![0xAB, 1]))
+        );
+        assert_eq!(node.get("missing"), None);
+    }
+}
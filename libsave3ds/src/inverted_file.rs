@@ -0,0 +1,71 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use std::sync::Arc;
+
+/// Implements read-only `RandomAccessFile` layer that XORs every byte of the underlying file with a mask.
+///
+/// Placed over the selector of a `DualFile` (mask 1) or a `DpfsLevel` (mask 0xFF),
+/// this makes the layer read from the inactive copies instead of the active ones.
+pub struct InvertedFile {
+    data: Arc<dyn RandomAccessFile>,
+    mask: u8,
+}
+
+impl InvertedFile {
+    pub fn new(data: Arc<dyn RandomAccessFile>, mask: u8) -> InvertedFile {
+        InvertedFile { data, mask }
+    }
+
+    /// Wraps `data` with the mask if `invert` is set, or returns it as-is otherwise.
+    pub fn wrap_if(
+        data: Arc<dyn RandomAccessFile>,
+        mask: u8,
+        invert: bool,
+    ) -> Arc<dyn RandomAccessFile> {
+        if invert {
+            Arc::new(InvertedFile::new(data, mask))
+        } else {
+            data
+        }
+    }
+}
+
+impl RandomAccessFile for InvertedFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.data.read(pos, buf)?;
+        for b in buf.iter_mut() {
+            *b ^= self.mask;
+        }
+        Ok(())
+    }
+    fn write(&self, _pos: usize, _buf: &[u8]) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::inverted_file::*;
+    use crate::memory_file::MemoryFile;
+
+    #[test]
+    fn invert() {
+        let data = Arc::new(MemoryFile::new(vec![0x00, 0x01, 0xF0, 0xFF]));
+        let file = InvertedFile::new(data.clone(), 0xFF);
+        let mut buf = [0; 3];
+        file.read(1, &mut buf).unwrap();
+        assert_eq!(buf, [0xFE, 0x0F, 0x00]);
+        assert!(file.write(0, &[0]).is_err());
+        assert_eq!(file.len(), 4);
+
+        let file = InvertedFile::wrap_if(data, 1, false);
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0x00, 0x01, 0xF0]);
+    }
+}
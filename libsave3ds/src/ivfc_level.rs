@@ -2,8 +2,8 @@ use crate::error::*;
 use crate::misc::*;
 use crate::random_access_file::*;
 use sha2::*;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
 
 // Values for block status
 const BLOCK_UNVERIFIED: u8 = 0;
@@ -11,29 +11,53 @@ const BLOCK_VERIFIED: u8 = 1;
 const BLOCK_MODIFIED: u8 = 2;
 const BLOCK_BROKEN: u8 = 3;
 
+/// Result of verifying all blocks of an IVFC level.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LevelReport {
+    /// Index of the DIFI partition for save data,
+    /// or the sub-file index for extdata (0 for the quota file).
+    pub partition: usize,
+    /// IVFC level, from 1 to 4. Level 0 stands for the partition table.
+    pub level: usize,
+    pub block_len: usize,
+    pub block_count: usize,
+    /// Indices of the blocks that fail hash verification and hold data in use,
+    /// or hashes of data in use.
+    pub broken_blocks: Vec<usize>,
+    /// Indices of the blocks that fail hash verification but only hold free space,
+    /// or hashes of free space. This is normal for space never written since formatting.
+    pub unused_broken_blocks: Vec<usize>,
+}
+
 /// Implements `RandomAccessFile` layer for a IVFC level.
 ///
 /// An IVFC level consists of a hash file and a data file as the underlying files.
 /// The data file is chunked into blocks. Each block is hashed using SHA-256 and the
 /// hash is recorded into the hash file.
 pub struct IvfcLevel {
-    hash: Rc<dyn RandomAccessFile>,
-    data: Rc<dyn RandomAccessFile>,
+    hash: Arc<dyn RandomAccessFile>,
+    data: Arc<dyn RandomAccessFile>,
     block_len: usize,
     len: usize,
-    status: RefCell<Vec<u8>>, // Array of u2. Status of each block.
+    level: usize,                                  // For error context only
+    status: Mutex<Vec<u8>>,                        // Array of u2. Status of each block.
+    undo: Mutex<Option<BTreeMap<usize, Vec<u8>>>>, // Original data of overwritten blocks.
 }
 
 impl IvfcLevel {
     pub fn new(
-        hash: Rc<dyn RandomAccessFile>,
-        data: Rc<dyn RandomAccessFile>,
+        hash: Arc<dyn RandomAccessFile>,
+        data: Arc<dyn RandomAccessFile>,
         block_len: usize,
+        level: usize,
     ) -> Result<IvfcLevel, Error> {
         let len = data.len();
         let block_count = divide_up(len, block_len);
         if block_count * 0x20 > hash.len() {
-            return make_error(Error::SizeMismatch);
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::Ivfc(level)),
+            );
         }
         let chunk_count = divide_up(block_count, 4);
         Ok(IvfcLevel {
@@ -41,30 +65,204 @@ impl IvfcLevel {
             data,
             block_len,
             len,
-            status: RefCell::new(vec![BLOCK_UNVERIFIED; chunk_count]),
+            level,
+            status: Mutex::new(vec![BLOCK_UNVERIFIED; chunk_count]),
+            undo: Mutex::new(None),
         })
     }
 
     pub fn get_status(&self, block_index: usize) -> u8 {
-        (self.status.borrow()[block_index / 4] >> ((block_index % 4) * 2)) & 3
+        (self.status.lock().unwrap()[block_index / 4] >> ((block_index % 4) * 2)) & 3
     }
 
     pub fn set_status(&self, block_index: usize, status: u8) {
-        let mut status_list = self.status.borrow_mut();
+        let mut status_list = self.status.lock().unwrap();
         let i = block_index / 4;
         let j = (block_index % 4) * 2;
         status_list[i] &= !(3 << j);
         status_list[i] |= status << j;
     }
+
+    pub fn block_len(&self) -> usize {
+        self.block_len
+    }
+
+    pub fn block_count(&self) -> usize {
+        divide_up(self.len, self.block_len)
+    }
+
+    /// Makes a `HashMismatch` error for block `block_index`,
+    /// chained to `upper` if the hash itself is broken.
+    fn hash_mismatch(&self, block_index: usize, upper: Option<Error>) -> Result<(), Error> {
+        let context = ErrorContext::new(Layer::Ivfc(self.level))
+            .index(block_index)
+            .offset(block_index * self.block_len);
+        match upper {
+            Some(e @ Error::HashMismatch(_)) => Err(e).context(|| context),
+            _ => make_error_at(Error::HashMismatch(Contexts::new()), context),
+        }
+    }
+
+    /// Hashes a block. The last block is hashed as if padded with zeros.
+    fn hash_block(&self, block: &[u8]) -> [u8; 0x20] {
+        let mut hasher = Sha256::new();
+        hasher.update(block);
+        if block.len() < self.block_len {
+            hasher.update(vec![0; self.block_len - block.len()]);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Verifies `blocks` and returns the broken ones, without updating their status.
+    fn verify_blocks(&self, blocks: &[usize]) -> Result<Vec<usize>, Error> {
+        let mut broken = vec![];
+        let mut block_buf = vec![0; self.block_len];
+        for &i in blocks {
+            let mut hash_stored = [0; 0x20];
+            match self.hash.read(i * 0x20, &mut hash_stored) {
+                Ok(()) => (),
+                Err(Error::HashMismatch(_)) => {
+                    broken.push(i);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            let begin = i * self.block_len;
+            let end = std::cmp::min(begin + self.block_len, self.len);
+            let block = match self.data.mapped() {
+                Some(data) => &data[begin..end],
+                None => {
+                    self.data.read(begin, &mut block_buf[0..end - begin])?;
+                    &block_buf[0..end - begin]
+                }
+            };
+            if self.hash_block(block) != hash_stored {
+                broken.push(i);
+            }
+        }
+        Ok(broken)
+    }
+
+    /// Verifies all blocks that haven't been verified yet, spread over all CPU cores,
+    /// and returns the indices of the blocks that fail hash verification.
+    /// The upper level should be verified first, as its blocks are verified on demand otherwise.
+    pub fn verify_all(&self) -> Result<Vec<usize>, Error> {
+        let mut broken = vec![];
+        let mut pending = vec![];
+        for i in 0..self.block_count() {
+            match self.get_status(i) {
+                BLOCK_UNVERIFIED => pending.push(i),
+                BLOCK_BROKEN => broken.push(i),
+                _ => (),
+            }
+        }
+
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_len = std::cmp::max(divide_up(pending.len(), threads), 1);
+        let results: Vec<Result<Vec<usize>, Error>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = pending
+                .chunks(chunk_len)
+                .map(|chunk| scope.spawn(move || self.verify_blocks(chunk)))
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        let mut newly_broken = BTreeSet::new();
+        for result in results {
+            newly_broken.extend(result?);
+        }
+
+        for i in pending {
+            if newly_broken.contains(&i) {
+                self.set_status(i, BLOCK_BROKEN);
+            } else {
+                self.set_status(i, BLOCK_VERIFIED);
+            }
+        }
+        broken.extend(newly_broken);
+        broken.sort_unstable();
+        Ok(broken)
+    }
+
+    /// Verifies all blocks and reports them as level `level` of `partition`.
+    pub fn verify_report(&self, partition: usize, level: usize) -> Result<LevelReport, Error> {
+        Ok(LevelReport {
+            partition,
+            level,
+            block_len: self.block_len,
+            block_count: self.block_count(),
+            broken_blocks: self.verify_all()?,
+            unused_broken_blocks: vec![],
+        })
+    }
+
+    /// Reads through all blocks and returns the indices of the blocks that fail hash verification.
+    pub fn broken_blocks(&self) -> Result<Vec<usize>, Error> {
+        self.broken_blocks_in(&[(0, self.len)])
+    }
+
+    /// Reads through the blocks that overlap `ranges`, given as `(offset, len)`,
+    /// and returns the indices of the blocks that fail hash verification.
+    pub fn broken_blocks_in(&self, ranges: &[(usize, usize)]) -> Result<Vec<usize>, Error> {
+        let mut blocks = BTreeSet::new();
+        for &(offset, len) in ranges {
+            let end = std::cmp::min(offset + len, self.len);
+            if offset < end {
+                blocks.extend(offset / self.block_len..divide_up(end, self.block_len));
+            }
+        }
+
+        let mut broken = vec![];
+        let mut buf = vec![0; self.block_len];
+        for i in blocks {
+            let begin = i * self.block_len;
+            let end = std::cmp::min(begin + self.block_len, self.len);
+            match self.read(begin, &mut buf[0..end - begin]) {
+                Ok(()) => (),
+                Err(Error::HashMismatch(_)) => broken.push(i),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(broken)
+    }
+
+    /// Finds all broken blocks and marks them as modified, so that they read back
+    /// their raw data and get rehashed on the next commit. Returns the broken block indices.
+    pub fn salvage(&self) -> Result<Vec<usize>, Error> {
+        let broken = self.broken_blocks()?;
+        for &i in broken.iter() {
+            self.set_status(i, BLOCK_MODIFIED);
+        }
+        Ok(broken)
+    }
+
+    /// Starts keeping the original data of blocks before they are overwritten, until the next
+    /// commit or rollback, so that rollback can restore them. This is needed when the data file
+    /// is written in place instead of being backed by a DPFS level.
+    pub fn keep_undo(&self) {
+        let mut undo = self.undo.lock().unwrap();
+        if undo.is_none() {
+            *undo = Some(BTreeMap::new());
+        }
+    }
+
+    /// Marks all blocks as modified, so that all hashes are recalculated on the next commit.
+    pub fn rehash(&self) {
+        for i in 0..divide_up(self.len, self.block_len) {
+            self.set_status(i, BLOCK_MODIFIED);
+        }
+    }
 }
 
 impl RandomAccessFile for IvfcLevel {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
         let mut result = Ok(());
-        let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // block index range the operation covers
         let begin_block = pos / self.block_len;
@@ -82,7 +280,7 @@ impl RandomAccessFile for IvfcLevel {
             let status = self.get_status(i);
             if status == BLOCK_BROKEN {
                 // Fill the region if we know the block is already broken
-                result = make_error(Error::HashMismatch);
+                result = self.hash_mismatch(i, None);
                 for i in buf[data_begin - pos..data_end - pos].iter_mut() {
                     *i = 0xDD;
                 }
@@ -92,37 +290,37 @@ impl RandomAccessFile for IvfcLevel {
                     .read(data_begin, &mut buf[data_begin - pos..data_end - pos])?;
             } else {
                 // We haven't touched this block yet. Read the entire block and verify it
-                let mut block_buf = vec![0; self.block_len];
-                self.data.read(
-                    data_begin_as_block,
-                    &mut block_buf[0..data_end_as_block - data_begin_as_block],
-                )?;
+                let mut block_buf;
+                let block = match self.data.mapped() {
+                    Some(data) => &data[data_begin_as_block..data_end_as_block],
+                    None => {
+                        block_buf = vec![0; data_end_as_block - data_begin_as_block];
+                        self.data.read(data_begin_as_block, &mut block_buf)?;
+                        &block_buf[..]
+                    }
+                };
 
                 let mut hash_stored = [0; 0x20];
-                if self.hash.read(i * 0x20, &mut hash_stored).is_err() {
+                if let Err(e) = self.hash.read(i * 0x20, &mut hash_stored) {
                     // If the upper level fails, we just assume a broken block
                     self.set_status(i, BLOCK_BROKEN);
-                    result = make_error(Error::HashMismatch);
+                    result = self.hash_mismatch(i, Some(e));
                     for i in buf[data_begin - pos..data_end - pos].iter_mut() {
                         *i = 0xDD;
                     }
                     continue;
                 }
 
-                let mut hasher = Sha256::new();
-                hasher.update(&block_buf);
-                let hash = hasher.finalize();
-                if hash[..] == hash_stored[..] {
+                if self.hash_block(block) == hash_stored {
                     // The hash is verified. Cache the status and copy the part we want
                     self.set_status(i, BLOCK_VERIFIED);
                     buf[data_begin - pos..data_end - pos].copy_from_slice(
-                        &block_buf
-                            [data_begin - data_begin_as_block..data_end - data_begin_as_block],
+                        &block[data_begin - data_begin_as_block..data_end - data_begin_as_block],
                     );
                 } else {
                     // The block is broken
                     self.set_status(i, BLOCK_BROKEN);
-                    result = make_error(Error::HashMismatch);
+                    result = self.hash_mismatch(i, None);
                     for i in buf[data_begin - pos..data_end - pos].iter_mut() {
                         *i = 0xDD;
                     }
@@ -133,16 +331,29 @@ impl RandomAccessFile for IvfcLevel {
         result
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
-        self.data.write(pos, buf)?;
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // block index range the operation covers
         let begin_block = pos / self.block_len;
         let end_block = divide_up(end, self.block_len);
 
+        if let Some(undo) = self.undo.lock().unwrap().as_mut() {
+            for i in begin_block..end_block {
+                if let btree_map::Entry::Vacant(entry) = undo.entry(i) {
+                    let begin = i * self.block_len;
+                    let end = std::cmp::min(begin + self.block_len, self.len);
+                    let mut block_buf = vec![0; end - begin];
+                    self.data.read(begin, &mut block_buf)?;
+                    entry.insert(block_buf);
+                }
+            }
+        }
+
+        self.data.write(pos, buf)?;
+
         for i in begin_block..end_block {
             self.set_status(i, BLOCK_MODIFIED);
         }
@@ -168,6 +379,19 @@ impl RandomAccessFile for IvfcLevel {
                 self.set_status(i, BLOCK_VERIFIED);
             }
         }
+        *self.undo.lock().unwrap() = None;
+        Ok(())
+    }
+    fn rollback(&self) -> Result<(), Error> {
+        if let Some(undo) = self.undo.lock().unwrap().take() {
+            for (i, block_buf) in undo {
+                self.data.write(i * self.block_len, &block_buf)?;
+            }
+        }
+        // The hashes roll back along with the upper level, so all blocks need to be verified again
+        for status in self.status.lock().unwrap().iter_mut() {
+            *status = BLOCK_UNVERIFIED;
+        }
         Ok(())
     }
 }
@@ -179,7 +403,7 @@ mod test {
     use crate::memory_file::MemoryFile;
     use crate::misc::*;
     use crate::random_access_file::*;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn fuzz() {
@@ -192,16 +416,16 @@ mod test {
             let block_len = rng.gen_range(1..100);
             let block_count = divide_up(len, block_len);
             let hash_len = block_count * 0x20;
-            let hash = Rc::new(MemoryFile::new(
+            let hash = Arc::new(MemoryFile::new(
                 (&mut rng).sample_iter(&Standard).take(hash_len).collect(),
             ));
-            let data = Rc::new(MemoryFile::new(
+            let data = Arc::new(MemoryFile::new(
                 (&mut rng).sample_iter(&Standard).take(len).collect(),
             ));
-            let ivfc_level = IvfcLevel::new(hash.clone(), data.clone(), block_len).unwrap();
+            let ivfc_level = IvfcLevel::new(hash.clone(), data.clone(), block_len, 4).unwrap();
             let mut buf = vec![0; len];
             match ivfc_level.read(0, &mut buf) {
-                Err(Error::HashMismatch) => (),
+                Err(Error::HashMismatch(_)) => (),
                 _ => unreachable!(),
             }
             let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
@@ -212,9 +436,38 @@ mod test {
                 ivfc_level,
                 |file| file,
                 |file| file.commit().unwrap(),
-                || IvfcLevel::new(hash.clone(), data.clone(), block_len).unwrap(),
+                || IvfcLevel::new(hash.clone(), data.clone(), block_len, 4).unwrap(),
                 plain,
             );
         }
     }
+
+    #[test]
+    fn verify_all() {
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let len = rng.gen_range(1..10_000);
+            let block_len = rng.gen_range(1..100);
+            let block_count = divide_up(len, block_len);
+            let data: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            let hash = Arc::new(MemoryFile::new(vec![0; block_count * 0x20]));
+            let data = Arc::new(MemoryFile::new(data));
+            let ivfc_level = IvfcLevel::new(hash.clone(), data.clone(), block_len, 4).unwrap();
+            ivfc_level.rehash();
+            ivfc_level.commit().unwrap();
+            for _ in 0..rng.gen_range(0..10) {
+                let pos = rng.gen_range(0..len);
+                data.write(pos, &[rng.gen()]).unwrap();
+            }
+
+            let lazy = IvfcLevel::new(hash.clone(), data.clone(), block_len, 4).unwrap();
+            let eager = IvfcLevel::new(hash.clone(), data.clone(), block_len, 4).unwrap();
+            let broken = eager.verify_all().unwrap();
+            assert_eq!(broken, lazy.broken_blocks().unwrap());
+            assert_eq!(eager.verify_all().unwrap(), broken);
+        }
+    }
 }
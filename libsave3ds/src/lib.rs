@@ -2,8 +2,10 @@
 #![allow(clippy::unusual_byte_groupings)]
 
 mod aes_ctr_file;
+mod block_cache;
 mod byte_struct_common;
 pub mod cart_save_data;
+mod check;
 pub mod db;
 mod diff;
 mod difi_partition;
@@ -13,45 +15,78 @@ mod dpfs_level;
 mod dual_file;
 pub mod error;
 pub mod ext_data;
+mod extent;
 mod fat;
+#[cfg(test)]
+mod fault_file;
 pub mod file_system;
 mod fs_meta;
+#[cfg(any(test, feature = "fuzz"))]
+#[doc(hidden)]
+pub mod fuzz;
+mod inspect;
+mod inverted_file;
 mod ivfc_level;
 mod key_engine;
 mod memory_file;
 mod misc;
+mod mmap_file;
 mod nand;
+mod overlay;
 mod random_access_file;
 pub mod save_data;
 mod save_ext_common;
 mod sd;
 mod sd_nand_common;
 mod signed_file;
+mod staged_file;
 mod sub_file;
+mod trace;
+mod tree_diff;
 mod wear_leveling;
 
+pub use block_cache::BlockCacheStats;
+pub use check::{Check, CheckIssue, CheckParts, MetaTableKind};
+pub use difi_partition::{GenerationReport, SelectorInversion};
+pub use disk_file::FileBackend;
+pub use extent::{Extent, FileExtents, Fragmentation};
+pub use inspect::{InspectNode, InspectValue};
+pub use ivfc_level::LevelReport;
+pub use overlay::{OverlayChange, OverlayPath};
+pub use save_ext_common::{BlockOwner, BrokenBlock};
+pub use trace::{
+    replay_trace, Divergence, ReplayReport, Trace, TraceArchive, TraceFormat, Traced, TracedDir,
+    TracedFile,
+};
+pub use tree_diff::{diff_archives, ArchiveDiff, DiffEntry};
+
 use aes::*;
+use block_cache::*;
 use cart_save_data::*;
 use db::*;
-use disk_file::DiskFile;
+use disk_file::{open_file, replace_file};
 use error::*;
 use ext_data::*;
+use file_system::CopyReport;
 use key_engine::*;
 use misc::*;
 use nand::Nand;
+use overlay::*;
+use random_access_file::*;
 use save_data::*;
 use sd::Sd;
 use sd_nand_common::*;
 use sha2::*;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::*;
-use std::rc::Rc;
+use std::sync::Arc;
+use trace::{TraceFile, TracedSdNand};
 
 /// Represents all resource associated with a 3DS console.
 /// Works as the root object to access all archives on the console.
 pub struct Resource {
-    sd: Option<Rc<Sd>>,
-    nand: Option<Rc<Nand>>,
+    sd: Option<Arc<Sd>>,
+    nand: Option<Arc<Nand>>,
     key_x_ncch: Option<[u8; 16]>,
     key_x_dec: Option<[u8; 16]>,
     key_sign: Option<[u8; 16]>,
@@ -64,6 +99,10 @@ pub struct Resource {
     x2f_key_y: Option<[u8; 16]>,
     x19_key_x: Option<[u8; 16]>,
     x1a_key_x: Option<[u8; 16]>,
+    overlay: Option<Arc<Overlay>>,
+    block_cache: Option<Arc<BlockCache>>,
+    backend: FileBackend,
+    trace: Option<Arc<Trace>>,
 }
 
 impl Resource {
@@ -162,13 +201,13 @@ impl Resource {
         let key_sign = (|| Some(scramble(key_x_sign?, key_y?)))();
 
         let sd = if let (Some(sd), Some(x), Some(y)) = (sd_path, key_x_dec, key_y) {
-            Some(Rc::new(Sd::new(&sd, x, y)?))
+            Some(Arc::new(Sd::new(&sd, x, y)?))
         } else {
             None
         };
 
         let nand = if let Some(nand_path) = nand_path {
-            Some(Rc::new(Nand::new(&nand_path)?))
+            Some(Arc::new(Nand::new(&nand_path)?))
         } else {
             None
         };
@@ -242,13 +281,202 @@ impl Resource {
             x2f_key_y,
             x19_key_x,
             x1a_key_x,
+            overlay: None,
+            block_cache: None,
+            backend: FileBackend::default(),
+            trace: None,
         })
     }
 
+    /// Enables or disables the overlay mode. In overlay mode, all archives are opened on top of
+    /// in-memory copies of their files, and nothing is written to the SD, the NAND or any
+    /// other file until [`persist_overlay`](#method.persist_overlay) is called.
+    /// Disabling the overlay mode discards all pending changes.
+    pub fn set_overlay(&mut self, enabled: bool) {
+        self.overlay = if enabled {
+            Some(Arc::new(Overlay::default()))
+        } else {
+            None
+        };
+    }
+
+    /// Lists the changes recorded in overlay mode.
+    pub fn overlay_changes(&self) -> Result<Vec<(OverlayPath, OverlayChange)>, Error> {
+        match &self.overlay {
+            Some(overlay) => overlay.changes(),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Writes all changes recorded in overlay mode to the real files, replacing each changed file as a whole.
+    /// On failure, the changes not written yet stay recorded.
+    /// Fails with `Error::Busy` if any archive opened in overlay mode is still alive.
+    pub fn persist_overlay(&self) -> Result<(), Error> {
+        if let Some(overlay) = &self.overlay {
+            overlay.persist(
+                self.sd.as_deref().map(|sd| sd as &dyn SdNandFileSystem),
+                self.nand
+                    .as_deref()
+                    .map(|nand| nand as &dyn SdNandFileSystem),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the content of the stand-alone or cartridge save at `path`, as changed in overlay
+    /// mode, to a new file at `new_path`, and drops the changes to `path` from the overlay.
+    pub fn persist_overlay_as(&self, path: &str, new_path: &str) -> Result<(), Error> {
+        let overlay = self.overlay.as_ref().ok_or(Error::NotFound)?;
+        overlay.check_unused()?;
+        let data = overlay.take(&OverlayPath::Host(PathBuf::from(path)))?;
+        replace_file(Path::new(new_path), data.len(), |file| file, &mut |file| {
+            file.write(0, &data)
+        })
+    }
+
+    /// Drops all changes recorded in overlay mode.
+    /// Fails with `Error::Busy` if any archive opened in overlay mode is still alive.
+    pub fn discard_overlay(&self) -> Result<(), Error> {
+        if let Some(overlay) = &self.overlay {
+            overlay.check_unused()?;
+            overlay.clear();
+        }
+        Ok(())
+    }
+
+    /// Enables the block cache with `capacity` bytes for every archive file opened afterwards
+    /// from the SD, the NAND or the host file system, or disables it if `capacity` is 0.
+    /// Changes stay in the cache until the archive is committed.
+    pub fn set_block_cache(&mut self, capacity: usize) {
+        self.block_cache = if capacity != 0 {
+            Some(Arc::new(BlockCache::new(capacity)))
+        } else {
+            None
+        };
+    }
+
+    /// Gets the hit and miss counters of the block cache.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.block_cache
+            .as_ref()
+            .map(|cache| cache.stats())
+            .unwrap_or_default()
+    }
+
+    /// Chooses how archive files are opened from the SD, the NAND or the host file system
+    /// afterwards. The default is `FileBackend::Disk`.
+    pub fn set_file_backend(&mut self, backend: FileBackend) {
+        if let Some(sd) = &mut self.sd {
+            Arc::make_mut(sd).set_backend(backend);
+        }
+        if let Some(nand) = &mut self.nand {
+            Arc::make_mut(nand).set_backend(backend);
+        }
+        self.backend = backend;
+    }
+
+    /// Records every read and write on the archive files opened afterwards from the SD, the NAND
+    /// or the host file system in `trace`, or stops recording if `trace` is `None`.
+    /// The layers inside an archive are not traced on their own; see [`Trace`](struct.Trace.html).
+    pub fn set_trace(&mut self, trace: Option<Arc<Trace>>) {
+        self.trace = trace;
+    }
+
+    fn traced(
+        &self,
+        base: Arc<dyn SdNandFileSystem>,
+        name: &'static str,
+    ) -> Arc<dyn SdNandFileSystem> {
+        match &self.trace {
+            Some(trace) => Arc::new(TracedSdNand {
+                base,
+                trace: trace.clone(),
+                name,
+            }),
+            None => base,
+        }
+    }
+
+    fn cached(&self, base: Arc<dyn SdNandFileSystem>) -> Arc<dyn SdNandFileSystem> {
+        match &self.block_cache {
+            Some(cache) => Arc::new(CachedSdNand {
+                base,
+                cache: cache.clone(),
+            }),
+            None => base,
+        }
+    }
+
+    fn sd(&self) -> Result<Arc<dyn SdNandFileSystem>, Error> {
+        let sd = self.cached(self.sd.clone().ok_or(Error::MissingSd)?);
+        let sd: Arc<dyn SdNandFileSystem> = match &self.overlay {
+            Some(overlay) => Arc::new(OverlaySdNand {
+                base: sd,
+                overlay: overlay.clone(),
+                make_path: OverlayPath::Sd,
+            }),
+            None => sd,
+        };
+        Ok(self.traced(sd, "sd"))
+    }
+
+    fn nand(&self) -> Result<Arc<dyn SdNandFileSystem>, Error> {
+        let nand = self.cached(self.nand.clone().ok_or(Error::MissingNand)?);
+        let nand: Arc<dyn SdNandFileSystem> = match &self.overlay {
+            Some(overlay) => Arc::new(OverlaySdNand {
+                base: nand,
+                overlay: overlay.clone(),
+                make_path: OverlayPath::Nand,
+            }),
+            None => nand,
+        };
+        Ok(self.traced(nand, "nand"))
+    }
+
+    fn open_host(&self, path: &str, write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        let open = |write| -> Result<Arc<dyn RandomAccessFile>, Error> {
+            let file = open_file(Path::new(path), write, self.backend)?;
+            Ok(match &self.block_cache {
+                Some(cache) => cache.wrap(file),
+                None => file,
+            })
+        };
+        let file = match &self.overlay {
+            Some(overlay) => {
+                overlay.open(OverlayPath::Host(PathBuf::from(path)), || open(false))?
+            }
+            None => open(write)?,
+        };
+        Ok(match &self.trace {
+            Some(trace) => {
+                // Only the file name, as the rest of the host path may identify the user
+                let name = Path::new(path).file_name().unwrap_or_default();
+                let name = format!("host/{}", name.to_string_lossy());
+                Arc::new(TraceFile::new(file, trace.clone(), &name))
+            }
+            None => file,
+        })
+    }
+
+    fn create_host(&self, path: &str, len: usize) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        match &self.overlay {
+            Some(overlay) => overlay.create(OverlayPath::Host(PathBuf::from(path)), vec![0; len]),
+            None => std::fs::File::create(path)?.set_len(len as u64)?,
+        }
+        self.open_host(path, true)
+    }
+
+    fn replace_host(&self, path: &str, len: usize, fill: Fill) -> Result<(), Error> {
+        match &self.overlay {
+            Some(overlay) => overlay.create_with(OverlayPath::Host(PathBuf::from(path)), len, fill),
+            None => replace_file(Path::new(path), len, |file| file, fill),
+        }
+    }
+
     /// Formats an extdata on SD.
     pub fn format_sd_ext(&self, id: u64, param: &ExtDataFormatParam) -> Result<(), Error> {
         ExtData::format(
-            self.sd.as_ref().ok_or(Error::MissingSd)?.as_ref(),
+            self.sd()?.as_ref(),
             &["extdata"],
             id,
             self.key_sign.ok_or(Error::MissingBoot9)?,
@@ -259,13 +487,41 @@ impl Resource {
 
     /// Opens an extdata on SD.
     pub fn open_sd_ext(&self, id: u64, write: bool) -> Result<ExtData, Error> {
+        self.open_sd_ext_with(id, write, SelectorInversion::default(), false)
+    }
+
+    /// Opens an extdata on SD read-only, reading the inactive copies selected by `inversion`.
+    pub fn open_sd_ext_inverted(
+        &self,
+        id: u64,
+        inversion: SelectorInversion,
+    ) -> Result<ExtData, Error> {
+        self.open_sd_ext_with(id, false, inversion, false)
+    }
+
+    /// Opens an extdata on SD in salvage mode, which skips signature checks, falls back to the other
+    /// partition table copy if the active one is broken, and reads broken blocks as they are.
+    /// Committing it rehashes the broken blocks and re-signs the archive.
+    pub fn open_sd_ext_salvage(&self, id: u64, write: bool) -> Result<ExtData, Error> {
+        self.open_sd_ext_with(id, write, SelectorInversion::default(), true)
+    }
+
+    fn open_sd_ext_with(
+        &self,
+        id: u64,
+        write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
+    ) -> Result<ExtData, Error> {
         ExtData::new(
-            self.sd.as_ref().ok_or(Error::MissingSd)?.clone(),
+            self.sd()?,
             &["extdata"],
             id,
             self.key_sign.ok_or(Error::MissingBoot9)?,
             false,
             write,
+            inversion,
+            salvage,
         )
     }
 
@@ -280,7 +536,7 @@ impl Resource {
         let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
         let sub_path = ["title", &id_high, &id_low, "data", "00000001.sav"];
 
-        let sd = self.sd.as_ref().ok_or(Error::MissingSd)?;
+        let sd = self.sd()?;
         sd.create(&sub_path, len)?;
         let file = sd.open(&sub_path, true)?;
 
@@ -295,22 +551,73 @@ impl Resource {
 
     /// Opens a save data on SD.
     pub fn open_sd_save(&self, id: u64, write: bool) -> Result<SaveData, Error> {
+        self.open_sd_save_with(id, write, SelectorInversion::default(), false)
+    }
+
+    /// Opens a save data on SD read-only, reading the inactive copies selected by `inversion`.
+    pub fn open_sd_save_inverted(
+        &self,
+        id: u64,
+        inversion: SelectorInversion,
+    ) -> Result<SaveData, Error> {
+        self.open_sd_save_with(id, false, inversion, false)
+    }
+
+    /// Opens a save data on SD in salvage mode, which skips signature checks, falls back to the other
+    /// partition table copy if the active one is broken, and reads broken blocks as they are.
+    /// Committing it rehashes the broken blocks and re-signs the archive.
+    pub fn open_sd_save_salvage(&self, id: u64, write: bool) -> Result<SaveData, Error> {
+        self.open_sd_save_with(id, write, SelectorInversion::default(), true)
+    }
+
+    fn open_sd_save_with(
+        &self,
+        id: u64,
+        write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
+    ) -> Result<SaveData, Error> {
         let id_high = format!("{:08x}", id >> 32);
         let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
         let sub_path = ["title", &id_high, &id_low, "data", "00000001.sav"];
 
-        let dec_file = self
-            .sd
-            .as_ref()
-            .ok_or(Error::MissingSd)?
-            .open(&sub_path, write)?;
+        let dec_file = self.sd()?.open(&sub_path, write)?;
 
         SaveData::new(
             dec_file,
             SaveDataType::Sd(self.key_sign.ok_or(Error::MissingBoot9)?, id),
+            inversion,
+            salvage,
         )
     }
 
+    /// Rebuilds a save data on SD with new format parameters and length,
+    /// keeping all its directories and files.
+    /// The original file is only replaced after the new image is completely built.
+    /// Files failing hash verification, such as those with data never written, are copied
+    /// as read and counted in `CopyReport::unverified_files`.
+    pub fn repack_sd_save(
+        &self,
+        id: u64,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<CopyReport, Error> {
+        let id_high = format!("{:08x}", id >> 32);
+        let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
+        let sub_path = ["title", &id_high, &id_low, "data", "00000001.sav"];
+
+        let save_data_type = SaveDataType::Sd(self.key_sign.ok_or(Error::MissingBoot9)?, id);
+        let mut save = Some(self.open_sd_save(id, false)?);
+        let mut report = CopyReport::default();
+        self.sd()?.replace(&sub_path, len, &mut |image| {
+            // Close the original before it is replaced
+            let save = save.take().ok_or(Error::Busy)?;
+            report = save.repack(image, save_data_type.clone(), param)?;
+            Ok(())
+        })?;
+        Ok(report)
+    }
+
     /// Formats a save data on NAND.
     pub fn format_nand_save(
         &self,
@@ -326,7 +633,7 @@ impl Resource {
             "00000000",
         ];
 
-        let nand = self.nand.as_ref().ok_or(Error::MissingNand)?;
+        let nand = self.nand()?;
         nand.create(&sub_path, len)?;
         let file = nand.open(&sub_path, true)?;
 
@@ -341,7 +648,33 @@ impl Resource {
 
     /// Opens a save data on NAND.
     pub fn open_nand_save(&self, id: u32, write: bool) -> Result<SaveData, Error> {
-        let file = self.nand.as_ref().ok_or(Error::MissingNand)?.open(
+        self.open_nand_save_with(id, write, SelectorInversion::default(), false)
+    }
+
+    /// Opens a save data on NAND read-only, reading the inactive copies selected by `inversion`.
+    pub fn open_nand_save_inverted(
+        &self,
+        id: u32,
+        inversion: SelectorInversion,
+    ) -> Result<SaveData, Error> {
+        self.open_nand_save_with(id, false, inversion, false)
+    }
+
+    /// Opens a save data on NAND in salvage mode, which skips signature checks, falls back to the other
+    /// partition table copy if the active one is broken, and reads broken blocks as they are.
+    /// Committing it rehashes the broken blocks and re-signs the archive.
+    pub fn open_nand_save_salvage(&self, id: u32, write: bool) -> Result<SaveData, Error> {
+        self.open_nand_save_with(id, write, SelectorInversion::default(), true)
+    }
+
+    fn open_nand_save_with(
+        &self,
+        id: u32,
+        write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
+    ) -> Result<SaveData, Error> {
+        let file = self.nand()?.open(
             &[
                 "data",
                 self.id0.as_ref().ok_or(Error::MissingNand)?,
@@ -354,13 +687,45 @@ impl Resource {
         SaveData::new(
             file,
             SaveDataType::Nand(self.key_sign.ok_or(Error::MissingBoot9)?, id),
+            inversion,
+            salvage,
         )
     }
 
+    /// Rebuilds a save data on NAND with new format parameters and length,
+    /// keeping all its directories and files.
+    /// The original file is only replaced after the new image is completely built.
+    /// Files failing hash verification, such as those with data never written, are copied
+    /// as read and counted in `CopyReport::unverified_files`.
+    pub fn repack_nand_save(
+        &self,
+        id: u32,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<CopyReport, Error> {
+        let sub_path = [
+            "data",
+            self.id0.as_ref().ok_or(Error::MissingNand)?,
+            "sysdata",
+            &format!("{:08x}", id),
+            "00000000",
+        ];
+
+        let save_data_type = SaveDataType::Nand(self.key_sign.ok_or(Error::MissingBoot9)?, id);
+        let mut save = Some(self.open_nand_save(id, false)?);
+        let mut report = CopyReport::default();
+        self.nand()?.replace(&sub_path, len, &mut |image| {
+            let save = save.take().ok_or(Error::Busy)?;
+            report = save.repack(image, save_data_type.clone(), param)?;
+            Ok(())
+        })?;
+        Ok(report)
+    }
+
     /// Formats an extdata on NAND.
     pub fn format_nand_ext(&self, id: u64, param: &ExtDataFormatParam) -> Result<(), Error> {
         ExtData::format(
-            self.nand.as_ref().ok_or(Error::MissingNand)?.as_ref(),
+            self.nand()?.as_ref(),
             &[
                 "data",
                 self.id0.as_ref().ok_or(Error::MissingNand)?,
@@ -375,8 +740,34 @@ impl Resource {
 
     /// Opens an extdata on NAND.
     pub fn open_nand_ext(&self, id: u64, write: bool) -> Result<ExtData, Error> {
+        self.open_nand_ext_with(id, write, SelectorInversion::default(), false)
+    }
+
+    /// Opens an extdata on NAND read-only, reading the inactive copies selected by `inversion`.
+    pub fn open_nand_ext_inverted(
+        &self,
+        id: u64,
+        inversion: SelectorInversion,
+    ) -> Result<ExtData, Error> {
+        self.open_nand_ext_with(id, false, inversion, false)
+    }
+
+    /// Opens an extdata on NAND in salvage mode, which skips signature checks, falls back to the other
+    /// partition table copy if the active one is broken, and reads broken blocks as they are.
+    /// Committing it rehashes the broken blocks and re-signs the archive.
+    pub fn open_nand_ext_salvage(&self, id: u64, write: bool) -> Result<ExtData, Error> {
+        self.open_nand_ext_with(id, write, SelectorInversion::default(), true)
+    }
+
+    fn open_nand_ext_with(
+        &self,
+        id: u64,
+        write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
+    ) -> Result<ExtData, Error> {
         ExtData::new(
-            self.nand.as_ref().ok_or(Error::MissingNand)?.clone(),
+            self.nand()?,
             &[
                 "data",
                 self.id0.as_ref().ok_or(Error::MissingNand)?,
@@ -386,6 +777,8 @@ impl Resource {
             self.key_sign.ok_or(Error::MissingBoot9)?,
             true,
             write,
+            inversion,
+            salvage,
         )
     }
 
@@ -401,14 +794,7 @@ impl Resource {
         param: &SaveDataFormatParam,
         len: usize,
     ) -> Result<(), Error> {
-        std::fs::File::create(path)?.set_len(len as u64)?;
-
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)?,
-        )?);
+        let file = self.create_host(path, len)?;
 
         SaveData::format(file, SaveDataType::Bare, param)?;
 
@@ -422,24 +808,76 @@ impl Resource {
     /// fixed using other tools to be usable on 3DS. Because of this limitation, this function is
     /// mostly for test purpose.
     pub fn open_bare_save(&self, path: &str, write: bool) -> Result<SaveData, Error> {
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(path)?,
-        )?);
+        self.open_bare_save_with(path, write, SelectorInversion::default(), false)
+    }
 
-        SaveData::new(file, SaveDataType::Bare)
+    /// Opens a stand-alone save data read-only, reading the inactive copies selected by `inversion`.
+    pub fn open_bare_save_inverted(
+        &self,
+        path: &str,
+        inversion: SelectorInversion,
+    ) -> Result<SaveData, Error> {
+        self.open_bare_save_with(path, false, inversion, false)
+    }
+
+    /// Opens a stand-alone save data in salvage mode, which skips signature checks, falls back to the other
+    /// partition table copy if the active one is broken, and reads broken blocks as they are.
+    /// Committing it rehashes the broken blocks and re-signs the archive.
+    pub fn open_bare_save_salvage(&self, path: &str, write: bool) -> Result<SaveData, Error> {
+        self.open_bare_save_with(path, write, SelectorInversion::default(), true)
+    }
+
+    fn open_bare_save_with(
+        &self,
+        path: &str,
+        write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
+    ) -> Result<SaveData, Error> {
+        let file = self.open_host(path, write)?;
+
+        SaveData::new(file, SaveDataType::Bare, inversion, salvage)
+    }
+
+    /// Rebuilds a stand-alone save data with new format parameters and length,
+    /// keeping all its directories and files.
+    /// The original file is only replaced after the new image is completely built.
+    /// Files failing hash verification, such as those with data never written, are copied
+    /// as read and counted in `CopyReport::unverified_files`.
+    ///
+    /// Warning: same as [`format_bare_save`](#method.format_bare_save),
+    /// the resulting save data never has a correct signature.
+    pub fn repack_bare_save(
+        &self,
+        path: &str,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<CopyReport, Error> {
+        let mut save = Some(self.open_bare_save(path, false)?);
+        let mut report = CopyReport::default();
+        self.replace_host(path, len, &mut |image| {
+            let save = save.take().ok_or(Error::Busy)?;
+            report = save.repack(image, SaveDataType::Bare, param)?;
+            Ok(())
+        })?;
+        Ok(report)
     }
 
     fn get_cart_format(&self) -> Result<CartFormat, Error> {
         let game = disk_file::DiskFile::new(std::fs::File::open(
             self.game_path.as_ref().ok_or(Error::MissingGame)?,
         )?)?;
+        self.read_cart_format(Arc::new(game))
+    }
 
+    /// Derives the cartridge save format from the game image.
+    fn read_cart_format(
+        &self,
+        game: Arc<dyn random_access_file::RandomAccessFile>,
+    ) -> Result<CartFormat, Error> {
         use byte_struct_common::*;
         use random_access_file::*;
-        if read_struct::<Magic>(&game, 0x100)?.v != *b"NCSD" {
+        if read_struct::<Magic>(game.as_ref(), 0x100)?.v != *b"NCSD" {
             return Err(Error::BrokenGame);
         }
 
@@ -452,9 +890,9 @@ impl Resource {
             _ => return Err(Error::BrokenGame),
         };
 
-        let cxi_offset = read_struct::<U32le>(&game, 0x120)?.v * 0x200;
-        let cxi_len = read_struct::<U32le>(&game, 0x124)?.v * 0x200;
-        let cxi = sub_file::SubFile::new(Rc::new(game), cxi_offset as usize, cxi_len as usize)?;
+        let cxi_offset = read_struct::<U32le>(game.as_ref(), 0x120)?.v as usize * 0x200;
+        let cxi_len = read_struct::<U32le>(game.as_ref(), 0x124)?.v as usize * 0x200;
+        let cxi = sub_file::SubFile::new(game, cxi_offset, cxi_len)?;
 
         if read_struct::<Magic>(&cxi, 0x100)?.v != *b"NCCH" {
             return Err(Error::BrokenGame);
@@ -469,7 +907,10 @@ impl Resource {
             key_engine::scramble(self.key_x_ncch.ok_or(Error::MissingBoot9)?, key_y_ncch);
 
         let ncch_version = read_struct::<U16le>(&cxi, 0x112)?.v;
-        let exefs_offset = read_struct::<U32le>(&cxi, 0x1A0)?.v * 0x200;
+        let exefs_offset = read_struct::<U32le>(&cxi, 0x1A0)?
+            .v
+            .checked_mul(0x200)
+            .ok_or(Error::BrokenGame)?;
 
         // CTR calculation below should use partition ID.
         // But for cartridge exe this should be always the same as program ID
@@ -490,10 +931,10 @@ impl Resource {
             return Err(Error::BrokenGame);
         }
 
-        let cxi = Rc::new(cxi);
+        let cxi = Arc::new(cxi);
 
         let exheader = aes_ctr_file::AesCtrFile::new(
-            Rc::new(sub_file::SubFile::new(cxi.clone(), 0x200, 0x800)?),
+            Arc::new(sub_file::SubFile::new(cxi.clone(), 0x200, 0x800)?),
             ncch_key,
             ctr_exheader,
             false,
@@ -502,7 +943,7 @@ impl Resource {
         exheader.read(0x400, &mut exheader_signature)?;
 
         let exefs = aes_ctr_file::AesCtrFile::new(
-            Rc::new(sub_file::SubFile::new(cxi, exefs_offset as usize, 0x200)?),
+            Arc::new(sub_file::SubFile::new(cxi, exefs_offset as usize, 0x200)?),
             ncch_key,
             ctr_exefs,
             false,
@@ -612,14 +1053,7 @@ impl Resource {
         param: &SaveDataFormatParam,
         len: usize,
     ) -> Result<(), Error> {
-        std::fs::File::create(path)?.set_len(len as u64)?;
-
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)?,
-        )?);
+        let file = self.create_host(path, len)?;
 
         CartSaveData::format(file, &self.get_cart_format()?, param)?;
 
@@ -628,66 +1062,88 @@ impl Resource {
 
     /// Opens a save data on cartridge.
     pub fn open_cart_save(&self, path: &str, write: bool) -> Result<CartSaveData, Error> {
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(path)?,
-        )?);
+        self.open_cart_save_with(path, write, SelectorInversion::default(), false)
+    }
+
+    /// Opens a save data on cartridge read-only, reading the inactive copies selected by `inversion`.
+    pub fn open_cart_save_inverted(
+        &self,
+        path: &str,
+        inversion: SelectorInversion,
+    ) -> Result<CartSaveData, Error> {
+        self.open_cart_save_with(path, false, inversion, false)
+    }
+
+    /// Opens a save data on cartridge in salvage mode, which skips signature checks, falls back to the other
+    /// partition table copy if the active one is broken, and reads broken blocks as they are.
+    /// Committing it rehashes the broken blocks and re-signs the archive.
+    pub fn open_cart_save_salvage(&self, path: &str, write: bool) -> Result<CartSaveData, Error> {
+        self.open_cart_save_with(path, write, SelectorInversion::default(), true)
+    }
+
+    fn open_cart_save_with(
+        &self,
+        path: &str,
+        write: bool,
+        inversion: SelectorInversion,
+        salvage: bool,
+    ) -> Result<CartSaveData, Error> {
+        let file = self.open_host(path, write)?;
 
-        CartSaveData::new(file, &self.get_cart_format()?)
+        CartSaveData::new(file, &self.get_cart_format()?, inversion, salvage)
+    }
+
+    /// Rebuilds a save data on cartridge with new format parameters and length,
+    /// keeping all its directories and files.
+    /// The original file is only replaced after the new image is completely built.
+    /// Files failing hash verification, such as those with data never written, are copied
+    /// as read and counted in `CopyReport::unverified_files`.
+    pub fn repack_cart_save(
+        &self,
+        path: &str,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<CopyReport, Error> {
+        let format = self.get_cart_format()?;
+        let mut save = Some(self.open_cart_save(path, false)?);
+        let mut report = CopyReport::default();
+        self.replace_host(path, len, &mut |image| {
+            let save = save.take().ok_or(Error::Busy)?;
+            report = save.repack(image, &format, param)?;
+            Ok(())
+        })?;
+        Ok(report)
     }
 
     /// Opens a title database.
     pub fn open_db(&self, db_type: DbType, write: bool) -> Result<Db, Error> {
         let (file, key) = match db_type {
             DbType::NandTitle => (
-                self.nand
-                    .as_ref()
-                    .ok_or(Error::MissingNand)?
-                    .open(&["dbs", "title.db"], write)?,
+                self.nand()?.open(&["dbs", "title.db"], write)?,
                 self.key_db.ok_or(Error::MissingOtp)?,
             ),
             DbType::NandImport => (
-                self.nand
-                    .as_ref()
-                    .ok_or(Error::MissingNand)?
-                    .open(&["dbs", "import.db"], write)?,
+                self.nand()?.open(&["dbs", "import.db"], write)?,
                 self.key_db.ok_or(Error::MissingOtp)?,
             ),
             DbType::TmpTitle => (
-                self.nand
-                    .as_ref()
-                    .ok_or(Error::MissingNand)?
-                    .open(&["dbs", "tmp_t.db"], write)?,
+                self.nand()?.open(&["dbs", "tmp_t.db"], write)?,
                 self.key_db.ok_or(Error::MissingOtp)?,
             ),
             DbType::TmpImport => (
-                self.nand
-                    .as_ref()
-                    .ok_or(Error::MissingNand)?
-                    .open(&["dbs", "tmp_i.db"], write)?,
+                self.nand()?.open(&["dbs", "tmp_i.db"], write)?,
                 self.key_db.ok_or(Error::MissingOtp)?,
             ),
             DbType::Ticket => (
-                self.nand
-                    .as_ref()
-                    .ok_or(Error::MissingNand)?
-                    .open(&["dbs", "ticket.db"], write)?,
+                self.nand()?.open(&["dbs", "ticket.db"], write)?,
                 self.key_db.ok_or(Error::MissingOtp)?,
             ),
             DbType::SdTitle => (
-                self.sd
-                    .as_ref()
-                    .ok_or(Error::MissingSd)?
-                    .open(&["dbs", "title.db"], write)?,
+                self.sd()?.open(&["dbs", "title.db"], write)?,
                 self.key_sign.ok_or(Error::MissingSd)?,
             ),
             DbType::SdImport => (
-                self.sd
-                    .as_ref()
-                    .ok_or(Error::MissingSd)?
-                    .open(&["dbs", "import.db"], write)?,
+                self.sd()?.open(&["dbs", "import.db"], write)?,
                 self.key_sign.ok_or(Error::MissingSd)?,
             ),
         };
@@ -695,3 +1151,104 @@ impl Resource {
         Db::new(file, db_type, key)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::file_system::*;
+    use crate::*;
+
+    #[test]
+    fn overlay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("save.bin");
+        let path = path.to_str().unwrap();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let mut resource =
+            Resource::new(None, None, None, None, None, None, None, None, None, None).unwrap();
+        resource.set_overlay(true);
+        let write = |resource: &Resource, name: u8, data: &[u8]| {
+            let save = resource.open_bare_save(path, true).unwrap();
+            let mut file_name = [0; 16];
+            file_name[0] = name;
+            let file = save
+                .open_root()
+                .unwrap()
+                .new_sub_file(file_name, data.len())
+                .unwrap();
+            file.write(0, data).unwrap();
+            drop(file);
+            save.commit().unwrap();
+        };
+        let read = |resource: &Resource, name: u8| {
+            let save = resource.open_bare_save(path, false).unwrap();
+            let mut file_name = [0; 16];
+            file_name[0] = name;
+            let file = save.open_root().unwrap().open_sub_file(file_name).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            buf
+        };
+
+        resource.format_bare_save(path, &param, 0x80_000).unwrap();
+        write(&resource, b'a', &[1; 100]);
+        assert!(!dir.path().join("save.bin").exists());
+        assert_eq!(resource.overlay_changes().unwrap().len(), 1);
+        resource.persist_overlay().unwrap();
+        assert_eq!(resource.overlay_changes().unwrap(), vec![]);
+
+        write(&resource, b'b', &[2; 100]);
+        assert_eq!(resource.overlay_changes().unwrap().len(), 1);
+        assert_eq!(read(&resource, b'b'), vec![2; 100]);
+        resource.set_overlay(false);
+        let save = resource.open_bare_save(path, false).unwrap();
+        let mut file_name = [0; 16];
+        file_name[0] = b'b';
+        assert!(save.open_root().unwrap().open_sub_file(file_name).is_err());
+        drop(save);
+
+        resource.set_overlay(true);
+        write(&resource, b'b', &[2; 100]);
+        resource.persist_overlay().unwrap();
+        resource.set_overlay(false);
+        assert_eq!(read(&resource, b'a'), vec![1; 100]);
+        assert_eq!(read(&resource, b'b'), vec![2; 100]);
+    }
+
+    #[test]
+    fn block_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("save.bin");
+        let path = path.to_str().unwrap();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let mut resource =
+            Resource::new(None, None, None, None, None, None, None, None, None, None).unwrap();
+        resource.format_bare_save(path, &param, 0x80_000).unwrap();
+        resource.set_block_cache(0x10_000);
+        let save = resource.open_bare_save(path, true).unwrap();
+        let data: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        write_all(&save, "a", &data).unwrap();
+        save.commit().unwrap();
+        assert_ne!(resource.block_cache_stats().write_backs, 0);
+
+        // The committed content is on disk while the cached archive is still open
+        resource.set_block_cache(0);
+        let uncached = resource.open_bare_save(path, false).unwrap();
+        assert_eq!(read_to_vec(&uncached, "a").unwrap(), data);
+        assert_eq!(uncached.check().unwrap(), vec![]);
+        drop(save);
+    }
+}
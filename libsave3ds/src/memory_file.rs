@@ -1,16 +1,16 @@
 use crate::error::*;
 use crate::random_access_file::*;
-use std::cell::RefCell;
+use std::sync::Mutex;
 
 /// Implements `RandomAccessFile` as a simple Vec<u8>
 pub struct MemoryFile {
-    data: RefCell<Vec<u8>>,
+    data: Mutex<Vec<u8>>,
 }
 
 impl MemoryFile {
     pub fn new(data: Vec<u8>) -> MemoryFile {
         MemoryFile {
-            data: RefCell::new(data),
+            data: Mutex::new(data),
         }
     }
 
@@ -19,30 +19,36 @@ impl MemoryFile {
         let mut data = vec![0; file.len()];
         file.read(0, &mut data)?;
         Ok(MemoryFile {
-            data: RefCell::new(data),
+            data: Mutex::new(data),
         })
     }
 }
 
 impl RandomAccessFile for MemoryFile {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        let data = self.data.borrow();
-        if pos + buf.len() > data.len() {
-            return make_error(Error::OutOfBound);
+        let data = self.data.lock().unwrap();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > data.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         buf.copy_from_slice(&data[pos..pos + buf.len()]);
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        let mut data = self.data.borrow_mut();
-        if pos + buf.len() > data.len() {
-            return make_error(Error::OutOfBound);
+        let mut data = self.data.lock().unwrap();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > data.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         data[pos..pos + buf.len()].copy_from_slice(buf);
         Ok(())
     }
     fn len(&self) -> usize {
-        self.data.borrow().len()
+        self.data.lock().unwrap().len()
     }
     fn commit(&self) -> Result<(), Error> {
         Ok(())
@@ -0,0 +1,120 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use memmap2::Mmap;
+use std::fs::File;
+
+/// Implements read-only `RandomAccessFile` on a file mapped into memory.
+pub struct MmapFile {
+    map: Mmap,
+}
+
+impl MmapFile {
+    pub fn new(file: &File) -> std::io::Result<MmapFile> {
+        // Safety: the file is opened read-only by us. As with any mapping, it must not be
+        // truncated by another process while it is mapped.
+        let map = unsafe { Mmap::map(file)? };
+        Ok(MmapFile { map })
+    }
+}
+
+impl RandomAccessFile for MmapFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
+        buf.copy_from_slice(&self.map[pos..end]);
+        Ok(())
+    }
+    fn write(&self, _pos: usize, _buf: &[u8]) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn mapped(&self) -> Option<&[u8]> {
+        Some(&self.map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::aes_ctr_file::AesCtrFile;
+    use crate::disk_file::*;
+    use crate::error::*;
+    use crate::file_system::*;
+    use crate::memory_file::MemoryFile;
+    use crate::random_access_file::*;
+    use crate::save_data::*;
+    use crate::sub_file::SubFile;
+    use crate::Resource;
+    use std::sync::Arc;
+
+    #[test]
+    fn mmap_backend() {
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+
+        let mut rng = rand::thread_rng();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("save.bin");
+        let data: Vec<u8> = (&mut rng).sample_iter(&Standard).take(10_000).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let file = open_file(&path, false, FileBackend::Mmap).unwrap();
+        assert!(file.mapped().is_some());
+        let sub = SubFile::new(file.clone(), 100, 1000).unwrap();
+        assert_eq!(sub.mapped(), Some(&data[100..1100]));
+        for _ in 0..100 {
+            let pos = rng.gen_range(0..data.len());
+            let mut buf = vec![0; rng.gen_range(0..data.len() - pos + 1)];
+            file.read(pos, &mut buf).unwrap();
+            assert_eq!(buf[..], data[pos..pos + buf.len()]);
+        }
+        assert!(matches!(file.write(0, &[0]), Err(Error::Unsupported)));
+
+        // AesCtrFile decrypts straight from the mapping
+        let key: [u8; 16] = rng.gen();
+        let ctr: [u8; 16] = rng.gen();
+        let mapped = AesCtrFile::new(file.clone(), key, ctr, false);
+        let copied = AesCtrFile::new(Arc::new(MemoryFile::new(data.clone())), key, ctr, false);
+        let (mut a, mut b) = (vec![0; 5000], vec![0; 5000]);
+        mapped.read(1234, &mut a).unwrap();
+        copied.read(1234, &mut b).unwrap();
+        assert_eq!(a, b);
+        drop((file, sub, mapped));
+
+        // writable files fall back to DiskFile
+        let file = open_file(&path, true, FileBackend::Mmap).unwrap();
+        assert!(file.mapped().is_none());
+        file.write(0, &[1]).unwrap();
+        drop(file);
+
+        // IvfcLevel verifies blocks straight from the mapping
+        let path_str = path.to_str().unwrap();
+        let mut resource =
+            Resource::new(None, None, None, None, None, None, None, None, None, None).unwrap();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        resource
+            .format_bare_save(path_str, &param, 0x10_0000)
+            .unwrap();
+        let save = resource.open_bare_save(path_str, true).unwrap();
+        write_all(&save, "/a", &data).unwrap();
+        save.commit().unwrap();
+        drop(save);
+        resource.set_file_backend(FileBackend::Mmap);
+        let save = resource.open_bare_save(path_str, false).unwrap();
+        assert_eq!(read_to_vec(&save, "/a").unwrap(), data);
+        drop(save);
+    }
+}
@@ -1,38 +1,41 @@
-use crate::disk_file::DiskFile;
+use crate::disk_file::{open_file, replace_file, FileBackend};
 use crate::error::*;
 use crate::random_access_file::*;
 use crate::sd_nand_common::*;
 use std::path::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct Nand {
     path: PathBuf,
+    backend: FileBackend,
 }
 
 impl Nand {
     pub fn new(nand_path: &str) -> Result<Nand, Error> {
         let path = PathBuf::from(nand_path);
-        Ok(Nand { path })
+        Ok(Nand {
+            path,
+            backend: FileBackend::default(),
+        })
+    }
+
+    pub fn set_backend(&mut self, backend: FileBackend) {
+        self.backend = backend;
     }
 }
 
 impl SdNandFileSystem for Nand {
-    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+    fn open(&self, path: &[&str], write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
         let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-
-        let file = DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(file_path)?,
-        )?;
-
-        Ok(Rc::new(file))
+        open_file(&file_path, write, self.backend)
     }
 
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
         let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        std::fs::create_dir_all(file_path.parent().unwrap())?;
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
         let f = std::fs::File::create(file_path)?;
         f.set_len(len as u64)?;
         Ok(())
@@ -51,4 +54,13 @@ impl SdNandFileSystem for Nand {
         }
         Ok(())
     }
+
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        list_dir(&path.iter().fold(self.path.clone(), |a, b| a.join(b)))
+    }
+
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
+        replace_file(&file_path, len, |file| file, fill)
+    }
 }
@@ -0,0 +1,560 @@
+use crate::disk_file::replace_file;
+use crate::error::*;
+use crate::memory_file::MemoryFile;
+use crate::misc::*;
+use crate::random_access_file::*;
+use crate::sd_nand_common::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const OVERLAY_BLOCK_LEN: usize = 0x200;
+
+/// Implements `RandomAccessFile` layer that keeps all writes in memory,
+/// leaving the underlying file untouched.
+///
+/// Written data is recorded in blocks of `OVERLAY_BLOCK_LEN` bytes.
+/// Committing does not flush anything to the underlying file.
+pub struct OverlayFile {
+    base: Arc<dyn RandomAccessFile>,
+    blocks: Mutex<BTreeMap<usize, Vec<u8>>>,
+}
+
+impl OverlayFile {
+    pub fn new(base: Arc<dyn RandomAccessFile>) -> OverlayFile {
+        OverlayFile {
+            base,
+            blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn block_range(&self, i: usize) -> (usize, usize) {
+        let begin = i * OVERLAY_BLOCK_LEN;
+        (
+            begin,
+            std::cmp::min(begin + OVERLAY_BLOCK_LEN, self.base.len()),
+        )
+    }
+
+    /// Returns the ranges, as `(offset, len)`, whose content differs from the underlying file.
+    /// The ranges are aligned to the recording block size.
+    pub fn changed_ranges(&self) -> Result<Vec<(usize, usize)>, Error> {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        let mut base_buf = vec![0; OVERLAY_BLOCK_LEN];
+        for (&i, data) in self.blocks.lock().unwrap().iter() {
+            let (begin, end) = self.block_range(i);
+            self.base.read(begin, &mut base_buf[0..end - begin])?;
+            if base_buf[0..end - begin] == data[..] {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((offset, len)) if *offset + *len == begin => *len += end - begin,
+                _ => ranges.push((begin, end - begin)),
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Writes the whole content to `target`, a piece at a time.
+    pub fn copy_to(&self, target: &dyn RandomAccessFile) -> Result<(), Error> {
+        if target.len() != self.len() {
+            return make_error(Error::SizeMismatch(Contexts::new()));
+        }
+        let mut buf = vec![0; OVERLAY_BLOCK_LEN * 0x80];
+        for pos in (0..self.len()).step_by(buf.len()) {
+            let len = std::cmp::min(buf.len(), self.len() - pos);
+            self.read(pos, &mut buf[0..len])?;
+            target.write(pos, &buf[0..len])?;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole content.
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; self.len()];
+        self.read(0, &mut data)?;
+        Ok(data)
+    }
+}
+
+impl RandomAccessFile for OverlayFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
+        let blocks = self.blocks.lock().unwrap();
+        for i in pos / OVERLAY_BLOCK_LEN..divide_up(end, OVERLAY_BLOCK_LEN) {
+            let (block_begin, block_end) = self.block_range(i);
+            let begin = std::cmp::max(block_begin, pos);
+            let end = std::cmp::min(block_end, end);
+            match blocks.get(&i) {
+                Some(data) => buf[begin - pos..end - pos]
+                    .copy_from_slice(&data[begin - block_begin..end - block_begin]),
+                None => self.base.read(begin, &mut buf[begin - pos..end - pos])?,
+            }
+        }
+        Ok(())
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
+        let mut blocks = self.blocks.lock().unwrap();
+        for i in pos / OVERLAY_BLOCK_LEN..divide_up(end, OVERLAY_BLOCK_LEN) {
+            let (block_begin, block_end) = self.block_range(i);
+            let begin = std::cmp::max(block_begin, pos);
+            let end = std::cmp::min(block_end, end);
+            let data = match blocks.entry(i) {
+                std::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::btree_map::Entry::Vacant(entry) => {
+                    let mut data = vec![0; block_end - block_begin];
+                    self.base.read(block_begin, &mut data)?;
+                    entry.insert(data)
+                }
+            };
+            data[begin - block_begin..end - block_begin]
+                .copy_from_slice(&buf[begin - pos..end - pos]);
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Identifies a file that an overlay keeps changes for.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum OverlayPath {
+    /// A path relative to the `Nintendo 3DS/<ID0>/<ID1>` directory on SD.
+    Sd(Vec<String>),
+    /// A path relative to the NAND root.
+    Nand(Vec<String>),
+    /// A path on the host file system, such as a stand-alone or a cartridge save.
+    Host(PathBuf),
+}
+
+/// What an overlay changed on a file.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum OverlayChange {
+    /// Changed ranges of an existing file, as `(offset, len)`.
+    Modified(Vec<(usize, usize)>),
+    /// The file is created, or its content is replaced as a whole, with the specified length.
+    Created(usize),
+    Removed,
+    /// The directory and everything in it are removed.
+    RemovedDir,
+}
+
+enum OverlayEntry {
+    Modified(Arc<OverlayFile>),
+    Created(Arc<OverlayFile>),
+    Removed,
+    RemovedDir,
+}
+
+/// Changes recorded in memory in place of the files under a `Resource`.
+#[derive(Default)]
+pub(crate) struct Overlay {
+    entries: Mutex<BTreeMap<OverlayPath, OverlayEntry>>,
+}
+
+fn is_under(path: &OverlayPath, dir: &OverlayPath) -> bool {
+    match (path, dir) {
+        (OverlayPath::Sd(path), OverlayPath::Sd(dir))
+        | (OverlayPath::Nand(path), OverlayPath::Nand(dir)) => {
+            path.len() > dir.len() && path.starts_with(dir)
+        }
+        (OverlayPath::Host(path), OverlayPath::Host(dir)) => path != dir && path.starts_with(dir),
+        _ => false,
+    }
+}
+
+impl Overlay {
+    /// Opens `path` through the overlay, calling `open_base` to open the file read-only
+    /// if the overlay has not recorded anything for it yet.
+    pub fn open(
+        &self,
+        path: OverlayPath,
+        open_base: impl FnOnce() -> Result<Arc<dyn RandomAccessFile>, Error>,
+    ) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&path) {
+            Some(OverlayEntry::Modified(file)) | Some(OverlayEntry::Created(file)) => {
+                return Ok(file.clone())
+            }
+            Some(OverlayEntry::Removed) | Some(OverlayEntry::RemovedDir) => {
+                return make_error(Error::NotFound)
+            }
+            None => (),
+        }
+        if entries
+            .iter()
+            .any(|(dir, entry)| matches!(entry, OverlayEntry::RemovedDir) && is_under(&path, dir))
+        {
+            return make_error(Error::NotFound);
+        }
+        let file = Arc::new(OverlayFile::new(open_base()?));
+        entries.insert(path, OverlayEntry::Modified(file.clone()));
+        Ok(file)
+    }
+
+    /// Records `path` as created, or replaced as a whole, with `data`.
+    pub fn create(&self, path: OverlayPath, data: Vec<u8>) {
+        let file = Arc::new(OverlayFile::new(Arc::new(MemoryFile::new(data))));
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path, OverlayEntry::Created(file));
+    }
+
+    /// Records `path` as created with `len` bytes written by `fill`.
+    pub fn create_with(&self, path: OverlayPath, len: usize, fill: Fill) -> Result<(), Error> {
+        let data = Arc::new(MemoryFile::new(vec![0; len]));
+        fill(data.clone())?;
+        let file = Arc::new(OverlayFile::new(data));
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path, OverlayEntry::Created(file));
+        Ok(())
+    }
+
+    /// Records `path` as removed. `in_base` tells whether the underlying file exists,
+    /// otherwise only a file created in the overlay is removed.
+    pub fn remove(&self, path: OverlayPath, in_base: bool) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if in_base {
+            entries.insert(path, OverlayEntry::Removed);
+        } else if let Some(OverlayEntry::Created(_)) = entries.get(&path) {
+            entries.remove(&path);
+        } else {
+            return make_error(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    pub fn remove_dir(&self, path: OverlayPath) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|p, _| !is_under(p, &path));
+        entries.insert(path, OverlayEntry::RemovedDir);
+    }
+
+    /// Lists the names directly under the directory `dir`, starting from `base`, the names
+    /// in the underlying directory, and applying the files created and removed in the overlay.
+    pub fn list(&self, dir: &OverlayPath, mut base: Vec<String>) -> Vec<String> {
+        // Entries are ordered by path, so a directory removal comes before what is created in it
+        for (path, entry) in self.entries.lock().unwrap().iter() {
+            let (path, dir) = match (path, dir) {
+                (OverlayPath::Sd(path), OverlayPath::Sd(dir))
+                | (OverlayPath::Nand(path), OverlayPath::Nand(dir)) => (path, dir),
+                _ => continue,
+            };
+            if path.len() <= dir.len() {
+                if dir.starts_with(path) && matches!(entry, OverlayEntry::RemovedDir) {
+                    base.clear();
+                }
+                continue;
+            }
+            if !path.starts_with(dir) {
+                continue;
+            }
+            let name = &path[dir.len()];
+            match entry {
+                OverlayEntry::Modified(_) | OverlayEntry::Created(_) => base.push(name.clone()),
+                OverlayEntry::Removed | OverlayEntry::RemovedDir => {
+                    if path.len() == dir.len() + 1 {
+                        base.retain(|n| n != name)
+                    }
+                }
+            }
+        }
+        base.sort();
+        base.dedup();
+        base
+    }
+
+    /// Fails with `Error::Busy` if any file opened through the overlay is still in use.
+    pub fn check_unused(&self) -> Result<(), Error> {
+        for entry in self.entries.lock().unwrap().values() {
+            if let OverlayEntry::Modified(file) | OverlayEntry::Created(file) = entry {
+                if Arc::strong_count(file) != 1 {
+                    return make_error(Error::Busy);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn changes(&self) -> Result<Vec<(OverlayPath, OverlayChange)>, Error> {
+        let mut changes = vec![];
+        for (path, entry) in self.entries.lock().unwrap().iter() {
+            let change = match entry {
+                OverlayEntry::Modified(file) => {
+                    let ranges = file.changed_ranges()?;
+                    if ranges.is_empty() {
+                        continue;
+                    }
+                    OverlayChange::Modified(ranges)
+                }
+                OverlayEntry::Created(file) => OverlayChange::Created(file.len()),
+                OverlayEntry::Removed => OverlayChange::Removed,
+                OverlayEntry::RemovedDir => OverlayChange::RemovedDir,
+            };
+            changes.push((path.clone(), change));
+        }
+        Ok(changes)
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Removes and returns the content of the file at `path`.
+    pub fn take(&self, path: &OverlayPath) -> Result<Vec<u8>, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let data = match entries.get(path) {
+            Some(OverlayEntry::Modified(file)) | Some(OverlayEntry::Created(file)) => {
+                file.to_vec()?
+            }
+            _ => return make_error(Error::NotFound),
+        };
+        entries.remove(path);
+        Ok(data)
+    }
+
+    /// Applies all changes to the underlying files.
+    /// Removed directories go first, then removed files, and then written files.
+    ///
+    /// Each written file is staged in a new file that then replaces it, so that an interruption
+    /// leaves it either as it was or with all changes. Changes are removed from the overlay
+    /// as soon as they are applied, so on failure the overlay keeps exactly those not applied yet.
+    pub fn persist(
+        &self,
+        sd: Option<&dyn SdNandFileSystem>,
+        nand: Option<&dyn SdNandFileSystem>,
+    ) -> Result<(), Error> {
+        self.check_unused()?;
+        let mut entries = self.entries.lock().unwrap();
+        for pass in 0..3 {
+            let paths: Vec<OverlayPath> = entries.keys().cloned().collect();
+            for path in paths {
+                let entry = &entries[&path];
+                let entry_pass = match entry {
+                    OverlayEntry::RemovedDir => 0,
+                    OverlayEntry::Removed => 1,
+                    OverlayEntry::Modified(file) if file.changed_ranges()?.is_empty() => {
+                        entries.remove(&path);
+                        continue;
+                    }
+                    OverlayEntry::Modified(_) | OverlayEntry::Created(_) => 2,
+                };
+                if entry_pass != pass {
+                    continue;
+                }
+                let target = match &path {
+                    OverlayPath::Sd(path) => Target::Fs(sd.ok_or(Error::MissingSd)?, path),
+                    OverlayPath::Nand(path) => Target::Fs(nand.ok_or(Error::MissingNand)?, path),
+                    OverlayPath::Host(path) => Target::Host(path),
+                };
+                match (entry, target) {
+                    (OverlayEntry::RemovedDir, Target::Fs(fs, path)) => {
+                        fs.remove_dir(&names(path))?
+                    }
+                    (OverlayEntry::RemovedDir, Target::Host(path)) => {
+                        std::fs::remove_dir_all(path)?
+                    }
+                    (OverlayEntry::Removed, Target::Fs(fs, path)) => fs.remove(&names(path))?,
+                    (OverlayEntry::Removed, Target::Host(path)) => std::fs::remove_file(path)?,
+                    (OverlayEntry::Modified(file), Target::Fs(fs, path))
+                    | (OverlayEntry::Created(file), Target::Fs(fs, path)) => {
+                        fs.replace(&names(path), file.len(), &mut |target| {
+                            file.copy_to(target.as_ref())
+                        })?
+                    }
+                    (OverlayEntry::Modified(file), Target::Host(path))
+                    | (OverlayEntry::Created(file), Target::Host(path)) => {
+                        replace_file(path, file.len(), |file| file, &mut |target| {
+                            file.copy_to(target.as_ref())
+                        })?
+                    }
+                }
+                entries.remove(&path);
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Target<'a> {
+    Fs(&'a dyn SdNandFileSystem, &'a [String]),
+    Host(&'a Path),
+}
+
+fn names(path: &[String]) -> Vec<&str> {
+    path.iter().map(String::as_str).collect()
+}
+
+/// Implements `SdNandFileSystem` on top of another one, recording all changes in an overlay.
+pub(crate) struct OverlaySdNand {
+    pub base: Arc<dyn SdNandFileSystem>,
+    pub overlay: Arc<Overlay>,
+    pub make_path: fn(Vec<String>) -> OverlayPath,
+}
+
+impl OverlaySdNand {
+    fn path(&self, path: &[&str]) -> OverlayPath {
+        (self.make_path)(path.iter().map(|s| s.to_string()).collect())
+    }
+}
+
+impl SdNandFileSystem for OverlaySdNand {
+    fn open(&self, path: &[&str], _write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        self.overlay
+            .open(self.path(path), || self.base.open(path, false))
+    }
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.overlay.create(self.path(path), vec![0; len]);
+        Ok(())
+    }
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        let in_base = self.base.open(path, false).is_ok();
+        self.overlay.remove(self.path(path), in_base)
+    }
+    fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+        self.overlay.remove_dir(self.path(path));
+        Ok(())
+    }
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        Ok(self.overlay.list(&self.path(path), self.base.list(path)?))
+    }
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+        self.overlay.create_with(self.path(path), len, fill)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::overlay::*;
+    use crate::sd_nand_common::test::VirtualFileSystem;
+
+    #[test]
+    fn overlay_file() {
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let len = rng.gen_range(1..10_000);
+            let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            let base = Arc::new(MemoryFile::new(init.clone()));
+            let overlay = OverlayFile::new(base.clone());
+            let plain = MemoryFile::new(init.clone());
+            for _ in 0..100 {
+                let pos = rng.gen_range(0..len);
+                let data_len = rng.gen_range(1..=len - pos);
+                let data: Vec<u8> = (&mut rng).sample_iter(&Standard).take(data_len).collect();
+                overlay.write(pos, &data).unwrap();
+                plain.write(pos, &data).unwrap();
+            }
+            overlay.commit().unwrap();
+            let mut buf = vec![0; len];
+            base.read(0, &mut buf).unwrap();
+            assert_eq!(buf, init);
+            let expected = MemoryFile::from_file(&plain).unwrap();
+            for (offset, len) in overlay.changed_ranges().unwrap() {
+                assert_eq!(offset % OVERLAY_BLOCK_LEN, 0);
+                base.write(offset, &overlay.to_vec().unwrap()[offset..offset + len])
+                    .unwrap();
+            }
+            let mut a = vec![0; len];
+            let mut b = vec![0; len];
+            base.read(0, &mut a).unwrap();
+            expected.read(0, &mut b).unwrap();
+            assert_eq!(a, b);
+            assert_eq!(overlay.to_vec().unwrap(), b);
+            assert_eq!(overlay.changed_ranges().unwrap(), vec![]);
+        }
+    }
+
+    #[test]
+    fn overlay_sd_nand() {
+        let base = Arc::new(VirtualFileSystem::new());
+        base.create(&["a", "x"], 0x300).unwrap();
+        base.create(&["a", "y"], 0x10).unwrap();
+        base.create(&["b"], 0x10).unwrap();
+        let overlay = Arc::new(Overlay::default());
+        let fs = OverlaySdNand {
+            base: base.clone(),
+            overlay: overlay.clone(),
+            make_path: OverlayPath::Sd,
+        };
+        let path = |p: &[&str]| OverlayPath::Sd(p.iter().map(|s| s.to_string()).collect());
+
+        let x = fs.open(&["a", "x"], true).unwrap();
+        x.write(0x250, &[1; 4]).unwrap();
+        assert!(matches!(overlay.check_unused(), Err(Error::Busy)));
+        drop(x);
+        fs.create(&["c"], 4).unwrap();
+        fs.open(&["c"], true).unwrap().write(0, &[2; 4]).unwrap();
+        fs.remove(&["b"]).unwrap();
+        fs.open(&["a", "y"], false).unwrap();
+        assert!(matches!(fs.open(&["d"], false), Err(Error::NotFound)));
+        assert_eq!(fs.list(&[]).unwrap(), vec!["a", "c"]);
+        assert_eq!(fs.list(&["a"]).unwrap(), vec!["x", "y"]);
+        assert_eq!(
+            overlay.changes().unwrap(),
+            vec![
+                (
+                    path(&["a", "x"]),
+                    OverlayChange::Modified(vec![(0x200, 0x100)])
+                ),
+                (path(&["b"]), OverlayChange::Removed),
+                (path(&["c"]), OverlayChange::Created(4)),
+            ]
+        );
+        let mut buf = [0; 4];
+        base.open(&["a", "x"], false)
+            .unwrap()
+            .read(0x250, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0; 4]);
+
+        let nand_path = OverlayPath::Nand(vec!["n".to_owned()]);
+        overlay.create(nand_path.clone(), vec![3; 4]);
+        assert!(matches!(
+            overlay.persist(Some(base.as_ref()), None),
+            Err(Error::MissingNand)
+        ));
+        assert_eq!(
+            overlay.changes().unwrap(),
+            vec![(nand_path.clone(), OverlayChange::Created(4))]
+        );
+        base.open(&["a", "x"], false)
+            .unwrap()
+            .read(0x250, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1; 4]);
+        base.open(&["c"], false).unwrap().read(0, &mut buf).unwrap();
+        assert_eq!(buf, [2; 4]);
+        assert!(matches!(base.open(&["b"], false), Err(Error::NotFound)));
+
+        let nand = VirtualFileSystem::new();
+        overlay.persist(Some(base.as_ref()), Some(&nand)).unwrap();
+        assert_eq!(overlay.changes().unwrap(), vec![]);
+        nand.open(&["n"], false).unwrap().read(0, &mut buf).unwrap();
+        assert_eq!(buf, [3; 4]);
+
+        fs.remove_dir(&["a"]).unwrap();
+        assert!(matches!(fs.open(&["a", "y"], false), Err(Error::NotFound)));
+        fs.create(&["a", "z"], 1).unwrap();
+        assert!(fs.open(&["a", "z"], false).is_ok());
+        assert_eq!(fs.list(&["a"]).unwrap(), vec!["z"]);
+        overlay.clear();
+        assert!(fs.open(&["a", "y"], false).is_ok());
+    }
+}
@@ -12,7 +12,7 @@ use std::borrow::Borrow;
 ///
 /// Many implementations of `RandomAccessFile` act as a "layer": they transforms data
 /// between the interface level and some other `RandomAccessFile`s as the underlying storage.
-pub trait RandomAccessFile {
+pub trait RandomAccessFile: Send + Sync {
     /// Reads bytes at position `pos` into `buf`. The lenth is determined by `buf.len()`.
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error>;
 
@@ -32,6 +32,21 @@ pub trait RandomAccessFile {
     /// to the underlying `RandomAccessFile`. Note that this doesn't recursively
     /// call commit on the underlying file.
     fn commit(&self) -> Result<(), Error>;
+
+    /// Discards all changes made to the file since the last commit,
+    /// so that it reads back the data as of that commit.
+    ///
+    /// Like `commit`, this doesn't recursively call rollback on the underlying file.
+    /// The default implementation does nothing, which fits layers that hold no pending state.
+    fn rollback(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns the whole content if it is mapped in memory, so that layers on top can read it
+    /// without copying or seeking. The default implementation returns `None`.
+    fn mapped(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 /// Helper for reading a `ByteStruct` from a `RandomAccessFile`.
@@ -1,9 +1,13 @@
+use crate::check::*;
 use crate::difi_partition::*;
 use crate::disa::Disa;
 use crate::error::*;
+use crate::extent::*;
 use crate::fat::*;
 use crate::file_system::*;
 use crate::fs_meta::{self, FileInfo, FsInfo, OffsetOrFatFile};
+use crate::inspect::*;
+use crate::ivfc_level::LevelReport;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::save_ext_common::*;
@@ -11,7 +15,7 @@ use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
-use std::rc::Rc;
+use std::sync::{Arc, RwLockWriteGuard};
 
 #[derive(ByteStruct, Clone)]
 #[byte_struct_le]
@@ -93,16 +97,17 @@ struct SaveHeader {
 }
 
 struct SaveDataInner {
-    disa: Rc<Disa>,
-    fat: Rc<Fat>,
-    fs: Rc<FsMeta>,
+    lock: ArchiveLock,
+    disa: Arc<Disa>,
+    fat: Arc<Fat>,
+    fs: Arc<FsMeta>,
     block_len: usize,
     block_count: usize,
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for game save data.
 pub struct SaveData {
-    center: Rc<SaveDataInner>,
+    center: Arc<SaveDataInner>,
 }
 
 #[derive(Clone)]
@@ -279,7 +284,7 @@ impl SaveData {
     }
 
     pub(crate) fn format(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         save_data_type: SaveDataType,
         param: &SaveDataFormatParam,
     ) -> Result<(), Error> {
@@ -295,21 +300,26 @@ impl SaveData {
             info.param_b.as_ref(),
         )?;
 
-        let disa = Rc::new(Disa::new(file, SaveData::get_signer(save_data_type))?);
+        let disa = Arc::new(Disa::new(
+            file,
+            SaveData::get_signer(save_data_type),
+            SelectorInversion::default(),
+            false,
+        )?);
 
-        let dir_hash = Rc::new(SubFile::new(
+        let dir_hash = Arc::new(SubFile::new(
             disa[0].clone(),
             info.dir_hash_offset,
             param.dir_buckets * 4,
         )?);
 
-        let file_hash = Rc::new(SubFile::new(
+        let file_hash = Arc::new(SubFile::new(
             disa[0].clone(),
             info.file_hash_offset,
             param.file_buckets * 4,
         )?);
 
-        let fat_table = Rc::new(SubFile::new(
+        let fat_table = Arc::new(SubFile::new(
             disa[0].clone(),
             info.fat_offset,
             (info.data_block_count + 1) * 8,
@@ -317,10 +327,10 @@ impl SaveData {
 
         Fat::format(fat_table.as_ref())?;
 
-        let data: Rc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
+        let data: Arc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
             disa[1].clone()
         } else {
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 disa[0].clone(),
                 info.data_offset.unwrap(),
                 info.data_block_count * info.block_len,
@@ -331,12 +341,12 @@ impl SaveData {
         let file_table_len = (param.max_file + 1) * (SaveExtKey::BYTE_LEN + SaveFile::BYTE_LEN + 4);
 
         let (dir_table, file_table) = if disa.partition_count() == 2 {
-            let dir_table = Rc::new(SubFile::new(
+            let dir_table = Arc::new(SubFile::new(
                 disa[0].clone(),
                 info.dir_table_offset.unwrap(),
                 dir_table_len,
             )?);
-            let file_table = Rc::new(SubFile::new(
+            let file_table = Arc::new(SubFile::new(
                 disa[0].clone(),
                 info.file_table_offset.unwrap(),
                 file_table_len,
@@ -370,10 +380,10 @@ impl SaveData {
             };
             FsMeta::format(
                 dir_hash,
-                Rc::new(dir_table),
+                Arc::new(dir_table),
                 param.max_dir + 2,
                 file_hash,
-                Rc::new(file_table),
+                Arc::new(file_table),
                 param.max_file + 1,
             )?;
             (dir_table_combo, file_table_combo)
@@ -419,17 +429,28 @@ impl SaveData {
     }
 
     pub(crate) fn new(
-        file: Rc<dyn RandomAccessFile>,
+        file: Arc<dyn RandomAccessFile>,
         save_data_type: SaveDataType,
+        inversion: SelectorInversion,
+        salvage: bool,
     ) -> Result<SaveData, Error> {
-        let disa = Rc::new(Disa::new(file, SaveData::get_signer(save_data_type))?);
+        let disa = Arc::new(Disa::new(
+            file,
+            SaveData::get_signer(save_data_type),
+            inversion,
+            salvage,
+        )?);
+        SaveData::from_disa(disa, salvage)
+    }
+
+    fn from_disa(disa: Arc<Disa>, salvage: bool) -> Result<SaveData, Error> {
         let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
         if header.magic != *b"SAVE" || header.version != 0x40000 {
             error!(
                 "Unexpected SAVE magic {:?} {:X}",
                 header.magic, header.version
             );
-            return make_error(Error::MagicMismatch);
+            return make_error(Error::MagicMismatch(Contexts::new()));
         }
         let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
         if fs_info.data_block_count != fs_info.fat_size {
@@ -437,65 +458,70 @@ impl SaveData {
                 "Unexpected data_block_count={}, fat_size={}",
                 fs_info.data_block_count, fs_info.fat_size
             );
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
 
-        let dir_hash = Rc::new(SubFile::new(
+        let dir_hash = Arc::new(SubFile::new(
             disa[0].clone(),
             fs_info.dir_hash_offset as usize,
             fs_info.dir_buckets as usize * 4,
         )?);
 
-        let file_hash = Rc::new(SubFile::new(
+        let file_hash = Arc::new(SubFile::new(
             disa[0].clone(),
             fs_info.file_hash_offset as usize,
             fs_info.file_buckets as usize * 4,
         )?);
 
-        let fat_table = Rc::new(SubFile::new(
+        let fat_table = Arc::new(SubFile::new(
             disa[0].clone(),
             fs_info.fat_offset as usize,
-            (fs_info.fat_size + 1) as usize * 8,
+            (fs_info.fat_size as usize + 1) * 8,
         )?);
 
-        let data: Rc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
+        let data: Arc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
             disa[1].clone()
         } else {
-            Rc::new(SubFile::new(
+            Arc::new(SubFile::new(
                 disa[0].clone(),
                 fs_info.data_offset as usize,
-                (fs_info.data_block_count * fs_info.block_len) as usize,
+                fs_info.data_block_count as usize * fs_info.block_len as usize,
             )?)
         };
 
         let fat = Fat::new(fat_table, data, fs_info.block_len as usize)?;
 
-        let dir_table: Rc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
-            Rc::new(SubFile::new(
+        let dir_table: Arc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
+            Arc::new(SubFile::new(
                 disa[0].clone(),
                 fs_info.dir_table.to_offset() as usize,
-                (fs_info.max_dir + 2) as usize * (SaveExtKey::BYTE_LEN + SaveExtDir::BYTE_LEN + 4),
+                (fs_info.max_dir as usize + 2) * (SaveExtKey::BYTE_LEN + SaveExtDir::BYTE_LEN + 4),
             )?)
         } else {
             let block = fs_info.dir_table.block_index as usize;
-            Rc::new(FatFile::open(fat.clone(), block)?)
+            Arc::new(FatFile::open(fat.clone(), block)?)
         };
 
-        let file_table: Rc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
-            Rc::new(SubFile::new(
+        let file_table: Arc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
+            Arc::new(SubFile::new(
                 disa[0].clone(),
                 fs_info.file_table.to_offset() as usize,
-                (fs_info.max_file + 1) as usize * (SaveExtKey::BYTE_LEN + SaveFile::BYTE_LEN + 4),
+                (fs_info.max_file as usize + 1) * (SaveExtKey::BYTE_LEN + SaveFile::BYTE_LEN + 4),
             )?)
         } else {
             let block = fs_info.file_table.block_index as usize;
-            Rc::new(FatFile::open(fat.clone(), block)?)
+            Arc::new(FatFile::open(fat.clone(), block)?)
         };
 
-        let fs = FsMeta::new(dir_hash, dir_table, file_hash, file_table)?;
+        let fs = if salvage {
+            FsMeta::new_salvage(dir_hash, dir_table, file_hash, file_table)?
+        } else {
+            FsMeta::new(dir_hash, dir_table, file_hash, file_table)?
+        };
 
         Ok(SaveData {
-            center: Rc::new(SaveDataInner {
+            center: Arc::new(SaveDataInner {
+                lock: ArchiveLock::default(),
                 disa,
                 fat,
                 fs,
@@ -504,31 +530,455 @@ impl SaveData {
             }),
         })
     }
+
+    /// Returns the parameters this save data was formatted with.
+    pub fn format_param(&self) -> Result<SaveDataFormatParam, Error> {
+        let disa = &self.center.disa;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        let block_type = match fs_info.block_len {
+            512 => SaveDataBlockType::Small,
+            4096 => SaveDataBlockType::Large,
+            _ => {
+                error!("Unexpected block_len {}", fs_info.block_len);
+                return make_error(Error::InvalidValue(Contexts::new()));
+            }
+        };
+        Ok(SaveDataFormatParam {
+            block_type,
+            max_dir: fs_info.max_dir as usize,
+            dir_buckets: fs_info.dir_buckets as usize,
+            max_file: fs_info.max_file as usize,
+            file_buckets: fs_info.file_buckets as usize,
+            duplicate_data: disa.partition_count() == 1,
+        })
+    }
+
+    /// Returns the length of the underlying DISA image.
+    pub fn image_len(&self) -> usize {
+        self.center.disa.parent_len()
+    }
+
+    /// Compares this save data with `other`, which is the same image opened with
+    /// different `SelectorInversion`. Returns one report for each DISA partition.
+    pub fn compare_generation(&self, other: &SaveData) -> Result<Vec<GenerationReport>, Error> {
+        let (a, b) = (&self.center.disa, &other.center.disa);
+        if a.partition_count() != b.partition_count() {
+            return make_error(Error::SizeMismatch(Contexts::new()));
+        }
+        (0..a.partition_count())
+            .map(|i| a[i].compare_generation(&b[i]))
+            .collect()
+    }
+
+    fn file_inos(&self) -> Result<Vec<u32>, Error> {
+        DirMeta::open_ino(self.center.fs.clone(), 1)?.list_files_recursive()
+    }
+
+    /// Lists the blocks allocated to each file, merged into runs of consecutive blocks,
+    /// ordered by inode.
+    pub fn file_extents(&self) -> Result<Vec<FileExtents>, Error> {
+        let center = &self.center;
+        let disa = &center.disa;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        let (partition, data_offset) = if disa.partition_count() == 2 {
+            (1, 0)
+        } else {
+            (0, fs_info.data_offset as usize)
+        };
+        let mut files = vec![];
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            files.push((ino, info.block, info.size));
+        }
+        file_extents(
+            &center.fat,
+            center.block_len,
+            partition,
+            data_offset,
+            &files,
+        )
+    }
+
+    /// Reports how fragmented the files and the free space are.
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        fragmentation(&self.center.fat, &self.file_extents()?)
+    }
+
+    /// Rearranges the allocation table so that each file and metadata table occupies consecutive
+    /// blocks, updates the metadata pointing to them, and commits.
+    /// The result is checked afterwards, and the reopened save data is returned.
+    /// File data never written is moved as it reads, so it may read as filler afterwards
+    /// instead of failing hash verification.
+    ///
+    /// All files and directories opened from this save data must be closed,
+    /// otherwise `Error::Busy` is returned. A save data with structural issues reported by
+    /// [`check`](#method.check) is refused with `Error::BrokenFat` and left untouched.
+    pub fn defragment(self) -> Result<SaveData, Error> {
+        let disa = self.center.disa.clone();
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info_offset = header.fs_info_offset as usize;
+        let mut fs_info: FsInfo = read_struct(disa[0].as_ref(), fs_info_offset)?;
+        if !self.check_fs(&fs_info, &mut [vec![], vec![]])?.is_empty() {
+            return make_error(Error::BrokenFat(Contexts::new()));
+        }
+
+        let mut files = vec![];
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(self.center.fs.clone(), ino)?.get_info()?;
+            if info.block != EMPTY_FILE_BLOCK {
+                files.push((ino, info.block as usize));
+            }
+        }
+        files.sort_unstable();
+        let mut first_blocks = if disa.partition_count() == 2 {
+            vec![]
+        } else {
+            vec![
+                fs_info.dir_table.block_index as usize,
+                fs_info.file_table.block_index as usize,
+            ]
+        };
+        let table_count = first_blocks.len();
+        first_blocks.extend(files.iter().map(|&(_, block)| block));
+
+        let center = match Arc::try_unwrap(self.center) {
+            Ok(center) => center,
+            Err(_) => return make_error(Error::Busy),
+        };
+        center.fat.defragment(&first_blocks, |new_first_blocks| {
+            let (tables, new_first_blocks) = new_first_blocks.split_at(table_count);
+            for (&(ino, _), &block) in files.iter().zip(new_first_blocks) {
+                let meta = FileMeta::open_ino(center.fs.clone(), ino)?;
+                let mut info = meta.get_info()?;
+                info.block = block as u32;
+                meta.set_info(info)?;
+            }
+            if table_count != 0 {
+                fs_info.dir_table.block_index = tables[0] as u32;
+                fs_info.file_table.block_index = tables[1] as u32;
+                write_struct(disa[0].as_ref(), fs_info_offset, fs_info)?;
+            }
+            Ok(())
+        })?;
+        drop(center);
+
+        // Everything opened before refers to the old block locations.
+        let save = SaveData::from_disa(disa.clone(), false)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), fs_info_offset)?;
+        if !save.check_fs(&fs_info, &mut [vec![], vec![]])?.is_empty() {
+            error!("Defragmented save data is inconsistent");
+            return make_error(Error::BrokenFat(Contexts::new()));
+        }
+        disa.commit()?;
+        let issues = save.check()?;
+        if !issues.is_empty() {
+            error!("Defragmented save data failed checking: {:?}", issues);
+            return make_error(Error::BrokenFat(Contexts::new()));
+        }
+        Ok(save)
+    }
+
+    /// Reports the broken blocks found when this save data was opened in salvage mode,
+    /// together with the owners of their data.
+    pub fn salvage_report(&self) -> Result<Vec<BrokenBlock>, Error> {
+        let center = &self.center;
+        let disa = &center.disa;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+
+        let mut block_owner = vec![BlockOwner::Free; center.block_count];
+        let mut claim = |first_block: u32, owner| -> Result<(), Error> {
+            for block in FatFile::open(center.fat.clone(), first_block as usize)?.block_indices() {
+                block_owner[block] = owner;
+            }
+            Ok(())
+        };
+        if disa.partition_count() == 1 {
+            claim(fs_info.dir_table.block_index, BlockOwner::Metadata)?;
+            claim(fs_info.file_table.block_index, BlockOwner::Metadata)?;
+        }
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            if info.block != EMPTY_FILE_BLOCK {
+                claim(info.block, BlockOwner::File(ino))?;
+            }
+        }
+
+        let (data_partition, data_begin) = if disa.partition_count() == 2 {
+            (1, 0)
+        } else {
+            (0, fs_info.data_offset as usize)
+        };
+        let data_end = data_begin + center.block_count * center.block_len;
+
+        let mut report = vec![];
+        for &(partition, level, index) in disa.salvaged_blocks() {
+            let mut owners = vec![];
+            if level != 4 {
+                owners.push(BlockOwner::Hash);
+            } else if partition != data_partition {
+                owners.push(BlockOwner::Metadata);
+            } else {
+                let block_len = disa[partition].data_block_len();
+                let begin = index * block_len;
+                let end = std::cmp::min(begin + block_len, disa[partition].len());
+                if begin < data_begin || end > data_end {
+                    owners.push(BlockOwner::Metadata);
+                }
+                let begin = std::cmp::max(begin, data_begin);
+                let end = std::cmp::min(end, data_end);
+                if begin < end {
+                    let first = (begin - data_begin) / center.block_len;
+                    let last = divide_up(end - data_begin, center.block_len);
+                    for &owner in block_owner[first..last].iter() {
+                        if !owners.contains(&owner) {
+                            owners.push(owner);
+                        }
+                    }
+                }
+            }
+            report.push(BrokenBlock {
+                partition,
+                level,
+                index,
+                owners,
+            });
+        }
+        Ok(report)
+    }
+
+    // Lists the ranges in use of each partition,
+    // along with the result of checking the metadata and the allocation table.
+    #[allow(clippy::type_complexity)]
+    fn used_ranges(
+        &self,
+    ) -> Result<(Vec<Vec<(usize, usize)>>, Result<Vec<CheckIssue>, Error>), Error> {
+        let disa = &self.center.disa;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        let mut ranges = vec![
+            vec![
+                (0, SaveHeader::BYTE_LEN),
+                (header.fs_info_offset as usize, FsInfo::BYTE_LEN),
+                (
+                    fs_info.dir_hash_offset as usize,
+                    fs_info.dir_buckets as usize * 4,
+                ),
+                (
+                    fs_info.file_hash_offset as usize,
+                    fs_info.file_buckets as usize * 4,
+                ),
+            ],
+            vec![],
+        ];
+        let fs_issues = self.check_fs(&fs_info, &mut ranges);
+        Ok((ranges, fs_issues))
+    }
+
+    // Checks the metadata and the allocation table,
+    // and adds the ranges in use of each partition to `ranges`.
+    fn check_fs(
+        &self,
+        fs_info: &FsInfo,
+        ranges: &mut [Vec<(usize, usize)>],
+    ) -> Result<Vec<CheckIssue>, Error> {
+        let center = &self.center;
+        let mut issues = vec![];
+        let mut files = vec![];
+        for ino in center.fs.check(&mut issues)? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            files.push((ino, info.block, info.size));
+        }
+
+        let (dir_len, file_len) = center.fs.used_table_len()?;
+        let (tables, data_partition, data_offset) = if center.disa.partition_count() == 2 {
+            ranges[0].push((fs_info.dir_table.to_offset() as usize, dir_len));
+            ranges[0].push((fs_info.file_table.to_offset() as usize, file_len));
+            (vec![], 1, 0)
+        } else {
+            let tables = vec![
+                (fs_info.dir_table.block_index, dir_len),
+                (fs_info.file_table.block_index, file_len),
+            ];
+            (tables, 0, fs_info.data_offset as usize)
+        };
+        let used = tables.iter().cloned().chain(
+            files
+                .iter()
+                .filter(|&&(_, block, _)| block != EMPTY_FILE_BLOCK)
+                .map(|&(_, block, size)| (block, size as usize)),
+        );
+        for (block, len) in used {
+            fat_file_ranges(
+                &center.fat,
+                center.block_len,
+                data_offset,
+                block,
+                len,
+                &mut ranges[data_partition],
+            )?;
+        }
+
+        let tables: Vec<u32> = tables.iter().map(|&(block, _)| block).collect();
+        issues.append(&mut check_fat_files(
+            &center.fat,
+            center.block_len,
+            fs_info.data_block_count as usize,
+            &tables,
+            &files,
+            fs_info.fat_offset as usize,
+            &mut ranges[0],
+        )?);
+        Ok(issues)
+    }
+
+    /// Hashes every block of the partition table and of all IVFC levels of each partition,
+    /// spread over all CPU cores, and reports the broken blocks of each level.
+    /// If the metadata is broken, all broken blocks are reported as in use.
+    pub fn verify_all(&self) -> Result<Vec<LevelReport>, Error> {
+        let _lock = self.center.lock.read();
+        let used = match self.used_ranges() {
+            Ok((ranges, Ok(_))) => Some(ranges),
+            Ok((_, Err(Error::HashMismatch(_)))) | Err(Error::HashMismatch(_)) => None,
+            Ok((_, Err(e))) | Err(e) => return Err(e),
+        };
+        self.center.disa.verify_all(used.as_deref())
+    }
+
+    /// Describes the container headers, the DPFS selectors and the file system layout
+    /// of this save data.
+    pub fn inspect(&self) -> Result<InspectNode, Error> {
+        let center = &self.center;
+        let disa = &center.disa;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        let table_lens = if disa.partition_count() == 2 {
+            Some([
+                (fs_info.max_dir as usize + 2) * (SaveExtKey::BYTE_LEN + SaveExtDir::BYTE_LEN + 4),
+                (fs_info.max_file as usize + 1) * (SaveExtKey::BYTE_LEN + SaveFile::BYTE_LEN + 4),
+            ])
+        } else {
+            None
+        };
+        let mut files = vec![];
+        for ino in self.file_inos()? {
+            let info = FileMeta::open_ino(center.fs.clone(), ino)?.get_info()?;
+            files.push((ino, info.block as usize));
+        }
+        let (mut meta, data) = inspect_fs(
+            &fs_info,
+            header.fs_info_offset as usize,
+            &center.fat,
+            table_lens,
+            &files,
+        )?;
+        meta.insert(
+            0,
+            InspectNode::new("SAVE header")
+                .at(0, SaveHeader::BYTE_LEN)
+                .hex("version", header.version)
+                .hex("image_size", header.image_size)
+                .hex("image_block_len", header.image_block_len),
+        );
+        let data = if disa.partition_count() == 2 {
+            let len = disa[1].len();
+            vec![meta, vec![data.at(0, len)]]
+        } else {
+            meta.push(data);
+            vec![meta]
+        };
+        disa.inspect(data)
+    }
+
+    /// Recalculates all hashes and the signature, and commits them.
+    /// Used after opening in salvage mode to make the save data valid again
+    /// with whatever data survived.
+    pub fn rehash(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        self.rehash_locked()
+    }
+
+    // Takes the write lock, so that a wrapping archive can commit its own layers
+    // together with this save data. The `*_locked` methods expect it to be held.
+    pub(crate) fn lock_write(&self) -> RwLockWriteGuard<'_, ()> {
+        self.center.lock.write()
+    }
+
+    pub(crate) fn rehash_locked(&self) -> Result<(), Error> {
+        self.center.disa.rehash();
+        self.center.disa.commit()
+    }
+
+    pub(crate) fn commit_locked(&self) -> Result<(), Error> {
+        self.center.disa.commit()
+    }
+
+    pub(crate) fn begin_locked(&self) -> Result<(), Error> {
+        self.center.disa.begin()
+    }
+
+    pub(crate) fn rollback_locked(&self) -> Result<(), Error> {
+        if Arc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        self.center.disa.rollback()?;
+        self.center.fat.rollback()
+    }
+
+    /// Formats `file` as a new save data with `param`,
+    /// and copies all directories and files of this save data into it.
+    /// Files failing hash verification are copied as read, and counted in the report.
+    pub(crate) fn repack(
+        &self,
+        file: Arc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+        param: &SaveDataFormatParam,
+    ) -> Result<CopyReport, Error> {
+        SaveData::format(file.clone(), save_data_type.clone(), param)?;
+        let save = SaveData::new(file, save_data_type, SelectorInversion::default(), false)?;
+        let report = copy_tree(
+            self,
+            &self.open_root()?,
+            &save,
+            &save.open_root()?,
+            CopyMode::Merge,
+            |name| Ok(*name),
+        )?;
+        save.commit()?;
+        Ok(report)
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for save data file.
 pub struct File {
-    center: Rc<SaveDataInner>,
+    center: Arc<SaveDataInner>,
     meta: FileMeta,
     data: Option<FatFile>,
     len: usize,
 }
 
 impl File {
-    fn from_meta(center: Rc<SaveDataInner>, meta: FileMeta) -> Result<File, Error> {
+    /// Returns whether `dir` belongs to the same opened archive as this file.
+    pub fn same_archive(&self, dir: &Dir) -> bool {
+        Arc::ptr_eq(&self.center, &dir.center)
+    }
+
+    fn from_meta(center: Arc<SaveDataInner>, meta: FileMeta) -> Result<File, Error> {
         let info = meta.get_info()?;
         let len = info.size as usize;
-        let data = if info.block == 0x8000_0000 {
+        let data = if info.block == EMPTY_FILE_BLOCK {
             if len != 0 {
                 error!("Non-empty file with invalid pointer");
-                return make_error(Error::SizeMismatch);
+                return make_error(Error::SizeMismatch(Contexts::new()));
             }
             None
         } else {
             let fat_file = FatFile::open(center.fat.clone(), info.block as usize)?;
             if len == 0 || len > fat_file.len() {
                 error!("Empty file with valid pointer");
-                return make_error(Error::SizeMismatch);
+                return make_error(Error::SizeMismatch(Contexts::new()));
             }
             Some(fat_file)
         };
@@ -546,6 +996,7 @@ impl FileSystemFile for File {
     type DirType = Dir;
 
     fn rename(&mut self, parent: &Self::DirType, name: [u8; 16]) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if parent.meta.open_sub_file(name).is_ok() || parent.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -553,6 +1004,7 @@ impl FileSystemFile for File {
     }
 
     fn get_parent_ino(&self) -> Result<u32, Error> {
+        let _lock = self.center.lock.read();
         self.meta.get_parent_ino()
     }
 
@@ -561,6 +1013,8 @@ impl FileSystemFile for File {
     }
 
     fn delete(self) -> Result<(), Error> {
+        let center = self.center.clone();
+        let _lock = center.lock.write();
         if let Some(f) = self.data {
             f.delete()?;
         }
@@ -568,6 +1022,7 @@ impl FileSystemFile for File {
     }
 
     fn resize(&mut self, len: usize) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if len == self.len {
             return Ok(());
         }
@@ -587,7 +1042,7 @@ impl FileSystemFile for File {
         } else if len == 0 {
             // non-zero => zero
             self.data.take().unwrap().delete()?;
-            info.block = 0x8000_0000;
+            info.block = EMPTY_FILE_BLOCK;
         } else {
             self.data
                 .as_mut()
@@ -604,21 +1059,23 @@ impl FileSystemFile for File {
     }
 
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let _lock = self.center.lock.read();
         if buf.is_empty() {
             return Ok(());
         }
-        if pos + buf.len() > self.len {
-            return make_error(Error::OutOfBound);
+        if pos.checked_add(buf.len()).is_none_or(|end| end > self.len) {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         self.data.as_ref().unwrap().read(pos, buf)
     }
 
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if buf.is_empty() {
             return Ok(());
         }
-        if pos + buf.len() > self.len {
-            return make_error(Error::OutOfBound);
+        if pos.checked_add(buf.len()).is_none_or(|end| end > self.len) {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         self.data.as_ref().unwrap().write(pos, buf)
     }
@@ -635,15 +1092,23 @@ impl FileSystemFile for File {
 
 /// Implements [`FileSystemDir`](../file_system/trait.FileSystemDir.html) for save data directory.
 pub struct Dir {
-    center: Rc<SaveDataInner>,
+    center: Arc<SaveDataInner>,
     meta: DirMeta,
 }
 
+impl Dir {
+    /// Returns whether `other` belongs to the same opened archive as this directory.
+    pub fn same_archive(&self, other: &Dir) -> bool {
+        Arc::ptr_eq(&self.center, &other.center)
+    }
+}
+
 impl FileSystemDir for Dir {
     type NameType = [u8; 16];
     type FileType = File;
 
     fn rename(&mut self, parent: &Self, name: [u8; 16]) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
         if parent.meta.open_sub_file(name).is_ok() || parent.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -651,6 +1116,7 @@ impl FileSystemDir for Dir {
     }
 
     fn get_parent_ino(&self) -> Result<u32, Error> {
+        let _lock = self.center.lock.read();
         self.meta.get_parent_ino()
     }
 
@@ -659,6 +1125,7 @@ impl FileSystemDir for Dir {
     }
 
     fn open_sub_dir(&self, name: [u8; 16]) -> Result<Self, Error> {
+        let _lock = self.center.lock.read();
         Ok(Dir {
             center: self.center.clone(),
             meta: self.meta.open_sub_dir(name)?,
@@ -666,18 +1133,22 @@ impl FileSystemDir for Dir {
     }
 
     fn open_sub_file(&self, name: [u8; 16]) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.read();
         File::from_meta(self.center.clone(), self.meta.open_sub_file(name)?)
     }
 
     fn list_sub_dir(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
+        let _lock = self.center.lock.read();
         self.meta.list_sub_dir()
     }
 
     fn list_sub_file(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
+        let _lock = self.center.lock.read();
         self.meta.list_sub_file()
     }
 
     fn new_sub_dir(&self, name: [u8; 16]) -> Result<Self, Error> {
+        let _lock = self.center.lock.write();
         if self.meta.open_sub_file(name).is_ok() || self.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
@@ -694,11 +1165,12 @@ impl FileSystemDir for Dir {
     }
 
     fn new_sub_file(&self, name: [u8; 16], len: usize) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.write();
         if self.meta.open_sub_file(name).is_ok() || self.meta.open_sub_dir(name).is_ok() {
             return make_error(Error::AlreadyExist);
         }
         let (fat_file, block) = if len == 0 {
-            (None, 0x8000_0000)
+            (None, EMPTY_FILE_BLOCK)
         } else {
             let (fat_file, block) = FatFile::create(
                 self.center.fat.clone(),
@@ -727,10 +1199,28 @@ impl FileSystemDir for Dir {
     }
 
     fn delete(self) -> Result<(), Error> {
+        let center = self.center.clone();
+        let _lock = center.lock.write();
         self.meta.delete()
     }
 }
 
+impl Check for SaveData {
+    fn check_parts(&self) -> Result<CheckParts, Error> {
+        let disa = &self.center.disa;
+        let (ranges, structure) = self.used_ranges()?;
+        Ok(CheckParts {
+            hashed: ranges
+                .into_iter()
+                .enumerate()
+                .take(disa.partition_count())
+                .map(|(i, ranges)| (i, disa[i].clone(), ranges))
+                .collect(),
+            structure: structure.map(|issues| (issues, vec![])),
+        })
+    }
+}
+
 impl FileSystem for SaveData {
     type FileType = File;
     type DirType = Dir;
@@ -741,11 +1231,13 @@ impl FileSystem for SaveData {
     type NameType = [u8; 16];
 
     fn open_file(&self, ino: u32) -> Result<Self::FileType, Error> {
+        let _lock = self.center.lock.read();
         let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
         File::from_meta(self.center.clone(), meta)
     }
 
     fn open_dir(&self, ino: u32) -> Result<Self::DirType, Error> {
+        let _lock = self.center.lock.read();
         let meta = DirMeta::open_ino(self.center.fs.clone(), ino)?;
         Ok(Dir {
             center: self.center.clone(),
@@ -762,11 +1254,26 @@ impl FileSystem for SaveData {
     /// roll back to the state the last time `commit` is called. Changes to file data are dropped and the
     /// affected region becomes uninitialized.
     ///  - `duplicate_data == true`: all data rolls back to the state the last time `commit` is called.
+    ///
+    /// Within a transaction started by `begin`, `rollback` restores all data regardless.
     fn commit(&self) -> Result<(), Error> {
-        self.center.disa.commit()
+        let _lock = self.center.lock.write();
+        self.commit_locked()
+    }
+
+    fn begin(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        self.begin_locked()
+    }
+
+    /// Fails with `Error::Busy` if any file or directory is still open.
+    fn rollback(&self) -> Result<(), Error> {
+        let _lock = self.center.lock.write();
+        self.rollback_locked()
     }
 
     fn stat(&self) -> Result<Stat, Error> {
+        let _lock = self.center.lock.read();
         let meta_stat = self.center.fs.stat()?;
         Ok(Stat {
             block_len: self.center.block_len,
@@ -828,18 +1335,1490 @@ mod test {
             };
 
             let disa_len = rng.gen_range(100_000..1_000_000);
-            let disa_raw = Rc::new(MemoryFile::new(vec![0; disa_len]));
+            let disa_raw = Arc::new(MemoryFile::new(vec![0; disa_len]));
             SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
-            let file_system = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            let file_system = SaveData::new(
+                disa_raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
 
             crate::file_system::test::fuzzer(
                 file_system,
                 param.max_dir,
                 param.max_file,
-                || SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap(),
+                || {
+                    SaveData::new(
+                        disa_raw.clone(),
+                        SaveDataType::Bare,
+                        SelectorInversion::default(),
+                        false,
+                    )
+                    .unwrap()
+                },
                 gen_name,
                 gen_len,
             );
         }
     }
+
+    #[test]
+    fn format_param_round_trip() {
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10 {
+            let param = SaveDataFormatParam {
+                block_type: match rng.gen_range(0..2) {
+                    0 => SaveDataBlockType::Small,
+                    1 => SaveDataBlockType::Large,
+                    _ => unreachable!(),
+                },
+                max_dir: rng.gen_range(10..100),
+                dir_buckets: rng.gen_range(10..100),
+                max_file: rng.gen_range(10..100),
+                file_buckets: rng.gen_range(10..100),
+                duplicate_data: rng.gen(),
+            };
+
+            let disa_len = rng.gen_range(100_000..1_000_000);
+            let disa_raw = Arc::new(MemoryFile::new(vec![0; disa_len]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(
+                disa_raw,
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(save.format_param().unwrap(), param);
+            assert_eq!(save.image_len(), disa_len);
+        }
+    }
+
+    fn assert_same_dir(a: &Dir, b: &Dir) {
+        let mut a_files = a.list_sub_file().unwrap();
+        let mut b_files = b.list_sub_file().unwrap();
+        a_files.sort();
+        b_files.sort();
+        assert_eq!(a_files.len(), b_files.len());
+        for ((a_name, _), (b_name, _)) in a_files.into_iter().zip(b_files) {
+            assert_eq!(a_name, b_name);
+            let a_file = a.open_sub_file(a_name).unwrap();
+            let b_file = b.open_sub_file(b_name).unwrap();
+            assert_eq!(a_file.len(), b_file.len());
+            let mut a_buf = vec![0; a_file.len()];
+            let mut b_buf = vec![0; b_file.len()];
+            a_file.read(0, &mut a_buf).unwrap();
+            b_file.read(0, &mut b_buf).unwrap();
+            assert_eq!(a_buf, b_buf);
+        }
+
+        let mut a_dirs = a.list_sub_dir().unwrap();
+        let mut b_dirs = b.list_sub_dir().unwrap();
+        a_dirs.sort();
+        b_dirs.sort();
+        assert_eq!(a_dirs.len(), b_dirs.len());
+        for ((a_name, _), (b_name, _)) in a_dirs.into_iter().zip(b_dirs) {
+            assert_eq!(a_name, b_name);
+            assert_same_dir(
+                &a.open_sub_dir(a_name).unwrap(),
+                &b.open_sub_dir(b_name).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn repack() {
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data: !duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false)
+                .unwrap();
+
+            let root = save.open_root().unwrap();
+            let dir = root.new_sub_dir([1; 16]).unwrap();
+            for i in 0..5 {
+                let len = rng.gen_range(0..5000);
+                let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                let file = [&root, &dir][i % 2]
+                    .new_sub_file([i as u8 + 2; 16], len)
+                    .unwrap();
+                file.write(0, &data).unwrap();
+                file.commit().unwrap();
+            }
+            save.commit().unwrap();
+
+            let new_param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Large,
+                max_dir: 20,
+                dir_buckets: 7,
+                max_file: 30,
+                file_buckets: 13,
+                duplicate_data,
+            };
+            let new_raw = Arc::new(MemoryFile::new(vec![0; 0x100_000]));
+            let report = save
+                .repack(new_raw.clone(), SaveDataType::Bare, &new_param)
+                .unwrap();
+            assert_eq!(report.unverified_files, 0);
+            let new_save = SaveData::new(
+                new_raw,
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(new_save.format_param().unwrap(), new_param);
+            assert_eq!(new_save.image_len(), 0x100_000);
+            assert_same_dir(&root, &new_save.open_root().unwrap());
+        }
+    }
+
+    #[test]
+    fn repack_unwritten() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false)
+                .unwrap();
+            let root = save.open_root().unwrap();
+            let file = root.new_sub_file([2; 16], 3000).unwrap();
+            file.write(0, &[2; 3000]).unwrap();
+            drop(file);
+            root.new_sub_file([1; 16], 5000).unwrap();
+            save.commit().unwrap();
+
+            let new_raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            let report = save
+                .repack(new_raw.clone(), SaveDataType::Bare, &param)
+                .unwrap();
+            assert_eq!(report.unverified_files, 1);
+            assert_eq!(report.files_written, 2);
+            let new_save = SaveData::new(
+                new_raw,
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            let new_root = new_save.open_root().unwrap();
+            assert_eq!(new_root.open_sub_file([1; 16]).unwrap().len(), 5000);
+            let mut buf = vec![0; 3000];
+            new_root
+                .open_sub_file([2; 16])
+                .unwrap()
+                .read(0, &mut buf)
+                .unwrap();
+            assert_eq!(buf, vec![2; 3000]);
+        }
+    }
+
+    #[test]
+    fn repack_sd() {
+        use crate::sd::Sd;
+        use crate::sd_nand_common::SdNandFileSystem;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        let key_x = [1; 16];
+        let key_y = [2; 16];
+        let key_sign = [3; 16];
+        let id = 0x0004_0000_0012_3400;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(
+            dir.path()
+                .join("Nintendo 3DS")
+                .join(hash_movable(key_y))
+                .join("id1"),
+        )
+        .unwrap();
+        let sd = Sd::new(dir.path().to_str().unwrap(), key_x, key_y).unwrap();
+        let path = ["title", "00040000", "00123400", "data", "00000001.sav"];
+
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        sd.create(&path, 0x80_000).unwrap();
+        SaveData::format(
+            sd.open(&path, true).unwrap(),
+            SaveDataType::Sd(key_sign, id),
+            &param,
+        )
+        .unwrap();
+        let save = SaveData::new(
+            sd.open(&path, true).unwrap(),
+            SaveDataType::Sd(key_sign, id),
+            SelectorInversion::default(),
+            false,
+        )
+        .unwrap();
+        let root = save.open_root().unwrap();
+        for i in 0..5 {
+            let len = rng.gen_range(0..5000);
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let file = root.new_sub_file([i as u8 + 2; 16], len).unwrap();
+            file.write(0, &data).unwrap();
+            file.commit().unwrap();
+        }
+        save.commit().unwrap();
+        drop(root);
+        drop(save);
+
+        // Keeps a decrypted copy of the original to compare against
+        let original = sd.open(&path, false).unwrap();
+        let mut copy = vec![0; original.len()];
+        original.read(0, &mut copy).unwrap();
+        drop(original);
+        let expected = SaveData::new(
+            Arc::new(MemoryFile::new(copy)),
+            SaveDataType::Sd(key_sign, id),
+            SelectorInversion::default(),
+            false,
+        )
+        .unwrap();
+
+        let new_param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Large,
+            max_dir: 20,
+            dir_buckets: 7,
+            max_file: 30,
+            file_buckets: 13,
+            duplicate_data: false,
+        };
+        let mut save = Some(
+            SaveData::new(
+                sd.open(&path, false).unwrap(),
+                SaveDataType::Sd(key_sign, id),
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap(),
+        );
+        sd.replace(&path, 0x100_000, &mut |image| {
+            let save = save.take().unwrap();
+            save.repack(image, SaveDataType::Sd(key_sign, id), &new_param)?;
+            Ok(())
+        })
+        .unwrap();
+
+        let new_save = SaveData::new(
+            sd.open(&path, false).unwrap(),
+            SaveDataType::Sd(key_sign, id),
+            SelectorInversion::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(new_save.format_param().unwrap(), new_param);
+        assert_eq!(new_save.image_len(), 0x100_000);
+        assert_same_dir(
+            &expected.open_root().unwrap(),
+            &new_save.open_root().unwrap(),
+        );
+    }
+
+    #[test]
+    fn salvage() {
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let open = |salvage| {
+                SaveData::new(
+                    raw.clone(),
+                    SaveDataType::Bare,
+                    SelectorInversion::default(),
+                    salvage,
+                )
+                .unwrap()
+            };
+
+            let save = open(false);
+            let data: Vec<u8> = (0..3000).map(|_| rng.gen()).collect();
+            let file = save
+                .open_root()
+                .unwrap()
+                .new_sub_file([1; 16], data.len())
+                .unwrap();
+            file.write(0, &data).unwrap();
+            file.commit().unwrap();
+            save.commit().unwrap();
+            let ino = file.get_ino();
+            drop(file);
+            drop(save);
+
+            // Damage every copy of a byte in the file content
+            let mut image = vec![0; raw.len()];
+            raw.read(0, &mut image).unwrap();
+            let needle = &data[1000..1064];
+            let mut found = false;
+            for (pos, window) in image.windows(needle.len()).enumerate() {
+                if window == needle {
+                    raw.write(pos, &[!data[1000]]).unwrap();
+                    found = true;
+                }
+            }
+            assert!(found);
+            let mut damaged = data;
+            damaged[1000] = !damaged[1000];
+
+            let mut buf = vec![0; damaged.len()];
+            // The damaged block may also hold metadata, which fails the normal open early
+            if let Ok(save) = SaveData::new(
+                raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            ) {
+                assert!(save
+                    .open_file(ino)
+                    .and_then(|file| file.read(0, &mut buf))
+                    .is_err());
+            }
+
+            let save = open(true);
+            let report = save.salvage_report().unwrap();
+            assert!(report
+                .iter()
+                .any(|b| b.level == 4 && b.owners.contains(&BlockOwner::File(ino))));
+            save.open_file(ino).unwrap().read(0, &mut buf).unwrap();
+            assert_eq!(buf, damaged);
+            save.rehash().unwrap();
+            drop(save);
+
+            let save = open(false);
+            save.open_file(ino).unwrap().read(0, &mut buf).unwrap();
+            assert_eq!(buf, damaged);
+        }
+    }
+
+    #[test]
+    fn verify_all() {
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let open = || {
+                SaveData::new(
+                    raw.clone(),
+                    SaveDataType::Bare,
+                    SelectorInversion::default(),
+                    false,
+                )
+                .unwrap()
+            };
+
+            let save = open();
+            let data: Vec<u8> = (0..0x4000).map(|_| rng.gen()).collect();
+            let file = save
+                .open_root()
+                .unwrap()
+                .new_sub_file([1; 16], data.len())
+                .unwrap();
+            file.write(0, &data).unwrap();
+            file.commit().unwrap();
+            save.commit().unwrap();
+            let ino = file.get_ino();
+            drop(file);
+
+            let reports = save.verify_all().unwrap();
+            let partitions = if duplicate_data { 1 } else { 2 };
+            assert_eq!(reports.len(), 1 + partitions * 4);
+            assert!(reports.iter().all(|r| r.broken_blocks.is_empty()));
+            assert!(reports.iter().all(|r| r.block_count > 0));
+            drop(save);
+
+            // Damage every copy of a byte in the middle of the file content
+            let mut image = vec![0; raw.len()];
+            raw.read(0, &mut image).unwrap();
+            let needle = &data[0x2000..0x2040];
+            for (pos, window) in image.windows(needle.len()).enumerate() {
+                if window == needle {
+                    raw.write(pos, &[!data[0x2000]]).unwrap();
+                }
+            }
+
+            let save = open();
+            let reports = save.verify_all().unwrap();
+            let broken: Vec<_> = reports
+                .iter()
+                .filter(|r| !r.broken_blocks.is_empty())
+                .collect();
+            assert_eq!(broken.len(), 1);
+            assert_eq!(broken[0].partition, partitions - 1);
+            assert_eq!(broken[0].level, 4);
+            assert_eq!(broken[0].broken_blocks.len(), 1);
+
+            // The verification result is kept for later reads
+            let mut buf = vec![0; data.len()];
+            assert!(save.open_file(ino).unwrap().read(0, &mut buf).is_err());
+        }
+    }
+
+    #[test]
+    fn crash_consistency() {
+        use crate::fault_file::*;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let disk = Arc::new(FaultDisk::new());
+            let raw = disk.insert(&["save"], vec![0; 0x80_000]);
+            SaveData::format(raw, SaveDataType::Bare, &param).unwrap();
+            let open = |disk: &Arc<FaultDisk>| {
+                SaveData::new(
+                    disk.file(&["save"]),
+                    SaveDataType::Bare,
+                    SelectorInversion::default(),
+                    false,
+                )
+            };
+
+            let save = open(&disk).unwrap();
+            let old: Vec<u8> = (0..0x1800).map(|_| rng.gen()).collect();
+            write_all(&save, "/a", &old).unwrap();
+            create_dir_all(&save, "/d").unwrap();
+            save.commit().unwrap();
+            drop(save);
+
+            let new_a: Vec<u8> = (0..0x2400).map(|_| rng.gen()).collect();
+            let new_b: Vec<u8> = (0..0x300).map(|_| rng.gen()).collect();
+            let change = |disk: &Arc<FaultDisk>| {
+                let save = open(disk)?;
+                write_all(&save, "/a", &new_a)?;
+                write_all(&save, "/d/b", &new_b)?;
+                save.commit()
+            };
+            let state = |disk: &Arc<FaultDisk>| snapshot(&open(disk)?, true);
+            // Without duplicate data, file data is written in place and may be torn
+            let tolerance = if duplicate_data {
+                Tolerance::Exact
+            } else {
+                Tolerance::TornData
+            };
+            crash_test_all(&disk, change, state, tolerance, &[0x11]);
+        }
+    }
+
+    #[test]
+    fn error_context() {
+        use rand::prelude::*;
+        use std::error::Error as _;
+        let mut rng = rand::thread_rng();
+
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let open = || {
+            SaveData::new(
+                raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap()
+        };
+        let save = open();
+        let data: Vec<u8> = (0..0x4000).map(|_| rng.gen()).collect();
+        write_all(&save, "/a", &data).unwrap();
+        save.commit().unwrap();
+        drop(save);
+
+        let mut image = vec![0; raw.len()];
+        raw.read(0, &mut image).unwrap();
+        let needle = &data[0x2000..0x2040];
+        let pos = image
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .unwrap();
+        raw.write(pos, &[!data[0x2000]]).unwrap();
+
+        let save = open();
+        let broken = save
+            .verify_all()
+            .unwrap()
+            .into_iter()
+            .find(|r| !r.broken_blocks.is_empty())
+            .unwrap();
+        // The contexts travel with the error, to other threads and past later errors
+        let error = std::thread::scope(|s| {
+            s.spawn(|| read_to_vec(&save, "/a").unwrap_err())
+                .join()
+                .unwrap()
+        });
+        let later = read_to_vec(&save, "/a").unwrap_err();
+        assert_eq!(later.contexts(), error.contexts());
+        assert!(matches!(error, Error::HashMismatch(_)));
+        assert!(error.is_corruption());
+        let contexts = error.contexts();
+        assert_eq!(contexts.len(), 2);
+        assert_eq!(contexts[0].layer, Layer::FileSystem);
+        assert_eq!(contexts[0].path.as_deref(), Some("/a"));
+        assert_eq!(contexts[1].layer, Layer::Ivfc(4));
+        assert_eq!(contexts[1].index, Some(broken.broken_blocks[0]));
+        assert_eq!(
+            contexts[1].offset,
+            Some(broken.broken_blocks[0] * broken.block_len)
+        );
+        let error = error.with_contexts();
+        assert_eq!(error.contexts, contexts);
+        assert!(error
+            .to_string()
+            .starts_with("file system (/a): IVFC level 4 block "));
+        let source = error.source().unwrap().downcast_ref::<Error>();
+        assert!(matches!(source, Some(Error::HashMismatch(_))));
+
+        // Errors not caused by corrupted data are left as they are
+        assert!(matches!(read_to_vec(&save, "/b"), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn file_extents() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(
+                raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            let root = save.open_root().unwrap();
+            let a = root.new_sub_file([1; 16], 1000).unwrap();
+            root.new_sub_file([2; 16], 512).unwrap();
+            a.delete().unwrap();
+            let empty = root.new_sub_file([3; 16], 0).unwrap().get_ino();
+            let c = root.new_sub_file([4; 16], 2048).unwrap();
+            let data: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+            c.write(0, &data).unwrap();
+
+            let extents = save.file_extents().unwrap();
+            assert_eq!(extents.len(), 3);
+            let e = extents.iter().find(|e| e.ino == empty).unwrap();
+            assert!(e.extents.is_empty());
+            let e = extents.iter().find(|e| e.ino == c.get_ino()).unwrap();
+            assert_eq!(e.size, 2048);
+            assert!(e.extents.len() > 1);
+            let mut read_back = vec![];
+            for extent in e.extents.iter() {
+                let mut buf = vec![0; extent.block_count * 512];
+                save.center.disa[extent.partition]
+                    .read(extent.offset, &mut buf)
+                    .unwrap();
+                read_back.append(&mut buf);
+            }
+            assert_eq!(read_back, data);
+
+            let stat = save.fragmentation().unwrap();
+            assert_eq!(stat.files, 2);
+            assert_eq!(stat.fragmented_files, 1);
+            assert_eq!(stat.file_extents, 1 + e.extents.len());
+            assert_eq!(stat.free_blocks, save.center.fat.free_blocks());
+            assert!(stat.largest_free_extent <= stat.free_blocks);
+        }
+    }
+
+    #[test]
+    fn defragment() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let open = || {
+                SaveData::new(
+                    raw.clone(),
+                    SaveDataType::Bare,
+                    SelectorInversion::default(),
+                    false,
+                )
+                .unwrap()
+            };
+            let save = open();
+            let root = save.open_root().unwrap();
+            let mut contents = vec![];
+            let mut files = vec![];
+            for i in 0..6u8 {
+                let len = 300 + i as usize * 700;
+                let file = root.new_sub_file([i + 1; 16], len).unwrap();
+                file.write(0, &vec![i; len]).unwrap();
+                files.push(file);
+                contents.push(([i + 1; 16], vec![i; len]));
+            }
+            for (i, file) in files.into_iter().enumerate() {
+                if i % 2 == 0 {
+                    file.delete().unwrap();
+                }
+            }
+            contents = contents.into_iter().skip(1).step_by(2).collect();
+            let file = root.new_sub_file([9; 16], 5000).unwrap();
+            file.write(0, &[9; 5000]).unwrap();
+            contents.push(([9; 16], vec![9; 5000]));
+            root.new_sub_file([10; 16], 0).unwrap();
+            drop(file);
+            save.commit().unwrap();
+            assert!(save.fragmentation().unwrap().fragmented_files > 0);
+
+            let busy = root.open_sub_file([9; 16]).unwrap();
+            drop(root);
+            assert!(matches!(save.defragment(), Err(Error::Busy)));
+            drop(busy);
+
+            let save = open().defragment().unwrap();
+            let stat = save.fragmentation().unwrap();
+            assert_eq!(stat.fragmented_files, 0);
+            assert_eq!(stat.free_extents, 1);
+            assert_eq!(stat.largest_free_extent, stat.free_blocks);
+
+            let save = open();
+            assert_eq!(save.check().unwrap(), vec![]);
+            let root = save.open_root().unwrap();
+            for (name, data) in contents {
+                let file = root.open_sub_file(name).unwrap();
+                let mut buf = vec![0; data.len()];
+                file.read(0, &mut buf).unwrap();
+                assert_eq!(buf, data);
+            }
+            assert_eq!(root.open_sub_file([10; 16]).unwrap().len(), 0);
+        }
+    }
+
+    #[test]
+    fn defragment_unwritten() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let open = || {
+                SaveData::new(
+                    raw.clone(),
+                    SaveDataType::Bare,
+                    SelectorInversion::default(),
+                    false,
+                )
+                .unwrap()
+            };
+            let save = open();
+            let root = save.open_root().unwrap();
+            let file = root.new_sub_file([1; 16], 3000).unwrap();
+            file.write(0, &[1; 3000]).unwrap();
+            root.new_sub_file([2; 16], 5000).unwrap();
+            file.delete().unwrap();
+            // Spread over the blocks freed above and the ones after the file never written
+            let half = root.new_sub_file([3; 16], 5000).unwrap();
+            half.write(0, &[3; 1000]).unwrap();
+            drop((root, half));
+            save.commit().unwrap();
+            assert!(save.fragmentation().unwrap().fragmented_files > 0);
+
+            let save = save.defragment().unwrap();
+            assert_eq!(save.fragmentation().unwrap().fragmented_files, 0);
+
+            let save = open();
+            assert_eq!(save.check().unwrap(), vec![]);
+            let root = save.open_root().unwrap();
+            assert_eq!(root.open_sub_file([2; 16]).unwrap().len(), 5000);
+            let half = root.open_sub_file([3; 16]).unwrap();
+            let mut buf = vec![0; 1000];
+            half.read(0, &mut buf).unwrap();
+            assert_eq!(buf, vec![3; 1000]);
+        }
+    }
+
+    #[test]
+    fn inspect() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(
+                raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            let before = save.inspect().unwrap();
+            save.open_root()
+                .unwrap()
+                .new_sub_file([1; 16], 3000)
+                .unwrap();
+            save.commit().unwrap();
+            let after = save.inspect().unwrap();
+
+            assert_eq!(after.name, "DISA");
+            assert_eq!(after.size, Some(0x80_000));
+            let partition_count = if duplicate_data { 1 } else { 2 };
+            assert_eq!(
+                after.get("partition_count"),
+                Some(&InspectValue::Int(partition_count))
+            );
+            assert_ne!(after.get("active_table"), before.get("active_table"));
+
+            let partitions: Vec<_> = after
+                .children
+                .iter()
+                .filter(|c| c.name == "DIFI partition")
+                .collect();
+            assert_eq!(partitions.len(), partition_count as usize);
+            let data_region = partitions.last().unwrap().find("data region").unwrap();
+            assert_eq!(
+                data_region.get("block_count"),
+                Some(&InspectValue::Int(save.center.block_count as u64))
+            );
+
+            let fat = after.find("allocation table").unwrap();
+            let free: u64 = fat
+                .children
+                .iter()
+                .filter(|c| c.name == "free extent")
+                .map(|c| match c.get("block_count") {
+                    Some(&InspectValue::Int(n)) => n,
+                    _ => panic!(),
+                })
+                .sum();
+            assert_eq!(
+                fat.get("free_blocks"),
+                Some(&InspectValue::Int(save.center.fat.free_blocks() as u64))
+            );
+            assert_eq!(free, save.center.fat.free_blocks() as u64);
+            let owners: Vec<_> = fat
+                .children
+                .iter()
+                .filter(|c| c.name == "chain")
+                .map(|c| c.get("owner").unwrap())
+                .collect();
+            let file = InspectValue::Text("file 1".to_owned());
+            if duplicate_data {
+                assert_eq!(owners.len(), 3);
+                assert!(owners.contains(&&InspectValue::Text("directory table".to_owned())));
+                assert!(owners.contains(&&file));
+            } else {
+                assert_eq!(owners, vec![&file]);
+            }
+            let selectors = after.find("DPFS level 2").unwrap().get("selector");
+            assert!(matches!(selectors, Some(InspectValue::Bytes(_))));
+            assert!(after.to_json().starts_with("{\"name\":\"DISA\""));
+        }
+    }
+
+    #[test]
+    fn check() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(
+                raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap();
+            let root = save.open_root().unwrap();
+            let file_a = root.new_sub_file([1; 16], 3000).unwrap();
+            file_a.write(0, &[0xAA; 3000]).unwrap();
+            let file_b = root
+                .new_sub_dir([2; 16])
+                .unwrap()
+                .new_sub_file([3; 16], 600)
+                .unwrap();
+            file_b.write(0, &[0xBB; 600]).unwrap();
+            let (a, b) = (file_a.get_ino(), file_b.get_ino());
+            drop((file_a, file_b));
+            save.commit().unwrap();
+            assert_eq!(save.check().unwrap(), vec![]);
+
+            let meta_a = FileMeta::open_ino(save.center.fs.clone(), a).unwrap();
+            let meta_b = FileMeta::open_ino(save.center.fs.clone(), b).unwrap();
+            let info_a = meta_a.get_info().unwrap();
+            let info_b = meta_b.get_info().unwrap();
+
+            let mut info = info_a.clone();
+            info.size = 100;
+            meta_a.set_info(info).unwrap();
+            save.commit().unwrap();
+            assert_eq!(
+                save.check().unwrap(),
+                vec![CheckIssue::SizeMismatch {
+                    ino: a,
+                    size: 100,
+                    blocks: 6
+                }]
+            );
+            meta_a.set_info(info_a.clone()).unwrap();
+            save.commit().unwrap();
+
+            let mut info = info_a.clone();
+            info.next = a;
+            meta_a.set_info(info).unwrap();
+            save.commit().unwrap();
+            assert_eq!(
+                save.check().unwrap(),
+                vec![CheckIssue::BrokenLink {
+                    table: MetaTableKind::File,
+                    ino: a
+                }]
+            );
+            meta_a.set_info(info_a.clone()).unwrap();
+            save.commit().unwrap();
+
+            let mut info = info_b.clone();
+            info.block = info_a.block;
+            info.size = info_a.size;
+            meta_b.set_info(info).unwrap();
+            save.commit().unwrap();
+            let issues = save.check().unwrap();
+            assert!(issues.contains(&CheckIssue::CrossLink {
+                block: info_a.block as usize
+            }));
+            assert!(issues.contains(&CheckIssue::LostBlocks { count: 2 }));
+            // a's blocks are counted twice and b's not at all
+            assert!(issues.iter().any(|issue| matches!(
+                issue,
+                CheckIssue::FreeCountMismatch { free_list, free_blocks }
+                    if *free_list == *free_blocks + 4
+            )));
+            meta_b.set_info(info_b).unwrap();
+            save.commit().unwrap();
+            assert_eq!(save.check().unwrap(), vec![]);
+        }
+    }
+
+    #[test]
+    fn path_api() {
+        use crate::file_system::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(
+            raw.clone(),
+            SaveDataType::Bare,
+            SelectorInversion::default(),
+            false,
+        )
+        .unwrap();
+
+        let dir = create_dir_all(&save, "/a/b\\x00c/").unwrap();
+        assert_eq!(
+            create_dir_all(&save, "a//b\\x00c").unwrap().get_ino(),
+            dir.get_ino()
+        );
+        write_all(&save, "a/b\\x00c/d.bin", &[1, 2, 3]).unwrap();
+        write_all(&save, "/a/e", &[]).unwrap();
+        assert_eq!(
+            read_to_vec(&save, "a/b\\x00c/d.bin").unwrap(),
+            vec![1, 2, 3]
+        );
+        write_all(&save, "a/b\\x00c/d.bin", &[4; 5000]).unwrap();
+        assert_eq!(
+            read_to_vec(&save, "./a/b\\x00c/d.bin").unwrap(),
+            vec![4; 5000]
+        );
+        write_all(&save, "a/b\\x00c/d.bin", &[5]).unwrap();
+        assert_eq!(read_to_vec(&save, "a/b\\x00c/d.bin").unwrap(), vec![5]);
+
+        assert!(exists(&save, "a/e").unwrap());
+        assert!(exists(&save, "").unwrap());
+        assert!(!exists(&save, "a/f").unwrap());
+        assert!(!exists(&save, "a/e/f").unwrap());
+        assert!(matches!(open_path(&save, "a"), Ok(Entry::Dir(_))));
+        assert!(matches!(open_path(&save, "a/e"), Ok(Entry::File(_))));
+        assert!(matches!(open_path(&save, "a/f"), Err(Error::NotFound)));
+        assert!(matches!(
+            open_path(&save, "a/e/f"),
+            Err(Error::NotADirectory)
+        ));
+        assert!(matches!(open_path(&save, "a/.."), Err(Error::InvalidName)));
+        assert!(matches!(
+            open_path(&save, "0123456789abcdefg"),
+            Err(Error::InvalidName)
+        ));
+        assert!(matches!(read_to_vec(&save, "a"), Err(Error::IsADirectory)));
+        assert!(matches!(
+            write_all(&save, "a", &[]),
+            Err(Error::IsADirectory)
+        ));
+        assert!(matches!(write_all(&save, "f/g", &[]), Err(Error::NotFound)));
+        assert!(matches!(
+            create_dir_all(&save, "a/e/f"),
+            Err(Error::NotADirectory)
+        ));
+
+        let mut a_name = [0; 16];
+        a_name[0] = b'a';
+        let mut name = [0; 16];
+        name[0] = b'b';
+        name[2] = b'c';
+        let a = save.open_root().unwrap().open_sub_dir(a_name).unwrap();
+        assert_eq!(a.open_sub_dir(name).unwrap().get_ino(), dir.get_ino());
+
+        let ino = |path: &str| match open_path(&save, path).unwrap() {
+            Entry::File(file) => file.get_ino(),
+            Entry::Dir(dir) => dir.get_ino(),
+        };
+        let entry = |path: &str, is_dir| WalkEntry {
+            path: path.to_owned(),
+            ino: ino(path),
+            is_dir,
+        };
+        assert_eq!(
+            walk(&save, "/").unwrap(),
+            vec![
+                entry("a", true),
+                entry("a/b\\x00c", true),
+                entry("a/b\\x00c/d.bin", false),
+                entry("a/e", false),
+            ]
+        );
+        assert_eq!(
+            walk(&save, "a/b\\x00c").unwrap(),
+            vec![entry("a/b\\x00c/d.bin", false)]
+                .into_iter()
+                .map(|e| WalkEntry {
+                    path: "d.bin".to_owned(),
+                    ..e
+                })
+                .collect::<Vec<_>>()
+        );
+
+        assert!(matches!(
+            remove_dir_all(&save, "/"),
+            Err(Error::DeletingRoot)
+        ));
+        assert!(matches!(
+            remove_dir_all(&save, "a/e"),
+            Err(Error::NotADirectory)
+        ));
+        drop((a, dir));
+        remove_dir_all(&save, "a").unwrap();
+        assert_eq!(walk(&save, "").unwrap(), vec![]);
+        save.commit().unwrap();
+        assert_eq!(save.check().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn file_cursor() {
+        use crate::file_system::*;
+        use std::io::{Read, Seek, SeekFrom, Write};
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let open = || {
+            SaveData::new(
+                raw.clone(),
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )
+            .unwrap()
+        };
+        let save = open();
+        let file = save.open_root().unwrap().new_sub_file([1; 16], 0).unwrap();
+        let mut cursor = FileCursor::new(file);
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        std::io::copy(&mut &data[..], &mut cursor).unwrap();
+        assert_eq!(cursor.get_ref().len(), 3000);
+        assert_eq!(cursor.seek(SeekFrom::End(-1000)).unwrap(), 2000);
+        cursor.write_all(&[0xAA; 1500]).unwrap();
+        assert_eq!(cursor.position(), 3500);
+        assert!(cursor.seek(SeekFrom::Current(-4000)).is_err());
+        cursor.seek(SeekFrom::Start(1990)).unwrap();
+        let mut buf = [0; 20];
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[0..10], &data[1990..2000]);
+        assert_eq!(buf[10..20], [0xAA; 10]);
+        cursor.flush().unwrap();
+        drop(cursor);
+        save.commit().unwrap();
+
+        let save = open();
+        let file = save.open_root().unwrap().open_sub_file([1; 16]).unwrap();
+        let mut content = vec![];
+        FileCursor::new(file).read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 3500);
+        assert_eq!(&content[0..2000], &data[0..2000]);
+        assert_eq!(content[2000..3500], [0xAA; 1500][..]);
+
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file([2; 16], 100)
+            .unwrap();
+        let error = FileCursor::new(file).read(&mut [0; 100]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            error.get_ref().unwrap().downcast_ref::<Error>(),
+            Some(Error::HashMismatch(_))
+        ));
+
+        let file = save.open_root().unwrap().new_sub_file([3; 16], 10).unwrap();
+        let mut cursor = FileCursor::new(file);
+        cursor.write_all(&[1; 10]).unwrap();
+        cursor.seek(SeekFrom::Start(5000)).unwrap();
+        cursor.write_all(&[2; 10]).unwrap();
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut content = vec![];
+        cursor.read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 5010);
+        assert_eq!(content[0..10], [1; 10]);
+        assert!(content[10..5000].iter().all(|&b| b == 0));
+        assert_eq!(content[5000..5010], [2; 10]);
+        cursor.seek(SeekFrom::Start(u64::MAX - 5)).unwrap();
+        let error = cursor.write(&[0; 10]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(cursor.get_ref().len(), 5010);
+    }
+
+    #[test]
+    fn copy_tree_modes() {
+        use crate::file_system::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let new_save = |len| {
+            let raw = Arc::new(MemoryFile::new(vec![0; len]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false).unwrap()
+        };
+        let paths = |save: &SaveData| -> Vec<String> {
+            let mut paths: Vec<String> = walk(save, "")
+                .unwrap()
+                .into_iter()
+                .map(|e| e.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+        let same = |name: &[u8; 16]| Ok(*name);
+
+        let src = new_save(0x80_000);
+        create_dir_all(&src, "a").unwrap();
+        write_all(&src, "a/x", &[1; 100]).unwrap();
+        write_all(&src, "y", &[2; 5000]).unwrap();
+        write_all(&src, "z", &[]).unwrap();
+        let dst = new_save(0x80_000);
+        create_dir_all(&dst, "a").unwrap();
+        create_dir_all(&dst, "b").unwrap();
+        write_all(&dst, "a/q", &[3; 10]).unwrap();
+        write_all(&dst, "y", &[4; 10]).unwrap();
+        write_all(&dst, "w", &[5; 10]).unwrap();
+        let (src_root, dst_root) = (src.open_root().unwrap(), dst.open_root().unwrap());
+
+        let report = copy_tree(&src, &src_root, &dst, &dst_root, CopyMode::UpdateOnly, same);
+        assert_eq!(report.unwrap().files_written, 1);
+        assert_eq!(paths(&dst), vec!["a", "a/q", "b", "w", "y"]);
+        assert_eq!(read_to_vec(&dst, "y").unwrap(), vec![2; 5000]);
+
+        let report = copy_tree(&src, &src_root, &dst, &dst_root, CopyMode::Merge, same).unwrap();
+        assert_eq!((report.files_created, report.files_written), (2, 3));
+        assert_eq!(paths(&dst), vec!["a", "a/q", "a/x", "b", "w", "y", "z"]);
+        assert_eq!(read_to_vec(&dst, "a/x").unwrap(), vec![1; 100]);
+
+        write_all(&dst, "y", &[4; 10]).unwrap();
+        let report = copy_tree(&src, &src_root, &dst, &dst_root, CopyMode::Mirror, same).unwrap();
+        assert_eq!((report.dirs_deleted, report.files_deleted), (1, 2));
+        assert_eq!(paths(&dst), paths(&src));
+        assert_eq!(read_to_vec(&dst, "y").unwrap(), vec![2; 5000]);
+        dst.commit().unwrap();
+        assert_eq!(dst.check().unwrap(), vec![]);
+
+        let small = new_save(0x20_000);
+        write_all(&small, "v", &[6; 10]).unwrap();
+        let small_root = small.open_root().unwrap();
+        write_all(
+            &src,
+            "y",
+            &vec![7; small.stat().unwrap().free_blocks * 0x200 + 1],
+        )
+        .unwrap();
+        assert!(matches!(
+            copy_tree(&src, &src_root, &small, &small_root, CopyMode::Merge, same),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(paths(&small), vec!["v"]);
+
+        write_all(&dst, "a", &[]).unwrap_err();
+        drop(dst_root);
+        remove_dir_all(&dst, "a").unwrap();
+        write_all(&dst, "a", &[]).unwrap();
+        let dst_root = dst.open_root().unwrap();
+        assert!(matches!(
+            copy_tree(&src, &src_root, &dst, &dst_root, CopyMode::Merge, same),
+            Err(Error::NotADirectory)
+        ));
+
+        assert_eq!(
+            convert_name::<u64, [u8; 16]>(&0x0004_0000_0012_3400).unwrap(),
+            *b"0004000000123400"
+        );
+        assert_eq!(
+            convert_name::<[u8; 16], u64>(b"0004000000123400").unwrap(),
+            0x0004_0000_0012_3400
+        );
+        assert!(matches!(
+            convert_name::<[u8; 16], u64>(&[b'x'; 16]),
+            Err(Error::InvalidName)
+        ));
+    }
+
+    #[test]
+    fn transaction() {
+        use crate::file_system::*;
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let open = || {
+                SaveData::new(
+                    raw.clone(),
+                    SaveDataType::Bare,
+                    SelectorInversion::default(),
+                    false,
+                )
+                .unwrap()
+            };
+            let save = open();
+            create_dir_all(&save, "a").unwrap();
+            write_all(&save, "a/x", &[1; 3000]).unwrap();
+            write_all(&save, "y", &[2; 700]).unwrap();
+            save.commit().unwrap();
+            let stat = save.stat().unwrap();
+
+            assert!(matches!(save.rollback(), Err(Error::NoTransaction)));
+            save.begin().unwrap();
+            write_all(&save, "a/x", &[3; 5000]).unwrap();
+            write_all(&save, "z", &[4; 100]).unwrap();
+            remove_dir_all(&save, "a").unwrap();
+            let root = save.open_root().unwrap();
+            assert!(matches!(save.rollback(), Err(Error::Busy)));
+            drop(root);
+            save.rollback().unwrap();
+
+            assert_eq!(save.stat().unwrap(), stat);
+            assert_eq!(read_to_vec(&save, "a/x").unwrap(), vec![1; 3000]);
+            assert_eq!(read_to_vec(&save, "y").unwrap(), vec![2; 700]);
+            assert!(!exists(&save, "z").unwrap());
+            assert_eq!(save.check().unwrap(), vec![]);
+            assert!(matches!(save.rollback(), Err(Error::NoTransaction)));
+            assert_eq!(read_to_vec(&open(), "a/x").unwrap(), vec![1; 3000]);
+
+            save.begin().unwrap();
+            write_all(&save, "y", &[5; 10]).unwrap();
+            save.commit().unwrap();
+            drop(save);
+            let save = open();
+            assert_eq!(read_to_vec(&save, "y").unwrap(), vec![5; 10]);
+            assert_eq!(save.check().unwrap(), vec![]);
+        }
+    }
+
+    #[test]
+    fn diff_archives() {
+        use crate::file_system::*;
+        use crate::tree_diff::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let new_save = || {
+            let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+            SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+            SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false).unwrap()
+        };
+
+        let old = new_save();
+        create_dir_all(&old, "a/b").unwrap();
+        write_all(&old, "a/b/x", &[1; 100]).unwrap();
+        write_all(&old, "a/y", &[2; 50]).unwrap();
+        write_all(&old, "c", &[3; 1000]).unwrap();
+        write_all(&old, "d", &[4; 10]).unwrap();
+        create_dir_all(&old, "e").unwrap();
+        write_all(&old, "e/z", &[5; 10]).unwrap();
+        let new = new_save();
+        let old_root = old.open_root().unwrap();
+        let new_root = new.open_root().unwrap();
+        copy_tree(&old, &old_root, &new, &new_root, CopyMode::Mirror, |n| {
+            Ok(*n)
+        })
+        .unwrap();
+        assert!(diff_archives(&old, &new).unwrap().is_empty());
+
+        let a = open_path(&new, "a").unwrap();
+        let f = open_path(&new, "d").unwrap();
+        match (a, f) {
+            (Entry::Dir(mut a), Entry::File(mut f)) => {
+                a.rename(&new_root, *b"a2\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+                    .unwrap();
+                f.rename(&new_root, *b"d2\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+                    .unwrap();
+            }
+            _ => unreachable!(),
+        }
+        let mut c = vec![3; 1000];
+        c[10] = 0;
+        c[11] = 0;
+        c[500] = 0;
+        c.extend_from_slice(&[9; 24]);
+        write_all(&new, "c", &c).unwrap();
+        remove_dir_all(&new, "e").unwrap();
+        write_all(&new, "f", &[6; 10]).unwrap();
+        // Empty files and content shared by several files are not matched as renames
+        write_all(&old, "g", &[]).unwrap();
+        write_all(&old, "h", &[]).unwrap();
+        write_all(&new, "i", &[]).unwrap();
+        write_all(&old, "k", &[7; 10]).unwrap();
+        write_all(&old, "l", &[7; 10]).unwrap();
+        write_all(&new, "m", &[7; 10]).unwrap();
+
+        let diff = diff_archives(&old, &new).unwrap();
+        assert_eq!(
+            diff.entries,
+            vec![
+                DiffEntry::Renamed {
+                    from: "a".to_owned(),
+                    to: "a2".to_owned(),
+                    is_dir: true
+                },
+                DiffEntry::Renamed {
+                    from: "d".to_owned(),
+                    to: "d2".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Removed {
+                    path: "e".to_owned(),
+                    is_dir: true
+                },
+                DiffEntry::Removed {
+                    path: "g".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Removed {
+                    path: "h".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Removed {
+                    path: "k".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Removed {
+                    path: "l".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Added {
+                    path: "f".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Added {
+                    path: "i".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Added {
+                    path: "m".to_owned(),
+                    is_dir: false
+                },
+                DiffEntry::Changed {
+                    path: "c".to_owned(),
+                    old_len: 1000,
+                    new_len: 1024,
+                    ranges: vec![(10, 2), (500, 1), (1000, 24)]
+                },
+            ]
+        );
+        assert_eq!(
+            diff.properties,
+            vec![
+                ("free_blocks".to_owned(), "816".to_owned(), "817".to_owned()),
+                ("free_files".to_owned(), "1".to_owned(), "3".to_owned()),
+                ("free_dirs".to_owned(), "7".to_owned(), "8".to_owned()),
+            ]
+        );
+        let json = diff.to_json();
+        assert!(json.starts_with(
+            "{\"entries\":[{\"kind\":\"renamed\",\"from\":\"a\",\"to\":\"a2\",\"is_dir\":true}"
+        ));
+        assert!(json.contains("\"ranges\":[[10,2],[500,1],[1000,24]]"));
+        assert!(json.ends_with("{\"name\":\"free_dirs\",\"old\":\"7\",\"new\":\"8\"}]}"));
+    }
+
+    #[test]
+    fn concurrent_access() {
+        use crate::file_system::*;
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SaveData>();
+        assert_send_sync::<File>();
+        assert_send_sync::<Dir>();
+        assert_send_sync::<crate::cart_save_data::CartSaveData>();
+        assert_send_sync::<crate::ext_data::ExtData>();
+        assert_send_sync::<crate::db::Db>();
+        assert_send_sync::<crate::Resource>();
+
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save =
+            SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false).unwrap();
+        for i in 0..4u8 {
+            write_all(&save, &format!("{}", i), &vec![i; 3000]).unwrap();
+        }
+        save.commit().unwrap();
+
+        let save = Arc::new(save);
+        let threads: Vec<_> = (0..5u8)
+            .map(|t| {
+                let save = save.clone();
+                std::thread::spawn(move || {
+                    for round in 0..20u8 {
+                        if t == 4 {
+                            write_all(&*save, "w", &vec![round; 1000 + round as usize]).unwrap();
+                            save.commit().unwrap();
+                        } else {
+                            let i = (t + round) % 4;
+                            let data = read_to_vec(&*save, &format!("{}", i)).unwrap();
+                            assert_eq!(data, vec![i; 3000]);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(read_to_vec(&*save, "w").unwrap(), vec![19; 1019]);
+        assert_eq!(save.check().unwrap(), vec![]);
+    }
 }
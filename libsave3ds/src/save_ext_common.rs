@@ -59,6 +59,33 @@ impl ParentedKey for SaveExtKey {
     }
 }
 
+/// What the data in a broken block belongs to.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum BlockOwner {
+    /// The partition table or an IVFC hash level, which are fully regenerated by rehashing.
+    Hash,
+    /// File system metadata, such as headers, the allocation table and directory/file entries.
+    Metadata,
+    /// Content of the file with the ino.
+    File(u32),
+    /// Unallocated space.
+    Free,
+}
+
+/// A block that failed hash verification, found when opening an archive in salvage mode.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct BrokenBlock {
+    /// Index of the DIFI partition for save data,
+    /// or the sub-file index for extdata (0 for the quota file).
+    pub partition: usize,
+    /// IVFC level of the block, from 1 to 4. Level 0 stands for the partition table.
+    pub level: usize,
+    /// Index of the block in its level.
+    pub index: usize,
+    /// Owners of the data in the block. A data block can span over several owners.
+    pub owners: Vec<BlockOwner>,
+}
+
 #[cfg(test)]
 mod test {
     use crate::save_ext_common::*;
@@ -1,5 +1,5 @@
 use crate::aes_ctr_file::AesCtrFile;
-use crate::disk_file::DiskFile;
+use crate::disk_file::{open_file, replace_file, FileBackend};
 use crate::error::*;
 use crate::key_engine::*;
 use crate::misc::*;
@@ -7,11 +7,13 @@ use crate::random_access_file::*;
 use crate::sd_nand_common::*;
 use sha2::*;
 use std::path::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct Sd {
     path: PathBuf,
     key: [u8; 16],
+    backend: FileBackend,
 }
 
 impl Sd {
@@ -29,20 +31,18 @@ impl Sd {
         .ok_or(Error::BrokenSd)??
         .path();
         let key = scramble(key_x, key_y);
-        Ok(Sd { path, key })
+        Ok(Sd {
+            path,
+            key,
+            backend: FileBackend::default(),
+        })
     }
-}
 
-impl SdNandFileSystem for Sd {
-    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
-        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(file_path)?,
-        )?);
+    pub fn set_backend(&mut self, backend: FileBackend) {
+        self.backend = backend;
+    }
 
+    fn path_ctr(path: &[&str]) -> [u8; 16] {
         let hash_path: Vec<u8> = path
             .iter()
             .flat_map(|s| std::iter::once(b'/').chain(s.bytes()))
@@ -58,12 +58,28 @@ impl SdNandFileSystem for Sd {
             *c = hash[i] ^ hash[i + 16];
         }
 
-        Ok(Rc::new(AesCtrFile::new(file, self.key, ctr, false)))
+        ctr
+    }
+}
+
+impl SdNandFileSystem for Sd {
+    fn open(&self, path: &[&str], write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
+        let file = open_file(&file_path, write, self.backend)?;
+
+        Ok(Arc::new(AesCtrFile::new(
+            file,
+            self.key,
+            Sd::path_ctr(path),
+            false,
+        )))
     }
 
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
         let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        std::fs::create_dir_all(file_path.parent().unwrap())?;
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
         let f = std::fs::File::create(file_path)?;
         f.set_len(len as u64)?;
         Ok(())
@@ -82,4 +98,20 @@ impl SdNandFileSystem for Sd {
         }
         Ok(())
     }
+
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        list_dir(&path.iter().fold(self.path.clone(), |a, b| a.join(b)))
+    }
+
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
+        let key = self.key;
+        let ctr = Sd::path_ctr(path);
+        replace_file(
+            &file_path,
+            len,
+            |file| Arc::new(AesCtrFile::new(file, key, ctr, false)),
+            fill,
+        )
+    }
 }
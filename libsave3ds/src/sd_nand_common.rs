@@ -1,39 +1,83 @@
 use crate::error::*;
 use crate::random_access_file::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
-pub trait SdNandFileSystem {
-    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error>;
+pub trait SdNandFileSystem: Send + Sync {
+    fn open(&self, path: &[&str], write: bool) -> Result<Arc<dyn RandomAccessFile>, Error>;
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error>;
     fn remove(&self, path: &[&str]) -> Result<(), Error>;
     fn remove_dir(&self, path: &[&str]) -> Result<(), Error>;
+
+    /// Lists the names of the files and directories directly in the directory at `path`,
+    /// sorted, or nothing if there is no such directory.
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error>;
+
+    /// Replaces the file with a new one of `len` bytes, whose content is written by `fill`,
+    /// so that an interruption leaves either the old or the new content.
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error>;
+}
+
+// Lists the names directly under `dir` among the file `paths`, for file systems kept in memory.
+#[cfg(test)]
+pub(crate) fn list_paths<'a>(
+    paths: impl Iterator<Item = &'a Vec<String>>,
+    dir: &[&str],
+) -> Vec<String> {
+    let mut names: Vec<String> = paths
+        .filter(|path| path.len() > dir.len() && path.iter().zip(dir).all(|(a, b)| a == b))
+        .map(|path| path[dir.len()].clone())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Lists a directory on the host file system.
+pub(crate) fn list_dir(dir: &std::path::Path) -> Result<Vec<String>, Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        // Names that aren't valid UTF-8 can't be opened through this interface anyway
+        if let Ok(name) = entry?.file_name().into_string() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
 }
 
+/// Writes the whole content of a file being replaced.
+pub type Fill<'a> = &'a mut dyn FnMut(Arc<dyn RandomAccessFile>) -> Result<(), Error>;
+
 #[cfg(test)]
 pub mod test {
     use super::*;
     use crate::memory_file::*;
-    use std::cell::*;
     use std::collections::HashMap;
-    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
 
     pub struct VirtualFileSystem {
-        files: RefCell<HashMap<Vec<String>, Rc<dyn RandomAccessFile>>>,
+        files: Mutex<HashMap<Vec<String>, Arc<dyn RandomAccessFile>>>,
     }
 
     impl VirtualFileSystem {
         pub fn new() -> VirtualFileSystem {
             VirtualFileSystem {
-                files: RefCell::new(HashMap::new()),
+                files: Mutex::new(HashMap::new()),
             }
         }
     }
 
     impl SdNandFileSystem for VirtualFileSystem {
-        fn open(&self, path: &[&str], _write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        fn open(&self, path: &[&str], _write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
             let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
             self.files
-                .borrow()
+                .lock()
+                .unwrap()
                 .get(&path)
                 .cloned()
                 .ok_or(Error::NotFound)
@@ -41,18 +85,29 @@ pub mod test {
         fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
             let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
             self.files
-                .borrow_mut()
-                .insert(path, Rc::new(MemoryFile::new(vec![0; len])));
+                .lock()
+                .unwrap()
+                .insert(path, Arc::new(MemoryFile::new(vec![0; len])));
             Ok(())
         }
         fn remove(&self, path: &[&str]) -> Result<(), Error> {
             let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
-            let file = self.files.borrow_mut().remove(&path);
-            assert!(Rc::strong_count(&file.unwrap()) == 1);
+            let file = self.files.lock().unwrap().remove(&path);
+            assert!(Arc::strong_count(&file.unwrap()) == 1);
             Ok(())
         }
         fn remove_dir(&self, _path: &[&str]) -> Result<(), Error> {
             Ok(())
         }
+        fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+            Ok(list_paths(self.files.lock().unwrap().keys(), path))
+        }
+        fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+            let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
+            let file = Arc::new(MemoryFile::new(vec![0; len]));
+            fill(file.clone())?;
+            self.files.lock().unwrap().insert(path, file);
+            Ok(())
+        }
     }
 }
@@ -4,10 +4,10 @@ use aes::*;
 use cmac::*;
 use log::*;
 use sha2::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
 /// Abstract interface for transforming the file data into a block ready for hash and CMAC.
-pub trait Signer {
+pub trait Signer: Send + Sync {
     fn hash(&self, data: Vec<u8>) -> Vec<u8> {
         let mut hasher = Sha256::new();
         hasher.update(&self.block(data));
@@ -18,8 +18,8 @@ pub trait Signer {
 
 /// Implements `RandomAccessFile` layer as a file with a AES-CMAC signature.
 pub struct SignedFile {
-    signature: Rc<dyn RandomAccessFile>,
-    data: Rc<dyn RandomAccessFile>,
+    signature: Arc<dyn RandomAccessFile>,
+    data: Arc<dyn RandomAccessFile>,
     signer: Box<dyn Signer>,
     key: [u8; 16], // AES-CMAC key
     len: usize,
@@ -27,13 +27,13 @@ pub struct SignedFile {
 
 impl SignedFile {
     pub fn new_unverified(
-        signature: Rc<dyn RandomAccessFile>,
-        data: Rc<dyn RandomAccessFile>,
+        signature: Arc<dyn RandomAccessFile>,
+        data: Arc<dyn RandomAccessFile>,
         signer: Box<dyn Signer>,
         key: [u8; 16],
     ) -> Result<SignedFile, Error> {
         if signature.len() != 16 {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         let len = data.len();
         let file = SignedFile {
@@ -47,13 +47,13 @@ impl SignedFile {
     }
 
     pub fn new(
-        signature: Rc<dyn RandomAccessFile>,
-        data: Rc<dyn RandomAccessFile>,
+        signature: Arc<dyn RandomAccessFile>,
+        data: Arc<dyn RandomAccessFile>,
         signer: Box<dyn Signer>,
         key: [u8; 16],
     ) -> Result<SignedFile, Error> {
         if signature.len() != 16 {
-            return make_error(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         let len = data.len();
         let file = SignedFile {
@@ -68,7 +68,7 @@ impl SignedFile {
         file.signature.read(0, &mut signature)?;
         if signature != file.calculate_signature()? {
             error!("Signature mismatch");
-            return make_error(Error::SignatureMismatch);
+            return make_error(Error::SignatureMismatch(Contexts::new()));
         }
 
         Ok(file)
@@ -106,7 +106,7 @@ pub mod test {
     use crate::memory_file::MemoryFile;
     use crate::random_access_file::*;
     use crate::signed_file::*;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[derive(Clone)]
     pub struct SimpleSigner {
@@ -149,8 +149,8 @@ pub mod test {
             let mut cmac_result = vec![0; 16];
             cmac_result.copy_from_slice(cmac.finalize().into_bytes().as_slice());
 
-            let data = Rc::new(MemoryFile::new(init));
-            let signature = Rc::new(MemoryFile::new(cmac_result));
+            let data = Arc::new(MemoryFile::new(init));
+            let signature = Arc::new(MemoryFile::new(cmac_result));
 
             let file =
                 SignedFile::new(signature.clone(), data.clone(), signer.clone(), key).unwrap();
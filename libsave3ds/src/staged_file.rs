@@ -0,0 +1,100 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Implements `RandomAccessFile` layer that keeps all changes in memory,
+/// and writes the whole content to the underlying file in one piece on commit.
+///
+/// Placed over the DISA / DIFF header together with its signature, this makes the table selector,
+/// the table hash and the signature change at once, so that a power loss during commit
+/// leaves either the old or the new header. Placed over the partition tables in salvage mode,
+/// this keeps a repaired table out of the file until the archive is committed.
+pub struct StagedFile {
+    parent: Arc<dyn RandomAccessFile>,
+    data: Mutex<Vec<u8>>,
+    dirty: AtomicBool,
+}
+
+impl StagedFile {
+    pub fn new(parent: Arc<dyn RandomAccessFile>) -> Result<StagedFile, Error> {
+        let mut data = vec![0; parent.len()];
+        parent.read(0, &mut data)?;
+        Ok(StagedFile {
+            parent,
+            data: Mutex::new(data),
+            dirty: AtomicBool::new(false),
+        })
+    }
+}
+
+impl RandomAccessFile for StagedFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let data = self.data.lock().unwrap();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > data.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
+        }
+        buf.copy_from_slice(&data[pos..pos + buf.len()]);
+        Ok(())
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > data.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
+        }
+        data[pos..pos + buf.len()].copy_from_slice(buf);
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.parent.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        let data = self.data.lock().unwrap();
+        if self.dirty.load(Ordering::Relaxed) {
+            self.parent.write(0, &data)?;
+            self.dirty.store(false, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+    fn rollback(&self) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        self.parent.read(0, &mut data)?;
+        self.dirty.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::staged_file::*;
+
+    #[test]
+    fn staged() {
+        let parent = Arc::new(MemoryFile::new(vec![0; 8]));
+        let file = StagedFile::new(parent.clone()).unwrap();
+        file.write(1, &[1, 2]).unwrap();
+        file.write(6, &[3]).unwrap();
+        let mut buf = [0; 8];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 0, 0, 0, 3, 0]);
+        parent.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+
+        file.commit().unwrap();
+        parent.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 0, 0, 0, 3, 0]);
+
+        file.write(0, &[4]).unwrap();
+        file.rollback().unwrap();
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 0, 0, 0, 3, 0]);
+    }
+}
@@ -1,22 +1,22 @@
 use crate::error::*;
 use crate::random_access_file::*;
-use std::rc::Rc;
+use std::sync::Arc;
 
 /// Implements `RandomAccessFile` layer as a sub region of a parent file.
 pub struct SubFile {
-    parent: Rc<dyn RandomAccessFile>,
+    parent: Arc<dyn RandomAccessFile>,
     begin: usize,
     len: usize,
 }
 
 impl SubFile {
     pub fn new(
-        parent: Rc<dyn RandomAccessFile>,
+        parent: Arc<dyn RandomAccessFile>,
         begin: usize,
         len: usize,
     ) -> Result<SubFile, Error> {
-        if begin + len > parent.len() {
-            return make_error(Error::OutOfBound);
+        if begin.checked_add(len).is_none_or(|end| end > parent.len()) {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         Ok(SubFile { parent, begin, len })
     }
@@ -24,14 +24,20 @@ impl SubFile {
 
 impl RandomAccessFile for SubFile {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len() {
-            return make_error(Error::OutOfBound);
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         self.parent.read(pos + self.begin, buf)
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        if pos + buf.len() > self.len() {
-            return make_error(Error::OutOfBound);
+        if pos
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len())
+        {
+            return make_error(Error::OutOfBound(Contexts::new()));
         }
         self.parent.write(pos + self.begin, buf)
     }
@@ -41,4 +47,7 @@ impl RandomAccessFile for SubFile {
     fn commit(&self) -> Result<(), Error> {
         Ok(())
     }
+    fn mapped(&self) -> Option<&[u8]> {
+        Some(&self.parent.mapped()?[self.begin..self.begin + self.len])
+    }
 }
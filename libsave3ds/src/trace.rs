@@ -0,0 +1,911 @@
+use crate::cart_save_data::CartSaveData;
+use crate::check::{Check, CheckIssue};
+use crate::db::Db;
+use crate::difi_partition::SelectorInversion;
+use crate::disk_file::{open_file, FileBackend};
+use crate::error::*;
+use crate::ext_data::*;
+use crate::file_system::*;
+use crate::nand::Nand;
+use crate::random_access_file::*;
+use crate::save_data::*;
+use crate::sd_nand_common::*;
+use sha2::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Arguments;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A log of the I/O on archive files and of the changes made to an archive, written as lines of
+/// text. Numbers are in hexadecimal. The log records
+/// - `layer ID NAME`: a traced archive file was opened, and named ID in the following lines.
+///   Only whole archive files are traced, as the archive reads them from the SD, the NAND or
+///   the host: the layers inside an archive (DISA / DIFF, DPFS, IVFC, ...) have no ID of their
+///   own, and their I/O shows up as the I/O it causes on the archive file.
+/// - `r ID POS LEN [HASH]` / `w ID POS LEN [HASH]`: a read or a write on a traced file.
+/// - `c ID` / `u ID`: a commit or a rollback on a traced file.
+/// - `create PATH LEN`, `remove PATH`, `replace PATH LEN`: a file changed on the SD or the NAND.
+/// - `archive ...`: the format parameters of an archive traced by [`Traced`](struct.Traced.html),
+///   followed by its directory tree between `tree` and `ops`.
+/// - `OPERATION ARGS = OUTCOME`: an operation on the traced archive, and whether it succeeded.
+///
+/// Names of files and directories are replaced with their order of appearance,
+/// so the log holds no file content or name, and can be shared in place of the archive
+/// to reproduce a problem with [`replay_trace`](fn.replay_trace.html).
+pub struct Trace {
+    out: Mutex<TraceOut>,
+    hash: bool,
+}
+
+struct TraceOut {
+    writer: Box<dyn Write + Send>,
+    layers: usize,
+    names: HashMap<String, usize>,
+}
+
+impl Trace {
+    /// Starts a trace written to `writer`. With `hash`, each read and write also records
+    /// the first 8 bytes of the SHA-256 of its data.
+    pub fn new(writer: Box<dyn Write + Send>, hash: bool) -> Trace {
+        let trace = Trace {
+            out: Mutex::new(TraceOut {
+                writer,
+                layers: 0,
+                names: HashMap::new(),
+            }),
+            hash,
+        };
+        trace.record(format_args!("save3ds-trace 1"));
+        trace
+    }
+
+    /// Flushes the log to its writer.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.out.lock().unwrap().writer.flush()?;
+        Ok(())
+    }
+
+    fn record(&self, line: Arguments) {
+        if let Err(e) = writeln!(self.out.lock().unwrap().writer, "{}", line) {
+            log::error!("Failed to write the trace: {}", e);
+        }
+    }
+
+    fn layer(&self, name: &str) -> usize {
+        let id = {
+            let mut out = self.out.lock().unwrap();
+            out.layers += 1;
+            out.layers - 1
+        };
+        self.record(format_args!("layer {:x} {}", id, name));
+        id
+    }
+
+    fn name<N: NameConvert>(&self, name: &N) -> usize {
+        let mut out = self.out.lock().unwrap();
+        let next = out.names.len();
+        *out.names.entry(N::name_3ds_to_str(name)).or_insert(next)
+    }
+
+    fn io(&self, kind: char, layer: usize, pos: usize, data: &[u8]) {
+        if self.hash {
+            let hash = u64::from_be_bytes(Sha256::digest(data)[0..8].try_into().unwrap());
+            self.record(format_args!(
+                "{} {:x} {:x} {:x} {:016x}",
+                kind,
+                layer,
+                pos,
+                data.len(),
+                hash
+            ));
+        } else {
+            self.record(format_args!(
+                "{} {:x} {:x} {:x}",
+                kind,
+                layer,
+                pos,
+                data.len()
+            ));
+        }
+    }
+
+    fn op(&self, op: Arguments, result: Result<Option<u32>, &Error>) {
+        match result {
+            Ok(None) => self.record(format_args!("{} = ok", op)),
+            Ok(Some(ino)) => self.record(format_args!("{} = ok {:x}", op, ino)),
+            Err(e) => self.record(format_args!("{} = {:?}", op, e)),
+        }
+    }
+}
+
+/// Implements `RandomAccessFile` layer that records every read, write, commit and rollback
+/// in a trace, without changing the data.
+pub(crate) struct TraceFile {
+    parent: Arc<dyn RandomAccessFile>,
+    trace: Arc<Trace>,
+    layer: usize,
+}
+
+impl TraceFile {
+    pub fn new(parent: Arc<dyn RandomAccessFile>, trace: Arc<Trace>, name: &str) -> TraceFile {
+        let layer = trace.layer(name);
+        TraceFile {
+            parent,
+            trace,
+            layer,
+        }
+    }
+}
+
+impl RandomAccessFile for TraceFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let result = self.parent.read(pos, buf);
+        self.trace.io('r', self.layer, pos, buf);
+        result
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        self.trace.io('w', self.layer, pos, buf);
+        self.parent.write(pos, buf)
+    }
+    fn len(&self) -> usize {
+        self.parent.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        self.trace.record(format_args!("c {:x}", self.layer));
+        self.parent.commit()
+    }
+    fn rollback(&self) -> Result<(), Error> {
+        self.trace.record(format_args!("u {:x}", self.layer));
+        self.parent.rollback()
+    }
+}
+
+/// Implements `SdNandFileSystem` on top of another one, tracing every opened file.
+pub(crate) struct TracedSdNand {
+    pub base: Arc<dyn SdNandFileSystem>,
+    pub trace: Arc<Trace>,
+    pub name: &'static str,
+}
+
+impl TracedSdNand {
+    fn path(&self, path: &[&str]) -> String {
+        format!("{}/{}", self.name, path.join("/"))
+    }
+}
+
+impl SdNandFileSystem for TracedSdNand {
+    fn open(&self, path: &[&str], write: bool) -> Result<Arc<dyn RandomAccessFile>, Error> {
+        Ok(Arc::new(TraceFile::new(
+            self.base.open(path, write)?,
+            self.trace.clone(),
+            &self.path(path),
+        )))
+    }
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        let name = self.path(path);
+        self.trace.record(format_args!("create {} {:x}", name, len));
+        self.base.create(path, len)
+    }
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        let name = self.path(path);
+        self.trace.record(format_args!("remove {}", name));
+        self.base.remove(path)
+    }
+    fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+        let name = self.path(path);
+        self.trace.record(format_args!("remove {}", name));
+        self.base.remove_dir(path)
+    }
+    fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        self.base.list(path)
+    }
+    fn replace(&self, path: &[&str], len: usize, fill: Fill) -> Result<(), Error> {
+        let name = self.path(path);
+        self.trace
+            .record(format_args!("replace {} {:x}", name, len));
+        self.base.replace(path, len, fill)
+    }
+}
+
+/// Format parameters of a traced archive, recorded so that its trace can be replayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// A save data, with its length.
+    Save(SaveDataFormatParam, usize),
+    Ext(ExtDataFormatParam),
+    /// A title database, whose trace can't be replayed.
+    Db,
+}
+
+/// An archive that can be traced with [`Traced`](struct.Traced.html).
+pub trait TraceArchive: FileSystem {
+    /// Returns the format parameters to record in the trace.
+    fn trace_format(&self) -> Result<TraceFormat, Error>;
+}
+
+impl TraceArchive for SaveData {
+    fn trace_format(&self) -> Result<TraceFormat, Error> {
+        Ok(TraceFormat::Save(self.format_param()?, self.image_len()))
+    }
+}
+
+impl TraceArchive for CartSaveData {
+    fn trace_format(&self) -> Result<TraceFormat, Error> {
+        Ok(TraceFormat::Save(self.format_param()?, self.image_len()))
+    }
+}
+
+impl TraceArchive for ExtData {
+    fn trace_format(&self) -> Result<TraceFormat, Error> {
+        Ok(TraceFormat::Ext(self.format_param()?))
+    }
+}
+
+impl TraceArchive for Db {
+    fn trace_format(&self) -> Result<TraceFormat, Error> {
+        Ok(TraceFormat::Db)
+    }
+}
+
+/// Implements [`FileSystem`](../file_system/trait.FileSystem.html) on top of another archive,
+/// recording in a trace its format parameters, the directory tree it starts with,
+/// and every operation that reads or changes it.
+pub struct Traced<T> {
+    inner: T,
+    trace: Arc<Trace>,
+}
+
+impl<T: TraceArchive> Traced<T>
+where
+    T::NameType: NameConvert,
+{
+    pub fn new(inner: T, trace: Arc<Trace>) -> Result<Traced<T>, Error> {
+        match inner.trace_format()? {
+            TraceFormat::Save(param, len) => trace.record(format_args!(
+                "archive save {:x} {} {:x} {:x} {:x} {:x} {}",
+                len,
+                match param.block_type {
+                    SaveDataBlockType::Small => "small",
+                    SaveDataBlockType::Large => "large",
+                },
+                param.max_dir,
+                param.dir_buckets,
+                param.max_file,
+                param.file_buckets,
+                param.duplicate_data as u8
+            )),
+            TraceFormat::Ext(param) => trace.record(format_args!(
+                "archive ext {:x} {:x} {:x} {:x}",
+                param.max_dir, param.dir_buckets, param.max_file, param.file_buckets
+            )),
+            TraceFormat::Db => trace.record(format_args!("archive db")),
+        }
+        trace.record(format_args!("tree"));
+        record_tree(&inner, &trace, &inner.open_root()?)?;
+        trace.record(format_args!("ops"));
+        Ok(Traced { inner, trace })
+    }
+}
+
+fn record_tree<T: FileSystem>(file_system: &T, trace: &Trace, dir: &T::DirType) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    let parent = dir.get_ino();
+    for (name, ino) in dir.list_sub_dir()? {
+        let name = trace.name(&name);
+        trace.record(format_args!("dir {:x} {:x} {:x}", ino, parent, name));
+        record_tree(file_system, trace, &file_system.open_dir(ino)?)?;
+    }
+    for (name, ino) in dir.list_sub_file()? {
+        let (name, len) = (trace.name(&name), file_system.open_file(ino)?.len());
+        trace.record(format_args!(
+            "file {:x} {:x} {:x} {:x}",
+            ino, parent, name, len
+        ));
+    }
+    Ok(())
+}
+
+/// A file opened from [`Traced`](struct.Traced.html).
+pub struct TracedFile<F> {
+    inner: F,
+    trace: Arc<Trace>,
+}
+
+/// A directory opened from [`Traced`](struct.Traced.html).
+pub struct TracedDir<D> {
+    inner: D,
+    trace: Arc<Trace>,
+}
+
+impl<F: FileSystemFile> FileSystemFile for TracedFile<F>
+where
+    F::NameType: NameConvert,
+    F::DirType: FileSystemDir,
+{
+    type NameType = F::NameType;
+    type DirType = TracedDir<F::DirType>;
+
+    fn rename(&mut self, parent: &Self::DirType, name: Self::NameType) -> Result<(), Error> {
+        let name_id = self.trace.name(&name);
+        let result = self.inner.rename(&parent.inner, name);
+        self.trace.op(
+            format_args!(
+                "mv {:x} {:x} {:x}",
+                self.inner.get_ino(),
+                parent.inner.get_ino(),
+                name_id
+            ),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+
+    fn get_parent_ino(&self) -> Result<u32, Error> {
+        self.inner.get_parent_ino()
+    }
+
+    fn get_ino(&self) -> u32 {
+        self.inner.get_ino()
+    }
+
+    fn delete(self) -> Result<(), Error> {
+        let ino = self.inner.get_ino();
+        let result = self.inner.delete();
+        self.trace
+            .op(format_args!("rm {:x}", ino), result.as_ref().map(|_| None));
+        result
+    }
+
+    fn resize(&mut self, len: usize) -> Result<(), Error> {
+        let result = self.inner.resize(len);
+        self.trace.op(
+            format_args!("resize {:x} {:x}", self.inner.get_ino(), len),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let result = self.inner.read(pos, buf);
+        self.trace.op(
+            format_args!("read {:x} {:x} {:x}", self.inner.get_ino(), pos, buf.len()),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let result = self.inner.write(pos, buf);
+        self.trace.op(
+            format_args!("write {:x} {:x} {:x}", self.inner.get_ino(), pos, buf.len()),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn commit(&self) -> Result<(), Error> {
+        let result = self.inner.commit();
+        self.trace.op(
+            format_args!("sync {:x}", self.inner.get_ino()),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+}
+
+impl<D: FileSystemDir> FileSystemDir for TracedDir<D>
+where
+    D::NameType: NameConvert,
+    D::FileType: FileSystemFile,
+{
+    type NameType = D::NameType;
+    type FileType = TracedFile<D::FileType>;
+
+    fn rename(&mut self, parent: &Self, name: Self::NameType) -> Result<(), Error> {
+        let name_id = self.trace.name(&name);
+        let result = self.inner.rename(&parent.inner, name);
+        self.trace.op(
+            format_args!(
+                "mvdir {:x} {:x} {:x}",
+                self.inner.get_ino(),
+                parent.inner.get_ino(),
+                name_id
+            ),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+
+    fn get_parent_ino(&self) -> Result<u32, Error> {
+        self.inner.get_parent_ino()
+    }
+
+    fn get_ino(&self) -> u32 {
+        self.inner.get_ino()
+    }
+
+    fn open_sub_dir(&self, name: Self::NameType) -> Result<Self, Error> {
+        Ok(TracedDir {
+            inner: self.inner.open_sub_dir(name)?,
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn open_sub_file(&self, name: Self::NameType) -> Result<Self::FileType, Error> {
+        Ok(TracedFile {
+            inner: self.inner.open_sub_file(name)?,
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn list_sub_dir(&self) -> Result<Vec<(Self::NameType, u32)>, Error> {
+        self.inner.list_sub_dir()
+    }
+
+    fn list_sub_file(&self) -> Result<Vec<(Self::NameType, u32)>, Error> {
+        self.inner.list_sub_file()
+    }
+
+    fn new_sub_dir(&self, name: Self::NameType) -> Result<Self, Error> {
+        let name_id = self.trace.name(&name);
+        let result = self.inner.new_sub_dir(name);
+        self.trace.op(
+            format_args!("mkdir {:x} {:x}", self.inner.get_ino(), name_id),
+            result.as_ref().map(|dir| Some(dir.get_ino())),
+        );
+        Ok(TracedDir {
+            inner: result?,
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn new_sub_file(&self, name: Self::NameType, len: usize) -> Result<Self::FileType, Error> {
+        let name_id = self.trace.name(&name);
+        let result = self.inner.new_sub_file(name, len);
+        self.trace.op(
+            format_args!("create {:x} {:x} {:x}", self.inner.get_ino(), name_id, len),
+            result.as_ref().map(|file| Some(file.get_ino())),
+        );
+        Ok(TracedFile {
+            inner: result?,
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn delete(self) -> Result<(), Error> {
+        let ino = self.inner.get_ino();
+        let result = self.inner.delete();
+        self.trace.op(
+            format_args!("rmdir {:x}", ino),
+            result.as_ref().map(|_| None),
+        );
+        result
+    }
+}
+
+impl<T: FileSystem> FileSystem for Traced<T>
+where
+    T::NameType: NameConvert,
+{
+    type FileType = TracedFile<T::FileType>;
+    type DirType = TracedDir<T::DirType>;
+    type NameType = T::NameType;
+
+    fn open_file(&self, ino: u32) -> Result<Self::FileType, Error> {
+        Ok(TracedFile {
+            inner: self.inner.open_file(ino)?,
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn open_dir(&self, ino: u32) -> Result<Self::DirType, Error> {
+        Ok(TracedDir {
+            inner: self.inner.open_dir(ino)?,
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn commit(&self) -> Result<(), Error> {
+        let result = self.inner.commit();
+        self.trace
+            .op(format_args!("commit"), result.as_ref().map(|_| None));
+        result
+    }
+
+    fn begin(&self) -> Result<(), Error> {
+        let result = self.inner.begin();
+        self.trace
+            .op(format_args!("begin"), result.as_ref().map(|_| None));
+        result
+    }
+
+    fn rollback(&self) -> Result<(), Error> {
+        let result = self.inner.rollback();
+        self.trace
+            .op(format_args!("rollback"), result.as_ref().map(|_| None));
+        result
+    }
+
+    fn stat(&self) -> Result<Stat, Error> {
+        self.inner.stat()
+    }
+}
+
+/// The result of [`replay_trace`](fn.replay_trace.html).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of operations replayed after building the directory tree.
+    pub operations: usize,
+    /// Operations that succeeded or failed differently from the trace.
+    pub divergences: Vec<Divergence>,
+    /// Issues found by checking the archive after replaying.
+    pub issues: Vec<CheckIssue>,
+}
+
+/// An operation replayed with a different outcome than the one in the trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Line number in the trace, counted from 1.
+    pub line: usize,
+    pub operation: String,
+    pub traced: String,
+    pub replayed: String,
+}
+
+/// Replays the operations recorded in `trace` by [`Traced`](struct.Traced.html) on a new archive
+/// with the same format parameters, created at `path`: a stand-alone save data file for
+/// save data, or a directory holding the extdata for extdata.
+/// The directory tree the trace starts with is built first, with files of synthetic content,
+/// and names are made from their order of appearance.
+///
+/// Nothing is overwritten: fails with an I/O error if the file at `path` already exists, or with
+/// `Error::NotEmpty` if the directory at `path` holds anything.
+/// Fails with `Error::Unsupported` for a title database, and with `Error::InvalidValue`
+/// if `trace` is malformed.
+pub fn replay_trace(trace: impl BufRead, path: &str) -> Result<ReplayReport, Error> {
+    let lines = trace.lines().collect::<Result<Vec<_>, _>>()?;
+    let tree = lines
+        .iter()
+        .position(|line| line == "tree")
+        .ok_or(Error::InvalidValue(Contexts::new()))?;
+    let archive = lines[..tree]
+        .iter()
+        .rev()
+        .find(|line| line.starts_with("archive "))
+        .ok_or(Error::InvalidValue(Contexts::new()))?;
+    let fields = archive.split(' ').skip(1).collect::<Vec<_>>();
+    let number = |i: usize| parse_hex(fields.get(i).copied());
+    match fields[0] {
+        "save" => {
+            let param = SaveDataFormatParam {
+                block_type: match fields.get(2) {
+                    Some(&"small") => SaveDataBlockType::Small,
+                    Some(&"large") => SaveDataBlockType::Large,
+                    _ => return make_error(Error::InvalidValue(Contexts::new())),
+                },
+                max_dir: number(3)?,
+                dir_buckets: number(4)?,
+                max_file: number(5)?,
+                file_buckets: number(6)?,
+                duplicate_data: number(7)? != 0,
+            };
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)?
+                .set_len(number(1)? as u64)?;
+            let file = open_file(Path::new(path), true, FileBackend::Disk)?;
+            SaveData::format(file.clone(), SaveDataType::Bare, &param)?;
+            let save = SaveData::new(
+                file,
+                SaveDataType::Bare,
+                SelectorInversion::default(),
+                false,
+            )?;
+            let mut report = Replay::new(&save).run(&lines, tree)?;
+            report.issues = save.check()?;
+            Ok(report)
+        }
+        "ext" => {
+            let param = ExtDataFormatParam {
+                max_dir: number(1)?,
+                dir_buckets: number(2)?,
+                max_file: number(3)?,
+                file_buckets: number(4)?,
+            };
+            std::fs::create_dir_all(path)?;
+            if std::fs::read_dir(path)?.next().is_some() {
+                return make_error(Error::NotEmpty);
+            }
+            let nand = Arc::new(Nand::new(path)?);
+            ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param)?;
+            let ext = ExtData::new(
+                nand,
+                &[],
+                0,
+                [0; 16],
+                false,
+                true,
+                SelectorInversion::default(),
+                false,
+            )?;
+            let mut report = Replay::new(&ext).run(&lines, tree)?;
+            report.issues = ext.check()?;
+            Ok(report)
+        }
+        "db" => make_error(Error::Unsupported),
+        _ => make_error(Error::InvalidValue(Contexts::new())),
+    }
+}
+
+fn parse_hex(field: Option<&str>) -> Result<usize, Error> {
+    match field.map(|field| usize::from_str_radix(field, 16)) {
+        Some(Ok(value)) => Ok(value),
+        _ => make_error(Error::InvalidValue(Contexts::new())),
+    }
+}
+
+// Content written in place of the traced data, different at each position.
+fn synthetic(pos: usize, len: usize) -> Vec<u8> {
+    (pos..pos + len).map(|i| (i % 251) as u8).collect()
+}
+
+struct Replay<'a, T> {
+    file_system: &'a T,
+    dirs: HashMap<usize, u32>,
+    files: HashMap<usize, u32>,
+}
+
+impl<'a, T: FileSystem> Replay<'a, T>
+where
+    T::NameType: NameConvert,
+{
+    fn new(file_system: &'a T) -> Replay<'a, T> {
+        Replay {
+            file_system,
+            dirs: std::iter::once((1, 1)).collect(),
+            files: HashMap::new(),
+        }
+    }
+
+    fn name(id: usize) -> Result<T::NameType, Error> {
+        <T::NameType as NameConvert>::name_str_to_3ds(&format!("{:x}", id))
+            .ok_or(Error::InvalidName)
+    }
+
+    fn dir(&self, ino: usize) -> Result<T::DirType, Error> {
+        self.file_system
+            .open_dir(*self.dirs.get(&ino).ok_or(Error::NotFound)?)
+    }
+
+    fn file(&self, ino: usize) -> Result<T::FileType, Error> {
+        self.file_system
+            .open_file(*self.files.get(&ino).ok_or(Error::NotFound)?)
+    }
+
+    fn run(mut self, lines: &[String], tree: usize) -> Result<ReplayReport, Error> {
+        let mut lines = lines.iter().enumerate().skip(tree + 1);
+        for (_, line) in &mut lines {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let number = |i: usize| parse_hex(fields.get(i).copied());
+            match fields[0] {
+                "dir" => {
+                    let dir = self.dir(number(2)?)?.new_sub_dir(Self::name(number(3)?)?)?;
+                    self.dirs.insert(number(1)?, dir.get_ino());
+                }
+                "file" => {
+                    let len = number(4)?;
+                    let file = self
+                        .dir(number(2)?)?
+                        .new_sub_file(Self::name(number(3)?)?, len)?;
+                    file.write(0, &synthetic(0, len))?;
+                    file.commit()?;
+                    self.files.insert(number(1)?, file.get_ino());
+                }
+                "ops" => break,
+                _ => (),
+            }
+        }
+        self.file_system.commit()?;
+
+        let mut report = ReplayReport::default();
+        for (i, line) in lines {
+            let (operation, traced) = match line.find(" = ") {
+                Some(mid) => (&line[..mid], &line[mid + 3..]),
+                None => continue,
+            };
+            let fields = operation.split(' ').collect::<Vec<_>>();
+            let replayed = self.operation(&fields);
+            report.operations += 1;
+
+            let traced_ok = traced == "ok" || traced.starts_with("ok ");
+            if let (Some(new), Some(ino)) = (
+                replayed.as_ref().ok().and_then(|ino| *ino),
+                traced.strip_prefix("ok ").map(|ino| parse_hex(Some(ino))),
+            ) {
+                let map = if fields[0] == "mkdir" {
+                    &mut self.dirs
+                } else {
+                    &mut self.files
+                };
+                map.insert(ino?, new);
+            }
+            let replayed = match replayed {
+                Ok(_) if traced_ok => continue,
+                Ok(_) => "ok".to_owned(),
+                Err(e) => format!("{:?}", e),
+            };
+            if replayed != traced {
+                report.divergences.push(Divergence {
+                    line: i + 1,
+                    operation: operation.to_owned(),
+                    traced: traced.to_owned(),
+                    replayed,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    fn operation(&self, fields: &[&str]) -> Result<Option<u32>, Error> {
+        let number = |i: usize| parse_hex(fields.get(i).copied());
+        match fields[0] {
+            "mkdir" => {
+                let dir = self.dir(number(1)?)?.new_sub_dir(Self::name(number(2)?)?)?;
+                Ok(Some(dir.get_ino()))
+            }
+            "create" => {
+                let file = self
+                    .dir(number(1)?)?
+                    .new_sub_file(Self::name(number(2)?)?, number(3)?)?;
+                Ok(Some(file.get_ino()))
+            }
+            "read" => {
+                let mut buf = vec![0; number(3)?];
+                self.file(number(1)?)?.read(number(2)?, &mut buf)?;
+                Ok(None)
+            }
+            "write" => {
+                let pos = number(2)?;
+                let data = synthetic(pos, number(3)?);
+                self.file(number(1)?)?.write(pos, &data)?;
+                Ok(None)
+            }
+            "resize" => {
+                self.file(number(1)?)?.resize(number(2)?)?;
+                Ok(None)
+            }
+            "sync" => {
+                self.file(number(1)?)?.commit()?;
+                Ok(None)
+            }
+            "rm" => {
+                self.file(number(1)?)?.delete()?;
+                Ok(None)
+            }
+            "rmdir" => {
+                self.dir(number(1)?)?.delete()?;
+                Ok(None)
+            }
+            "mv" => {
+                let parent = self.dir(number(2)?)?;
+                self.file(number(1)?)?
+                    .rename(&parent, Self::name(number(3)?)?)?;
+                Ok(None)
+            }
+            "mvdir" => {
+                let parent = self.dir(number(2)?)?;
+                self.dir(number(1)?)?
+                    .rename(&parent, Self::name(number(3)?)?)?;
+                Ok(None)
+            }
+            "commit" => {
+                self.file_system.commit()?;
+                Ok(None)
+            }
+            "begin" => {
+                self.file_system.begin()?;
+                Ok(None)
+            }
+            "rollback" => {
+                self.file_system.rollback()?;
+                Ok(None)
+            }
+            _ => make_error(Error::InvalidValue(Contexts::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::trace::*;
+
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_replay() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let trace = Arc::new(Trace::new(Box::new(SharedLog(log.clone())), true));
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let raw = Arc::new(MemoryFile::new(vec![0; 0x80_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let raw = Arc::new(TraceFile::new(raw, trace.clone(), "raw"));
+        let save =
+            SaveData::new(raw, SaveDataType::Bare, SelectorInversion::default(), false).unwrap();
+        write_all(&save, "/secret", b"personal data").unwrap();
+        create_dir_all(&save, "/saves").unwrap();
+        save.commit().unwrap();
+
+        let save = Traced::new(save, trace.clone()).unwrap();
+        write_all(&save, "/saves/slot1", &[1; 0x1234]).unwrap();
+        let root = save.open_root().unwrap();
+        let mut file = root.open_sub_file(*b"secret\0\0\0\0\0\0\0\0\0\0").unwrap();
+        file.resize(0x300).unwrap();
+        file.rename(&root, *b"renamed\0\0\0\0\0\0\0\0\0").unwrap();
+        assert!(root.new_sub_dir(*b"saves\0\0\0\0\0\0\0\0\0\0\0").is_err());
+        drop((root, file));
+        save.commit().unwrap();
+        drop(save);
+        trace.flush().unwrap();
+
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        assert!(!log.contains("secret") && !log.contains("personal"));
+        assert!(log.lines().any(|line| line.starts_with("w 0 ")));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.bin");
+        let path = path.to_str().unwrap();
+        let report = replay_trace(log.as_bytes(), path).unwrap();
+        assert_eq!(report.divergences, vec![]);
+        assert_eq!(report.issues, vec![]);
+        assert!(report.operations > 5);
+        assert!(replay_trace(log.as_bytes(), path).is_err());
+
+        let file = open_file(Path::new(path), false, FileBackend::Disk).unwrap();
+        let replayed = SaveData::new(
+            file,
+            SaveDataType::Bare,
+            SelectorInversion::default(),
+            false,
+        )
+        .unwrap();
+        let mut lens: Vec<_> = walk(&replayed, "")
+            .unwrap()
+            .iter()
+            .map(|entry| match entry.is_dir {
+                true => None,
+                false => Some(replayed.open_file(entry.ino).unwrap().len()),
+            })
+            .collect();
+        lens.sort_unstable();
+        assert_eq!(lens, vec![None, Some(0x300), Some(0x1234)]);
+        drop(replayed);
+    }
+}
@@ -0,0 +1,339 @@
+use crate::error::*;
+use crate::file_system::*;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use sha2::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Debug, Write};
+
+/// A content difference found by [`diff_archives`](fn.diff_archives.html).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DiffEntry {
+    /// A file or a directory only the new archive has.
+    /// The content of an added directory is not listed separately.
+    Added { path: String, is_dir: bool },
+    /// A file or a directory only the old archive has.
+    /// The content of a removed directory is not listed separately.
+    Removed { path: String, is_dir: bool },
+    /// A file or a directory moved to another path with its content unchanged.
+    Renamed {
+        from: String,
+        to: String,
+        is_dir: bool,
+    },
+    /// A file whose content changed. `ranges` lists the differing bytes as `(offset, len)`,
+    /// including the bytes past the end of the shorter version.
+    Changed {
+        path: String,
+        old_len: usize,
+        new_len: usize,
+        ranges: Vec<(usize, usize)>,
+    },
+}
+
+/// The differences between two archives.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct ArchiveDiff {
+    pub entries: Vec<DiffEntry>,
+    /// Capacity and format properties that differ, as `(name, old, new)`.
+    #[serde(serialize_with = "serialize_properties")]
+    pub properties: Vec<(String, String, String)>,
+}
+
+// Serializes each property as an object with the fields `name`, `old` and `new`.
+fn serialize_properties<S: Serializer>(
+    properties: &[(String, String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    struct Property<'a>(&'a (String, String, String));
+    impl Serialize for Property<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let (name, old, new) = self.0;
+            let mut s = serializer.serialize_struct("Property", 3)?;
+            s.serialize_field("name", name)?;
+            s.serialize_field("old", old)?;
+            s.serialize_field("new", new)?;
+            s.end()
+        }
+    }
+    serializer.collect_seq(properties.iter().map(Property))
+}
+
+impl ArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.properties.is_empty()
+    }
+
+    /// Records the property `name` if `old` and `new` differ.
+    pub fn property(&mut self, name: &str, old: impl Debug, new: impl Debug) {
+        let (old, new) = (format!("{:?}", old), format!("{:?}", new));
+        if old != new {
+            self.properties.push((name.to_owned(), old, new));
+        }
+    }
+
+    /// Serializes the differences as a JSON object.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Renders the differences as human-readable text, one per line.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for (name, old, new) in self.properties.iter() {
+            writeln!(out, "property {}: {} -> {}", name, old, new).unwrap();
+        }
+        let kind = |is_dir: bool| if is_dir { "directory" } else { "file" };
+        for entry in self.entries.iter() {
+            match entry {
+                DiffEntry::Added { path, is_dir } => {
+                    writeln!(out, "added {} {}", kind(*is_dir), path).unwrap()
+                }
+                DiffEntry::Removed { path, is_dir } => {
+                    writeln!(out, "removed {} {}", kind(*is_dir), path).unwrap()
+                }
+                DiffEntry::Renamed { from, to, is_dir } => {
+                    writeln!(out, "renamed {} {} -> {}", kind(*is_dir), from, to).unwrap()
+                }
+                DiffEntry::Changed {
+                    path,
+                    old_len,
+                    new_len,
+                    ranges,
+                } => {
+                    write!(
+                        out,
+                        "changed file {} ({} -> {} bytes):",
+                        path, old_len, new_len
+                    )
+                    .unwrap();
+                    for (offset, len) in ranges.iter() {
+                        write!(out, " 0x{:X}+0x{:X}", offset, len).unwrap();
+                    }
+                    out.push('\n');
+                }
+            }
+        }
+        out
+    }
+}
+
+struct Node {
+    is_dir: bool,
+    /// An empty file or a directory with nothing in it, which is never matched as a rename.
+    empty: bool,
+    ino: u32,
+    len: usize,
+    hash: [u8; 32],
+}
+
+/// Reads the whole file. Fails with `Error::HashMismatch` if any block fails hash verification.
+fn read_file<T: FileSystem>(file_system: &T, ino: u32) -> Result<Vec<u8>, Error> {
+    let file = file_system.open_file(ino)?;
+    let mut data = vec![0; file.len()];
+    file.read(0, &mut data)?;
+    Ok(data)
+}
+
+/// Lists everything under `dir` into `nodes` keyed by path, and returns the hash of `dir`,
+/// which covers the names and the content of everything under it.
+fn collect<T: FileSystem>(
+    file_system: &T,
+    dir: &T::DirType,
+    prefix: &str,
+    nodes: &mut BTreeMap<String, Node>,
+) -> Result<[u8; 32], Error>
+where
+    T::NameType: NameConvert,
+{
+    let mut children = vec![];
+    for (name, ino) in dir.list_sub_dir()? {
+        let name = T::NameType::name_3ds_to_str(&name);
+        let path = format!("{}{}", prefix, name);
+        let sub_dir = file_system.open_dir(ino)?;
+        let empty = sub_dir.list_sub_dir()?.is_empty() && sub_dir.list_sub_file()?.is_empty();
+        let hash = collect(file_system, &sub_dir, &(path.clone() + "/"), nodes)?;
+        nodes.insert(
+            path,
+            Node {
+                is_dir: true,
+                empty,
+                ino,
+                len: 0,
+                hash,
+            },
+        );
+        children.push((name, true, hash));
+    }
+    for (name, ino) in dir.list_sub_file()? {
+        let name = T::NameType::name_3ds_to_str(&name);
+        let data = read_file(file_system, ino)?;
+        let hash = Sha256::digest(&data).into();
+        nodes.insert(
+            format!("{}{}", prefix, name),
+            Node {
+                is_dir: false,
+                empty: data.is_empty(),
+                ino,
+                len: data.len(),
+                hash,
+            },
+        );
+        children.push((name, false, hash));
+    }
+    children.sort();
+    let mut hasher = Sha256::new();
+    for (name, is_dir, hash) in children {
+        hasher.update(name.as_bytes());
+        hasher.update([0, is_dir as u8]);
+        hasher.update(hash);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Lists the ranges of differing bytes between `a` and `b`, as `(offset, len)`.
+fn diff_ranges(a: &[u8], b: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    let common = std::cmp::min(a.len(), b.len());
+    let mut push = |offset: usize, len: usize| match ranges.last_mut() {
+        Some((last_offset, last_len)) if *last_offset + *last_len == offset => *last_len += len,
+        _ => ranges.push((offset, len)),
+    };
+    for i in (0..common).filter(|&i| a[i] != b[i]) {
+        push(i, 1);
+    }
+    if a.len() != b.len() {
+        push(common, std::cmp::max(a.len(), b.len()) - common);
+    }
+    ranges
+}
+
+fn is_under(path: &str, dir: &str) -> bool {
+    path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+/// Groups the entries at `paths` of the kind `is_dir` by content, leaving out empty entries.
+fn group_by_content<'a>(
+    paths: &BTreeSet<&'a str>,
+    nodes: &BTreeMap<String, Node>,
+    is_dir: bool,
+) -> HashMap<[u8; 32], Vec<&'a str>> {
+    let mut groups: HashMap<[u8; 32], Vec<&str>> = HashMap::new();
+    for &path in paths.iter() {
+        let node = &nodes[path];
+        if node.is_dir == is_dir && !node.empty {
+            groups.entry(node.hash).or_default().push(path);
+        }
+    }
+    groups
+}
+
+/// Compares the content and the capacity of two archives.
+///
+/// Files and directories missing on one side are matched by content as renames,
+/// directories first. A rename is only reported if the content is not empty
+/// and exactly one missing entry on each side has it. Capacity properties come from [`stat`](trait.FileSystem.html#tymethod.stat);
+/// format properties specific to an archive type can be added with
+/// [`ArchiveDiff::property`](struct.ArchiveDiff.html#method.property).
+pub fn diff_archives<A: FileSystem, B: FileSystem<NameType = A::NameType>>(
+    old: &A,
+    new: &B,
+) -> Result<ArchiveDiff, Error>
+where
+    A::NameType: NameConvert,
+{
+    let mut diff = ArchiveDiff::default();
+    let (old_stat, new_stat) = (old.stat()?, new.stat()?);
+    diff.property("block_len", old_stat.block_len, new_stat.block_len);
+    diff.property("total_blocks", old_stat.total_blocks, new_stat.total_blocks);
+    diff.property("free_blocks", old_stat.free_blocks, new_stat.free_blocks);
+    diff.property("total_files", old_stat.total_files, new_stat.total_files);
+    diff.property("free_files", old_stat.free_files, new_stat.free_files);
+    diff.property("total_dirs", old_stat.total_dirs, new_stat.total_dirs);
+    diff.property("free_dirs", old_stat.free_dirs, new_stat.free_dirs);
+
+    let mut old_nodes = BTreeMap::new();
+    collect(old, &old.open_root()?, "", &mut old_nodes)?;
+    let mut new_nodes = BTreeMap::new();
+    collect(new, &new.open_root()?, "", &mut new_nodes)?;
+
+    let mut removed = BTreeSet::new();
+    let mut added: BTreeSet<&str> = new_nodes
+        .iter()
+        .filter(|(path, node)| !matches!(old_nodes.get(*path), Some(o) if o.is_dir == node.is_dir))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    let mut changed = vec![];
+    for (path, node) in old_nodes.iter() {
+        match new_nodes.get(path) {
+            Some(new_node) if new_node.is_dir == node.is_dir => {
+                if !node.is_dir && new_node.hash != node.hash {
+                    changed.push((path, node, new_node));
+                }
+            }
+            _ => {
+                removed.insert(path.as_str());
+            }
+        }
+    }
+
+    let mut renamed = vec![];
+    for is_dir in [true, false] {
+        let removed_groups = group_by_content(&removed, &old_nodes, is_dir);
+        let added_groups = group_by_content(&added, &new_nodes, is_dir);
+        let mut pairs: Vec<(&str, &str)> = removed_groups
+            .iter()
+            .filter_map(
+                |(hash, from)| match (from.as_slice(), added_groups.get(hash)) {
+                    (&[from], Some(to)) if to.len() == 1 => Some((from, to[0])),
+                    _ => None,
+                },
+            )
+            .collect();
+        pairs.sort_unstable();
+        for (from, to) in pairs {
+            // A directory renamed along with its parent is covered by the parent.
+            if !removed.contains(from) || !added.contains(to) {
+                continue;
+            }
+            removed.retain(|path| *path != from && !is_under(path, from));
+            added.retain(|path| *path != to && !is_under(path, to));
+            renamed.push(DiffEntry::Renamed {
+                from: from.to_owned(),
+                to: to.to_owned(),
+                is_dir,
+            });
+        }
+    }
+
+    diff.entries.extend(renamed);
+    for path in removed.iter() {
+        if !removed.iter().any(|dir| is_under(path, dir)) {
+            diff.entries.push(DiffEntry::Removed {
+                path: path.to_string(),
+                is_dir: old_nodes[*path].is_dir,
+            });
+        }
+    }
+    for path in added.iter() {
+        if !added.iter().any(|dir| is_under(path, dir)) {
+            diff.entries.push(DiffEntry::Added {
+                path: path.to_string(),
+                is_dir: new_nodes[*path].is_dir,
+            });
+        }
+    }
+    for (path, old_node, new_node) in changed {
+        diff.entries.push(DiffEntry::Changed {
+            path: path.clone(),
+            old_len: old_node.len,
+            new_len: new_node.len,
+            ranges: diff_ranges(
+                &read_file(old, old_node.ino)?,
+                &read_file(new, new_node.ino)?,
+            ),
+        });
+    }
+    Ok(diff)
+}
@@ -1,12 +1,13 @@
 use crate::byte_struct_common::*;
 use crate::error::*;
+use crate::inspect::InspectNode;
 use crate::memory_file::*;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::sub_file::SubFile;
-use std::cell::*;
 use std::collections::HashSet;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 pub fn crc16_ninty(data: &[u8]) -> u16 {
     let poly = 0xA001;
@@ -24,19 +25,19 @@ pub fn crc16_ninty(data: &[u8]) -> u16 {
     crc
 }
 
-trait CrcStub {
+trait CrcStub: Send + Sync {
     fn verify(&self, crc: u16) -> Result<bool, Error>;
     fn sign(&self, crc: u16) -> Result<(), Error>;
 }
 
 struct SimpleCrcStub<F> {
-    parent: Rc<F>,
+    parent: Arc<F>,
 }
 
 impl<F: RandomAccessFile> SimpleCrcStub<F> {
-    fn new(parent: Rc<F>) -> Result<SimpleCrcStub<F>, Error> {
+    fn new(parent: Arc<F>) -> Result<SimpleCrcStub<F>, Error> {
         if parent.len() != 2 {
-            return Err(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         Ok(SimpleCrcStub { parent })
     }
@@ -53,13 +54,13 @@ impl<F: RandomAccessFile> CrcStub for SimpleCrcStub<F> {
 }
 
 struct XorCrcStub<F> {
-    parent: Rc<F>,
+    parent: Arc<F>,
 }
 
 impl<F: RandomAccessFile> XorCrcStub<F> {
-    fn new(parent: Rc<F>) -> Result<XorCrcStub<F>, Error> {
+    fn new(parent: Arc<F>) -> Result<XorCrcStub<F>, Error> {
         if parent.len() != 1 {
-            return Err(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         Ok(XorCrcStub { parent })
     }
@@ -83,17 +84,17 @@ impl<F: RandomAccessFile> CrcStub for XorCrcStub<F> {
 
 struct CrcFile<C, F> {
     crc_stub: C,
-    data: Rc<F>,
+    data: Arc<F>,
     len: usize,
 }
 
 impl<C: CrcStub, F: RandomAccessFile> CrcFile<C, F> {
-    fn new(crc_stub: C, data: Rc<F>, initialized: bool) -> Result<CrcFile<C, F>, Error> {
+    fn new(crc_stub: C, data: Arc<F>, initialized: bool) -> Result<CrcFile<C, F>, Error> {
         let len = data.len();
         let mut buf = vec![0; len];
         data.read(0, &mut buf)?;
         if initialized && !crc_stub.verify(crc16_ninty(&buf))? {
-            return Err(Error::SignatureMismatch);
+            return make_error(Error::SignatureMismatch(Contexts::new()));
         }
         Ok(CrcFile {
             crc_stub,
@@ -121,21 +122,21 @@ impl<C: CrcStub, F: RandomAccessFile> RandomAccessFile for CrcFile<C, F> {
 }
 
 struct MirroredFile<F0, F1> {
-    data0: Rc<F0>,
-    data1: Rc<F1>,
+    data0: Arc<F0>,
+    data1: Arc<F1>,
 }
 
 impl<F0: RandomAccessFile, F1: RandomAccessFile> MirroredFile<F0, F1> {
-    fn new(data0: Rc<F0>, data1: Rc<F1>) -> Result<MirroredFile<F0, F1>, Error> {
+    fn new(data0: Arc<F0>, data1: Arc<F1>) -> Result<MirroredFile<F0, F1>, Error> {
         if data0.len() != data1.len() {
-            return Err(Error::SizeMismatch);
+            return make_error(Error::SizeMismatch(Contexts::new()));
         }
         let mut buf0 = vec![0; data0.len()];
         let mut buf1 = vec![0; data0.len()];
         data0.read(0, &mut buf0)?;
         data1.read(0, &mut buf1)?;
         if buf0 != buf1 {
-            return Err(Error::SignatureMismatch);
+            return make_error(Error::SignatureMismatch(Contexts::new()));
         }
         Ok(MirroredFile { data0, data1 })
     }
@@ -161,23 +162,29 @@ struct WearLevelingBlock {
     physical_block: u8,
     allocate_count: u8,
     initialized: bool,
-    dirty: bool,
-    crc_ticket: Option<Rc<MemoryFile>>,
+    crc_ticket: Option<Arc<MemoryFile>>,
     data: Vec<Box<dyn RandomAccessFile>>,
+    // The new content of the whole block, written to another physical block on commit
+    pending: Option<Vec<u8>>,
 }
 
 pub struct WearLeveling {
-    block_map: Rc<CrcFile<SimpleCrcStub<SubFile>, SubFile>>,
-    journal_list: Rc<SubFile>,
-    blocks: RefCell<Vec<WearLevelingBlock>>,
+    parent: Arc<dyn RandomAccessFile>,
+    block_map: Arc<CrcFile<SimpleCrcStub<SubFile>, SubFile>>,
+    journal_list: Arc<SubFile>,
+    journal_entries: AtomicUsize,
+    blocks: Mutex<Vec<WearLevelingBlock>>,
     large_save: bool,
 }
 
 impl WearLeveling {
-    pub fn format(parent: Rc<dyn RandomAccessFile>) -> Result<(), Error> {
+    pub fn format(parent: Arc<dyn RandomAccessFile>) -> Result<(), Error> {
         let len = parent.len();
         if len != 0x20_000 && len != 0x80_000 && len != 0x100_000 {
-            return Err(Error::SizeMismatch);
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::WearLeveling),
+            );
         }
         let large_save = len == 0x100_000;
 
@@ -189,9 +196,9 @@ impl WearLeveling {
             8 + virtual_block_count * 10
         };
 
-        let block_map = Rc::new(SubFile::new(parent.clone(), 0, block_map_len)?);
-        let block_map_crc = Rc::new(SubFile::new(parent.clone(), block_map_len, 2)?);
-        let block_map = Rc::new(CrcFile::new(
+        let block_map = Arc::new(SubFile::new(parent.clone(), 0, block_map_len)?);
+        let block_map_crc = Arc::new(SubFile::new(parent.clone(), block_map_len, 2)?);
+        let block_map = Arc::new(CrcFile::new(
             SimpleCrcStub::new(block_map_crc)?,
             block_map,
             false,
@@ -217,7 +224,7 @@ impl WearLeveling {
         block_map.commit()?;
 
         let journal_start = block_map_len + 2;
-        let journal_list = Rc::new(SubFile::new(
+        let journal_list = Arc::new(SubFile::new(
             parent.clone(),
             journal_start,
             0x1000 - journal_start,
@@ -230,10 +237,13 @@ impl WearLeveling {
         Ok(())
     }
 
-    pub fn new(parent: Rc<dyn RandomAccessFile>) -> Result<WearLeveling, Error> {
+    pub fn new(parent: Arc<dyn RandomAccessFile>) -> Result<WearLeveling, Error> {
         let len = parent.len();
         if len != 0x20_000 && len != 0x80_000 && len != 0x100_000 {
-            return Err(Error::SizeMismatch);
+            return make_error_at(
+                Error::SizeMismatch(Contexts::new()),
+                ErrorContext::new(Layer::WearLeveling),
+            );
         }
         let large_save = len == 0x100_000;
         let physical_block_count = len / 0x1000;
@@ -245,13 +255,12 @@ impl WearLeveling {
             8 + virtual_block_count * 10
         };
 
-        let block_map = Rc::new(SubFile::new(parent.clone(), 0, block_map_len)?);
-        let block_map_crc = Rc::new(SubFile::new(parent.clone(), block_map_len, 2)?);
-        let block_map = Rc::new(CrcFile::new(
-            SimpleCrcStub::new(block_map_crc)?,
-            block_map,
-            true,
-        )?);
+        let block_map = Arc::new(SubFile::new(parent.clone(), 0, block_map_len)?);
+        let block_map_crc = Arc::new(SubFile::new(parent.clone(), block_map_len, 2)?);
+        let block_map = Arc::new(
+            CrcFile::new(SimpleCrcStub::new(block_map_crc)?, block_map, true)
+                .context(|| ErrorContext::new(Layer::WearLeveling).offset(0))?,
+        );
 
         struct Block {
             physical_block: u8,
@@ -289,23 +298,32 @@ impl WearLeveling {
         }
 
         let mut physical_block_set: HashSet<_> = (1..physical_block_count).collect();
-        for block in blocks.iter() {
+        for (i, block) in blocks.iter().enumerate() {
             if !physical_block_set.remove(&(block.physical_block as usize)) {
-                return Err(Error::InvalidValue);
+                return make_error_at(
+                    Error::InvalidValue(Contexts::new()),
+                    ErrorContext::new(Layer::WearLeveling).offset(i * item_len + 8),
+                );
             }
         }
 
         let journal_start = block_map_len + 2;
-        let journal_list = Rc::new(SubFile::new(
+        let journal_list = Arc::new(SubFile::new(
             parent.clone(),
             journal_start,
             0x1000 - journal_start,
         )?);
 
+        let mut journal_entries = 0;
         for offset in (0..journal_list.len()).step_by(0x20) {
-            let journal0 = Rc::new(SubFile::new(journal_list.clone(), offset, 14)?);
-            let journal1 = Rc::new(SubFile::new(journal_list.clone(), offset + 14, 14)?);
-            let journal = MirroredFile::new(journal0, journal1)?;
+            let journal0 = Arc::new(SubFile::new(journal_list.clone(), offset, 14)?);
+            let journal1 = Arc::new(SubFile::new(journal_list.clone(), offset + 14, 14)?);
+            let journal_context = || {
+                ErrorContext::new(Layer::WearLeveling)
+                    .index(offset / 0x20)
+                    .offset(journal_start + offset)
+            };
+            let journal = MirroredFile::new(journal0, journal1).context(journal_context)?;
             let mut buf = [0; 6];
             journal.read(0, &mut buf)?;
             let virtual_block = buf[0] as usize;
@@ -317,39 +335,40 @@ impl WearLeveling {
             if virtual_block == 0xFF {
                 break;
             }
+            journal_entries += 1;
 
             if virtual_block >= virtual_block_count {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
             if virtual_block_prev >= virtual_block_count {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
             if physical_block as usize >= physical_block_count || physical_block == 0 {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
             if physical_block_prev as usize >= physical_block_count || physical_block_prev == 0 {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
 
             if blocks[virtual_block].physical_block != physical_block_prev {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
 
             if blocks[virtual_block_prev].physical_block != physical_block {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
 
             if blocks[virtual_block_prev].initialized {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
 
             if blocks[virtual_block].allocate_count != allocate_count_prev {
-                return Err(Error::InvalidValue);
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
 
             // Wrapping???
-            if blocks[virtual_block_prev].allocate_count != allocate_count - 1 {
-                return Err(Error::InvalidValue);
+            if blocks[virtual_block_prev].allocate_count != allocate_count.wrapping_sub(1) {
+                return make_error_at(Error::InvalidValue(Contexts::new()), journal_context());
             }
 
             blocks[virtual_block_prev].allocate_count = allocate_count_prev;
@@ -363,58 +382,220 @@ impl WearLeveling {
             blocks[virtual_block].initialized = true;
             if !large_save {
                 blocks[virtual_block].crc_ticket = Some(MemoryFile::from_file(
-                    &(SubFile::new(Rc::new(journal), 6, 8)?),
+                    &(SubFile::new(Arc::new(journal), 6, 8)?),
                 )?);
             }
         }
 
         if blocks.last().unwrap().initialized {
-            return Err(Error::InvalidValue);
+            return make_error_at(
+                Error::InvalidValue(Contexts::new()),
+                ErrorContext::new(Layer::WearLeveling)
+                    .offset((virtual_block_count - 1) * item_len + 8),
+            );
         }
 
         let mut final_blocks = vec![];
         for block in blocks {
-            let mut data_list: Vec<Box<dyn RandomAccessFile>> = vec![];
-            let crc_ticket = block.crc_ticket.map(Rc::new);
-            for i in 0..8 {
-                let offset = i * 0x200 + block.physical_block as usize * 0x1000;
-                let data = SubFile::new(parent.clone(), offset, 0x200)?;
-                let data: Box<dyn RandomAccessFile> = if let Some(crc_ticket) = crc_ticket.clone() {
-                    let crc = Rc::new(SubFile::new(crc_ticket.clone(), i, 1)?);
-                    Box::new(CrcFile::new(
-                        XorCrcStub::new(crc)?,
-                        Rc::new(data),
-                        block.initialized,
-                    )?)
-                } else {
-                    Box::new(data)
-                };
-                data_list.push(data);
-            }
+            let crc_ticket = block.crc_ticket.map(Arc::new);
+            let data = block_data(
+                &parent,
+                block.physical_block,
+                crc_ticket.as_ref(),
+                block.initialized,
+            )?;
             final_blocks.push(WearLevelingBlock {
                 physical_block: block.physical_block,
                 allocate_count: block.allocate_count,
                 initialized: block.initialized,
-                dirty: false,
                 crc_ticket,
-                data: data_list,
+                data,
+                pending: None,
             });
         }
 
         Ok(WearLeveling {
+            parent: parent.clone(),
             block_map,
             journal_list,
-            blocks: RefCell::new(final_blocks),
+            journal_entries: AtomicUsize::new(journal_entries),
+            blocks: Mutex::new(final_blocks),
             large_save,
         })
     }
+
+    // Moves the pending content of `block_index` into the physical block of the spare block,
+    // which is the last one and never initialized, and records the swap in the journal.
+    // The old physical block becomes the spare one.
+    fn relocate(&self, blocks: &mut [WearLevelingBlock], block_index: usize) -> Result<(), Error> {
+        let spare_index = blocks.len() - 1;
+        let pending = blocks[block_index].pending.take().unwrap();
+        let spare = &blocks[spare_index];
+        let crc_ticket = if self.large_save {
+            None
+        } else {
+            Some(Arc::new(MemoryFile::new(vec![0; 8])))
+        };
+        let data = block_data(
+            &self.parent,
+            spare.physical_block,
+            crc_ticket.as_ref(),
+            false,
+        )?;
+        for (chunk, buf) in data.iter().zip(pending.chunks(0x200)) {
+            chunk.write(0, buf)?;
+            chunk.commit()?;
+        }
+        // The new data must land before the journal points to it
+        self.parent.commit()?;
+
+        let mask = if self.large_save { 0x7F } else { 0xFF };
+        let allocate_count = spare.allocate_count.wrapping_add(1) & mask;
+        // The journal can't record an allocation count that wraps around
+        let wraps = allocate_count < spare.allocate_count;
+        let block = &blocks[block_index];
+        let mut entry = [0xFF; 0x1C];
+        entry[0..6].copy_from_slice(&[
+            block_index as u8,
+            spare_index as u8,
+            spare.physical_block,
+            block.physical_block,
+            allocate_count,
+            block.allocate_count,
+        ]);
+        if let Some(crc_ticket) = &crc_ticket {
+            crc_ticket.read(0, &mut entry[6..14])?;
+        }
+        entry.copy_within(0..14, 14);
+
+        let old = (block.physical_block, block.allocate_count);
+        let new = (spare.physical_block, allocate_count);
+        blocks[block_index].physical_block = new.0;
+        blocks[block_index].allocate_count = new.1;
+        blocks[block_index].initialized = true;
+        blocks[block_index].crc_ticket = crc_ticket;
+        blocks[block_index].data = data;
+        blocks[spare_index].physical_block = old.0;
+        blocks[spare_index].allocate_count = old.1;
+        if !self.large_save {
+            blocks[spare_index].crc_ticket = Some(Arc::new(MemoryFile::new(vec![0; 8])));
+        }
+        blocks[spare_index].data = block_data(
+            &self.parent,
+            old.0,
+            blocks[spare_index].crc_ticket.as_ref(),
+            false,
+        )?;
+
+        let journal_entries = self.journal_entries.load(Ordering::Relaxed);
+        if (journal_entries + 1) * 0x20 > self.journal_list.len() || wraps {
+            self.squash(blocks)?;
+        } else {
+            self.journal_list
+                .write(journal_entries * 0x20, &entry[..])?;
+            self.journal_entries
+                .store(journal_entries + 1, Ordering::Relaxed);
+        }
+        // The old physical block may only be reused after the journal no longer points to it
+        self.parent.commit()
+    }
+
+    // Writes the block map as of `blocks` and clears the journal, in one write.
+    fn squash(&self, blocks: &[WearLevelingBlock]) -> Result<(), Error> {
+        let block_map_len = self.block_map.len();
+        let mut buf = vec![0xFF; 0x1000];
+        self.block_map.read(0, &mut buf[0..block_map_len])?;
+        let item_len = if self.large_save { 2 } else { 10 };
+        for (i, block) in blocks.iter().enumerate() {
+            let item = &mut buf[8 + item_len * i..8 + item_len * (i + 1)];
+            if self.large_save {
+                item[0] = block.allocate_count + ((block.initialized as u8) << 7);
+                item[1] = block.physical_block;
+            } else {
+                item[0] = block.physical_block + ((block.initialized as u8) << 7);
+                item[1] = block.allocate_count;
+            }
+            if let Some(crc_ticket) = &block.crc_ticket {
+                crc_ticket.read(0, &mut item[2..10])?;
+            }
+        }
+        let crc = crc16_ninty(&buf[0..block_map_len]);
+        buf[block_map_len..block_map_len + 2].copy_from_slice(&crc.to_le_bytes());
+        self.parent.write(0, &buf)?;
+        self.journal_entries.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Describes the block map and the journal, and where each virtual block is mapped
+    /// in the raw image.
+    pub fn inspect(&self) -> Result<InspectNode, Error> {
+        let blocks = self.blocks.lock().unwrap();
+        let mut journal_entries = 0usize;
+        for offset in (0..self.journal_list.len()).step_by(0x20) {
+            let mut virtual_block = [0];
+            self.journal_list.read(offset, &mut virtual_block)?;
+            if virtual_block[0] == 0xFF {
+                break;
+            }
+            journal_entries += 1;
+        }
+
+        let journal_start = self.block_map.len() + 2;
+        let mut node = InspectNode::new("wear leveling")
+            .at(0, (blocks.len() + 1) * 0x1000)
+            .field("large_save", self.large_save)
+            .child(InspectNode::new("block map").at(0, journal_start))
+            .child(
+                InspectNode::new("journal")
+                    .at(journal_start, self.journal_list.len())
+                    .field("entries", journal_entries),
+            );
+        for (i, block) in blocks.iter().enumerate() {
+            node = node.child(
+                InspectNode::new("virtual block")
+                    .at(block.physical_block as usize * 0x1000, 0x1000)
+                    .field("index", i)
+                    .field("physical_block", block.physical_block)
+                    .field("allocate_count", block.allocate_count)
+                    .field("initialized", block.initialized),
+            );
+        }
+        Ok(node)
+    }
 }
 
-const CHUNK_INIT: [u8; 0x200] = [0xFF; 0x200];
+// Makes the chunks of a physical block, each with its CRC in `crc_ticket` for small saves,
+// which is verified if the block is `initialized`.
+fn block_data(
+    parent: &Arc<dyn RandomAccessFile>,
+    physical_block: u8,
+    crc_ticket: Option<&Arc<MemoryFile>>,
+    initialized: bool,
+) -> Result<Vec<Box<dyn RandomAccessFile>>, Error> {
+    let mut data_list: Vec<Box<dyn RandomAccessFile>> = vec![];
+    for i in 0..8 {
+        let offset = i * 0x200 + physical_block as usize * 0x1000;
+        let data = SubFile::new(parent.clone(), offset, 0x200)?;
+        let data: Box<dyn RandomAccessFile> = if let Some(crc_ticket) = crc_ticket {
+            let crc = Arc::new(SubFile::new(crc_ticket.clone(), i, 1)?);
+            Box::new(
+                CrcFile::new(XorCrcStub::new(crc)?, Arc::new(data), initialized)
+                    .context(|| ErrorContext::new(Layer::WearLeveling).offset(offset))?,
+            )
+        } else {
+            Box::new(data)
+        };
+        data_list.push(data);
+    }
+    Ok(data_list)
+}
 
 impl RandomAccessFile for WearLeveling {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        let end = pos + buf.len();
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
         // chunk index range the operation covers
         let begin_chunk = pos / 0x200;
@@ -429,15 +610,16 @@ impl RandomAccessFile for WearLeveling {
             let data_begin = std::cmp::max(data_begin_as_chunk, pos);
             let data_end = std::cmp::min(data_end_as_chunk, end);
 
-            let block = &self.blocks.borrow()[i / 8];
-            if block.initialized {
+            let block = &self.blocks.lock().unwrap()[i / 8];
+            let buf = &mut buf[data_begin - pos..data_end - pos];
+            if let Some(pending) = &block.pending {
+                let block_begin = (i / 8) * 0x1000;
+                buf.copy_from_slice(&pending[data_begin - block_begin..data_end - block_begin]);
+            } else if block.initialized {
                 let chunk = i % 8;
-                block.data[chunk].read(
-                    data_begin - data_begin_as_chunk,
-                    &mut buf[data_begin - pos..data_end - pos],
-                )?
+                block.data[chunk].read(data_begin - data_begin_as_chunk, buf)?
             } else {
-                for i in buf[data_begin - pos..data_end - pos].iter_mut() {
+                for i in buf.iter_mut() {
                     *i = 0xFF;
                 }
             }
@@ -446,88 +628,61 @@ impl RandomAccessFile for WearLeveling {
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        // TODO: implement proper reallocating
-
-        let end = pos + buf.len();
-
-        // chunk index range the operation covers
-        let begin_chunk = pos / 0x200;
-        let end_chunk = divide_up(end, 0x200);
-
-        for i in begin_chunk..end_chunk {
-            // data range of this chunk
-            let data_begin_as_chunk = i * 0x200;
-            let data_end_as_chunk = (i + 1) * 0x200;
-
-            // data range to read within this chunk
-            let data_begin = std::cmp::max(data_begin_as_chunk, pos);
-            let data_end = std::cmp::min(data_end_as_chunk, end);
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return make_error(Error::OutOfBound(Contexts::new())),
+        };
 
-            let block = &mut self.blocks.borrow_mut()[i / 8];
-            if !block.initialized {
-                block.initialized = true;
-                if block.allocate_count == 0 {
-                    block.allocate_count = 1;
-                }
-                for chunk in block.data.iter() {
-                    chunk.write(0, &CHUNK_INIT)?;
+        // block index range the operation covers
+        let begin_block = pos / 0x1000;
+        let end_block = divide_up(end, 0x1000);
+
+        let mut blocks = self.blocks.lock().unwrap();
+        for i in begin_block..end_block {
+            // data range of this block
+            let data_begin_as_block = i * 0x1000;
+            let data_end_as_block = (i + 1) * 0x1000;
+
+            // data range to write within this block
+            let data_begin = std::cmp::max(data_begin_as_block, pos);
+            let data_end = std::cmp::min(data_end_as_block, end);
+
+            // Blocks are never written in place, but moved to another physical block on commit
+            let block = &mut blocks[i];
+            if block.pending.is_none() {
+                let mut pending = vec![0xFF; 0x1000];
+                if block.initialized {
+                    for (chunk, buf) in block.data.iter().zip(pending.chunks_mut(0x200)) {
+                        chunk.read(0, buf)?;
+                    }
                 }
+                block.pending = Some(pending);
             }
-
-            block.dirty = true;
-
-            let chunk = i % 8;
-            block.data[chunk].write(
-                data_begin - data_begin_as_chunk,
-                &buf[data_begin - pos..data_end - pos],
-            )?
+            block.pending.as_mut().unwrap()
+                [data_begin - data_begin_as_block..data_end - data_begin_as_block]
+                .copy_from_slice(&buf[data_begin - pos..data_end - pos]);
         }
 
         Ok(())
     }
     fn len(&self) -> usize {
         // -1 for the reserved block
-        (self.blocks.borrow().len() - 1) * 0x1000
+        (self.blocks.lock().unwrap().len() - 1) * 0x1000
     }
     fn commit(&self) -> Result<(), Error> {
-        // TODO: implement proper reallocating and journal recording.
-        // we now simply squash the journal.
-        let item_len = if self.large_save { 2 } else { 10 };
-        for (i, block) in self.blocks.borrow_mut().iter_mut().enumerate() {
-            if block.initialized && block.dirty {
-                for data in block.data.iter() {
-                    data.commit()?;
-                }
-                block.dirty = false;
-            }
-
-            let buf = if self.large_save {
-                [
-                    block.allocate_count + ((block.initialized as u8) << 7),
-                    block.physical_block,
-                ]
-            } else {
-                [
-                    block.physical_block + ((block.initialized as u8) << 7),
-                    block.allocate_count,
-                ]
-            };
-
-            self.block_map.write(8 + item_len * i, &buf)?;
-
-            if let Some(block_crc_ticket) = &block.crc_ticket {
-                let mut crc_ticket = [0; 8];
-                block_crc_ticket.read(0, &mut crc_ticket)?;
-                self.block_map.write(8 + item_len * i + 2, &crc_ticket)?;
+        let mut blocks = self.blocks.lock().unwrap();
+        for i in 0..blocks.len() {
+            if blocks[i].pending.is_some() {
+                self.relocate(&mut blocks, i)?;
             }
         }
-
-        self.block_map.commit()?;
-
-        for offset in 0..self.journal_list.len() {
-            self.journal_list.write(offset, &[0xFF])?;
+        self.parent.commit()
+    }
+    fn rollback(&self) -> Result<(), Error> {
+        // Nothing is written to the blocks in use before commit
+        for block in self.blocks.lock().unwrap().iter_mut() {
+            block.pending = None;
         }
-
         Ok(())
     }
 }
@@ -546,8 +701,8 @@ pub mod test {
             let len = rng.gen_range(1..100);
             let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
             let crc = crc16_ninty(&init).to_le_bytes().to_vec();
-            let crc = Rc::new(MemoryFile::new(crc));
-            let data = Rc::new(MemoryFile::new(init));
+            let crc = Arc::new(MemoryFile::new(crc));
+            let data = Arc::new(MemoryFile::new(init));
             let file =
                 CrcFile::new(SimpleCrcStub::new(crc.clone()).unwrap(), data.clone(), true).unwrap();
             let mut buf = vec![0; len];
@@ -574,8 +729,8 @@ pub mod test {
             let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
             let crc = crc16_ninty(&init).to_le_bytes();
             let crc = vec![crc[0] ^ crc[1]];
-            let crc = Rc::new(MemoryFile::new(crc));
-            let data = Rc::new(MemoryFile::new(init));
+            let crc = Arc::new(MemoryFile::new(crc));
+            let data = Arc::new(MemoryFile::new(init));
             let file =
                 CrcFile::new(XorCrcStub::new(crc.clone()).unwrap(), data.clone(), true).unwrap();
             let mut buf = vec![0; len];
@@ -598,8 +753,8 @@ pub mod test {
             let len = rng.gen_range(1..100);
             let init0: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
             let init1: Vec<u8> = init0.clone();
-            let data0 = Rc::new(MemoryFile::new(init0));
-            let data1 = Rc::new(MemoryFile::new(init1));
+            let data0 = Arc::new(MemoryFile::new(init0));
+            let data1 = Arc::new(MemoryFile::new(init1));
             let file = MirroredFile::new(data0.clone(), data1.clone()).unwrap();
             let mut buf = vec![0; len];
             file.read(0, &mut buf).unwrap();
@@ -620,7 +775,7 @@ pub mod test {
         for i in 0..10 {
             let len = if rng.gen() { 0x20_000 } else { 0x80_000 };
             let virtual_block_count = len / 0x1000 - 1;
-            let init = Rc::new(MemoryFile::new(vec![0xFF; len]));
+            let init = Arc::new(MemoryFile::new(vec![0xFF; len]));
             let plain = MemoryFile::new(vec![0xFF; len - 0x2000]);
 
             if i % 2 == 0 {
@@ -629,10 +784,10 @@ pub mod test {
                 blocks[..].shuffle(&mut rng);
 
                 let block_map =
-                    Rc::new(SubFile::new(init.clone(), 0, 8 + virtual_block_count * 10).unwrap());
+                    Arc::new(SubFile::new(init.clone(), 0, 8 + virtual_block_count * 10).unwrap());
                 let block_map_crc =
-                    Rc::new(SubFile::new(init.clone(), 8 + virtual_block_count * 10, 2).unwrap());
-                let block_map = Rc::new(
+                    Arc::new(SubFile::new(init.clone(), 8 + virtual_block_count * 10, 2).unwrap());
+                let block_map = Arc::new(
                     CrcFile::new(SimpleCrcStub::new(block_map_crc).unwrap(), block_map, false)
                         .unwrap(),
                 );
@@ -665,7 +820,7 @@ pub mod test {
         let mut rng = rand::thread_rng();
         for i in 0..10 {
             let len = 0x100_000;
-            let init = Rc::new(MemoryFile::new(vec![0xFF; len]));
+            let init = Arc::new(MemoryFile::new(vec![0xFF; len]));
             let plain = MemoryFile::new(vec![0xFF; len - 0x2000]);
 
             if i % 2 == 0 {
@@ -673,9 +828,9 @@ pub mod test {
                 let mut blocks: Vec<_> = (1..=255).collect();
                 blocks[..].shuffle(&mut rng);
 
-                let block_map = Rc::new(SubFile::new(init.clone(), 0, 0x3FE).unwrap());
-                let block_map_crc = Rc::new(SubFile::new(init.clone(), 0x3FE, 2).unwrap());
-                let block_map = Rc::new(
+                let block_map = Arc::new(SubFile::new(init.clone(), 0, 0x3FE).unwrap());
+                let block_map_crc = Arc::new(SubFile::new(init.clone(), 0x3FE, 2).unwrap());
+                let block_map = Arc::new(
                     CrcFile::new(SimpleCrcStub::new(block_map_crc).unwrap(), block_map, false)
                         .unwrap(),
                 );
@@ -704,4 +859,56 @@ pub mod test {
             );
         }
     }
+
+    #[test]
+    fn rollback() {
+        for &len in &[0x20_000, 0x100_000] {
+            let raw = Arc::new(MemoryFile::new(vec![0xFF; len]));
+            WearLeveling::format(raw.clone()).unwrap();
+            let file = WearLeveling::new(raw.clone()).unwrap();
+            file.write(0x100, &[1; 0x2000]).unwrap();
+            file.commit().unwrap();
+            let mut committed = vec![0; file.len()];
+            file.read(0, &mut committed).unwrap();
+
+            file.write(0x800, &[2; 0x1000]).unwrap();
+            file.write(0x5000, &[3; 0x300]).unwrap();
+            file.rollback().unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, committed);
+
+            file.write(0x6000, &[4; 0x10]).unwrap();
+            file.commit().unwrap();
+            committed[0x6000..0x6010].copy_from_slice(&[4; 0x10]);
+            let file = WearLeveling::new(raw.clone()).unwrap();
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, committed);
+        }
+    }
+
+    #[test]
+    fn journal() {
+        for &len in &[0x20_000, 0x100_000] {
+            let raw = Arc::new(MemoryFile::new(vec![0xFF; len]));
+            WearLeveling::format(raw.clone()).unwrap();
+            let file = WearLeveling::new(raw.clone()).unwrap();
+            let mut expected = vec![0xFF; file.len()];
+            // More commits than the journal holds, so that it is squashed into the block map
+            for i in 0..200 {
+                let pos = (i * 0x1234) % (file.len() - 0x10);
+                file.write(pos, &[i as u8; 0x10]).unwrap();
+                expected[pos..pos + 0x10].copy_from_slice(&[i as u8; 0x10]);
+                file.commit().unwrap();
+                if i == 0 {
+                    assert_eq!(file.journal_entries.load(Ordering::Relaxed), 1);
+                }
+
+                let reopened = WearLeveling::new(raw.clone()).unwrap();
+                let mut buf = vec![0; file.len()];
+                reopened.read(0, &mut buf).unwrap();
+                assert_eq!(buf, expected);
+            }
+        }
+    }
 }
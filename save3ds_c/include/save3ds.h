@@ -0,0 +1,200 @@
+/*
+ * C interface of libsave3ds.
+ *
+ * Link against the static or shared library built from the save3ds_c crate.
+ *
+ * All objects are opaque handles, released with the matching `*_free` function.
+ * Directory and file handles keep their archive alive, so they stay valid after the archive
+ * handle is freed.
+ *
+ * Functions returning `int` return SAVE3DS_OK on success, or one of the error codes below.
+ * A description of the last failure on the calling thread is given by save3ds_last_error.
+ *
+ * Names of files and directories are NUL-terminated strings in the same form as the paths
+ * of save3ds_fuse: save data and extdata names are their bytes up to the trailing zeros, with
+ * bytes other than printable ASCII, '/' and '\' written as "\xNN"; title database names are
+ * title IDs in 16 hexadecimal digits.
+ *
+ * Handles may be shared between threads, except that a function taking a non-const handle
+ * needs exclusive access to it.
+ */
+
+#ifndef SAVE3DS_H
+#define SAVE3DS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Status codes. Except SAVE3DS_ERROR_INVALID_ARGUMENT and SAVE3DS_ERROR_PANIC, each maps to a
+ * libsave3ds Error variant.
+ */
+#define SAVE3DS_OK 0
+#define SAVE3DS_ERROR_INVALID_ARGUMENT (-1) /* null pointer, bad UTF-8 or mismatched handles */
+/*
+ * An internal error of libsave3ds, caught before it reaches C. The handles passed to the call
+ * may be left in an inconsistent state: free them without committing.
+ */
+#define SAVE3DS_ERROR_PANIC (-2)
+#define SAVE3DS_ERROR_IO 1
+#define SAVE3DS_ERROR_HASH_MISMATCH 2
+#define SAVE3DS_ERROR_OUT_OF_BOUND 3
+#define SAVE3DS_ERROR_MAGIC_MISMATCH 4
+#define SAVE3DS_ERROR_SIZE_MISMATCH 5
+#define SAVE3DS_ERROR_INVALID_VALUE 6
+#define SAVE3DS_ERROR_BROKEN_FAT 7
+#define SAVE3DS_ERROR_NO_SPACE 8
+#define SAVE3DS_ERROR_NOT_FOUND 9
+#define SAVE3DS_ERROR_ALREADY_EXIST 10
+#define SAVE3DS_ERROR_DELETING_ROOT 11
+#define SAVE3DS_ERROR_SIGNATURE_MISMATCH 12
+#define SAVE3DS_ERROR_MISSING_BOOT9 13
+#define SAVE3DS_ERROR_MISSING_SD 14
+#define SAVE3DS_ERROR_MISSING_NAND 15
+#define SAVE3DS_ERROR_MISSING_GAME 16
+#define SAVE3DS_ERROR_MISSING_PRIV 17
+#define SAVE3DS_ERROR_MISSING_KEY_Y_2F 18
+#define SAVE3DS_ERROR_MISSING_KEY_X_19 19
+#define SAVE3DS_ERROR_MISSING_KEY_X_1A 20
+#define SAVE3DS_ERROR_MISSING_OTP 21
+#define SAVE3DS_ERROR_BROKEN_SD 22
+#define SAVE3DS_ERROR_NOT_EMPTY 23
+#define SAVE3DS_ERROR_UNSUPPORTED 24
+#define SAVE3DS_ERROR_UNIQUE_ID_MISMATCH 25
+#define SAVE3DS_ERROR_BROKEN_OTP 26
+#define SAVE3DS_ERROR_BUSY 27
+#define SAVE3DS_ERROR_BROKEN_GAME 28
+#define SAVE3DS_ERROR_NOT_A_DIRECTORY 29
+#define SAVE3DS_ERROR_IS_A_DIRECTORY 30
+#define SAVE3DS_ERROR_INVALID_NAME 31
+#define SAVE3DS_ERROR_NO_TRANSACTION 32
+
+/* Title database types for save3ds_open_db. */
+#define SAVE3DS_DB_TICKET 0
+#define SAVE3DS_DB_NAND_TITLE 1
+#define SAVE3DS_DB_NAND_IMPORT 2
+#define SAVE3DS_DB_TMP_TITLE 3
+#define SAVE3DS_DB_TMP_IMPORT 4
+#define SAVE3DS_DB_SD_TITLE 5
+#define SAVE3DS_DB_SD_IMPORT 6
+
+typedef struct save3ds_resource save3ds_resource;
+typedef struct save3ds_archive save3ds_archive;
+typedef struct save3ds_dir save3ds_dir;
+typedef struct save3ds_file save3ds_file;
+
+/* Parameters of save3ds_resource_new. Every member is optional and may be NULL.
+ * Keys point to 16 bytes each. */
+typedef struct save3ds_resource_param {
+    const char *boot9_path;
+    const char *movable_path;
+    const char *sd_path;
+    const char *nand_path;
+    const char *otp_path;
+    const char *priv_path;
+    const char *game_path;
+    const uint8_t *x2f_key_y;
+    const uint8_t *x19_key_x;
+    const uint8_t *x1a_key_x;
+} save3ds_resource_param;
+
+/* Parameters of save3ds_format_bare_save. */
+typedef struct save3ds_save_format_param {
+    int large_block; /* non-zero for 4096-byte blocks, zero for 512-byte blocks */
+    size_t max_dir;
+    size_t dir_buckets;
+    size_t max_file;
+    size_t file_buckets;
+    int duplicate_data;
+} save3ds_save_format_param;
+
+/* Capacity of an archive, as returned by save3ds_archive_stat. */
+typedef struct save3ds_stat {
+    uint64_t block_len;
+    uint64_t total_blocks;
+    uint64_t free_blocks;
+    uint64_t total_files;
+    uint64_t free_files;
+    uint64_t total_dirs;
+    uint64_t free_dirs;
+} save3ds_stat;
+
+/* Called by save3ds_dir_list for each entry. `name` is only valid during the call. */
+typedef void (*save3ds_list_callback)(void *user, const char *name, uint32_t ino, int is_dir);
+
+/* Returns the description of the last failure on this thread, or NULL if nothing failed yet.
+ * The string is valid until the next failing call on this thread. */
+const char *save3ds_last_error(void);
+
+/* Resource: the keys and paths of a console. See libsave3ds::Resource::new. */
+int save3ds_resource_new(const save3ds_resource_param *param, save3ds_resource **out);
+void save3ds_resource_free(save3ds_resource *resource);
+
+/* Creates a stand-alone save data image of `len` bytes at `path`. */
+int save3ds_format_bare_save(const save3ds_resource *resource, const char *path,
+                             const save3ds_save_format_param *param, size_t len);
+
+/* Opening archives. Pass non-zero `write` to allow changes. */
+int save3ds_open_sd_save(const save3ds_resource *resource, uint64_t id, int write,
+                         save3ds_archive **out);
+int save3ds_open_nand_save(const save3ds_resource *resource, uint32_t id, int write,
+                           save3ds_archive **out);
+int save3ds_open_bare_save(const save3ds_resource *resource, const char *path, int write,
+                           save3ds_archive **out);
+int save3ds_open_cart_save(const save3ds_resource *resource, const char *path, int write,
+                           save3ds_archive **out);
+int save3ds_open_sd_ext(const save3ds_resource *resource, uint64_t id, int write,
+                        save3ds_archive **out);
+int save3ds_open_nand_ext(const save3ds_resource *resource, uint64_t id, int write,
+                          save3ds_archive **out);
+int save3ds_open_db(const save3ds_resource *resource, int db_type, int write,
+                    save3ds_archive **out);
+void save3ds_archive_free(save3ds_archive *archive);
+
+/* Flushes all changes made to the archive. Changes not committed may be lost on closing. */
+int save3ds_archive_commit(const save3ds_archive *archive);
+int save3ds_archive_stat(const save3ds_archive *archive, save3ds_stat *out);
+
+/* Opens a directory or a file by inode. Inode 1 is the root directory. */
+int save3ds_archive_open_dir(const save3ds_archive *archive, uint32_t ino, save3ds_dir **out);
+int save3ds_archive_open_file(const save3ds_archive *archive, uint32_t ino, save3ds_file **out);
+
+/* Directories */
+void save3ds_dir_free(save3ds_dir *dir);
+uint32_t save3ds_dir_ino(const save3ds_dir *dir);
+int save3ds_dir_open_sub_dir(const save3ds_dir *dir, const char *name, save3ds_dir **out);
+int save3ds_dir_open_sub_file(const save3ds_dir *dir, const char *name, save3ds_file **out);
+int save3ds_dir_new_sub_dir(const save3ds_dir *dir, const char *name, save3ds_dir **out);
+int save3ds_dir_new_sub_file(const save3ds_dir *dir, const char *name, size_t len,
+                             save3ds_file **out);
+/* Calls `callback` for each sub directory, then for each sub file. */
+int save3ds_dir_list(const save3ds_dir *dir, save3ds_list_callback callback, void *user);
+/* Moves `dir` into `parent` under `name`. Both must come from the same archive, and be different
+ * handles. */
+int save3ds_dir_rename(save3ds_dir *dir, const save3ds_dir *parent, const char *name);
+/* Deletes an empty directory. `dir` is freed whether or not this succeeds. */
+int save3ds_dir_delete(save3ds_dir *dir);
+
+/* Files */
+void save3ds_file_free(save3ds_file *file);
+uint32_t save3ds_file_ino(const save3ds_file *file);
+size_t save3ds_file_len(const save3ds_file *file);
+int save3ds_file_read(const save3ds_file *file, size_t pos, void *buf, size_t len);
+int save3ds_file_write(const save3ds_file *file, size_t pos, const void *buf, size_t len);
+int save3ds_file_resize(save3ds_file *file, size_t len);
+int save3ds_file_commit(const save3ds_file *file);
+/* Moves `file` into `parent` under `name`. Both must come from the same archive, and be different
+ * handles. */
+int save3ds_file_rename(save3ds_file *file, const save3ds_dir *parent, const char *name);
+/* Deletes the file. `file` is freed whether or not this succeeds. */
+int save3ds_file_delete(save3ds_file *file);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
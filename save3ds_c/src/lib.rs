@@ -0,0 +1,693 @@
+//! C interface of libsave3ds. The functions and types are documented in `include/save3ds.h`.
+//!
+//! Every handle is a boxed Rust value whose layout is hidden from C. Functions that can fail
+//! return `SAVE3DS_OK` or an error code, and leave a message for `save3ds_last_error`.
+
+#![allow(clippy::missing_safety_doc)]
+
+use libsave3ds::cart_save_data::CartSaveData;
+use libsave3ds::db::{self, Db, DbType};
+use libsave3ds::error::Error;
+use libsave3ds::ext_data::{self, ExtData};
+use libsave3ds::file_system::*;
+use libsave3ds::save_data::{self, SaveData, SaveDataBlockType, SaveDataFormatParam};
+use libsave3ds::Resource;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+pub const SAVE3DS_OK: c_int = 0;
+pub const SAVE3DS_ERROR_INVALID_ARGUMENT: c_int = -1;
+pub const SAVE3DS_ERROR_PANIC: c_int = -2;
+
+/// Failures of a call: either from the library, or from checking the arguments passed from C.
+enum Failure {
+    Lib(Error),
+    Argument(&'static str),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        Failure::Lib(e)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Maps an error to its code in the header. The codes are part of the ABI and must not change.
+fn error_code(e: &Error) -> c_int {
+    match e {
+        Error::IO(_) => 1,
+        Error::HashMismatch(_) => 2,
+        Error::OutOfBound(_) => 3,
+        Error::MagicMismatch(_) => 4,
+        Error::SizeMismatch(_) => 5,
+        Error::InvalidValue(_) => 6,
+        Error::BrokenFat(_) => 7,
+        Error::NoSpace => 8,
+        Error::NotFound => 9,
+        Error::AlreadyExist => 10,
+        Error::DeletingRoot => 11,
+        Error::SignatureMismatch(_) => 12,
+        Error::MissingBoot9 => 13,
+        Error::MissingSd => 14,
+        Error::MissingNand => 15,
+        Error::MissingGame => 16,
+        Error::MissingPriv => 17,
+        Error::MissingKeyY2F => 18,
+        Error::MissingKeyX19 => 19,
+        Error::MissingKeyX1A => 20,
+        Error::MissingOtp => 21,
+        Error::BrokenSd => 22,
+        Error::NotEmpty => 23,
+        Error::Unsupported => 24,
+        Error::UniqueIdMismatch(_) => 25,
+        Error::BrokenOtp => 26,
+        Error::Busy => 27,
+        Error::BrokenGame => 28,
+        Error::NotADirectory => 29,
+        Error::IsADirectory => 30,
+        Error::InvalidName => 31,
+        Error::NoTransaction => 32,
+    }
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).ok();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Runs the body of an exported function and turns its result into a status code.
+/// A panic is caught here, as unwinding into C is undefined behavior.
+fn run(body: impl FnOnce() -> Result<(), Failure>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => SAVE3DS_OK,
+        Ok(Err(Failure::Lib(e))) => {
+            set_last_error(e.to_string());
+            error_code(&e)
+        }
+        Ok(Err(Failure::Argument(message))) => {
+            set_last_error(message.to_owned());
+            SAVE3DS_ERROR_INVALID_ARGUMENT
+        }
+        Err(payload) => {
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "unknown panic".to_owned(),
+                },
+            };
+            set_last_error(format!("panic: {}", message));
+            SAVE3DS_ERROR_PANIC
+        }
+    }
+}
+
+/// Moves `value` to the heap and hands it to C through `out`.
+unsafe fn output<T>(out: *mut *mut T, value: T) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(Failure::Argument("null output pointer"));
+    }
+    *out = Box::into_raw(Box::new(value));
+    Ok(())
+}
+
+unsafe fn handle<'a, T>(p: *const T) -> Result<&'a T, Failure> {
+    p.as_ref().ok_or(Failure::Argument("null handle"))
+}
+
+unsafe fn handle_mut<'a, T>(p: *mut T) -> Result<&'a mut T, Failure> {
+    p.as_mut().ok_or(Failure::Argument("null handle"))
+}
+
+unsafe fn string(s: *const c_char) -> Result<String, Failure> {
+    match optional_string(s)? {
+        Some(s) => Ok(s),
+        None => Err(Failure::Argument("null string")),
+    }
+}
+
+unsafe fn optional_string(s: *const c_char) -> Result<Option<String>, Failure> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Ok(Some(s.to_owned())),
+        Err(_) => Err(Failure::Argument("string is not valid UTF-8")),
+    }
+}
+
+unsafe fn optional_key(key: *const u8) -> Option<[u8; 16]> {
+    if key.is_null() {
+        return None;
+    }
+    let mut result = [0; 16];
+    result.copy_from_slice(std::slice::from_raw_parts(key, 16));
+    Some(result)
+}
+
+unsafe fn name<N: NameConvert>(name: *const c_char) -> Result<N, Failure> {
+    N::name_str_to_3ds(&string(name)?).ok_or(Failure::Lib(Error::InvalidName))
+}
+
+pub enum Archive {
+    Save(SaveData),
+    Cart(CartSaveData),
+    Ext(ExtData),
+    Db(Db),
+}
+
+pub enum Dir {
+    Save(save_data::Dir),
+    Ext(ext_data::Dir),
+    Db(db::Dir),
+}
+
+pub enum File {
+    Save(save_data::File),
+    Ext(ext_data::File),
+    Db(db::File),
+}
+
+macro_rules! wrap {
+    ($outer:ident, $inner:ty, $variant:ident) => {
+        impl From<$inner> for $outer {
+            fn from(value: $inner) -> $outer {
+                $outer::$variant(value)
+            }
+        }
+    };
+}
+
+wrap!(Dir, save_data::Dir, Save);
+wrap!(Dir, ext_data::Dir, Ext);
+wrap!(Dir, db::Dir, Db);
+wrap!(File, save_data::File, Save);
+wrap!(File, ext_data::File, Ext);
+wrap!(File, db::File, Db);
+
+/// Evaluates `$body` with `$x` bound to the value inside whichever variant `$value` is.
+macro_rules! dispatch {
+    ($value:expr, $x:ident => $body:expr) => {
+        match $value {
+            Archive::Save($x) => $body,
+            Archive::Cart($x) => $body,
+            Archive::Ext($x) => $body,
+            Archive::Db($x) => $body,
+        }
+    };
+    ($value:expr, $kind:ident, $x:ident => $body:expr) => {
+        match $value {
+            $kind::Save($x) => $body,
+            $kind::Ext($x) => $body,
+            $kind::Db($x) => $body,
+        }
+    };
+}
+
+#[repr(C)]
+pub struct ResourceParam {
+    pub boot9_path: *const c_char,
+    pub movable_path: *const c_char,
+    pub sd_path: *const c_char,
+    pub nand_path: *const c_char,
+    pub otp_path: *const c_char,
+    pub priv_path: *const c_char,
+    pub game_path: *const c_char,
+    pub x2f_key_y: *const u8,
+    pub x19_key_x: *const u8,
+    pub x1a_key_x: *const u8,
+}
+
+#[repr(C)]
+pub struct SaveFormatParam {
+    pub large_block: c_int,
+    pub max_dir: usize,
+    pub dir_buckets: usize,
+    pub max_file: usize,
+    pub file_buckets: usize,
+    pub duplicate_data: c_int,
+}
+
+#[repr(C)]
+pub struct ArchiveStat {
+    pub block_len: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub total_files: u64,
+    pub free_files: u64,
+    pub total_dirs: u64,
+    pub free_dirs: u64,
+}
+
+pub type ListCallback =
+    Option<unsafe extern "C" fn(user: *mut c_void, name: *const c_char, ino: u32, is_dir: c_int)>;
+
+#[no_mangle]
+pub extern "C" fn save3ds_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_resource_new(
+    param: *const ResourceParam,
+    out: *mut *mut Resource,
+) -> c_int {
+    run(|| {
+        let param = handle(param)?;
+        let resource = Resource::new(
+            optional_string(param.boot9_path)?,
+            optional_string(param.movable_path)?,
+            optional_string(param.sd_path)?,
+            optional_string(param.nand_path)?,
+            optional_string(param.otp_path)?,
+            optional_string(param.priv_path)?,
+            optional_string(param.game_path)?,
+            optional_key(param.x2f_key_y),
+            optional_key(param.x19_key_x),
+            optional_key(param.x1a_key_x),
+        )?;
+        output(out, resource)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_resource_free(resource: *mut Resource) {
+    if !resource.is_null() {
+        drop(Box::from_raw(resource));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_format_bare_save(
+    resource: *const Resource,
+    path: *const c_char,
+    param: *const SaveFormatParam,
+    len: usize,
+) -> c_int {
+    run(|| {
+        let param = handle(param)?;
+        let param = SaveDataFormatParam {
+            block_type: if param.large_block != 0 {
+                SaveDataBlockType::Large
+            } else {
+                SaveDataBlockType::Small
+            },
+            max_dir: param.max_dir,
+            dir_buckets: param.dir_buckets,
+            max_file: param.max_file,
+            file_buckets: param.file_buckets,
+            duplicate_data: param.duplicate_data != 0,
+        };
+        handle(resource)?.format_bare_save(&string(path)?, &param, len)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_sd_save(
+    resource: *const Resource,
+    id: u64,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let save = handle(resource)?.open_sd_save(id, write != 0)?;
+        output(out, Archive::Save(save))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_nand_save(
+    resource: *const Resource,
+    id: u32,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let save = handle(resource)?.open_nand_save(id, write != 0)?;
+        output(out, Archive::Save(save))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_bare_save(
+    resource: *const Resource,
+    path: *const c_char,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let save = handle(resource)?.open_bare_save(&string(path)?, write != 0)?;
+        output(out, Archive::Save(save))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_cart_save(
+    resource: *const Resource,
+    path: *const c_char,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let save = handle(resource)?.open_cart_save(&string(path)?, write != 0)?;
+        output(out, Archive::Cart(save))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_sd_ext(
+    resource: *const Resource,
+    id: u64,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let ext = handle(resource)?.open_sd_ext(id, write != 0)?;
+        output(out, Archive::Ext(ext))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_nand_ext(
+    resource: *const Resource,
+    id: u64,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let ext = handle(resource)?.open_nand_ext(id, write != 0)?;
+        output(out, Archive::Ext(ext))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_db(
+    resource: *const Resource,
+    db_type: c_int,
+    write: c_int,
+    out: *mut *mut Archive,
+) -> c_int {
+    run(|| {
+        let db_type = match db_type {
+            0 => DbType::Ticket,
+            1 => DbType::NandTitle,
+            2 => DbType::NandImport,
+            3 => DbType::TmpTitle,
+            4 => DbType::TmpImport,
+            5 => DbType::SdTitle,
+            6 => DbType::SdImport,
+            _ => return Err(Failure::Argument("unknown database type")),
+        };
+        let db = handle(resource)?.open_db(db_type, write != 0)?;
+        output(out, Archive::Db(db))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_archive_free(archive: *mut Archive) {
+    if !archive.is_null() {
+        drop(Box::from_raw(archive));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_archive_commit(archive: *const Archive) -> c_int {
+    run(|| Ok(dispatch!(handle(archive)?, a => a.commit())?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_archive_stat(
+    archive: *const Archive,
+    out: *mut ArchiveStat,
+) -> c_int {
+    run(|| {
+        let stat = dispatch!(handle(archive)?, a => a.stat())?;
+        let out = handle_mut(out)?;
+        *out = ArchiveStat {
+            block_len: stat.block_len as u64,
+            total_blocks: stat.total_blocks as u64,
+            free_blocks: stat.free_blocks as u64,
+            total_files: stat.total_files as u64,
+            free_files: stat.free_files as u64,
+            total_dirs: stat.total_dirs as u64,
+            free_dirs: stat.free_dirs as u64,
+        };
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_archive_open_dir(
+    archive: *const Archive,
+    ino: u32,
+    out: *mut *mut Dir,
+) -> c_int {
+    run(|| {
+        let dir = dispatch!(handle(archive)?, a => Dir::from(a.open_dir(ino)?));
+        output(out, dir)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_archive_open_file(
+    archive: *const Archive,
+    ino: u32,
+    out: *mut *mut File,
+) -> c_int {
+    run(|| {
+        let file = dispatch!(handle(archive)?, a => File::from(a.open_file(ino)?));
+        output(out, file)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_free(dir: *mut Dir) {
+    if !dir.is_null() {
+        drop(Box::from_raw(dir));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_ino(dir: *const Dir) -> u32 {
+    match dir.as_ref() {
+        Some(dir) => dispatch!(dir, Dir, d => d.get_ino()),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_open_sub_dir(
+    dir: *const Dir,
+    name_str: *const c_char,
+    out: *mut *mut Dir,
+) -> c_int {
+    run(|| {
+        let sub = dispatch!(handle(dir)?, Dir, d => Dir::from(d.open_sub_dir(name(name_str)?)?));
+        output(out, sub)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_open_sub_file(
+    dir: *const Dir,
+    name_str: *const c_char,
+    out: *mut *mut File,
+) -> c_int {
+    run(|| {
+        let sub = dispatch!(handle(dir)?, Dir, d => File::from(d.open_sub_file(name(name_str)?)?));
+        output(out, sub)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_new_sub_dir(
+    dir: *const Dir,
+    name_str: *const c_char,
+    out: *mut *mut Dir,
+) -> c_int {
+    run(|| {
+        let sub = dispatch!(handle(dir)?, Dir, d => Dir::from(d.new_sub_dir(name(name_str)?)?));
+        output(out, sub)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_new_sub_file(
+    dir: *const Dir,
+    name_str: *const c_char,
+    len: usize,
+    out: *mut *mut File,
+) -> c_int {
+    run(|| {
+        let sub = dispatch!(
+            handle(dir)?,
+            Dir,
+            d => File::from(d.new_sub_file(name(name_str)?, len)?)
+        );
+        output(out, sub)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_list(
+    dir: *const Dir,
+    callback: ListCallback,
+    user: *mut c_void,
+) -> c_int {
+    run(|| {
+        let callback = callback.ok_or(Failure::Argument("null callback"))?;
+        let entries: Vec<(String, u32, bool)> = dispatch!(handle(dir)?, Dir, d => {
+            let dirs = d.list_sub_dir()?.into_iter().map(|(n, ino)| (n, ino, true));
+            let files = d.list_sub_file()?.into_iter().map(|(n, ino)| (n, ino, false));
+            dirs.chain(files)
+                .map(|(n, ino, is_dir)| (NameConvert::name_3ds_to_str(&n), ino, is_dir))
+                .collect()
+        });
+        for (name, ino, is_dir) in entries {
+            // Textual names never contain NUL, as it is escaped
+            let name = CString::new(name).map_err(|_| Failure::Lib(Error::InvalidName))?;
+            callback(user, name.as_ptr(), ino, is_dir as c_int);
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_rename(
+    dir: *mut Dir,
+    parent: *const Dir,
+    name_str: *const c_char,
+) -> c_int {
+    run(|| {
+        if std::ptr::eq(dir, parent) {
+            return Err(Failure::Argument("dir and parent are the same handle"));
+        }
+        match (handle_mut(dir)?, handle(parent)?) {
+            (Dir::Save(d), Dir::Save(p)) if d.same_archive(p) => d.rename(p, name(name_str)?)?,
+            (Dir::Ext(d), Dir::Ext(p)) if d.same_archive(p) => d.rename(p, name(name_str)?)?,
+            (Dir::Db(d), Dir::Db(p)) if d.same_archive(p) => d.rename(p, name(name_str)?)?,
+            _ => return Err(Failure::Argument("parent is from another archive")),
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_dir_delete(dir: *mut Dir) -> c_int {
+    run(|| {
+        handle(dir)?;
+        let dir = Box::from_raw(dir);
+        Ok(dispatch!(*dir, Dir, d => d.delete())?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_free(file: *mut File) {
+    if !file.is_null() {
+        drop(Box::from_raw(file));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_ino(file: *const File) -> u32 {
+    match file.as_ref() {
+        Some(file) => dispatch!(file, File, f => f.get_ino()),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_len(file: *const File) -> usize {
+    match file.as_ref() {
+        Some(file) => dispatch!(file, File, f => f.len()),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_read(
+    file: *const File,
+    pos: usize,
+    buf: *mut c_void,
+    len: usize,
+) -> c_int {
+    run(|| {
+        let file = handle(file)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if buf.is_null() {
+            return Err(Failure::Argument("null buffer"));
+        }
+        let buf = std::slice::from_raw_parts_mut(buf as *mut u8, len);
+        Ok(dispatch!(file, File, f => f.read(pos, buf))?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_write(
+    file: *const File,
+    pos: usize,
+    buf: *const c_void,
+    len: usize,
+) -> c_int {
+    run(|| {
+        let file = handle(file)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if buf.is_null() {
+            return Err(Failure::Argument("null buffer"));
+        }
+        let buf = std::slice::from_raw_parts(buf as *const u8, len);
+        Ok(dispatch!(file, File, f => f.write(pos, buf))?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_resize(file: *mut File, len: usize) -> c_int {
+    run(|| Ok(dispatch!(handle_mut(file)?, File, f => f.resize(len))?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_commit(file: *const File) -> c_int {
+    run(|| Ok(dispatch!(handle(file)?, File, f => f.commit())?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_rename(
+    file: *mut File,
+    parent: *const Dir,
+    name_str: *const c_char,
+) -> c_int {
+    run(|| {
+        if std::ptr::eq(file as *const c_void, parent as *const c_void) {
+            return Err(Failure::Argument("file and parent are the same handle"));
+        }
+        match (handle_mut(file)?, handle(parent)?) {
+            (File::Save(f), Dir::Save(p)) if f.same_archive(p) => f.rename(p, name(name_str)?)?,
+            (File::Ext(f), Dir::Ext(p)) if f.same_archive(p) => f.rename(p, name(name_str)?)?,
+            (File::Db(f), Dir::Db(p)) if f.same_archive(p) => f.rename(p, name(name_str)?)?,
+            _ => return Err(Failure::Argument("parent is from another archive")),
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_file_delete(file: *mut File) -> c_int {
+    run(|| {
+        handle(file)?;
+        let file = Box::from_raw(file);
+        Ok(dispatch!(*file, File, f => f.delete())?)
+    })
+}
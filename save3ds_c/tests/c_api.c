@@ -0,0 +1,136 @@
+/* Exercises the C interface on a bare save data image created at the path in argv[1]. */
+
+#include "save3ds.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define CHECK(expr, expected)                                                                 \
+    do {                                                                                      \
+        int status_ = (expr);                                                                 \
+        if (status_ != (expected)) {                                                          \
+            const char *message_ = save3ds_last_error();                                      \
+            fprintf(stderr, "%s:%d: %s returned %d, expected %d (%s)\n", __FILE__, __LINE__,  \
+                    #expr, status_, (expected), message_ ? message_ : "no message");          \
+            exit(1);                                                                          \
+        }                                                                                     \
+    } while (0)
+
+#define ASSERT(cond)                                                                          \
+    do {                                                                                      \
+        if (!(cond)) {                                                                        \
+            fprintf(stderr, "%s:%d: assertion failed: %s\n", __FILE__, __LINE__, #cond);      \
+            exit(1);                                                                          \
+        }                                                                                     \
+    } while (0)
+
+struct listing {
+    int dirs;
+    int files;
+    int found_moved;
+};
+
+static void count_entry(void *user, const char *name, uint32_t ino, int is_dir) {
+    struct listing *listing = user;
+    (void)ino;
+    if (is_dir) {
+        listing->dirs++;
+    } else {
+        listing->files++;
+        if (strcmp(name, "moved") == 0) {
+            listing->found_moved = 1;
+        }
+    }
+}
+
+int main(int argc, char **argv) {
+    save3ds_resource_param resource_param;
+    save3ds_save_format_param format_param;
+    save3ds_resource *resource;
+    save3ds_archive *archive, *other;
+    save3ds_dir *root, *sub, *other_root;
+    save3ds_file *file;
+    save3ds_stat stat;
+    struct listing listing = {0, 0, 0};
+    unsigned char data[100], back[100];
+    size_t i;
+
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s <image path>\n", argv[0]);
+        return 2;
+    }
+
+    memset(&resource_param, 0, sizeof(resource_param));
+    CHECK(save3ds_resource_new(&resource_param, &resource), SAVE3DS_OK);
+
+    format_param.large_block = 0;
+    format_param.max_dir = 10;
+    format_param.dir_buckets = 10;
+    format_param.max_file = 10;
+    format_param.file_buckets = 10;
+    format_param.duplicate_data = 0;
+    CHECK(save3ds_format_bare_save(resource, argv[1], &format_param, 0x20000), SAVE3DS_OK);
+
+    CHECK(save3ds_open_bare_save(resource, argv[1], 1, &archive), SAVE3DS_OK);
+    CHECK(save3ds_archive_open_dir(archive, 1, &root), SAVE3DS_OK);
+    ASSERT(save3ds_dir_ino(root) == 1);
+
+    CHECK(save3ds_dir_new_sub_dir(root, "sub", &sub), SAVE3DS_OK);
+    CHECK(save3ds_dir_new_sub_dir(root, "sub", &sub), SAVE3DS_ERROR_ALREADY_EXIST);
+    ASSERT(save3ds_last_error() != NULL);
+
+    for (i = 0; i < sizeof(data); ++i) {
+        data[i] = (unsigned char)(i * 7);
+    }
+    CHECK(save3ds_dir_new_sub_file(sub, "file", sizeof(data), &file), SAVE3DS_OK);
+    CHECK(save3ds_file_write(file, 0, data, sizeof(data)), SAVE3DS_OK);
+    CHECK(save3ds_file_resize(file, 200), SAVE3DS_OK);
+    ASSERT(save3ds_file_len(file) == 200);
+    CHECK(save3ds_dir_rename(sub, sub, "loop"), SAVE3DS_ERROR_INVALID_ARGUMENT);
+    CHECK(save3ds_file_rename(file, (const save3ds_dir *)file, "loop"),
+          SAVE3DS_ERROR_INVALID_ARGUMENT);
+    CHECK(save3ds_file_rename(file, root, "moved"), SAVE3DS_OK);
+    CHECK(save3ds_file_write(file, 0, data, 300), SAVE3DS_ERROR_OUT_OF_BOUND);
+    save3ds_file_free(file);
+
+    CHECK(save3ds_dir_list(root, count_entry, &listing), SAVE3DS_OK);
+    ASSERT(listing.dirs == 1 && listing.files == 1 && listing.found_moved);
+
+    CHECK(save3ds_archive_stat(archive, &stat), SAVE3DS_OK);
+    ASSERT(stat.total_files == 10 && stat.free_files == 9);
+    ASSERT(stat.total_dirs == 11 && stat.free_dirs == 9);
+
+    CHECK(save3ds_dir_delete(sub), SAVE3DS_OK);
+    CHECK(save3ds_archive_commit(archive), SAVE3DS_OK);
+    save3ds_dir_free(root);
+    save3ds_archive_free(archive);
+
+    CHECK(save3ds_open_bare_save(resource, argv[1], 0, &archive), SAVE3DS_OK);
+    CHECK(save3ds_archive_open_dir(archive, 1, &root), SAVE3DS_OK);
+    CHECK(save3ds_dir_open_sub_dir(root, "sub", &sub), SAVE3DS_ERROR_NOT_FOUND);
+    CHECK(save3ds_dir_open_sub_file(root, "name_longer_than_16_bytes", &file),
+          SAVE3DS_ERROR_INVALID_NAME);
+    CHECK(save3ds_dir_open_sub_file(root, "moved", &file), SAVE3DS_OK);
+    CHECK(save3ds_file_read(file, 0, back, sizeof(back)), SAVE3DS_OK);
+    ASSERT(memcmp(data, back, sizeof(data)) == 0);
+
+    /* A parent from another archive is rejected even if it is of the same kind */
+    CHECK(save3ds_open_bare_save(resource, argv[1], 0, &other), SAVE3DS_OK);
+    CHECK(save3ds_archive_open_dir(other, 1, &other_root), SAVE3DS_OK);
+    CHECK(save3ds_file_rename(file, other_root, "moved"), SAVE3DS_ERROR_INVALID_ARGUMENT);
+    CHECK(save3ds_dir_rename(root, other_root, "root"), SAVE3DS_ERROR_INVALID_ARGUMENT);
+    save3ds_dir_free(other_root);
+    save3ds_archive_free(other);
+    save3ds_file_free(file);
+    save3ds_dir_free(root);
+    save3ds_archive_free(archive);
+
+    CHECK(save3ds_archive_commit(NULL), SAVE3DS_ERROR_INVALID_ARGUMENT);
+    CHECK(save3ds_open_bare_save(resource, NULL, 0, &archive), SAVE3DS_ERROR_INVALID_ARGUMENT);
+    CHECK(save3ds_open_sd_save(resource, 0, 0, &archive), SAVE3DS_ERROR_MISSING_SD);
+
+    save3ds_resource_free(resource);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,38 @@
+//! Builds `c_api.c` against the static library with the system C compiler and runs it.
+//! The compiler can be chosen with the `CC` environment variable.
+
+#[cfg(unix)]
+#[test]
+fn c_api() {
+    use std::path::Path;
+    use std::process::Command;
+
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let tmp = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    // The test executable lives in `target/<profile>/deps`, next to the library
+    let deps = std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .to_owned();
+    let program = tmp.join("c_api");
+    let image = tmp.join("c_api.bin");
+    let _ = std::fs::remove_file(&image);
+
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    let status = Command::new(cc)
+        .arg(manifest.join("tests").join("c_api.c"))
+        .arg("-I")
+        .arg(manifest.join("include"))
+        .arg(deps.join("libsave3ds.a"))
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(&program).arg(&image).output().unwrap();
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+}
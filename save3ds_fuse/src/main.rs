@@ -4,10 +4,15 @@ use libsave3ds::error::*;
 use libsave3ds::ext_data::*;
 use libsave3ds::file_system::*;
 use libsave3ds::save_data::*;
-use libsave3ds::Resource;
+use libsave3ds::{
+    diff_archives, replay_trace, ArchiveDiff, BrokenBlock, Check, CheckIssue, FileBackend,
+    GenerationReport, InspectNode, LevelReport, OverlayChange, OverlayPath, ReplayReport, Resource,
+    SelectorInversion, Trace, TraceArchive, Traced,
+};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::io::Read;
+use std::sync::Arc;
 
 #[cfg(all(unix, feature = "unixfuse"))]
 use {
@@ -16,6 +21,8 @@ use {
         getegid, geteuid, EBADF, EEXIST, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOSYS,
         ENOTDIR, ENOTEMPTY, EROFS,
     },
+    std::cell::Cell,
+    std::rc::Rc,
     std::time::{Duration, SystemTime},
 };
 
@@ -26,86 +33,6 @@ enum FileSystemOperation {
     Touch,
 }
 
-fn is_legal_char(c: u8) -> bool {
-    (32..127).contains(&c) && c != 47 && c != 92
-}
-
-trait NameConvert {
-    fn name_3ds_to_str(name: &Self) -> String;
-    fn name_str_to_3ds(name: &str) -> Option<Self>
-    where
-        Self: Sized;
-}
-
-impl NameConvert for u64 {
-    fn name_3ds_to_str(name: &u64) -> String {
-        format!("{:016x}", name)
-    }
-
-    fn name_str_to_3ds(name: &str) -> Option<u64> {
-        u64::from_str_radix(name, 16).ok()
-    }
-}
-
-impl NameConvert for [u8; 16] {
-    fn name_3ds_to_str(name: &[u8; 16]) -> String {
-        let mut last_char = 15;
-        loop {
-            if name[last_char] != 0 || last_char == 0 {
-                break;
-            }
-            last_char -= 1;
-        }
-
-        name[0..=last_char]
-            .iter()
-            .map(|x| {
-                if is_legal_char(*x) {
-                    String::from_utf8(vec![*x]).unwrap()
-                } else {
-                    format!("\\x{:02x}", *x)
-                }
-            })
-            .fold("".to_owned(), |mut x, y| {
-                x.push_str(&y);
-                x
-            })
-    }
-
-    fn name_str_to_3ds(name: &str) -> Option<[u8; 16]> {
-        let mut name_converted = [0; 16];
-        let bytes = name.as_bytes();
-        let mut out_i = 0;
-        let mut in_i = 0;
-        loop {
-            if in_i == bytes.len() {
-                break;
-            }
-            if out_i == name_converted.len() {
-                return None;
-            }
-
-            if bytes[in_i] != b'\\' {
-                name_converted[out_i] = bytes[in_i];
-                out_i += 1;
-                in_i += 1;
-            } else {
-                in_i += 1;
-                if *bytes.get(in_i)? != b'x' {
-                    return None;
-                }
-                in_i += 1;
-                name_converted[out_i] =
-                    u8::from_str_radix(std::str::from_utf8(bytes.get(in_i..in_i + 2)?).ok()?, 16)
-                        .ok()?;
-                out_i += 1;
-                in_i += 2;
-            }
-        }
-        Some(name_converted)
-    }
-}
-
 fn extract_impl<T: FileSystem>(
     save: &T,
     dir: T::DirType,
@@ -138,7 +65,7 @@ where
         let file = save.open_file(ino)?;
         let mut buffer = vec![0; file.len()];
         match file.read(0, &mut buffer) {
-            Ok(()) | Err(Error::HashMismatch) => (),
+            Ok(()) | Err(Error::HashMismatch(_)) => (),
             e => return e,
         }
         std::fs::write(&path.join(name), &buffer)?;
@@ -244,14 +171,19 @@ where
 {
     #[cfg(all(unix, feature = "unixfuse"))]
     {
-        mount2(FileSystemFrontend::new(save, read_only), &mountpoint, &[])?;
-        return Ok(());
+        let save_error = Rc::new(Cell::new(None));
+        let frontend = FileSystemFrontend::new(save, read_only, save_error.clone());
+        mount2(frontend, &mountpoint, &[])?;
+        return match save_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
     }
     println!("fuse not implemented. Please specify --extract or --import flag");
     Ok(())
 }
 
-fn start<T: FileSystem>(
+fn run<T: FileSystem>(
     save: T,
     operation: FileSystemOperation,
     mountpoint: &std::path::Path,
@@ -269,6 +201,21 @@ where
     Ok(())
 }
 
+fn start<T: TraceArchive>(
+    save: T,
+    operation: FileSystemOperation,
+    mountpoint: &std::path::Path,
+    trace: Option<&Arc<Trace>>,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    match trace {
+        Some(trace) => run(Traced::new(save, trace.clone())?, operation, mountpoint),
+        None => run(save, operation, mountpoint),
+    }
+}
+
 #[cfg(all(unix, feature = "unixfuse"))]
 struct DirEntry {
     ino: u64,
@@ -285,6 +232,8 @@ struct FileSystemFrontend<T: FileSystem> {
     next_fh: u64,
     uid: u32,
     gid: u32,
+    // Where the error of the commit on unmount is handed back to `do_mount`
+    save_error: Rc<Cell<Option<Error>>>,
 }
 
 #[cfg(all(unix, feature = "unixfuse"))]
@@ -292,7 +241,7 @@ impl<T: FileSystem> FileSystemFrontend<T>
 where
     T::NameType: NameConvert + Clone,
 {
-    fn new(save: T, read_only: bool) -> FileSystemFrontend<T> {
+    fn new(save: T, read_only: bool, save_error: Rc<Cell<Option<Error>>>) -> FileSystemFrontend<T> {
         FileSystemFrontend::<T> {
             save,
             file_fh_map: HashMap::new(),
@@ -301,6 +250,7 @@ where
             read_only,
             uid: 0,
             gid: 0,
+            save_error,
         }
     }
 }
@@ -388,8 +338,13 @@ impl Ino {
 impl<T: FileSystem> Drop for FileSystemFrontend<T> {
     fn drop(&mut self) {
         if !self.read_only {
-            self.save.commit().unwrap();
-            println!("Saved");
+            match self.save.commit() {
+                Ok(()) => println!("Saved"),
+                Err(e) => {
+                    eprintln!("Failed to save: {}", e);
+                    self.save_error.set(Some(e));
+                }
+            }
         }
     }
 }
@@ -817,7 +772,7 @@ where
             }
             let mut buf = vec![0; end - offset];
             match file.read(offset, &mut buf) {
-                Ok(()) | Err(Error::HashMismatch) => reply.data(&buf),
+                Ok(()) | Err(Error::HashMismatch(_)) => reply.data(&buf),
                 _ => reply.error(EIO),
             }
         } else {
@@ -1151,7 +1106,7 @@ fn to_save_data_format_param(
         4096 => SaveDataBlockType::Large,
         _ => {
             println!("Unsupported block_len value");
-            return Err(Box::from(Error::InvalidValue));
+            return Err(Box::from(Error::InvalidValue(Contexts::new())));
         }
     };
 
@@ -1204,6 +1159,268 @@ fn to_save_data_format_param(
     ))
 }
 
+fn to_save_data_repack_param(
+    raw: HashMap<String, String>,
+    (current, current_len): (SaveDataFormatParam, usize),
+) -> Result<(SaveDataFormatParam, usize), Box<dyn std::error::Error>> {
+    let block_type = match raw
+        .get("block_len")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+    {
+        None => current.block_type,
+        Some(512) => SaveDataBlockType::Small,
+        Some(4096) => SaveDataBlockType::Large,
+        Some(_) => {
+            println!("Unsupported block_len value");
+            return Err(Box::from(Error::InvalidValue(Contexts::new())));
+        }
+    };
+
+    let max_dir = raw.get("max_dir").map(|s| s.parse::<usize>()).transpose()?;
+
+    let dir_buckets = raw
+        .get("dir_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| max_dir.map_or(current.dir_buckets, get_default_bucket));
+
+    let max_file = raw
+        .get("max_file")
+        .map(|s| s.parse::<usize>())
+        .transpose()?;
+
+    let file_buckets = raw
+        .get("file_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| max_file.map_or(current.file_buckets, get_default_bucket));
+
+    let duplicate_data = raw
+        .get("duplicate_data")
+        .map(|s| s.parse::<bool>())
+        .transpose()?
+        .unwrap_or(current.duplicate_data);
+
+    let len = raw
+        .get("len")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(current_len);
+
+    Ok((
+        SaveDataFormatParam {
+            block_type,
+            max_dir: max_dir.unwrap_or(current.max_dir),
+            dir_buckets,
+            max_file: max_file.unwrap_or(current.max_file),
+            file_buckets,
+            duplicate_data,
+        },
+        len,
+    ))
+}
+
+fn to_selector_inversion(raw: &str) -> Result<SelectorInversion, Box<dyn std::error::Error>> {
+    match raw {
+        "" | "previous" => return Ok(SelectorInversion::previous()),
+        "all" => return Ok(SelectorInversion::all()),
+        _ => (),
+    }
+    let mut inversion = SelectorInversion::default();
+    for level in raw.split(',') {
+        match level {
+            "table" => inversion.table = true,
+            "dpfs1" => inversion.dpfs_level1 = true,
+            "dpfs2" => inversion.dpfs_level2 = true,
+            "dpfs3" => inversion.dpfs_level3 = true,
+            _ => {
+                println!("Unknown level {}", level);
+                return Err(Box::from(Error::InvalidValue(Contexts::new())));
+            }
+        }
+    }
+    Ok(inversion)
+}
+
+fn print_generation_report<'a>(reports: impl IntoIterator<Item = (String, &'a GenerationReport)>) {
+    for (name, report) in reports {
+        println!(
+            "{}: {} changed block(s) of 0x{:X} bytes {:?}",
+            name,
+            report.changed_blocks.len(),
+            report.block_len,
+            report.changed_blocks
+        );
+        for (level, broken) in report.broken_blocks.iter().enumerate() {
+            if !broken.is_empty() {
+                println!("    IVFC level {} broken block(s) {:?}", level + 1, broken);
+            }
+        }
+    }
+}
+
+fn print_salvage_report(report: &[BrokenBlock]) {
+    if report.is_empty() {
+        println!("No broken block found");
+    }
+    for block in report {
+        println!(
+            "Partition {} IVFC level {} block {}: {:?}",
+            block.partition, block.level, block.index, block.owners
+        );
+    }
+}
+
+fn print_check_report(issues: Vec<CheckIssue>) -> Result<(), Box<dyn std::error::Error>> {
+    if issues.is_empty() {
+        println!("No issue found");
+        return Ok(());
+    }
+    for issue in issues.iter() {
+        println!("{:?}", issue);
+    }
+    Err(Box::from(format!("Found {} issue(s)", issues.len())))
+}
+
+fn print_verify_report(reports: Vec<LevelReport>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut broken = 0;
+    for report in reports.iter() {
+        println!(
+            "Partition {} IVFC level {}: {} block(s) of 0x{:X} bytes",
+            report.partition, report.level, report.block_count, report.block_len
+        );
+        if !report.broken_blocks.is_empty() {
+            println!("    broken block(s) {:?}", report.broken_blocks);
+        }
+        if !report.unused_broken_blocks.is_empty() {
+            println!(
+                "    broken block(s) not in use {:?}",
+                report.unused_broken_blocks
+            );
+        }
+        broken += report.broken_blocks.len();
+    }
+    if broken == 0 {
+        println!("No broken block in use found");
+        return Ok(());
+    }
+    Err(Box::from(format!(
+        "Found {} broken block(s) in use",
+        broken
+    )))
+}
+
+fn print_repack_report(report: CopyReport) {
+    if report.unverified_files != 0 {
+        println!(
+            "{} file(s) failed hash verification, such as those with data never written, \
+             and were copied as read",
+            report.unverified_files
+        );
+    }
+    println!("Repacking done");
+}
+
+fn print_replay_report(report: ReplayReport) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Replayed {} operation(s)", report.operations);
+    for divergence in report.divergences.iter() {
+        println!(
+            "Line {}: {} traced {}, replayed {}",
+            divergence.line, divergence.operation, divergence.traced, divergence.replayed
+        );
+    }
+    for issue in report.issues.iter() {
+        println!("{:?}", issue);
+    }
+    if report.divergences.is_empty() && report.issues.is_empty() {
+        return Ok(());
+    }
+    Err(Box::from(format!(
+        "Found {} divergence(s) and {} issue(s)",
+        report.divergences.len(),
+        report.issues.len()
+    )))
+}
+
+fn print_inspect(node: &InspectNode, format: &str) {
+    if format == "json" {
+        println!("{}", node.to_json());
+    } else {
+        print!("{}", node.to_text());
+    }
+}
+
+fn print_diff(diff: &ArchiveDiff, format: &str) {
+    if format == "json" {
+        println!("{}", diff.to_json());
+    } else if diff.is_empty() {
+        println!("No difference found");
+    } else {
+        print!("{}", diff.to_text());
+    }
+}
+
+fn diff_save_format(
+    diff: &mut ArchiveDiff,
+    (old, old_len): (SaveDataFormatParam, usize),
+    (new, new_len): (SaveDataFormatParam, usize),
+) {
+    diff.property("len", old_len, new_len);
+    diff.property("block_type", old.block_type, new.block_type);
+    diff.property("max_dir", old.max_dir, new.max_dir);
+    diff.property("dir_buckets", old.dir_buckets, new.dir_buckets);
+    diff.property("max_file", old.max_file, new.max_file);
+    diff.property("file_buckets", old.file_buckets, new.file_buckets);
+    diff.property("duplicate_data", old.duplicate_data, new.duplicate_data);
+}
+
+fn diff_save(old: &SaveData, new: &SaveData) -> Result<ArchiveDiff, Error> {
+    let mut diff = diff_archives(old, new)?;
+    diff_save_format(
+        &mut diff,
+        (old.format_param()?, old.image_len()),
+        (new.format_param()?, new.image_len()),
+    );
+    Ok(diff)
+}
+
+fn diff_ext(old: &ExtData, new: &ExtData) -> Result<ArchiveDiff, Error> {
+    let mut diff = diff_archives(old, new)?;
+    let (old, new) = (old.format_param()?, new.format_param()?);
+    diff.property("max_dir", old.max_dir, new.max_dir);
+    diff.property("dir_buckets", old.dir_buckets, new.dir_buckets);
+    diff.property("max_file", old.max_file, new.max_file);
+    diff.property("file_buckets", old.file_buckets, new.file_buckets);
+    Ok(diff)
+}
+
+fn print_overlay_changes(changes: &[(OverlayPath, OverlayChange)]) {
+    if changes.is_empty() {
+        println!("Dry run: nothing would be changed");
+        return;
+    }
+    println!("Dry run: the following changes are discarded");
+    for (path, change) in changes {
+        let path = match path {
+            OverlayPath::Sd(path) => format!("SD/{}", path.join("/")),
+            OverlayPath::Nand(path) => format!("NAND/{}", path.join("/")),
+            OverlayPath::Host(path) => path.display().to_string(),
+        };
+        match change {
+            OverlayChange::Modified(ranges) => println!(
+                "Modified {}: {} byte(s) in {} range(s)",
+                path,
+                ranges.iter().map(|(_, len)| len).sum::<usize>(),
+                ranges.len()
+            ),
+            OverlayChange::Created(len) => println!("Written {}: {} byte(s)", path, len),
+            OverlayChange::Removed => println!("Removed {}", path),
+            OverlayChange::RemovedDir => println!("Removed directory {}", path),
+        }
+    }
+}
+
 fn read_key(s: String) -> std::io::Result<[u8; 16]> {
     let mut key = [0; 16];
     if s.len() == 32 {
@@ -1233,6 +1450,18 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     opts.optopt("", "bare", "mount a bare DISA file", "FILE");
     opts.optopt("b", "boot9", "boot9.bin file path", "FILE");
     opts.optopt("c", "cart", "(experimental) mount a cartridge save", "FILE");
+    opts.optopt(
+        "",
+        "cache",
+        "keep up to SIZE bytes of each opened file in memory, writing changes back on commit, \
+        and print the cache counters at exit",
+        "SIZE",
+    );
+    opts.optflag(
+        "",
+        "check",
+        "check the archive structure instead of mounting, and exit with an error on any issue",
+    );
     opts.optopt(
         "",
         "db",
@@ -1240,16 +1469,51 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     nandtitle, nandimport, tmptitle, tmpimport, sdtitle, sdimport, ticket",
         "DB_TYPE",
     );
+    opts.optopt(
+        "",
+        "diff",
+        "compare the archive with the same archive in another SD or NAND root, \
+        or with another stand-alone or cartridge save file, instead of mounting",
+        "OTHER",
+    );
+    opts.optopt(
+        "",
+        "diff-format",
+        "output format of --diff, text by default",
+        "text|json",
+    );
     opts.optflag("x", "extract", "extract the content instead of mounting");
+    opts.optflag(
+        "",
+        "defrag",
+        "make the blocks of every file contiguous before mounting",
+    );
+    opts.optflag(
+        "",
+        "dry-run",
+        "keep all changes in memory, report the changed files at exit and discard them",
+    );
     opts.optopt(
         "f",
         "format",
-        "format the specified archive",
-        "[\"\"|param1:value1[,...]]",
+        "format the specified archive. \"same\" reuses the parameters of the existing archive",
+        "[\"\"|same|param1:value1[,...]]",
     );
     opts.optopt("g", "game", "cartridge ROM in CCI/NCSD format", "FILE");
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("i", "import", "import the content instead of mounting");
+    opts.optflagopt(
+        "",
+        "inactive",
+        "open the inactive image read-only and report blocks that differ from the active one",
+        "[previous|all|table,dpfs1,dpfs2,dpfs3]",
+    );
+    opts.optflagopt(
+        "",
+        "inspect",
+        "print the container headers and layout instead of mounting",
+        "[json|text]",
+    );
     opts.optopt(
         "k",
         "key",
@@ -1269,17 +1533,59 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         "HEX|FILE",
     );
     opts.optopt("m", "movable", "movable.sed file path", "FILE");
+    opts.optflag(
+        "",
+        "mmap",
+        "map files opened read-only into memory instead of reading them through the file handle",
+    );
     opts.optopt("", "nand", "NAND root path", "DIR");
     opts.optopt("", "nandext", "mount the NAND Extdata with the ID", "ID");
     opts.optopt("", "nandsave", "mount the NAND save with the ID", "ID");
     opts.optopt("o", "otp", "OTP file path", "FILE");
     opts.optopt("p", "priv", "cartridge private header path", "FILE");
     opts.optflag("r", "readonly", "mount as read-only file system");
+    opts.optopt(
+        "",
+        "repack",
+        "rebuild the save data with new format parameters, keeping its content",
+        "[\"\"|param1:value1[,...]]",
+    );
+    opts.optopt(
+        "",
+        "replay",
+        "replay the changes recorded by --trace on a new synthetic archive \
+        at the mount path instead of mounting, and exit with an error if any outcome differs",
+        "TRACE",
+    );
+    opts.optflag(
+        "",
+        "salvage",
+        "open a damaged archive ignoring signatures and hashes, and report broken blocks. \
+        Unless read-only, all hashes and signatures are then rebuilt",
+    );
     opts.optopt("", "sd", "SD root path", "DIR");
     opts.optopt("", "sdext", "mount the SD Extdata with the ID", "ID");
     opts.optopt("", "sdsave", "mount the SD save with the ID", "ID");
     opts.optflag("t", "touch", "just try opening and closing the archive");
+    opts.optopt(
+        "",
+        "trace",
+        "record the reads and writes on the archive files, and the changes to the archive, \
+        into FILE without any file content or name",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "trace-hash",
+        "also record a hash of the data of each read and write in --trace",
+    );
     opts.optflagmulti("v", "verbose", "more v for more verbose logging");
+    opts.optflag(
+        "",
+        "verify",
+        "hash every block of the archive on all CPU cores instead of mounting, \
+        and exit with an error on any broken block in use",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -1305,16 +1611,97 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     let touch = matches.opt_present("touch");
     let import = matches.opt_present("import");
     let extract = matches.opt_present("extract");
+    let check = matches.opt_present("check");
+    let verify = matches.opt_present("verify");
+    let inspect = if matches.opt_present("inspect") {
+        let format = matches
+            .opt_str("inspect")
+            .unwrap_or_else(|| "json".to_owned());
+        if format != "json" && format != "text" {
+            println!("Unknown inspect format {}", format);
+            return Ok(());
+        }
+        Some(format)
+    } else {
+        None
+    };
+
+    let replay = matches.opt_str("replay");
+    let diff_other = matches.opt_str("diff");
+    let diff_format = matches
+        .opt_str("diff-format")
+        .unwrap_or_else(|| "text".to_owned());
+    if diff_format != "json" && diff_format != "text" {
+        println!("Unknown diff format {}", diff_format);
+        return Ok(());
+    }
 
-    if touch as i32 + import as i32 + extract as i32 > 1 {
+    if touch as i32
+        + import as i32
+        + extract as i32
+        + check as i32
+        + verify as i32
+        + inspect.is_some() as i32
+        + diff_other.is_some() as i32
+        + replay.is_some() as i32
+        > 1
+    {
         println!(
             "At most one of the following can be specified:
-    --extract, --import, --touch "
+    --check, --diff, --extract, --import, --inspect, --replay, --touch, --verify "
         );
         return Ok(());
     }
 
-    let read_only = matches.opt_present("r") || extract || touch;
+    if let Some(replay) = replay {
+        if matches.free.len() != 1 {
+            println!("Please specify one path for the replayed archive");
+            return Ok(());
+        }
+        let trace = std::io::BufReader::new(std::fs::File::open(replay)?);
+        return print_replay_report(replay_trace(trace, &matches.free[0])?);
+    }
+
+    let inversion = if matches.opt_present("inactive") {
+        Some(to_selector_inversion(
+            &matches.opt_str("inactive").unwrap_or_default(),
+        )?)
+    } else {
+        None
+    };
+
+    let salvage = matches.opt_present("salvage");
+    if salvage && inversion.is_some() {
+        println!("At most one of --inactive and --salvage can be specified");
+        return Ok(());
+    }
+
+    if check && (salvage || inversion.is_some()) {
+        println!("--check can't be used with --inactive or --salvage");
+        return Ok(());
+    }
+
+    if verify && (salvage || inversion.is_some()) {
+        println!("--verify can't be used with --inactive or --salvage");
+        return Ok(());
+    }
+
+    if inspect.is_some() && (salvage || inversion.is_some()) {
+        println!("--inspect can't be used with --inactive or --salvage");
+        return Ok(());
+    }
+
+    if diff_other.is_some() && (salvage || inversion.is_some()) {
+        println!("--diff can't be used with --inactive or --salvage");
+        return Ok(());
+    }
+
+    if inversion.is_some() && import {
+        println!("--inactive can't be used with --import");
+        return Ok(());
+    }
+
+    let read_only = matches.opt_present("r") || extract || touch || inversion.is_some();
 
     let operation = if extract {
         FileSystemOperation::Extract
@@ -1326,12 +1713,13 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         FileSystemOperation::Mount(read_only)
     };
 
-    if matches.free.len() != 1 && !touch {
+    let no_mount = touch || check || verify || inspect.is_some() || diff_other.is_some();
+    if matches.free.len() != 1 && !no_mount {
         println!("Please specify one mount path");
         return Ok(());
     }
 
-    let mountpoint = if touch {
+    let mountpoint = if no_mount {
         std::path::Path::new("dummy")
     } else {
         std::path::Path::new(&matches.free[0])
@@ -1350,6 +1738,21 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     let nand_save_id = matches.opt_str("nandsave");
     let db_type = matches.opt_str("db");
     let format_param = matches.opt_str("format");
+    let repack_param = matches.opt_str("repack");
+    let defrag = matches.opt_present("defrag");
+    let dry_run = matches.opt_present("dry-run");
+    let mmap = matches.opt_present("mmap");
+    let trace = match matches.opt_str("trace") {
+        Some(path) => Some(Arc::new(Trace::new(
+            Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+            matches.opt_present("trace-hash"),
+        ))),
+        None => None,
+    };
+    let cache_size = matches
+        .opt_str("cache")
+        .map(|s| s.parse::<usize>())
+        .transpose()?;
     let priv_path = matches.opt_str("priv");
     let game_path = matches.opt_str("game");
     let x2f_key_y = matches.opt_str("key");
@@ -1360,7 +1763,7 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     let x19_key_x = x19_key_x.map(read_key).transpose()?;
     let x1a_key_x = x1a_key_x.map(read_key).transpose()?;
 
-    let format_param: Option<HashMap<String, String>> = format_param.map(|s| {
+    let parse_param = |s: String| -> HashMap<String, String> {
         s.split(',')
             .filter_map(|p| {
                 if let Some(mid) = p.find(':') {
@@ -1371,7 +1774,30 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
                 }
             })
             .collect()
-    });
+    };
+    let format_same = format_param.as_deref() == Some("same");
+    let format_param = format_param.map(parse_param);
+    let repack_param = repack_param.map(parse_param);
+
+    if format_param.is_some() && repack_param.is_some() {
+        println!("At most one of --format and --repack can be specified");
+        return Ok(());
+    }
+
+    if inversion.is_some() && (format_param.is_some() || repack_param.is_some()) {
+        println!("--inactive can't be used with --format or --repack");
+        return Ok(());
+    }
+
+    if salvage && (format_param.is_some() || repack_param.is_some()) {
+        println!("--salvage can't be used with --format or --repack");
+        return Ok(());
+    }
+
+    if defrag && (inversion.is_some() || salvage) {
+        println!("--defrag can't be used with --inactive or --salvage");
+        return Ok(());
+    }
 
     if [
         &sd_save_id,
@@ -1394,92 +1820,367 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    let resource = Resource::new(
-        boot9_path,
-        movable_path,
-        sd_path,
-        nand_path,
-        otp_path,
-        priv_path,
-        game_path,
-        x2f_key_y,
-        x19_key_x,
-        x1a_key_x,
-    )?;
+    let new_resource = |sd_path: Option<String>, nand_path: Option<String>| {
+        let mut resource = Resource::new(
+            boot9_path.clone(),
+            movable_path.clone(),
+            sd_path,
+            nand_path,
+            otp_path.clone(),
+            priv_path.clone(),
+            game_path.clone(),
+            x2f_key_y,
+            x19_key_x,
+            x1a_key_x,
+        )?;
+        if mmap {
+            resource.set_file_backend(FileBackend::Mmap);
+        }
+        Ok::<_, Error>(resource)
+    };
+    let mut resource = new_resource(sd_path.clone(), nand_path.clone())?;
+    let other_on_sd = sd_save_id.is_some()
+        || sd_ext_id.is_some()
+        || db_type.as_deref().is_some_and(|db| db.starts_with("sd"));
+    let other_resource = match &diff_other {
+        Some(_) if bare_path.is_some() || cart_path.is_some() => None,
+        Some(other) if other_on_sd => Some(new_resource(Some(other.clone()), nand_path.clone())?),
+        Some(other) => Some(new_resource(sd_path.clone(), Some(other.clone()))?),
+        None => None,
+    };
+    resource.set_overlay(dry_run);
+    resource.set_block_cache(cache_size.unwrap_or(0));
+    resource.set_trace(trace.clone());
 
     if let Some(bare) = bare_path {
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            let (param, len) = if format_same {
+                let save = resource.open_bare_save(&bare, false)?;
+                (save.format_param()?, save.image_len())
+            } else {
+                to_save_data_format_param(format_param, 512)?
+            };
             resource.format_bare_save(&bare, &param, len)?;
             println!("Formatting done");
         }
 
+        if let Some(repack_param) = repack_param {
+            println!("Repacking...");
+            let current = {
+                let save = resource.open_bare_save(&bare, false)?;
+                (save.format_param()?, save.image_len())
+            };
+            let (param, len) = to_save_data_repack_param(repack_param, current)?;
+            print_repack_report(resource.repack_bare_save(&bare, &param, len)?);
+        }
+
+        if defrag {
+            println!("Defragmenting...");
+            resource.open_bare_save(&bare, true)?.defragment()?;
+            println!("Defragmenting done");
+        }
+
         println!(
             "WARNING: After modification, you need to sign the CMAC header using other tools."
         );
 
-        start(
-            resource.open_bare_save(&bare, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+        if let Some(inversion) = inversion {
+            let save = resource.open_bare_save_inverted(&bare, inversion)?;
+            let reports = save.compare_generation(&resource.open_bare_save(&bare, false)?)?;
+            print_generation_report(
+                reports
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| (format!("Partition {}", i), r)),
+            );
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if salvage {
+            let save = resource.open_bare_save_salvage(&bare, !read_only)?;
+            print_salvage_report(&save.salvage_report()?);
+            if !read_only {
+                println!("Rehashing...");
+                save.rehash()?;
+                println!("Rehashing done");
+            }
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if let Some(other) = &diff_other {
+            let diff = diff_save(
+                &resource.open_bare_save(&bare, false)?,
+                &resource.open_bare_save(other, false)?,
+            )?;
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_bare_save(&bare, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_bare_save(&bare, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_bare_save(&bare, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_bare_save(&bare, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else if let Some(id) = nand_save_id {
         let id = u32::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 4096)?;
+            let (param, len) = if format_same {
+                let save = resource.open_nand_save(id, false)?;
+                (save.format_param()?, save.image_len())
+            } else {
+                to_save_data_format_param(format_param, 4096)?
+            };
             resource.format_nand_save(id, &param, len)?;
             println!("Formatting done");
         }
 
-        start(
-            resource.open_nand_save(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+        if let Some(repack_param) = repack_param {
+            println!("Repacking...");
+            let current = {
+                let save = resource.open_nand_save(id, false)?;
+                (save.format_param()?, save.image_len())
+            };
+            let (param, len) = to_save_data_repack_param(repack_param, current)?;
+            print_repack_report(resource.repack_nand_save(id, &param, len)?);
+        }
+
+        if defrag {
+            println!("Defragmenting...");
+            resource.open_nand_save(id, true)?.defragment()?;
+            println!("Defragmenting done");
+        }
+
+        if let Some(inversion) = inversion {
+            let save = resource.open_nand_save_inverted(id, inversion)?;
+            let reports = save.compare_generation(&resource.open_nand_save(id, false)?)?;
+            print_generation_report(
+                reports
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| (format!("Partition {}", i), r)),
+            );
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if salvage {
+            let save = resource.open_nand_save_salvage(id, !read_only)?;
+            print_salvage_report(&save.salvage_report()?);
+            if !read_only {
+                println!("Rehashing...");
+                save.rehash()?;
+                println!("Rehashing done");
+            }
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if let Some(other) = &other_resource {
+            let diff = diff_save(
+                &resource.open_nand_save(id, false)?,
+                &other.open_nand_save(id, false)?,
+            )?;
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_nand_save(id, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_nand_save(id, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_nand_save(id, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_nand_save(id, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else if let Some(id) = sd_save_id {
         let id = u64::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            let (param, len) = if format_same {
+                let save = resource.open_sd_save(id, false)?;
+                (save.format_param()?, save.image_len())
+            } else {
+                to_save_data_format_param(format_param, 512)?
+            };
             resource.format_sd_save(id, &param, len)?;
             println!("Formatting done");
         }
 
-        start(
-            resource.open_sd_save(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+        if let Some(repack_param) = repack_param {
+            println!("Repacking...");
+            let current = {
+                let save = resource.open_sd_save(id, false)?;
+                (save.format_param()?, save.image_len())
+            };
+            let (param, len) = to_save_data_repack_param(repack_param, current)?;
+            print_repack_report(resource.repack_sd_save(id, &param, len)?);
+        }
+
+        if defrag {
+            println!("Defragmenting...");
+            resource.open_sd_save(id, true)?.defragment()?;
+            println!("Defragmenting done");
+        }
+
+        if let Some(inversion) = inversion {
+            let save = resource.open_sd_save_inverted(id, inversion)?;
+            let reports = save.compare_generation(&resource.open_sd_save(id, false)?)?;
+            print_generation_report(
+                reports
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| (format!("Partition {}", i), r)),
+            );
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if salvage {
+            let save = resource.open_sd_save_salvage(id, !read_only)?;
+            print_salvage_report(&save.salvage_report()?);
+            if !read_only {
+                println!("Rehashing...");
+                save.rehash()?;
+                println!("Rehashing done");
+            }
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if let Some(other) = &other_resource {
+            let diff = diff_save(
+                &resource.open_sd_save(id, false)?,
+                &other.open_sd_save(id, false)?,
+            )?;
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_sd_save(id, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_sd_save(id, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_sd_save(id, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_sd_save(id, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else if let Some(id) = sd_ext_id {
         let id = u64::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let param = to_ext_data_format_param(format_param)?;
+            let param = if format_same {
+                resource.open_sd_ext(id, false)?.format_param()?
+            } else {
+                to_ext_data_format_param(format_param)?
+            };
             resource.format_sd_ext(id, &param)?;
             println!("Formatting done");
         }
+        if repack_param.is_some() {
+            println!("Warning: repacking not supported");
+        }
+        if defrag {
+            println!("Warning: defragmenting not supported");
+        }
 
-        start(resource.open_sd_ext(id, !read_only)?, operation, mountpoint)?
+        if let Some(inversion) = inversion {
+            let ext = resource.open_sd_ext_inverted(id, inversion)?;
+            let reports = ext.compare_generation(&resource.open_sd_ext(id, false)?)?;
+            print_generation_report(reports.iter().map(|(i, r)| (format!("File {:08x}", i), r)));
+            start(ext, operation, mountpoint, trace.as_ref())?
+        } else if salvage {
+            let ext = resource.open_sd_ext_salvage(id, !read_only)?;
+            print_salvage_report(&ext.salvage_report()?);
+            if !read_only {
+                println!("Rehashing...");
+                ext.rehash()?;
+                println!("Rehashing done");
+            }
+            start(ext, operation, mountpoint, trace.as_ref())?
+        } else if let Some(other) = &other_resource {
+            let diff = diff_ext(
+                &resource.open_sd_ext(id, false)?,
+                &other.open_sd_ext(id, false)?,
+            )?;
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_sd_ext(id, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_sd_ext(id, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_sd_ext(id, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_sd_ext(id, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else if let Some(id) = nand_ext_id {
         let id = u64::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let param = to_ext_data_format_param(format_param)?;
+            let param = if format_same {
+                resource.open_nand_ext(id, false)?.format_param()?
+            } else {
+                to_ext_data_format_param(format_param)?
+            };
             resource.format_nand_ext(id, &param)?;
             println!("Formatting done");
         }
+        if repack_param.is_some() {
+            println!("Warning: repacking not supported");
+        }
+        if defrag {
+            println!("Warning: defragmenting not supported");
+        }
 
-        start(
-            resource.open_nand_ext(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+        if let Some(inversion) = inversion {
+            let ext = resource.open_nand_ext_inverted(id, inversion)?;
+            let reports = ext.compare_generation(&resource.open_nand_ext(id, false)?)?;
+            print_generation_report(reports.iter().map(|(i, r)| (format!("File {:08x}", i), r)));
+            start(ext, operation, mountpoint, trace.as_ref())?
+        } else if salvage {
+            let ext = resource.open_nand_ext_salvage(id, !read_only)?;
+            print_salvage_report(&ext.salvage_report()?);
+            if !read_only {
+                println!("Rehashing...");
+                ext.rehash()?;
+                println!("Rehashing done");
+            }
+            start(ext, operation, mountpoint, trace.as_ref())?
+        } else if let Some(other) = &other_resource {
+            let diff = diff_ext(
+                &resource.open_nand_ext(id, false)?,
+                &other.open_nand_ext(id, false)?,
+            )?;
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_nand_ext(id, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_nand_ext(id, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_nand_ext(id, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_nand_ext(id, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else if let Some(db_type) = db_type {
         if format_param.is_some() {
             println!("Warning: formatting not supported");
         }
+        if repack_param.is_some() {
+            println!("Warning: repacking not supported");
+        }
+        if inversion.is_some() {
+            println!("Warning: --inactive not supported");
+        }
+        if salvage {
+            println!("Warning: --salvage not supported");
+        }
         let db_type = match db_type.as_ref() {
             "nandtitle" => DbType::NandTitle,
             "nandimport" => DbType::NandImport,
@@ -1494,33 +2195,135 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
-        start(
-            resource.open_db(db_type, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+        if defrag {
+            println!("Defragmenting...");
+            resource.open_db(db_type, true)?.defragment()?;
+            println!("Defragmenting done");
+        }
+
+        if let Some(other) = &other_resource {
+            let diff = diff_archives(
+                &resource.open_db(db_type, false)?,
+                &other.open_db(db_type, false)?,
+            )?;
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_db(db_type, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_db(db_type, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_db(db_type, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_db(db_type, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else if let Some(cart) = cart_path {
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            let (param, len) = if format_same {
+                let save = resource.open_cart_save(&cart, false)?;
+                (save.format_param()?, save.image_len())
+            } else {
+                to_save_data_format_param(format_param, 512)?
+            };
             resource.format_cart_save(&cart, &param, len)?;
             println!("Formatting done");
         }
-        start(
-            resource.open_cart_save(&cart, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+
+        if let Some(repack_param) = repack_param {
+            println!("Repacking...");
+            let current = {
+                let save = resource.open_cart_save(&cart, false)?;
+                (save.format_param()?, save.image_len())
+            };
+            let (param, len) = to_save_data_repack_param(repack_param, current)?;
+            print_repack_report(resource.repack_cart_save(&cart, &param, len)?);
+        }
+
+        if defrag {
+            println!("Defragmenting...");
+            resource.open_cart_save(&cart, true)?.defragment()?;
+            println!("Defragmenting done");
+        }
+        if let Some(inversion) = inversion {
+            let save = resource.open_cart_save_inverted(&cart, inversion)?;
+            let reports = save.compare_generation(&resource.open_cart_save(&cart, false)?)?;
+            print_generation_report(
+                reports
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| (format!("Partition {}", i), r)),
+            );
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if salvage {
+            let save = resource.open_cart_save_salvage(&cart, !read_only)?;
+            print_salvage_report(&save.salvage_report()?);
+            if !read_only {
+                println!("Rehashing...");
+                save.rehash()?;
+                println!("Rehashing done");
+            }
+            start(save, operation, mountpoint, trace.as_ref())?
+        } else if let Some(other) = &diff_other {
+            let old = resource.open_cart_save(&cart, false)?;
+            let new = resource.open_cart_save(other, false)?;
+            let mut diff = diff_archives(&old, &new)?;
+            diff_save_format(
+                &mut diff,
+                (old.format_param()?, old.image_len()),
+                (new.format_param()?, new.image_len()),
+            );
+            print_diff(&diff, &diff_format)
+        } else if check {
+            print_check_report(resource.open_cart_save(&cart, false)?.check()?)?
+        } else if verify {
+            print_verify_report(resource.open_cart_save(&cart, false)?.verify_all()?)?
+        } else if let Some(format) = &inspect {
+            print_inspect(&resource.open_cart_save(&cart, false)?.inspect()?, format)
+        } else {
+            start(
+                resource.open_cart_save(&cart, !read_only)?,
+                operation,
+                mountpoint,
+                trace.as_ref(),
+            )?
+        }
     } else {
         panic!()
     };
+
+    if dry_run {
+        print_overlay_changes(&resource.overlay_changes()?);
+    }
+    if cache_size.is_some() {
+        let stats = resource.block_cache_stats();
+        println!(
+            "Block cache: {} hits, {} misses, {} blocks written back",
+            stats.hits, stats.misses, stats.write_backs
+        );
+    }
+    if let Some(trace) = &trace {
+        trace.flush()?;
+    }
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let result = main_inner();
     if let Err(e) = &result {
-        println!("{}", e);
+        match e.downcast_ref::<Error>() {
+            Some(e) => {
+                for context in e.contexts() {
+                    print!("{}: ", context);
+                }
+                println!("{}", e);
+            }
+            None => println!("{}", e),
+        }
     }
     result
 }